@@ -0,0 +1,246 @@
+//! Readers for cpio (`newc`) and tar (`ustar`) archives, for loading files from the initrd
+//!
+//! Both are read in place: entries borrow their names and contents from the archive.
+
+use core::fmt::{Display, Formatter};
+
+/// File type bits of [`Entry::mode`], as in `st_mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+const NEWC_MAGIC: &[u8] = b"070701";
+/// The same as `newc`, plus a checksum we ignore
+const NEWC_CRC_MAGIC: &[u8] = b"070702";
+const NEWC_HEADER_LEN: usize = 110;
+const NEWC_TRAILER: &str = "TRAILER!!!";
+
+const USTAR_MAGIC: &[u8] = b"ustar";
+const USTAR_BLOCK_LEN: usize = 512;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Newc,
+    Ustar,
+}
+
+#[derive(Copy, Clone)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+    format: Format,
+}
+
+#[derive(Copy, Clone)]
+pub struct Entry<'a> {
+    /// Directory part of the path, only used by tar for long paths
+    prefix: &'a str,
+    name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> Archive<'a> {
+    /// Detects the archive format, returns `None` if it's neither cpio nor tar
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let format = if data.starts_with(NEWC_MAGIC) || data.starts_with(NEWC_CRC_MAGIC) {
+            Format::Newc
+        } else if data.get(257..262) == Some(USTAR_MAGIC) {
+            Format::Ustar
+        } else {
+            return None;
+        };
+        Some(Self { data, format })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            archive: *self,
+            offset: 0,
+        }
+    }
+
+    /// Finds an entry by path, e.g. `etc/motd`, ignoring leading `/` and `./`
+    pub fn find(&self, path: &str) -> Option<Entry<'a>> {
+        let path = normalize(path);
+        self.entries().find(|entry| entry.path_eq(path))
+    }
+}
+
+impl Entry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    /// Whether the entry's full path is `path`, which must already be normalized
+    pub fn path_eq(&self, path: &str) -> bool {
+        joined_eq(path, self.prefix, self.name)
+    }
+
+    /// Whether the entry is directly inside the directory `dir`, "" being the root
+    pub fn is_in_dir(&self, dir: &str) -> bool {
+        match self.name.rsplit_once('/') {
+            Some((parent, _)) => joined_eq(dir, self.prefix, parent),
+            None => dir == self.prefix,
+        }
+    }
+}
+
+/// Whether `path` is `dir/name`, or just `name` if `dir` is empty
+fn joined_eq(path: &str, dir: &str, name: &str) -> bool {
+    if dir.is_empty() {
+        return path == name;
+    }
+    path.strip_prefix(dir)
+        .and_then(|rest| rest.strip_prefix('/'))
+        == Some(name)
+}
+
+/// Displays the full path
+impl Display for Entry<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if !self.prefix.is_empty() {
+            write!(f, "{}/", self.prefix)?;
+        }
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Strips leading `/` and `./`, which archive tools add depending on how they were invoked
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if path == "." {
+            return "";
+        } else {
+            return path.trim_end_matches('/');
+        }
+    }
+}
+
+/// Parses a number field, which is padded with NULs or spaces in tar
+fn parse_num(field: &[u8], radix: u32) -> Option<u64> {
+    let field = core::str::from_utf8(field).ok()?;
+    let field = field.trim_matches(|c: char| c == '\0' || c == ' ');
+    if field.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(field, radix).ok()
+}
+
+/// Reads a NUL-terminated string from a fixed-size field
+fn parse_str(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).ok()
+}
+
+pub struct Entries<'a> {
+    archive: Archive<'a>,
+    offset: usize,
+}
+
+impl<'a> Entries<'a> {
+    /// Parses the entry at `self.offset`, returns `None` at the end of the archive or if it's
+    /// malformed
+    fn next_newc(&mut self) -> Option<Entry<'a>> {
+        let data = self.archive.data;
+        let header = data.get(self.offset..self.offset + NEWC_HEADER_LEN)?;
+        if !header.starts_with(NEWC_MAGIC) && !header.starts_with(NEWC_CRC_MAGIC) {
+            return None;
+        }
+        let field = |index: usize| parse_num(&header[6 + index * 8..14 + index * 8], 16);
+        let mode = field(1)? as u32;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // The name and the data are both padded to 4 bytes
+        let name_start = self.offset + NEWC_HEADER_LEN;
+        let name = parse_str(data.get(name_start..name_start + name_size)?)?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let file_data = data.get(data_start..data_start + file_size)?;
+        self.offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == NEWC_TRAILER {
+            return None;
+        }
+        Some(Entry {
+            prefix: "",
+            name: normalize(name),
+            mode,
+            data: file_data,
+        })
+    }
+
+    fn next_ustar(&mut self) -> Option<Entry<'a>> {
+        let data = self.archive.data;
+        let header = data.get(self.offset..self.offset + USTAR_BLOCK_LEN)?;
+        // The archive ends with zeroed blocks
+        if header[0] == 0 {
+            return None;
+        }
+        let name = parse_str(&header[0..100])?;
+        let perms = parse_num(&header[100..108], 8)? as u32 & 0o7777;
+        let file_size = parse_num(&header[124..136], 8)? as usize;
+        let type_flag = header[156];
+        let prefix = if &header[257..262] == USTAR_MAGIC {
+            parse_str(&header[345..500])?
+        } else {
+            ""
+        };
+
+        let data_start = self.offset + USTAR_BLOCK_LEN;
+        // Links, devices and directories have no data, whatever their size field says
+        let data_len = match type_flag {
+            b'1'..=b'6' => 0,
+            _ => file_size,
+        };
+        let file_data = data.get(data_start..data_start + data_len)?;
+        self.offset = data_start + data_len.next_multiple_of(USTAR_BLOCK_LEN);
+
+        let file_type = match type_flag {
+            b'0' | 0 | b'7' => S_IFREG,
+            b'2' => S_IFLNK,
+            b'5' => S_IFDIR,
+            // Skip pax and GNU extended headers, their attributes aren't needed
+            b'x' | b'g' | b'K' | b'L' => return self.next_ustar(),
+            // Hard links, devices and FIFOs
+            _ => 0,
+        };
+        Some(Entry {
+            prefix: normalize(prefix),
+            name: normalize(name),
+            mode: file_type | perms,
+            data: file_data,
+        })
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.archive.format {
+                Format::Newc => self.next_newc(),
+                Format::Ustar => self.next_ustar(),
+            };
+            match entry {
+                // Skip the entry for the root directory itself
+                Some(entry) if entry.prefix.is_empty() && entry.name.is_empty() => continue,
+                entry => return entry,
+            }
+        }
+    }
+}
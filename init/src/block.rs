@@ -0,0 +1,41 @@
+//! Block device interface, implemented by the initrd and disk drivers
+
+use kernel_api::KError;
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice {
+    /// Size of the device, in [`BLOCK_SIZE`] blocks
+    fn block_count(&self) -> u64;
+
+    fn read_only(&self) -> bool;
+
+    /// Reads whole blocks starting at `lba`, `buf.len()` must be a multiple of [`BLOCK_SIZE`]
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), KError>;
+
+    /// Writes whole blocks starting at `lba`, `buf.len()` must be a multiple of [`BLOCK_SIZE`]
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), KError>;
+
+    /// Makes the blocks written so far durable
+    fn flush(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+
+    /// Tells the device `count` blocks starting at `lba` are unused, their contents become
+    /// undefined
+    fn discard(&mut self, _lba: u64, _count: u64) -> Result<(), KError> {
+        Err(KError::NotSupported)
+    }
+}
+
+/// Checks that a request is made of whole blocks within the device
+pub fn check_request(dev: &impl BlockDevice, lba: u64, len: usize) -> Result<(), KError> {
+    if !len.is_multiple_of(BLOCK_SIZE) {
+        return Err(KError::InvalidArgument);
+    }
+    let block_count = (len / BLOCK_SIZE) as u64;
+    match lba.checked_add(block_count) {
+        Some(end) if end <= dev.block_count() => Ok(()),
+        _ => Err(KError::InvalidArgument),
+    }
+}
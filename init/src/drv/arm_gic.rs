@@ -5,7 +5,7 @@ use fdt_rs::{
 };
 use kernel_api::kernel_device;
 
-use crate::{get_msr, println, utils::load_kernel_device};
+use crate::{println, utils::load_kernel_device};
 
 #[allow(dead_code)]
 pub struct GicAndTimer {
@@ -102,12 +102,4 @@ impl GicAndTimer {
             timer_ppi_interrupt,
         })
     }
-
-    pub fn current_time_ms() -> u64 {
-        unsafe {
-            let ticks = get_msr!(cntpct_el0);
-            let freq = get_msr!(cntfrq_el0);
-            (ticks * 1000) / freq
-        }
-    }
 }
@@ -0,0 +1,298 @@
+//! QEMU firmware configuration device (fw_cfg), MMIO interface
+//!
+//! Items are selected by a 16-bit key and then read as a byte stream from the data register, or
+//! copied by the DMA interface if the device supports it. Writing, e.g. to `etc/ramfb`, needs
+//! the DMA interface. Numbers in the fixed items are little-endian, while the file directory and
+//! the registers are big-endian. See `docs/specs/fw_cfg.rst` in the QEMU sources.
+
+use crate::dtb;
+use crate::println;
+use crate::utils::{dma_alloc, map_mmio, PAGE_SIZE};
+use core::sync::atomic::{fence, Ordering};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::KError;
+
+/// Data register, reads the selected item
+const REG_DATA: usize = 0x00;
+/// Selector register, 16-bit big-endian key
+const REG_SELECTOR: usize = 0x08;
+/// DMA address register, writing the big-endian address of a [`DmaAccess`] starts the transfer
+const REG_DMA: usize = 0x10;
+
+pub const FW_CFG_SIGNATURE: u16 = 0x00;
+pub const FW_CFG_ID: u16 = 0x01;
+pub const FW_CFG_INITRD_SIZE: u16 = 0x0b;
+pub const FW_CFG_INITRD_DATA: u16 = 0x12;
+pub const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+pub const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+pub const FW_CFG_FILE_DIR: u16 = 0x19;
+
+const SIGNATURE: &[u8; 4] = b"QEMU";
+/// Feature bit in `FW_CFG_ID`
+const ID_DMA: u32 = 1 << 1;
+
+const DMA_CTL_ERROR: u32 = 1 << 0;
+const DMA_CTL_READ: u32 = 1 << 1;
+const DMA_CTL_SKIP: u32 = 1 << 2;
+const DMA_CTL_SELECT: u32 = 1 << 3;
+const DMA_CTL_WRITE: u32 = 1 << 4;
+
+/// Size of the bounce buffer for DMA transfers
+const DMA_BUF_SIZE: usize = 64 * 1024;
+
+const FILE_NAME_LEN: usize = 56;
+/// Size of a file directory entry: size, select, reserved and name
+const FILE_ENTRY_SIZE: usize = 4 + 2 + 2 + FILE_NAME_LEN;
+
+/// A DMA request, all fields are big-endian
+#[repr(C)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+struct DmaBuffer {
+    access: *mut DmaAccess,
+    access_phys: usize,
+    data: *mut u8,
+    data_phys: usize,
+}
+
+/// An entry of the fw_cfg file directory, e.g. `etc/ramfb` or `opt/...` from `-fw_cfg`
+#[derive(Copy, Clone)]
+pub struct File {
+    pub size: u32,
+    pub select: u16,
+    name: [u8; FILE_NAME_LEN],
+}
+
+impl File {
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(FILE_NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+pub struct FwCfg {
+    base: *mut u8,
+    dma: Option<DmaBuffer>,
+}
+
+impl FwCfg {
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(node) = dtb::find_compatible(dtb, "qemu,fw-cfg-mmio")? else {
+            return Ok(None);
+        };
+        let (base, len) = dtb::reg(&node)?.expect("fw_cfg node has no reg property");
+        let base = unsafe { map_mmio(base as usize, len as usize) }.expect("Failed to map fw_cfg");
+
+        let mut fw_cfg = FwCfg { base, dma: None };
+        let mut signature = [0u8; 4];
+        fw_cfg.pio_read(FW_CFG_SIGNATURE, 0, &mut signature);
+        if &signature != SIGNATURE {
+            println!("fw_cfg signature mismatch: {:?}", signature);
+            return Ok(None);
+        }
+
+        let mut id = [0u8; 4];
+        fw_cfg.pio_read(FW_CFG_ID, 0, &mut id);
+        if u32::from_le_bytes(id) & ID_DMA != 0 {
+            let (buf, buf_phys) = unsafe { dma_alloc(PAGE_SIZE + DMA_BUF_SIZE) }
+                .expect("Failed to allocate fw_cfg DMA buffer");
+            fw_cfg.dma = Some(DmaBuffer {
+                access: buf as *mut DmaAccess,
+                access_phys: buf_phys,
+                data: unsafe { buf.add(PAGE_SIZE) },
+                data_phys: buf_phys + PAGE_SIZE,
+            });
+        }
+        Ok(Some(fw_cfg))
+    }
+
+    pub fn has_dma(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    /// Reads `buf.len()` bytes of the item `key`, starting `offset` bytes into it
+    pub fn read(&self, key: u16, offset: usize, buf: &mut [u8]) -> Result<(), KError> {
+        match &self.dma {
+            Some(dma) => self.dma_read(dma, key, offset, buf),
+            None => {
+                self.pio_read(key, offset, buf);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `data` to the start of the item `key`, which has to be a writable file
+    pub fn write(&self, key: u16, data: &[u8]) -> Result<(), KError> {
+        let Some(dma) = &self.dma else {
+            return Err(KError::NotSupported);
+        };
+        if data.len() > DMA_BUF_SIZE {
+            return Err(KError::InvalidArgument);
+        }
+        unsafe { core::slice::from_raw_parts_mut(dma.data, data.len()) }.copy_from_slice(data);
+        let control = ((key as u32) << 16) | DMA_CTL_SELECT | DMA_CTL_WRITE;
+        self.dma_transfer(dma, control, data.len() as u32)
+    }
+
+    /// Reads a little-endian number, like the size items
+    pub fn read_u32(&self, key: u16) -> Result<u32, KError> {
+        let mut buf = [0u8; 4];
+        self.read(key, 0, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Reads through the data register, one 64-bit word at a time
+    fn pio_read(&self, key: u16, offset: usize, buf: &mut [u8]) {
+        unsafe {
+            (self.base.add(REG_SELECTOR) as *mut u16).write_volatile(key.to_be());
+        }
+        let data = unsafe { self.base.add(REG_DATA) };
+        for _ in 0..offset {
+            unsafe { data.read_volatile() };
+        }
+        // Wider reads return the next bytes in order, regardless of endianness
+        let mut chunks = buf.chunks_exact_mut(8);
+        for chunk in &mut chunks {
+            let word = unsafe { (data as *const u64).read_volatile() };
+            chunk.copy_from_slice(&word.to_ne_bytes());
+        }
+        for byte in chunks.into_remainder() {
+            *byte = unsafe { data.read_volatile() };
+        }
+    }
+
+    /// Reads through the DMA bounce buffer, in chunks of [`DMA_BUF_SIZE`]
+    fn dma_read(
+        &self,
+        dma: &DmaBuffer,
+        key: u16,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), KError> {
+        let offset = u32::try_from(offset).map_err(|_| KError::InvalidArgument)?;
+        // Only the first transfer selects the item, the others continue where it stopped
+        let mut control = ((key as u32) << 16) | DMA_CTL_SELECT;
+        if offset != 0 {
+            self.dma_transfer(dma, control | DMA_CTL_SKIP, offset)?;
+            control = 0;
+        }
+        for chunk in buf.chunks_mut(DMA_BUF_SIZE) {
+            self.dma_transfer(dma, control | DMA_CTL_READ, chunk.len() as u32)?;
+            control = 0;
+            let data = unsafe { core::slice::from_raw_parts(dma.data, chunk.len()) };
+            chunk.copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn dma_transfer(&self, dma: &DmaBuffer, control: u32, length: u32) -> Result<(), KError> {
+        unsafe {
+            dma.access.write_volatile(DmaAccess {
+                control: control.to_be(),
+                length: length.to_be(),
+                address: (dma.data_phys as u64).to_be(),
+            });
+            // The request must be visible before the device reads it
+            fence(Ordering::SeqCst);
+            (self.base.add(REG_DMA) as *mut u64).write_volatile((dma.access_phys as u64).to_be());
+
+            // QEMU completes the transfer right away, but the spec wants us to wait for the
+            // control field to clear
+            let control = &raw const (*dma.access).control;
+            loop {
+                let control = u32::from_be(control.read_volatile());
+                if control & DMA_CTL_ERROR != 0 {
+                    return Err(KError::InvalidArgument);
+                }
+                if control == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            fence(Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Iterates over the file directory
+    pub fn files(&self) -> Result<Files<'_>, KError> {
+        let mut count = [0u8; 4];
+        self.read(FW_CFG_FILE_DIR, 0, &mut count)?;
+        Ok(Files {
+            fw_cfg: self,
+            index: 0,
+            count: u32::from_be_bytes(count),
+        })
+    }
+
+    pub fn find_file(&self, name: &str) -> Result<Option<File>, KError> {
+        for file in self.files()? {
+            let file = file?;
+            if file.name() == name {
+                return Ok(Some(file));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the command line given with `-append`, if QEMU passed the kernel through fw_cfg
+    pub fn cmdline<'a>(&self, buf: &'a mut [u8]) -> Result<Option<&'a str>, KError> {
+        let size = self.read_u32(FW_CFG_CMDLINE_SIZE)? as usize;
+        if size == 0 {
+            return Ok(None);
+        }
+        let len = size.min(buf.len());
+        let buf = &mut buf[..len];
+        self.read(FW_CFG_CMDLINE_DATA, 0, buf)?;
+        // The size includes the NUL terminator
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(core::str::from_utf8(&buf[..len]).ok())
+    }
+
+    /// Size of the initrd given with `-initrd`, 0 if there's none in fw_cfg
+    pub fn initrd_size(&self) -> Result<u32, KError> {
+        self.read_u32(FW_CFG_INITRD_SIZE)
+    }
+
+    pub fn read_initrd(&self, offset: usize, buf: &mut [u8]) -> Result<(), KError> {
+        self.read(FW_CFG_INITRD_DATA, offset, buf)
+    }
+}
+
+pub struct Files<'a> {
+    fw_cfg: &'a FwCfg,
+    index: u32,
+    count: u32,
+}
+
+impl Iterator for Files<'_> {
+    type Item = Result<File, KError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+        let mut entry = [0u8; FILE_ENTRY_SIZE];
+        let offset = 4 + self.index as usize * FILE_ENTRY_SIZE;
+        self.index += 1;
+        if let Err(err) = self.fw_cfg.read(FW_CFG_FILE_DIR, offset, &mut entry) {
+            return Some(Err(err));
+        }
+        let mut name = [0u8; FILE_NAME_LEN];
+        name.copy_from_slice(&entry[8..]);
+        Some(Ok(File {
+            size: u32::from_be_bytes(entry[0..4].try_into().unwrap()),
+            select: u16::from_be_bytes(entry[4..6].try_into().unwrap()),
+            name,
+        }))
+    }
+}
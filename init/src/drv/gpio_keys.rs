@@ -0,0 +1,129 @@
+//! Keys and buttons wired to GPIO pins, the `gpio-keys` binding
+//!
+//! Every key is a child of the `gpio-keys` node, with its pin in `gpios` and the Linux key code
+//! it sends in `linux,code`. fdt-rs doesn't tell where a node ends, so they're found as the nodes
+//! that have both, rather than as children. On QEMU's `virt` machine there's a single one, the
+//! power button, pressed by `system_powerdown` in the monitor.
+
+use crate::drv::pl061::{Direction, Pl061, Trigger};
+use crate::dtb;
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use fdt_rs::prelude::{FallibleIterator, PropReader};
+
+pub const KEY_POWER: u32 = 116;
+
+const MAX_KEYS: usize = 8;
+const MAX_LABEL_LEN: usize = 32;
+/// Flag in the second cell of a `gpios` specifier
+const GPIO_ACTIVE_LOW: u32 = 1;
+
+#[derive(Copy, Clone)]
+pub struct Key {
+    /// The Linux key code it sends
+    pub code: u32,
+    pub pin: u8,
+    active_low: bool,
+    pressed: bool,
+    label: [u8; MAX_LABEL_LEN],
+    label_len: usize,
+}
+
+impl Key {
+    pub fn label(&self) -> &str {
+        core::str::from_utf8(&self.label[..self.label_len]).unwrap_or("")
+    }
+
+    pub fn pressed(&self) -> bool {
+        self.pressed
+    }
+}
+
+pub struct GpioKeys {
+    keys: [Option<Key>; MAX_KEYS],
+}
+
+impl GpioKeys {
+    /// Finds the keys wired to `gpio` and makes their pins raise interrupts. Returns `None` if
+    /// there are none.
+    pub fn find_and_init(dtb: &DevTree, gpio: &Pl061) -> Result<Option<Self>, DevTreeError> {
+        if dtb::find_compatible(dtb, "gpio-keys")?.is_none() {
+            return Ok(None);
+        }
+        let mut keys = [None; MAX_KEYS];
+        let mut count = 0;
+        let mut nodes = dtb.nodes();
+        while let Some(node) = nodes.next()? {
+            if count == MAX_KEYS {
+                break;
+            }
+            let (Some(gpios), Some(code)) = (
+                dtb::find_prop(&node, "gpios")?,
+                dtb::find_prop(&node, "linux,code")?,
+            ) else {
+                continue;
+            };
+            if !gpio.is_phandle(gpios.u32(0)?) {
+                continue;
+            }
+            let Ok(pin) = u8::try_from(gpios.u32(1)?) else {
+                continue;
+            };
+            let mut label = [0u8; MAX_LABEL_LEN];
+            let mut label_len = 0;
+            if let Some(prop) = dtb::find_prop(&node, "label")? {
+                let name = prop.str()?.as_bytes();
+                label_len = name.len().min(MAX_LABEL_LEN);
+                label[..label_len].copy_from_slice(&name[..label_len]);
+            }
+            let key = Key {
+                code: code.u32(0)?,
+                pin,
+                active_low: gpios.u32(2)? & GPIO_ACTIVE_LOW != 0,
+                pressed: false,
+                label,
+                label_len,
+            };
+            // Both edges, to see releases too
+            if gpio.set_direction(pin, Direction::Input).is_err()
+                || gpio.set_interrupt(pin, Some(Trigger::BothEdges)).is_err()
+            {
+                continue;
+            }
+            keys[count] = Some(Key {
+                pressed: gpio.value(pin) == Ok(!key.active_low),
+                ..key
+            });
+            count += 1;
+        }
+        Ok((count != 0).then_some(Self { keys }))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.keys.iter().flatten()
+    }
+
+    /// The key on `pin`, if there's one
+    pub fn key(&self, pin: u8) -> Option<&Key> {
+        self.keys().find(|key| key.pin == pin)
+    }
+
+    /// Acknowledges the interrupts of the keys, and calls `f` for each one that was pressed or
+    /// released since, with whether it's pressed now
+    pub fn poll(&mut self, gpio: &Pl061, mut f: impl FnMut(&Key, bool)) {
+        let pending = gpio.pending();
+        for key in self.keys.iter_mut().flatten() {
+            if pending & 1 << key.pin == 0 {
+                continue;
+            }
+            gpio.clear(1 << key.pin);
+            let pressed = gpio.value(key.pin) == Ok(!key.active_low);
+            if pressed == key.pressed {
+                // Both edges came since the last poll, e.g. a short tap
+                f(key, !pressed);
+            }
+            key.pressed = pressed;
+            f(key, pressed);
+        }
+    }
+}
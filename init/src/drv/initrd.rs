@@ -0,0 +1,68 @@
+//! The initial ramdisk loaded by QEMU's `-initrd`, found through `/chosen` in the DTB
+
+use crate::block::{check_request, BlockDevice, BLOCK_SIZE};
+use crate::dtb;
+use crate::utils::{phy_map, reserve_memory, PAGE_SIZE};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::{KError, PhyMapFlags};
+
+pub struct Initrd {
+    phy_addr: usize,
+    data: &'static [u8],
+}
+
+impl Initrd {
+    /// Finds the initrd and protects it from the kernel's page allocator, which has to happen
+    /// right after the kernel was told about RAM
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some((start, end)) = dtb::initrd(dtb)? else {
+            return Ok(None);
+        };
+        let (phy_addr, len) = (start as usize, (end - start) as usize);
+        if len == 0 {
+            return Ok(None);
+        }
+        reserve_memory(phy_addr, len).expect("Failed to reserve the initrd");
+
+        let page_offset = phy_addr % PAGE_SIZE;
+        let map_len = (page_offset + len).next_multiple_of(PAGE_SIZE);
+        let base = unsafe { phy_map(phy_addr - page_offset, map_len, PhyMapFlags::empty()) }
+            .expect("Failed to map the initrd");
+        let data =
+            unsafe { core::slice::from_raw_parts((base as *const u8).add(page_offset), len) };
+        Ok(Some(Initrd { phy_addr, data }))
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+impl BlockDevice for Initrd {
+    fn block_count(&self) -> u64 {
+        self.data.len().div_ceil(BLOCK_SIZE) as u64
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), KError> {
+        check_request(self, lba, buf.len())?;
+        // The last block is zero-padded
+        let start = lba as usize * BLOCK_SIZE;
+        let len = buf.len().min(self.data.len() - start);
+        buf[..len].copy_from_slice(&self.data[start..start + len]);
+        buf[len..].fill(0);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, _lba: u64, _buf: &[u8]) -> Result<(), KError> {
+        Err(KError::NotSupported)
+    }
+}
@@ -0,0 +1,17 @@
+pub mod arm_gic;
+pub mod fw_cfg;
+pub mod gpio_keys;
+pub mod initrd;
+pub mod nvme;
+pub mod pl011;
+pub mod pl031;
+pub mod pl061;
+pub mod psci;
+pub mod ramfb;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtio_gpu;
+pub mod virtio_input;
+pub mod virtio_net;
+pub mod virtio_rng;
@@ -0,0 +1,623 @@
+//! NVMe controllers on the PCI bus, e.g. QEMU's `-device nvme`
+//!
+//! The first controller found by its class code is claimed for good. It gets an admin and an
+//! I/O queue pair, each queue a page of DMA memory, and commands go one at a time: submitted,
+//! then waited for on the function's INTx interrupt. Callers' buffers aren't DMA memory, so
+//! transfers go through a bounce buffer that a PRP list describes page by page. Every active
+//! namespace is a block device.
+
+use crate::block::{check_request, BlockDevice, BLOCK_SIZE};
+use crate::pci::device::PciDevice;
+use crate::pci::host::PciBus;
+use crate::time;
+use crate::utils::{dma_alloc, PAGE_SIZE};
+use core::sync::atomic::{fence, Ordering};
+use kernel_api::clock::ClockId;
+use kernel_api::KError;
+
+/// Class, subclass and programming interface of NVMe controllers
+const NVME_CLASS: (u8, u8, u8) = (0x01, 0x08, 0x02);
+pub const MAX_NAMESPACES: usize = 4;
+/// Entries per queue at most, 64-byte submissions fill a page
+const QUEUE_ENTRIES: usize = 64;
+const BOUNCE_SIZE: usize = 128 * 1024;
+const COMMAND_TIMEOUT_NS: u64 = 5_000_000_000;
+/// How long to wait for the interrupt before checking the completion queue anyway
+const INTERRUPT_POLL_NS: u64 = 10_000_000;
+
+/// Controller registers
+const CAP: usize = 0x00;
+const VS: usize = 0x08;
+const INTMC: usize = 0x10;
+const CC: usize = 0x14;
+const CSTS: usize = 0x1c;
+const AQA: usize = 0x24;
+const ASQ: usize = 0x28;
+const ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+/// `CAP` fields
+const CAP_MQES: u64 = 0xffff;
+const CAP_CSS_NVM: u64 = 1 << 37;
+/// `CC` fields: 64-byte submissions and 16-byte completions, 4 KiB pages
+const CC_ENABLE: u32 = 1 << 0;
+const CC_SHUTDOWN_NORMAL: u32 = 1 << 14;
+const CC_SHUTDOWN_MASK: u32 = 0x3 << 14;
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+/// `CSTS` fields
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+const CSTS_SHUTDOWN_MASK: u32 = 0x3 << 2;
+const CSTS_SHUTDOWN_COMPLETE: u32 = 0x2 << 2;
+
+/// Admin command opcodes
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+/// NVM command opcodes
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+
+/// What `ADMIN_IDENTIFY` returns
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// Creating queues: physically contiguous, and for completions, raising interrupts
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+
+const ADMIN_QUEUE: u16 = 0;
+const IO_QUEUE: u16 = 1;
+
+/// A submission queue entry, as dwords: opcode and command ID in 0, namespace in 1, the data
+/// pointer in 6 to 9, and the command's own fields from 10
+type Command = [u32; 16];
+
+fn command(opcode: u8, nsid: u32) -> Command {
+    let mut command = [0; 16];
+    command[0] = opcode as u32;
+    command[1] = nsid;
+    command
+}
+
+fn set_data(command: &mut Command, prp1: u64, prp2: u64) {
+    command[6] = prp1 as u32;
+    command[7] = (prp1 >> 32) as u32;
+    command[8] = prp2 as u32;
+    command[9] = (prp2 >> 32) as u32;
+}
+
+/// The error of a completion's status field: status code type in bits 10:8, code in 7:0
+fn status_to_kerror(status: u16) -> KError {
+    match ((status >> 8) & 0x7, status & 0xff) {
+        // Invalid opcode
+        (0, 0x01) => KError::NotSupported,
+        // Invalid field, invalid namespace, LBA out of range
+        (0, 0x02 | 0x0b | 0x80) => KError::InvalidArgument,
+        _ => KError::IoError,
+    }
+}
+
+/// Zeroed, physically contiguous memory the controller reads and writes
+struct DmaBuf {
+    virt: *mut u8,
+    phy_addr: u64,
+}
+
+impl DmaBuf {
+    fn new(len: usize) -> Result<Self, KError> {
+        let (virt, phy_addr) = unsafe { dma_alloc(len) }?;
+        Ok(Self {
+            virt,
+            phy_addr: phy_addr as u64,
+        })
+    }
+
+    fn slice(&self, len: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt, len) }
+    }
+
+    fn slice_mut(&mut self, len: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt, len) }
+    }
+}
+
+/// The controller's registers, in BAR 0
+#[derive(Copy, Clone)]
+struct Registers {
+    base: *mut u8,
+    doorbell_stride: usize,
+}
+
+impl Registers {
+    fn read32(&self, reg: usize) -> u32 {
+        unsafe { (self.base.add(reg) as *mut u32).read_volatile() }
+    }
+
+    fn read64(&self, reg: usize) -> u64 {
+        self.read32(reg) as u64 | (self.read32(reg + 4) as u64) << 32
+    }
+
+    fn write32(&self, reg: usize, val: u32) {
+        unsafe { (self.base.add(reg) as *mut u32).write_volatile(val) }
+    }
+
+    fn write64(&self, reg: usize, val: u64) {
+        self.write32(reg, val as u32);
+        self.write32(reg + 4, (val >> 32) as u32);
+    }
+
+    /// Tells the controller about a new submission queue tail, or completion queue head
+    fn ring(&self, queue: u16, completion: bool, index: usize) {
+        let doorbell = 2 * queue as usize + completion as usize;
+        self.write32(DOORBELLS + doorbell * self.doorbell_stride, index as u32);
+    }
+
+    /// Waits for the bits of `mask` in `CSTS` to become `value`
+    fn wait_status(&self, mask: u32, value: u32, timeout_ns: u64) -> Result<(), KError> {
+        let deadline = time::now(ClockId::Monotonic).saturating_add(timeout_ns);
+        loop {
+            let status = self.read32(CSTS);
+            if status & mask == value {
+                return Ok(());
+            }
+            if status & CSTS_FATAL != 0 {
+                return Err(KError::IoError);
+            }
+            if time::now(ClockId::Monotonic) >= deadline {
+                return Err(KError::TimedOut);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+struct Completion {
+    result: u32,
+    id: u16,
+    /// 0 on success
+    status: u16,
+}
+
+/// A submission queue and the completion queue it reports to
+struct QueuePair {
+    id: u16,
+    submissions: DmaBuf,
+    completions: DmaBuf,
+    entries: usize,
+    tail: usize,
+    head: usize,
+    /// Phase tag of new completions, it flips on every pass over the queue
+    phase: bool,
+    next_id: u16,
+}
+
+impl QueuePair {
+    fn new(id: u16) -> Result<Self, KError> {
+        Ok(Self {
+            id,
+            submissions: DmaBuf::new(PAGE_SIZE)?,
+            completions: DmaBuf::new(PAGE_SIZE)?,
+            entries: QUEUE_ENTRIES,
+            tail: 0,
+            head: 0,
+            phase: true,
+            next_id: 0,
+        })
+    }
+
+    /// Writes a command at the tail, returns its ID
+    fn push(&mut self, mut command: Command) -> u16 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        command[0] |= (id as u32) << 16;
+        unsafe {
+            (self.submissions.virt as *mut Command)
+                .add(self.tail)
+                .write_volatile(command)
+        };
+        self.tail = (self.tail + 1) % self.entries;
+        id
+    }
+
+    /// Takes the completion at the head, if the controller wrote it
+    fn pop(&mut self) -> Option<Completion> {
+        let entry = unsafe { (self.completions.virt as *const u32).add(self.head * 4) };
+        let status = unsafe { entry.add(3).read_volatile() };
+        if (status & 1 << 16 != 0) != self.phase {
+            return None;
+        }
+        // The phase tag is written last, the rest can only be read after it
+        fence(Ordering::SeqCst);
+        let completion = Completion {
+            result: unsafe { entry.read_volatile() },
+            id: status as u16,
+            status: (status >> 17) as u16,
+        };
+        self.head += 1;
+        if self.head == self.entries {
+            self.head = 0;
+            self.phase = !self.phase;
+        }
+        Some(completion)
+    }
+}
+
+/// An active namespace
+#[derive(Copy, Clone, Debug)]
+pub struct Namespace {
+    pub id: u32,
+    /// Size in logical blocks
+    size: u64,
+    /// log2 of the logical block size
+    lba_shift: u32,
+    read_only: bool,
+}
+
+impl Namespace {
+    pub fn lba_size(&self) -> usize {
+        1 << self.lba_shift
+    }
+
+    /// Size in [`BLOCK_SIZE`] blocks
+    pub fn block_count(&self) -> u64 {
+        self.size << self.lba_shift >> BLOCK_SIZE.trailing_zeros()
+    }
+}
+
+pub struct NvmeController {
+    device: PciDevice,
+    regs: Registers,
+    /// `CAP.TO`, how long enabling and shutting down may take
+    ready_timeout_ns: u64,
+    admin: QueuePair,
+    io: QueuePair,
+    bounce: DmaBuf,
+    /// Pointers to the pages of the bounce buffer after the first
+    prp_list: DmaBuf,
+    /// The largest transfer in a single command
+    max_transfer: usize,
+    volatile_write_cache: bool,
+    version: u32,
+    serial: [u8; 20],
+    model: [u8; 40],
+    firmware: [u8; 8],
+    namespaces: [Option<Namespace>; MAX_NAMESPACES],
+}
+
+impl NvmeController {
+    /// Claims and resets the first NVMe controller on the bus, and finds its namespaces
+    pub fn find_and_init(pci: &mut PciBus) -> Result<Option<Self>, KError> {
+        let Some(address) = pci
+            .functions()
+            .find(|function| function.class() == NVME_CLASS && !pci.is_claimed(function.address()))
+            .map(|function| function.address())
+        else {
+            return Ok(None);
+        };
+        let admin = QueuePair::new(ADMIN_QUEUE)?;
+        let io = QueuePair::new(IO_QUEUE)?;
+        let bounce = DmaBuf::new(BOUNCE_SIZE)?;
+        let mut prp_list = DmaBuf::new(PAGE_SIZE)?;
+        for (i, entry) in prp_list
+            .slice_mut(PAGE_SIZE)
+            .chunks_exact_mut(8)
+            .enumerate()
+        {
+            let page = bounce.phy_addr + ((i + 1) * PAGE_SIZE) as u64;
+            if page < bounce.phy_addr + BOUNCE_SIZE as u64 {
+                entry.copy_from_slice(&page.to_le_bytes());
+            }
+        }
+
+        let mut device = pci.claim(address)?;
+        let regs = match device.map_bar(0) {
+            Ok(base) => Registers {
+                base,
+                doorbell_stride: 4,
+            },
+            Err(err) => {
+                pci.release(device);
+                return Err(err);
+            }
+        };
+        let mut nvme = Self {
+            device,
+            regs,
+            ready_timeout_ns: 0,
+            admin,
+            io,
+            bounce,
+            prp_list,
+            max_transfer: BOUNCE_SIZE,
+            volatile_write_cache: false,
+            version: 0,
+            serial: [0; 20],
+            model: [0; 40],
+            firmware: [0; 8],
+            namespaces: [None; MAX_NAMESPACES],
+        };
+        if let Err(err) = nvme.init() {
+            pci.release(nvme.device);
+            return Err(err);
+        }
+        Ok(Some(nvme))
+    }
+
+    fn init(&mut self) -> Result<(), KError> {
+        let cap = self.regs.read64(CAP);
+        // Only the NVM command set, and our 4 KiB pages
+        if cap & CAP_CSS_NVM == 0 || (cap >> 48) & 0xf != 0 {
+            return Err(KError::NotSupported);
+        }
+        self.regs.doorbell_stride = 4 << ((cap >> 32) & 0xf);
+        self.ready_timeout_ns = ((cap >> 24) & 0xff).max(1) * 500_000_000;
+        let entries = QUEUE_ENTRIES.min((cap & CAP_MQES) as usize + 1);
+        self.admin.entries = entries;
+        self.io.entries = entries;
+        self.version = self.regs.read32(VS);
+
+        // Reset, the admin queues can only be set while it's disabled
+        let cc = self.regs.read32(CC);
+        self.regs.write32(CC, cc & !CC_ENABLE);
+        self.regs
+            .wait_status(CSTS_READY, 0, self.ready_timeout_ns)?;
+        let size = entries as u32 - 1;
+        self.regs.write32(AQA, size << 16 | size);
+        self.regs.write64(ASQ, self.admin.submissions.phy_addr);
+        self.regs.write64(ACQ, self.admin.completions.phy_addr);
+        self.regs.write32(CC, CC_IOCQES | CC_IOSQES | CC_ENABLE);
+        self.regs
+            .wait_status(CSTS_READY, CSTS_READY, self.ready_timeout_ns)?;
+        // Unmask the interrupt, INTx is vector 0
+        self.regs.write32(INTMC, 1);
+
+        self.identify(IDENTIFY_CONTROLLER, 0)?;
+        let data = self.bounce.slice(PAGE_SIZE);
+        self.serial.copy_from_slice(&data[4..24]);
+        self.model.copy_from_slice(&data[24..64]);
+        self.firmware.copy_from_slice(&data[64..72]);
+        // In units of the minimum page size, 0 for no limit
+        let mdts = data[77];
+        self.volatile_write_cache = data[525] & 1 != 0;
+        if mdts != 0 && (mdts as u32) < usize::BITS - PAGE_SIZE.trailing_zeros() {
+            self.max_transfer = self.max_transfer.min(PAGE_SIZE << mdts);
+        }
+
+        // One I/O queue pair, the counts are 0-based
+        let mut set_features = command(ADMIN_SET_FEATURES, 0);
+        set_features[10] = FEATURE_NUMBER_OF_QUEUES;
+        self.execute(ADMIN_QUEUE, set_features)?;
+        let mut create_cq = command(ADMIN_CREATE_IO_CQ, 0);
+        set_data(&mut create_cq, self.io.completions.phy_addr, 0);
+        create_cq[10] = size << 16 | IO_QUEUE as u32;
+        create_cq[11] = QUEUE_INTERRUPTS | QUEUE_CONTIGUOUS;
+        self.execute(ADMIN_QUEUE, create_cq)?;
+        let mut create_sq = command(ADMIN_CREATE_IO_SQ, 0);
+        set_data(&mut create_sq, self.io.submissions.phy_addr, 0);
+        create_sq[10] = size << 16 | IO_QUEUE as u32;
+        create_sq[11] = (IO_QUEUE as u32) << 16 | QUEUE_CONTIGUOUS;
+        self.execute(ADMIN_QUEUE, create_sq)?;
+
+        self.find_namespaces()
+    }
+
+    /// Identifies the active namespaces, skipping the ones whose format we can't use
+    fn find_namespaces(&mut self) -> Result<(), KError> {
+        self.identify(IDENTIFY_ACTIVE_NAMESPACES, 0)?;
+        let mut ids = [0u32; MAX_NAMESPACES];
+        for (id, bytes) in ids
+            .iter_mut()
+            .zip(self.bounce.slice(PAGE_SIZE).chunks_exact(4))
+        {
+            *id = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        let mut count = 0;
+        for id in ids.into_iter().take_while(|&id| id != 0) {
+            self.identify(IDENTIFY_NAMESPACE, id)?;
+            let data = self.bounce.slice(PAGE_SIZE);
+            let size = u64::from_le_bytes(data[0..8].try_into().unwrap());
+            let format = 128 + 4 * (data[26] & 0xf) as usize;
+            let format = u32::from_le_bytes(data[format..format + 4].try_into().unwrap());
+            let metadata = format & 0xffff;
+            let lba_shift = (format >> 16) & 0xff;
+            // Metadata would need buffers of its own
+            if size == 0
+                || metadata != 0
+                || lba_shift < BLOCK_SIZE.trailing_zeros()
+                || 1 << lba_shift > self.max_transfer
+            {
+                continue;
+            }
+            self.namespaces[count] = Some(Namespace {
+                id,
+                size,
+                lba_shift,
+                read_only: data[99] & 1 != 0,
+            });
+            count += 1;
+        }
+        Ok(())
+    }
+
+    /// Runs a command and waits for it, returns its result dword
+    fn execute(&mut self, queue: u16, command: Command) -> Result<u32, KError> {
+        let regs = self.regs;
+        let queue = match queue {
+            ADMIN_QUEUE => &mut self.admin,
+            _ => &mut self.io,
+        };
+        let id = queue.push(command);
+        // The command has to be in memory before the controller hears about it
+        fence(Ordering::SeqCst);
+        regs.ring(queue.id, false, queue.tail);
+
+        let deadline = time::now(ClockId::Monotonic).saturating_add(COMMAND_TIMEOUT_NS);
+        loop {
+            while let Some(completion) = queue.pop() {
+                regs.ring(queue.id, true, queue.head);
+                // Anything else is left over from a command that timed out
+                if completion.id == id {
+                    return match completion.status {
+                        0 => Ok(completion.result),
+                        status => Err(status_to_kerror(status)),
+                    };
+                }
+            }
+            let left = deadline.saturating_sub(time::now(ClockId::Monotonic));
+            if left == 0 {
+                return Err(KError::TimedOut);
+            }
+            match self.device.wait_interrupt(left.min(INTERRUPT_POLL_NS)) {
+                Ok(_) => {}
+                // Not routed, poll
+                Err(KError::NoDevice) => core::hint::spin_loop(),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads an identify structure into the bounce buffer
+    fn identify(&mut self, cns: u32, nsid: u32) -> Result<(), KError> {
+        let mut identify = command(ADMIN_IDENTIFY, nsid);
+        set_data(&mut identify, self.bounce.phy_addr, 0);
+        identify[10] = cns;
+        self.execute(ADMIN_QUEUE, identify).map(|_| ())
+    }
+
+    /// Reads or writes `len` bytes of the bounce buffer, `lba` being in [`BLOCK_SIZE`] blocks
+    fn transfer(&mut self, opcode: u8, ns: &Namespace, lba: u64, len: usize) -> Result<(), KError> {
+        let blocks_per_lba = (ns.lba_size() / BLOCK_SIZE) as u64;
+        if !lba.is_multiple_of(blocks_per_lba)
+            || !len.is_multiple_of(ns.lba_size())
+            || len > self.max_transfer
+        {
+            return Err(KError::InvalidArgument);
+        }
+        let prp2 = match len.div_ceil(PAGE_SIZE) {
+            0 | 1 => 0,
+            2 => self.bounce.phy_addr + PAGE_SIZE as u64,
+            _ => self.prp_list.phy_addr,
+        };
+        let mut transfer = command(opcode, ns.id);
+        set_data(&mut transfer, self.bounce.phy_addr, prp2);
+        let slba = lba / blocks_per_lba;
+        transfer[10] = slba as u32;
+        transfer[11] = (slba >> 32) as u32;
+        // 0-based
+        transfer[12] = (len / ns.lba_size() - 1) as u32;
+        self.execute(IO_QUEUE, transfer).map(|_| ())
+    }
+
+    pub fn device(&self) -> &PciDevice {
+        &self.device
+    }
+
+    /// Major, minor and tertiary version of the NVMe specification it implements
+    pub fn version(&self) -> (u16, u8, u8) {
+        let [tertiary, minor, major @ ..] = self.version.to_le_bytes();
+        (u16::from_le_bytes(major), minor, tertiary)
+    }
+
+    pub fn serial(&self) -> &str {
+        ascii(&self.serial)
+    }
+
+    pub fn model(&self) -> &str {
+        ascii(&self.model)
+    }
+
+    pub fn firmware(&self) -> &str {
+        ascii(&self.firmware)
+    }
+
+    pub fn namespaces(&self) -> impl Iterator<Item = &Namespace> {
+        self.namespaces.iter().flatten()
+    }
+
+    /// The namespace with ID `nsid`, as a block device
+    pub fn disk(&mut self, nsid: u32) -> Option<NvmeDisk<'_>> {
+        let namespace = *self.namespaces().find(|ns| ns.id == nsid)?;
+        Some(NvmeDisk {
+            controller: self,
+            namespace,
+        })
+    }
+
+    /// Tells the controller we're going away, so it makes everything written durable
+    pub fn shutdown(&mut self) -> Result<(), KError> {
+        let cc = self.regs.read32(CC);
+        self.regs
+            .write32(CC, cc & !CC_SHUTDOWN_MASK | CC_SHUTDOWN_NORMAL);
+        self.regs.wait_status(
+            CSTS_SHUTDOWN_MASK,
+            CSTS_SHUTDOWN_COMPLETE,
+            self.ready_timeout_ns,
+        )
+    }
+}
+
+/// Identify strings are ASCII, padded with spaces
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("").trim_end()
+}
+
+/// A namespace of a controller, as a block device
+pub struct NvmeDisk<'a> {
+    controller: &'a mut NvmeController,
+    namespace: Namespace,
+}
+
+impl BlockDevice for NvmeDisk<'_> {
+    fn block_count(&self) -> u64 {
+        self.namespace.block_count()
+    }
+
+    fn read_only(&self) -> bool {
+        self.namespace.read_only
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), KError> {
+        check_request(self, lba, buf.len())?;
+        let max = self.controller.max_transfer;
+        for (i, chunk) in buf.chunks_mut(max).enumerate() {
+            let chunk_lba = lba + (i * max / BLOCK_SIZE) as u64;
+            self.controller
+                .transfer(NVM_READ, &self.namespace, chunk_lba, chunk.len())?;
+            chunk.copy_from_slice(self.controller.bounce.slice(chunk.len()));
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), KError> {
+        check_request(self, lba, buf.len())?;
+        if self.namespace.read_only {
+            return Err(KError::NotSupported);
+        }
+        let max = self.controller.max_transfer;
+        for (i, chunk) in buf.chunks(max).enumerate() {
+            let chunk_lba = lba + (i * max / BLOCK_SIZE) as u64;
+            self.controller
+                .bounce
+                .slice_mut(chunk.len())
+                .copy_from_slice(chunk);
+            self.controller
+                .transfer(NVM_WRITE, &self.namespace, chunk_lba, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        // Without a volatile write cache everything written is durable already
+        if !self.controller.volatile_write_cache {
+            return Ok(());
+        }
+        let flush = command(NVM_FLUSH, self.namespace.id);
+        self.controller.execute(IO_QUEUE, flush).map(|_| ())
+    }
+}
@@ -0,0 +1,127 @@
+//! ARM PrimeCell PL011 UARTs, driven by the kernel as the console and the GDB stub
+
+use crate::dtb;
+use crate::println;
+use crate::utils::load_kernel_device;
+use fdt_rs::base::{DevTree, DevTreeNode};
+use fdt_rs::error::DevTreeError;
+use fdt_rs::prelude::FallibleIterator;
+use kernel_api::kernel_device;
+
+#[allow(dead_code)]
+pub struct Pl011 {
+    base: u64,
+    clock_hz: u32,
+    interrupt_id: u32,
+}
+
+impl Pl011 {
+    fn from_node(dtb: &DevTree, node: &DevTreeNode) -> Result<Self, DevTreeError> {
+        let (base, _len) = dtb::reg(node)?.expect("UART node has no reg property");
+        let interrupt_id = dtb::interrupt(node)?.expect("UART node has no interrupts property");
+        let clock_hz = dtb::clock_frequency(dtb, node)?.expect("UART clock not found");
+        Ok(Pl011 {
+            base,
+            clock_hz,
+            interrupt_id,
+        })
+    }
+
+    /// Whether the node is the console, i.e. `/chosen/stdout-path` points to it
+    fn is_console(dtb: &DevTree, node: &DevTreeNode) -> Result<bool, DevTreeError> {
+        let Some(stdout_path) = dtb::stdout_path(dtb)? else {
+            return Ok(false);
+        };
+        Ok(stdout_path.rsplit('/').next() == Some(node.name()?))
+    }
+
+    /// Whether the node is the one named by the `console` boot option, either by node name or as
+    /// `ttyAMAn`, the n-th UART
+    fn is_named(node: &DevTreeNode, index: usize, name: &str) -> Result<bool, DevTreeError> {
+        if let Some(tty_index) = name.strip_prefix("ttyAMA") {
+            return Ok(tty_index.parse() == Ok(index));
+        }
+        Ok(node.name()? == name)
+    }
+
+    /// Finds the console UART: the one chosen with the `console` boot option, the one
+    /// `/chosen/stdout-path` points to, or the first one
+    fn find_console<'a, 'dt>(
+        dtb: &'a DevTree<'dt>,
+        console_name: Option<&str>,
+    ) -> Result<Option<DevTreeNode<'a, 'dt>>, DevTreeError> {
+        if let Some(name) = console_name {
+            let mut nodes = dtb.compatible_nodes("arm,pl011");
+            let mut index = 0;
+            while let Some(node) = nodes.next()? {
+                if Self::is_named(&node, index, name)? {
+                    return Ok(Some(node));
+                }
+                index += 1;
+            }
+            println!("Console {:?} not found, using the default", name);
+        }
+
+        let mut console = None;
+        let mut nodes = dtb.compatible_nodes("arm,pl011");
+        while let Some(node) = nodes.next()? {
+            if Self::is_console(dtb, &node)? {
+                return Ok(Some(node));
+            }
+            console.get_or_insert(node);
+        }
+        Ok(console)
+    }
+
+    /// Finds the console UART and hands it to the kernel
+    pub fn find_and_init(
+        dtb: &DevTree,
+        console_name: Option<&str>,
+    ) -> Result<Option<Self>, DevTreeError> {
+        let Some(node) = Self::find_console(dtb, console_name)? else {
+            return Ok(None);
+        };
+        let uart = Self::from_node(dtb, &node)?;
+
+        unsafe {
+            load_kernel_device(&kernel_device::Pl011 {
+                base: uart.base,
+                clock_hz: uart.clock_hz,
+                interrupt_id: uart.interrupt_id,
+            })
+        }
+        .expect("Failed to load kernel device");
+
+        Ok(Some(uart))
+    }
+
+    /// Hands the first UART that isn't the console to the kernel's GDB stub
+    pub fn find_and_init_gdb_stub(
+        dtb: &DevTree,
+        console_name: Option<&str>,
+    ) -> Result<Option<Self>, DevTreeError> {
+        let console_base = match Self::find_console(dtb, console_name)? {
+            Some(node) => dtb::reg(&node)?.map(|(base, _len)| base),
+            None => None,
+        };
+        let mut nodes = dtb.compatible_nodes("arm,pl011");
+        while let Some(node) = nodes.next()? {
+            let uart = Self::from_node(dtb, &node)?;
+            if Some(uart.base) == console_base {
+                continue;
+            }
+
+            unsafe {
+                load_kernel_device(&kernel_device::GdbStub {
+                    base: uart.base,
+                    clock_hz: uart.clock_hz,
+                    interrupt_id: uart.interrupt_id,
+                })
+            }
+            .expect("Failed to load kernel device");
+
+            return Ok(Some(uart));
+        }
+        Ok(None)
+    }
+}
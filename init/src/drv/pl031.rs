@@ -0,0 +1,86 @@
+//! ARM PrimeCell PL031 real-time clock
+
+use crate::dtb;
+use crate::utils::{irq_wait, map_mmio};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::KError;
+
+/// Data register, the current time in seconds
+const RTCDR: usize = 0x00;
+/// Match register, raises the interrupt when equal to the data register
+const RTCMR: usize = 0x04;
+/// Load register, sets the current time
+const RTCLR: usize = 0x08;
+/// Control register, bit 0 starts the clock
+const RTCCR: usize = 0x0C;
+/// Interrupt mask set/clear register
+const RTCIMSC: usize = 0x10;
+/// Raw interrupt status register
+const RTCRIS: usize = 0x14;
+/// Interrupt clear register
+const RTCICR: usize = 0x1C;
+
+pub struct Pl031 {
+    base: *mut u32,
+    interrupt_id: u32,
+}
+
+impl Pl031 {
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(node) = dtb::find_compatible(dtb, "arm,pl031")? else {
+            return Ok(None);
+        };
+        let (base, len) = dtb::reg(&node)?.expect("RTC node has no reg property");
+        let interrupt_id = dtb::interrupt(&node)?.expect("RTC node has no interrupts property");
+        let base = unsafe { map_mmio(base as usize, len as usize) }.expect("Failed to map RTC");
+
+        let rtc = Pl031 {
+            base: base as *mut u32,
+            interrupt_id,
+        };
+        // Make sure the clock is running, and that there's no stale alarm
+        rtc.write(RTCCR, 1);
+        rtc.cancel_alarm();
+        Ok(Some(rtc))
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { self.base.byte_add(reg).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, val: u32) {
+        unsafe { self.base.byte_add(reg).write_volatile(val) }
+    }
+
+    /// Current time, in seconds since the unix epoch
+    pub fn time(&self) -> u32 {
+        self.read(RTCDR)
+    }
+
+    /// Sets the time, in seconds since the unix epoch
+    pub fn set_time(&self, secs: u32) {
+        self.write(RTCLR, secs);
+    }
+
+    /// Raises the RTC interrupt once the time reaches `secs`
+    pub fn set_alarm(&self, secs: u32) {
+        self.write(RTCICR, 1);
+        self.write(RTCMR, secs);
+        self.write(RTCIMSC, 1);
+    }
+
+    pub fn cancel_alarm(&self) {
+        self.write(RTCIMSC, 0);
+        self.write(RTCICR, 1);
+    }
+
+    /// Blocks until the alarm set by [`Self::set_alarm`] fires, then disarms it
+    pub fn wait_alarm(&self) -> Result<(), KError> {
+        while self.read(RTCRIS) & 1 == 0 {
+            irq_wait(self.interrupt_id)?;
+        }
+        self.cancel_alarm();
+        Ok(())
+    }
+}
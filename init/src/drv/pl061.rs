@@ -0,0 +1,208 @@
+//! ARM PrimeCell PL061 GPIO controller, 8 pins
+//!
+//! Each pin is an input or an output, and inputs can raise the controller's interrupt on an edge
+//! or while at a level. Consumers, like [`GpioKeys`](crate::drv::gpio_keys::GpioKeys), find
+//! their pins through the `gpios` properties that reference the controller's phandle.
+
+use crate::dtb;
+use crate::utils::{irq_wait_timeout, map_mmio};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use fdt_rs::prelude::PropReader;
+use kernel_api::KError;
+
+pub const PINS: u8 = 8;
+
+/// Data register. Address bits 9:2 select the pins accessed, the others read as 0 and ignore
+/// writes.
+const GPIODATA: usize = 0x000;
+/// Direction register, 1 for outputs
+const GPIODIR: usize = 0x400;
+/// Interrupt sense register, 1 for level-triggered
+const GPIOIS: usize = 0x404;
+/// Interrupt both-edges register, overrides `GPIOIEV` for edges
+const GPIOIBE: usize = 0x408;
+/// Interrupt event register, 1 for rising edges or high levels
+const GPIOIEV: usize = 0x40C;
+/// Interrupt mask register, 1 to enable
+const GPIOIE: usize = 0x410;
+/// Raw interrupt status register
+const GPIORIS: usize = 0x414;
+/// Masked interrupt status register
+const GPIOMIS: usize = 0x418;
+/// Interrupt clear register, for edge-triggered interrupts
+const GPIOIC: usize = 0x41C;
+/// Mode control select register, 1 for pins driven by other hardware
+const GPIOAFSEL: usize = 0x420;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// What raises a pin's interrupt
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    RisingEdge,
+    FallingEdge,
+    BothEdges,
+    HighLevel,
+    LowLevel,
+}
+
+pub struct Pl061 {
+    base: *mut u32,
+    interrupt_id: u32,
+    phandle: Option<u32>,
+    phy_addr: usize,
+}
+
+impl Pl061 {
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(node) = dtb::find_compatible(dtb, "arm,pl061")? else {
+            return Ok(None);
+        };
+        let (phy_addr, len) = dtb::reg(&node)?.expect("GPIO node has no reg property");
+        let interrupt_id = dtb::interrupt(&node)?.expect("GPIO node has no interrupts property");
+        let phandle = match dtb::find_prop(&node, "phandle")? {
+            Some(prop) => Some(prop.u32(0)?),
+            None => None,
+        };
+        let base =
+            unsafe { map_mmio(phy_addr as usize, len as usize) }.expect("Failed to map GPIO");
+
+        let gpio = Pl061 {
+            base: base as *mut u32,
+            interrupt_id,
+            phandle,
+            phy_addr: phy_addr as usize,
+        };
+        // Start with every pin a software-controlled input that raises no interrupt
+        gpio.write(GPIOIE, 0);
+        gpio.write(GPIOIC, 0xff);
+        gpio.write(GPIOAFSEL, 0);
+        gpio.write(GPIODIR, 0);
+        Ok(Some(gpio))
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { self.base.byte_add(reg).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, val: u32) {
+        unsafe { self.base.byte_add(reg).write_volatile(val) }
+    }
+
+    /// Sets or clears the bit of `pin` in a register
+    fn update(&self, reg: usize, pin: u8, set: bool) {
+        let val = self.read(reg) & !(1 << pin);
+        self.write(reg, val | (set as u32) << pin);
+    }
+
+    fn check_pin(pin: u8) -> Result<(), KError> {
+        match pin < PINS {
+            true => Ok(()),
+            false => Err(KError::InvalidArgument),
+        }
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    /// Whether `gpios` properties with `phandle` refer to this controller
+    pub fn is_phandle(&self, phandle: u32) -> bool {
+        self.phandle == Some(phandle)
+    }
+
+    pub fn direction(&self, pin: u8) -> Result<Direction, KError> {
+        Self::check_pin(pin)?;
+        Ok(match self.read(GPIODIR) & 1 << pin != 0 {
+            true => Direction::Output,
+            false => Direction::Input,
+        })
+    }
+
+    pub fn set_direction(&self, pin: u8, direction: Direction) -> Result<(), KError> {
+        Self::check_pin(pin)?;
+        self.update(GPIODIR, pin, direction == Direction::Output);
+        Ok(())
+    }
+
+    /// The level of `pin`, for outputs the one it's driven to
+    pub fn value(&self, pin: u8) -> Result<bool, KError> {
+        Self::check_pin(pin)?;
+        Ok(self.read(GPIODATA + (4 << pin)) != 0)
+    }
+
+    /// Drives `pin`, once it's an output
+    pub fn set_value(&self, pin: u8, value: bool) -> Result<(), KError> {
+        Self::check_pin(pin)?;
+        // Only the pin selected by the address is written
+        self.write(GPIODATA + (4 << pin), (value as u32) << pin);
+        Ok(())
+    }
+
+    /// Makes `pin` raise the interrupt on `trigger`, or stops it with `None`
+    pub fn set_interrupt(&self, pin: u8, trigger: Option<Trigger>) -> Result<(), KError> {
+        Self::check_pin(pin)?;
+        // Changing the sense can raise a spurious interrupt, so it's masked meanwhile
+        self.update(GPIOIE, pin, false);
+        let Some(trigger) = trigger else {
+            return Ok(());
+        };
+        let level = matches!(trigger, Trigger::HighLevel | Trigger::LowLevel);
+        let rising_or_high = matches!(trigger, Trigger::RisingEdge | Trigger::HighLevel);
+        self.update(GPIOIS, pin, level);
+        self.update(GPIOIBE, pin, trigger == Trigger::BothEdges);
+        self.update(GPIOIEV, pin, rising_or_high);
+        self.write(GPIOIC, 1 << pin);
+        self.update(GPIOIE, pin, true);
+        Ok(())
+    }
+
+    /// The trigger of `pin`'s interrupt, if it's enabled
+    pub fn interrupt(&self, pin: u8) -> Result<Option<Trigger>, KError> {
+        Self::check_pin(pin)?;
+        let bit = |reg| self.read(reg) & 1 << pin != 0;
+        if !bit(GPIOIE) {
+            return Ok(None);
+        }
+        Ok(Some(match (bit(GPIOIS), bit(GPIOIBE), bit(GPIOIEV)) {
+            (true, _, true) => Trigger::HighLevel,
+            (true, _, false) => Trigger::LowLevel,
+            (false, true, _) => Trigger::BothEdges,
+            (false, false, true) => Trigger::RisingEdge,
+            (false, false, false) => Trigger::FallingEdge,
+        }))
+    }
+
+    /// The pins whose enabled interrupt is raised, one bit each
+    pub fn pending(&self) -> u8 {
+        self.read(GPIOMIS) as u8
+    }
+
+    /// The pins whose interrupt condition happened, whether it's enabled or not
+    pub fn raw_pending(&self) -> u8 {
+        self.read(GPIORIS) as u8
+    }
+
+    /// Acknowledges the edge interrupts of `pins`, level ones stay raised while the level lasts
+    pub fn clear(&self, pins: u8) {
+        self.write(GPIOIC, pins as u32);
+    }
+
+    /// Blocks until the controller raises its interrupt, or for at most `timeout_ns`
+    /// nanoseconds. Returns whether it was raised.
+    pub fn wait(&self, timeout_ns: u64) -> Result<bool, KError> {
+        if self.pending() != 0 {
+            return Ok(true);
+        }
+        match irq_wait_timeout(self.interrupt_id, timeout_ns) {
+            Ok(()) => Ok(true),
+            Err(KError::TimedOut) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! PSCI, the firmware interface for power management
+//!
+//! The kernel makes the calls, it only needs to know whether they go through `hvc` or `smc`, the
+//! `method` of the DTB's `psci` node. Turning the machine off or rebooting is then a privileged
+//! syscall.
+
+use crate::dtb;
+use crate::utils::{load_kernel_device, psci_call};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use fdt_rs::prelude::PropReader;
+use kernel_api::kernel_device::{self, PSCI_CONDUIT_HVC, PSCI_CONDUIT_SMC};
+use kernel_api::{KError, PsciFunction};
+
+pub struct Psci {
+    method: &'static str,
+    version: u64,
+}
+
+impl Psci {
+    /// Finds the `psci` node and hands it to the kernel. PSCI 0.1, which has neither a standard
+    /// `SYSTEM_OFF` nor `SYSTEM_RESET`, isn't supported.
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(node) = dtb::find_compatible(dtb, "arm,psci-0.2")? else {
+            return Ok(None);
+        };
+        let (method, conduit) = match dtb::find_prop(&node, "method")? {
+            Some(prop) if prop.str()? == "hvc" => ("hvc", PSCI_CONDUIT_HVC),
+            Some(prop) if prop.str()? == "smc" => ("smc", PSCI_CONDUIT_SMC),
+            _ => panic!("PSCI node has no valid method property"),
+        };
+        unsafe {
+            load_kernel_device(&kernel_device::Psci {
+                conduit,
+                _padding: 0,
+            })
+        }
+        .expect("Failed to load PSCI");
+        let version = psci_call(PsciFunction::Version).expect("Failed to read the PSCI version");
+        Ok(Some(Self { method, version }))
+    }
+
+    /// Whether the firmware is called with `hvc` or `smc`
+    pub fn method(&self) -> &'static str {
+        self.method
+    }
+
+    pub fn version(&self) -> (u16, u16) {
+        ((self.version >> 16) as u16, self.version as u16)
+    }
+
+    /// Turns the machine off, only returns if that failed
+    pub fn power_off(&self) -> KError {
+        psci_call(PsciFunction::SystemOff)
+            .err()
+            .unwrap_or(KError::IoError)
+    }
+
+    /// Reboots the machine, only returns if that failed
+    pub fn reboot(&self) -> KError {
+        psci_call(PsciFunction::SystemReset)
+            .err()
+            .unwrap_or(KError::IoError)
+    }
+}
@@ -0,0 +1,93 @@
+//! QEMU's RAM framebuffer, `-device ramfb`, configured through the fw_cfg file `etc/ramfb`
+//!
+//! The guest allocates the framebuffer and writes its address, format and size to the file.
+//! QEMU then reads it on every display refresh, so there's nothing to flush. It needs nothing
+//! but fw_cfg, which makes it usable early at boot.
+
+use crate::drv::fw_cfg::FwCfg;
+use crate::framebuffer::{FbInfo, Framebuffer, Rect};
+use crate::utils::{dma_alloc, load_kernel_device};
+use kernel_api::{kernel_device, KError};
+
+const FILE_NAME: &str = "etc/ramfb";
+/// DRM_FORMAT_XRGB8888, "XR24": 32-bit `0x00RRGGBB` pixels, like the framebuffer interface
+const FOURCC_XRGB8888: u32 = u32::from_le_bytes(*b"XR24");
+const CONFIG_LEN: usize = 28;
+const WIDTH: u32 = 1024;
+const HEIGHT: u32 = 768;
+
+pub struct RamFb {
+    pixels: *mut u32,
+    len: usize,
+    info: FbInfo,
+    phy_addr: usize,
+}
+
+impl RamFb {
+    /// Allocates the framebuffer and hands it to the display, if there's a ramfb device
+    pub fn find_and_init(fw_cfg: &FwCfg) -> Result<Option<Self>, KError> {
+        let Some(file) = fw_cfg.find_file(FILE_NAME)? else {
+            return Ok(None);
+        };
+        if (file.size as usize) < CONFIG_LEN {
+            return Err(KError::InvalidArgument);
+        }
+        let stride = WIDTH * size_of::<u32>() as u32;
+        let len = (WIDTH * HEIGHT) as usize;
+        let (pixels, phy_addr) = unsafe { dma_alloc(len * size_of::<u32>())? };
+
+        // Big-endian, like the rest of fw_cfg
+        let mut config = [0u8; CONFIG_LEN];
+        config[0..8].copy_from_slice(&(phy_addr as u64).to_be_bytes());
+        config[8..12].copy_from_slice(&FOURCC_XRGB8888.to_be_bytes());
+        // Flags, none are defined
+        config[12..16].copy_from_slice(&0u32.to_be_bytes());
+        config[16..20].copy_from_slice(&WIDTH.to_be_bytes());
+        config[20..24].copy_from_slice(&HEIGHT.to_be_bytes());
+        config[24..28].copy_from_slice(&stride.to_be_bytes());
+        fw_cfg.write(file.select, &config)?;
+
+        Ok(Some(Self {
+            pixels: pixels as *mut u32,
+            len,
+            info: FbInfo {
+                width: WIDTH,
+                height: HEIGHT,
+                stride: WIDTH,
+            },
+            phy_addr,
+        }))
+    }
+
+    /// The address of the framebuffer memory
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    /// Hands the framebuffer to the kernel's console, which mirrors the UART on it from now on
+    pub fn attach_console(&self) -> Result<(), KError> {
+        unsafe {
+            load_kernel_device(&kernel_device::Framebuffer {
+                base: self.phy_addr as u64,
+                width: self.info.width,
+                height: self.info.height,
+                stride: self.info.stride * size_of::<u32>() as u32,
+                _padding: 0,
+            })
+        }
+    }
+}
+
+impl Framebuffer for RamFb {
+    fn info(&self) -> FbInfo {
+        self.info
+    }
+
+    fn pixels(&mut self) -> &mut [u32] {
+        unsafe { core::slice::from_raw_parts_mut(self.pixels, self.len) }
+    }
+
+    fn flush(&mut self, _rect: Rect) -> Result<(), KError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! Glue for the virtio crate: how it gets memory and interrupts, and probing of the
+//! `virtio,mmio` slots in the DTB
+
+use crate::dtb;
+use crate::println;
+use crate::utils::{dma_alloc, irq_wait, irq_wait_timeout, map_mmio};
+use core::ptr::NonNull;
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use fdt_rs::prelude::FallibleIterator;
+use kernel_api::KError;
+use virtio::mmio::MmioTransport;
+use virtio::{DeviceType, Error, Hal};
+
+pub struct InitHal;
+
+fn to_virtio_error(err: KError) -> Error {
+    match err {
+        KError::OOM => Error::OutOfMemory,
+        _ => Error::Hal,
+    }
+}
+
+/// Converts a driver error for callers that only deal with kernel errors
+pub fn to_kerror(err: Error) -> KError {
+    match err {
+        Error::OutOfMemory => KError::OOM,
+        Error::InvalidArgument => KError::InvalidArgument,
+        Error::Unsupported | Error::ReadOnly => KError::NotSupported,
+        Error::NoDevice => KError::NoDevice,
+        _ => KError::IoError,
+    }
+}
+
+impl Hal for InitHal {
+    fn dma_alloc(len: usize) -> Result<(NonNull<u8>, usize), Error> {
+        let (virt, phy_addr) = unsafe { dma_alloc(len) }.map_err(to_virtio_error)?;
+        Ok((NonNull::new(virt).ok_or(Error::OutOfMemory)?, phy_addr))
+    }
+
+    unsafe fn mmio_map(phy_addr: usize, len: usize) -> Result<NonNull<u8>, Error> {
+        let base = unsafe { map_mmio(phy_addr, len) }.map_err(to_virtio_error)?;
+        NonNull::new(base).ok_or(Error::Hal)
+    }
+
+    fn wait_interrupt(interrupt_id: u32) -> Result<(), Error> {
+        irq_wait(interrupt_id).map_err(to_virtio_error)
+    }
+
+    fn wait_interrupt_timeout(interrupt_id: u32, timeout_ns: u64) -> Result<bool, Error> {
+        match irq_wait_timeout(interrupt_id, timeout_ns) {
+            Ok(()) => Ok(true),
+            Err(KError::TimedOut) => Ok(false),
+            Err(err) => Err(to_virtio_error(err)),
+        }
+    }
+}
+
+/// A virtio-mmio slot with a device behind it
+pub struct VirtioDevice {
+    pub phy_addr: usize,
+    pub transport: MmioTransport,
+}
+
+/// Calls `f` for every `virtio,mmio` slot that has a device, until it returns false. Slots that
+/// fail to probe are reported and skipped.
+pub fn for_each_device(
+    dtb: &DevTree,
+    mut f: impl FnMut(VirtioDevice) -> bool,
+) -> Result<(), DevTreeError> {
+    let mut nodes = dtb.compatible_nodes("virtio,mmio");
+    while let Some(node) = nodes.next()? {
+        let (phy_addr, len) = dtb::reg(&node)?.expect("Virtio node has no reg property");
+        let interrupt_id = dtb::interrupt(&node)?.expect("Virtio node has no interrupts property");
+        let phy_addr = phy_addr as usize;
+        match MmioTransport::probe::<InitHal>(phy_addr, len as usize, interrupt_id) {
+            Ok(transport) => {
+                if !f(VirtioDevice {
+                    phy_addr,
+                    transport,
+                }) {
+                    break;
+                }
+            }
+            Err(Error::NoDevice) => {}
+            Err(err) => println!("Virtio slot 0p{phy_addr:x}: {err}"),
+        }
+    }
+    Ok(())
+}
+
+/// Finds the first device of the given type
+pub fn find_device(
+    dtb: &DevTree,
+    device_type: DeviceType,
+) -> Result<Option<VirtioDevice>, DevTreeError> {
+    let mut found = None;
+    for_each_device(dtb, |device| {
+        if device.transport.device_type() == device_type {
+            found = Some(device);
+            return false;
+        }
+        true
+    })?;
+    Ok(found)
+}
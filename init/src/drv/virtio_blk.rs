@@ -0,0 +1,146 @@
+//! Virtio block devices, e.g. QEMU's `-device virtio-blk-device`
+//!
+//! Callers' buffers aren't DMA memory, so transfers go through a bounce buffer. Large transfers
+//! are split into chunks no bigger than the device's largest segment, all in flight at once.
+
+use crate::block::{check_request, BlockDevice, BLOCK_SIZE};
+use crate::drv::virtio::{find_device, to_kerror, InitHal};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::KError;
+use virtio::blk::{Request, VirtioBlk, MAX_REQUESTS};
+use virtio::{DeviceType, Dma};
+
+/// Size of a single request, unless the device takes less
+const CHUNK_SIZE: usize = 16 * 1024;
+const BOUNCE_SIZE: usize = 8 * CHUNK_SIZE;
+
+pub struct VirtioBlockDevice {
+    dev: VirtioBlk<InitHal>,
+    bounce: Dma,
+    /// Bytes per request, at most [`CHUNK_SIZE`]
+    chunk_size: usize,
+    phy_addr: usize,
+}
+
+impl VirtioBlockDevice {
+    /// Initializes the first virtio block device
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(device) = find_device(dtb, DeviceType::Block)? else {
+            return Ok(None);
+        };
+        let dev = VirtioBlk::new(device.transport).expect("Failed to initialize virtio-blk");
+        let bounce = Dma::new::<InitHal>(BOUNCE_SIZE).expect("Failed to allocate a DMA buffer");
+        let chunk_size = CHUNK_SIZE.min(dev.max_transfer()) / BLOCK_SIZE * BLOCK_SIZE;
+        assert_ne!(chunk_size, 0, "virtio-blk device can't transfer a block");
+        Ok(Some(Self {
+            dev,
+            bounce,
+            chunk_size,
+            phy_addr: device.phy_addr,
+        }))
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    pub fn device(&self) -> &VirtioBlk<InitHal> {
+        &self.dev
+    }
+
+    /// Reads or writes `len` bytes of the bounce buffer, submitting as many chunks as the queue
+    /// takes before waiting for any of them
+    fn transfer(&mut self, lba: u64, len: usize, write: bool) -> Result<(), KError> {
+        let mut in_flight = 0;
+        let mut result = Ok(());
+        for offset in (0..len).step_by(self.chunk_size) {
+            // Small chunks may not all fit in the queue
+            if in_flight == MAX_REQUESTS {
+                let (_, completion) = self.dev.wait().map_err(to_kerror)?;
+                in_flight -= 1;
+                if let Err(err) = completion {
+                    result = Err(err);
+                    break;
+                }
+            }
+            let sector = lba + (offset / BLOCK_SIZE) as u64;
+            let buf = self.bounce.range(offset, self.chunk_size.min(len - offset));
+            let request = if write {
+                Request::Write { sector, buf }
+            } else {
+                Request::Read { sector, buf }
+            };
+            match self.dev.submit(request) {
+                Ok(_) => in_flight += 1,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        // Collect everything that was submitted, even after a failure, so the chunks aren't
+        // still in flight when the bounce buffer gets reused
+        for _ in 0..in_flight {
+            let (_, completion) = self.dev.wait().map_err(to_kerror)?;
+            result = result.and(completion);
+        }
+        result.map_err(to_kerror)
+    }
+}
+
+impl BlockDevice for VirtioBlockDevice {
+    fn block_count(&self) -> u64 {
+        self.dev.capacity()
+    }
+
+    fn read_only(&self) -> bool {
+        self.dev.read_only()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), KError> {
+        check_request(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(BOUNCE_SIZE).enumerate() {
+            let chunk_lba = lba + (i * BOUNCE_SIZE / BLOCK_SIZE) as u64;
+            self.transfer(chunk_lba, chunk.len(), false)?;
+            chunk.copy_from_slice(&self.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), KError> {
+        check_request(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks(BOUNCE_SIZE).enumerate() {
+            let chunk_lba = lba + (i * BOUNCE_SIZE / BLOCK_SIZE) as u64;
+            self.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.transfer(chunk_lba, chunk.len(), true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        // Without the flush feature the device has no write cache
+        if !self.dev.supports_flush() {
+            return Ok(());
+        }
+        self.dev.execute(Request::Flush).map_err(to_kerror)
+    }
+
+    fn discard(&mut self, lba: u64, count: u64) -> Result<(), KError> {
+        check_request(self, lba, count as usize * BLOCK_SIZE)?;
+        let max = match self.dev.max_discard_sectors() {
+            0 => u32::MAX,
+            max => max,
+        };
+        let end = lba + count;
+        let mut sector = lba;
+        while sector < end {
+            let count = (end - sector).min(max as u64) as u32;
+            self.dev
+                .execute(Request::Discard { sector, count })
+                .map_err(to_kerror)?;
+            sector += count as u64;
+        }
+        Ok(())
+    }
+}
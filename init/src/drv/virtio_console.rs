@@ -0,0 +1,65 @@
+//! Virtio consoles, e.g. QEMU's `-device virtio-serial-device` with `virtserialport`s
+//!
+//! These are channels besides the PL011 console, ports are picked by number or by name.
+
+use crate::drv::virtio::{find_device, InitHal};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use virtio::console::{PortInfo, VirtioConsole};
+use virtio::{DeviceType, Error};
+
+pub struct VirtioConsoleDevice {
+    dev: VirtioConsole<InitHal>,
+    phy_addr: usize,
+}
+
+impl VirtioConsoleDevice {
+    /// Initializes the first virtio console
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(device) = find_device(dtb, DeviceType::Console)? else {
+            return Ok(None);
+        };
+        let dev =
+            VirtioConsole::new(device.transport).expect("Failed to initialize virtio-console");
+        Ok(Some(Self {
+            dev,
+            phy_addr: device.phy_addr,
+        }))
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    pub fn is_multiport(&self) -> bool {
+        self.dev.is_multiport()
+    }
+
+    /// Calls `f` for each port, after catching up with the ports the host added or removed
+    pub fn for_each_port(&mut self, f: impl FnMut(PortInfo)) -> Result<(), Error> {
+        self.dev.process_control()?;
+        self.dev.ports().for_each(f);
+        Ok(())
+    }
+
+    /// Finds a port by number or by name
+    pub fn port(&mut self, name: &str) -> Result<u32, Error> {
+        self.dev.process_control()?;
+        if let Some(id) = self.dev.find_port(name) {
+            return Ok(id);
+        }
+        match name.parse::<u32>() {
+            Ok(id) if self.dev.ports().any(|port| port.id == id) => Ok(id),
+            _ => Err(Error::NoDevice),
+        }
+    }
+
+    /// Blocks until the port has data
+    pub fn read(&mut self, port: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        self.dev.read(port, buf)
+    }
+
+    pub fn write(&mut self, port: u32, data: &[u8]) -> Result<(), Error> {
+        self.dev.write(port, data)
+    }
+}
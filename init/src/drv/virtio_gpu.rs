@@ -0,0 +1,94 @@
+//! Virtio GPUs, e.g. QEMU's `-device virtio-gpu-device`, as a framebuffer on the first display
+//!
+//! The framebuffer is a single resource backed by DMA memory, shown on the first enabled
+//! scanout at the size the host prefers.
+
+use crate::drv::virtio::{find_device, to_kerror, InitHal};
+use crate::framebuffer::{FbInfo, Framebuffer, Rect};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::KError;
+use virtio::gpu::{self, Format, VirtioGpu};
+use virtio::{DeviceType, Dma};
+
+const RESOURCE_ID: u32 = 1;
+/// The size to use when the host has no preference
+const DEFAULT_WIDTH: u32 = 1024;
+const DEFAULT_HEIGHT: u32 = 768;
+
+pub struct VirtioGpuDevice {
+    dev: VirtioGpu<InitHal>,
+    backing: Dma,
+    scanout: u32,
+    info: FbInfo,
+    phy_addr: usize,
+}
+
+impl VirtioGpuDevice {
+    /// Initializes the first virtio GPU and sets up its framebuffer
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(device) = find_device(dtb, DeviceType::Gpu)? else {
+            return Ok(None);
+        };
+        let mut dev = VirtioGpu::new(device.transport).expect("Failed to initialize virtio-gpu");
+        let displays = dev.display_info().expect("Failed to get the display info");
+        let (scanout, width, height) = displays
+            .iter()
+            .enumerate()
+            .find_map(|(i, rect)| rect.map(|rect| (i as u32, rect.width, rect.height)))
+            .unwrap_or((0, DEFAULT_WIDTH, DEFAULT_HEIGHT));
+
+        let len = (width * height) as usize * size_of::<u32>();
+        let backing = Dma::new::<InitHal>(len).expect("Failed to allocate the framebuffer");
+        dev.resource_create_2d(RESOURCE_ID, Format::B8G8R8X8, width, height)
+            .and_then(|()| dev.resource_attach_backing(RESOURCE_ID, &backing))
+            .and_then(|()| {
+                let rect = gpu::Rect::new(0, 0, width, height);
+                dev.set_scanout(scanout, RESOURCE_ID, rect)
+            })
+            .expect("Failed to set up the framebuffer");
+        Ok(Some(Self {
+            dev,
+            backing,
+            scanout,
+            info: FbInfo {
+                width,
+                height,
+                stride: width,
+            },
+            phy_addr: device.phy_addr,
+        }))
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    pub fn scanout(&self) -> u32 {
+        self.scanout
+    }
+}
+
+impl Framebuffer for VirtioGpuDevice {
+    fn info(&self) -> FbInfo {
+        self.info
+    }
+
+    fn pixels(&mut self) -> &mut [u32] {
+        let len = self.backing.len() / size_of::<u32>();
+        unsafe { core::slice::from_raw_parts_mut(self.backing.as_ptr() as *mut u32, len) }
+    }
+
+    fn flush(&mut self, rect: Rect) -> Result<(), KError> {
+        let rect = rect.intersect(self.info.bounds());
+        if rect.is_empty() {
+            return Ok(());
+        }
+        let offset = (rect.y * self.info.stride + rect.x) as u64 * size_of::<u32>() as u64;
+        let rect = gpu::Rect::new(rect.x, rect.y, rect.width, rect.height);
+        self.dev
+            .transfer_to_host_2d(RESOURCE_ID, rect, offset)
+            .and_then(|()| self.dev.resource_flush(RESOURCE_ID, rect))
+            .map_err(to_kerror)
+    }
+}
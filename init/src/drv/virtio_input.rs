@@ -0,0 +1,77 @@
+//! Virtio input devices, e.g. QEMU's `-device virtio-keyboard-device` or
+//! `-device virtio-tablet-device`
+//!
+//! There's usually more than one, so all of them are initialized. They're handed to the
+//! [`InputService`](crate::input::service::InputService), which reads their events.
+
+use crate::drv::virtio::{for_each_device, to_kerror, InitHal};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::KError;
+use virtio::input::{AbsInfo, Bitmap, InputEvent, VirtioInput, MAX_CONFIG_LEN};
+use virtio::DeviceType;
+
+pub const MAX_DEVICES: usize = 4;
+
+pub struct VirtioInputDevice {
+    dev: VirtioInput<InitHal>,
+    name: [u8; MAX_CONFIG_LEN],
+    name_len: usize,
+    phy_addr: usize,
+}
+
+impl VirtioInputDevice {
+    /// Initializes the virtio input devices, up to [`MAX_DEVICES`] of them
+    pub fn find_and_init_all(dtb: &DevTree) -> Result<[Option<Self>; MAX_DEVICES], DevTreeError> {
+        let mut devices = [const { None }; MAX_DEVICES];
+        let mut count = 0;
+        for_each_device(dtb, |device| {
+            if device.transport.device_type() != DeviceType::Input {
+                return true;
+            }
+            let dev =
+                VirtioInput::new(device.transport).expect("Failed to initialize virtio-input");
+            let mut name = [0u8; MAX_CONFIG_LEN];
+            let name_len = dev.name(&mut name).len();
+            devices[count] = Some(Self {
+                dev,
+                name,
+                name_len,
+                phy_addr: device.phy_addr,
+            });
+            count += 1;
+            count < MAX_DEVICES
+        })?;
+        Ok(devices)
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    /// The codes the device sends events of `event_type` for
+    pub fn event_codes(&self, event_type: u16) -> Bitmap {
+        self.dev.event_codes(event_type)
+    }
+
+    pub fn supports(&self, event_type: u16) -> bool {
+        !self.event_codes(event_type).is_empty()
+    }
+
+    pub fn abs_info(&self, axis: u16) -> Option<AbsInfo> {
+        self.dev.abs_info(axis)
+    }
+
+    pub fn pop_event(&mut self) -> Result<Option<InputEvent>, KError> {
+        self.dev.pop_event().map_err(to_kerror)
+    }
+
+    /// Blocks until there's an event, or for at most `timeout_ns` nanoseconds
+    pub fn wait(&self, timeout_ns: u64) -> Result<bool, KError> {
+        self.dev.wait(timeout_ns).map_err(to_kerror)
+    }
+}
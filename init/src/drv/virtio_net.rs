@@ -0,0 +1,156 @@
+//! Virtio network cards, e.g. QEMU's `-device virtio-net-device` with `-netdev user`
+//!
+//! The card exchanges raw frames with the network stack through a pair of [`FrameRing`]s:
+//! [`VirtioNetDevice::pump`] moves received frames to the receive ring and sends what the stack
+//! left on the transmit ring.
+
+use crate::drv::virtio::{find_device, InitHal};
+use crate::net::ring::{FrameChecksum, FrameRing, MAX_FRAME_LEN};
+use crate::utils::get_random;
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::GetRandomFlags;
+use virtio::net::{Checksum, RxChecksum, VirtioNet};
+use virtio::{DeviceType, Error};
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NetStats {
+    pub rx_frames: u64,
+    pub tx_frames: u64,
+}
+
+pub struct VirtioNetDevice {
+    dev: VirtioNet<InitHal>,
+    mac: [u8; 6],
+    rx_ring: FrameRing,
+    tx_ring: FrameRing,
+    stats: NetStats,
+    phy_addr: usize,
+}
+
+/// Stores the Internet checksum of `frame[start..]` at `start + offset`, for cards that can't
+/// compute it themselves
+fn fill_checksum(frame: &mut [u8], start: usize, offset: usize) {
+    let Some(data) = frame.get(start..) else {
+        return;
+    };
+    let mut sum = data.chunks(2).fold(0u32, |sum, word| {
+        let word = u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]);
+        sum + word as u32
+    });
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    if let Some(field) = frame.get_mut(start + offset..start + offset + 2) {
+        field.copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    }
+}
+
+impl VirtioNetDevice {
+    /// Initializes the first virtio network card
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(device) = find_device(dtb, DeviceType::Network)? else {
+            return Ok(None);
+        };
+        let dev = VirtioNet::new(device.transport).expect("Failed to initialize virtio-net");
+        // Without an address from the host, make up a locally administered one
+        let mac = dev.mac().unwrap_or_else(|| {
+            let mut mac = [0u8; 6];
+            get_random(&mut mac, GetRandomFlags::empty()).expect("Failed to get random bytes");
+            mac[0] = (mac[0] & !0x01) | 0x02;
+            mac
+        });
+        let rx_ring = FrameRing::new().expect("Failed to allocate a frame ring");
+        let tx_ring = FrameRing::new().expect("Failed to allocate a frame ring");
+        Ok(Some(Self {
+            dev,
+            mac,
+            rx_ring,
+            tx_ring,
+            stats: NetStats::default(),
+            phy_addr: device.phy_addr,
+        }))
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.dev.link_up()
+    }
+
+    pub fn device(&self) -> &VirtioNet<InitHal> {
+        &self.dev
+    }
+
+    pub fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    /// Received frames, for the stack to consume
+    pub fn rx_ring(&self) -> &FrameRing {
+        &self.rx_ring
+    }
+
+    /// Frames to send, for the stack to produce
+    pub fn tx_ring(&self) -> &FrameRing {
+        &self.tx_ring
+    }
+
+    /// Moves frames between the card and the rings, without blocking. Returns whether any frame
+    /// moved. Once the receive ring is full, frames are left to the card, which drops them when
+    /// it runs out of buffers.
+    pub fn pump(&mut self) -> Result<bool, Error> {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let mut moved = false;
+        while !self.rx_ring.is_full() {
+            let Some(frame) = self.dev.recv(&mut buf)? else {
+                break;
+            };
+            let checksum = match frame.checksum {
+                RxChecksum::None => FrameChecksum::None,
+                RxChecksum::Valid => FrameChecksum::Valid,
+                RxChecksum::Partial(Checksum { start, offset }) => {
+                    FrameChecksum::Partial { start, offset }
+                }
+            };
+            let len = frame.len.min(buf.len());
+            self.rx_ring
+                .push(&buf[..len], checksum)
+                .map_err(|_| Error::InvalidResponse)?;
+            self.stats.rx_frames += 1;
+            moved = true;
+        }
+        while let Some((len, checksum)) = self.tx_ring.pop(&mut buf) {
+            let frame = &mut buf[..len];
+            let checksum = match checksum {
+                FrameChecksum::Partial { start, offset } if self.dev.supports_tx_checksum() => {
+                    Some(Checksum { start, offset })
+                }
+                FrameChecksum::Partial { start, offset } => {
+                    fill_checksum(frame, start as usize, offset as usize);
+                    None
+                }
+                FrameChecksum::None | FrameChecksum::Valid => None,
+            };
+            self.dev.send(frame, checksum)?;
+            self.stats.tx_frames += 1;
+            moved = true;
+        }
+        Ok(moved)
+    }
+
+    /// Blocks until the card receives a frame, or for at most `timeout_ns` nanoseconds. Returns
+    /// right away if the receive ring has no room for it.
+    pub fn wait(&mut self, timeout_ns: u64) -> Result<(), Error> {
+        if self.rx_ring.is_full() {
+            return Ok(());
+        }
+        self.dev.wait_rx(timeout_ns).map(|_| ())
+    }
+}
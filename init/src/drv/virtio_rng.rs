@@ -0,0 +1,45 @@
+//! Virtio entropy sources, e.g. QEMU's `-device virtio-rng-device`, which feed the kernel's
+//! entropy pool
+
+use crate::drv::virtio::{find_device, InitHal};
+use crate::utils::add_entropy;
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use kernel_api::KError;
+use virtio::rng::VirtioRng;
+use virtio::DeviceType;
+
+/// How much to read each time the pool is fed
+const SEED_LEN: usize = 64;
+
+pub struct VirtioRngDevice {
+    dev: VirtioRng<InitHal>,
+    phy_addr: usize,
+}
+
+impl VirtioRngDevice {
+    /// Initializes the first virtio entropy source
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(device) = find_device(dtb, DeviceType::Entropy)? else {
+            return Ok(None);
+        };
+        let dev = VirtioRng::new(device.transport).expect("Failed to initialize virtio-rng");
+        Ok(Some(Self {
+            dev,
+            phy_addr: device.phy_addr,
+        }))
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    /// Mixes bytes from the device into the kernel's pool, crediting them as fully random
+    pub fn feed_kernel(&mut self) -> Result<(), KError> {
+        let mut seed = [0u8; SEED_LEN];
+        self.dev.fill(&mut seed).map_err(|_| KError::IoError)?;
+        let result = add_entropy(&seed, 8 * SEED_LEN as u32);
+        seed.fill(0);
+        result
+    }
+}
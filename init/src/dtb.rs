@@ -0,0 +1,135 @@
+//! Helpers for looking up device information in the device tree
+
+use fdt_rs::base::{DevTree, DevTreeNode, DevTreeProp};
+use fdt_rs::error::DevTreeError;
+use fdt_rs::prelude::{FallibleIterator, PropReader};
+
+/// Interrupt type in the first cell of a GIC `interrupts` specifier
+const GIC_SPI: u32 = 0;
+const GIC_PPI: u32 = 1;
+const SPI_OFFSET: u32 = 32;
+const PPI_OFFSET: u32 = 16;
+
+/// Finds the first node compatible with `compatible`
+pub fn find_compatible<'a, 'dt>(
+    dtb: &'a DevTree<'dt>,
+    compatible: &str,
+) -> Result<Option<DevTreeNode<'a, 'dt>>, DevTreeError> {
+    dtb.compatible_nodes(compatible).next()
+}
+
+/// Finds a property of a node by name
+pub fn find_prop<'a, 'dt>(
+    node: &DevTreeNode<'a, 'dt>,
+    name: &str,
+) -> Result<Option<DevTreeProp<'a, 'dt>>, DevTreeError> {
+    node.props().find(|prop| Ok(prop.name()? == name))
+}
+
+/// Reads the first `reg` entry of a node, assuming `#address-cells = <2>` and `#size-cells = <2>`
+pub fn reg(node: &DevTreeNode) -> Result<Option<(u64, u64)>, DevTreeError> {
+    let Some(prop) = find_prop(node, "reg")? else {
+        return Ok(None);
+    };
+    Ok(Some((prop.u64(0)?, prop.u64(1)?)))
+}
+
+/// Reads the first entry of a node's `interrupts` property, as a GIC interrupt ID
+pub fn interrupt(node: &DevTreeNode) -> Result<Option<u32>, DevTreeError> {
+    let Some(prop) = find_prop(node, "interrupts")? else {
+        return Ok(None);
+    };
+    Ok(gic_interrupt_id(prop.u32(0)?, prop.u32(1)?))
+}
+
+/// The GIC interrupt ID of the type and number cells of a GIC interrupt specifier
+pub fn gic_interrupt_id(interrupt_type: u32, number: u32) -> Option<u32> {
+    match interrupt_type {
+        GIC_SPI => Some(SPI_OFFSET + number),
+        GIC_PPI => Some(PPI_OFFSET + number),
+        _ => None,
+    }
+}
+
+/// Finds the node with the given phandle
+pub fn find_by_phandle<'a, 'dt>(
+    dtb: &'a DevTree<'dt>,
+    phandle: u32,
+) -> Result<Option<DevTreeNode<'a, 'dt>>, DevTreeError> {
+    let mut node_iter = dtb.nodes();
+    while let Some(node) = node_iter.next()? {
+        if let Some(prop) = find_prop(&node, "phandle")? {
+            if prop.u32(0)? == phandle {
+                return Ok(Some(node));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the frequency of a node's first clock, assuming it's a `fixed-clock`
+pub fn clock_frequency(dtb: &DevTree, node: &DevTreeNode) -> Result<Option<u32>, DevTreeError> {
+    let Some(clocks) = find_prop(node, "clocks")? else {
+        return Ok(None);
+    };
+    let Some(clock_node) = find_by_phandle(dtb, clocks.u32(0)?)? else {
+        return Ok(None);
+    };
+    let Some(frequency) = find_prop(&clock_node, "clock-frequency")? else {
+        return Ok(None);
+    };
+    Ok(Some(frequency.u32(0)?))
+}
+
+/// Finds the `/chosen` node, which holds the boot parameters
+pub fn chosen<'a, 'dt>(
+    dtb: &'a DevTree<'dt>,
+) -> Result<Option<DevTreeNode<'a, 'dt>>, DevTreeError> {
+    dtb.nodes().find(|node| Ok(node.name()? == "chosen"))
+}
+
+/// Reads the kernel command line, `/chosen/bootargs`
+pub fn bootargs<'dt>(dtb: &DevTree<'dt>) -> Result<Option<&'dt str>, DevTreeError> {
+    let Some(node) = chosen(dtb)? else {
+        return Ok(None);
+    };
+    let Some(prop) = find_prop(&node, "bootargs")? else {
+        return Ok(None);
+    };
+    prop.iter_str().next()
+}
+
+/// Reads `/chosen/stdout-path`, without the options suffix (e.g. `:115200n8`)
+pub fn stdout_path<'dt>(dtb: &DevTree<'dt>) -> Result<Option<&'dt str>, DevTreeError> {
+    let Some(node) = chosen(dtb)? else {
+        return Ok(None);
+    };
+    let Some(prop) = find_prop(&node, "stdout-path")? else {
+        return Ok(None);
+    };
+    let path = prop.iter_str().next()?;
+    Ok(path.map(|path| path.split(':').next().unwrap_or(path)))
+}
+
+/// Reads an address that's either one or two cells long, like `linux,initrd-start`
+fn read_addr(prop: &DevTreeProp) -> Result<u64, DevTreeError> {
+    if prop.length() == 4 {
+        Ok(prop.u32(0)? as u64)
+    } else {
+        prop.u64(0)
+    }
+}
+
+/// Reads the physical address range of the initrd from `/chosen`
+pub fn initrd(dtb: &DevTree) -> Result<Option<(u64, u64)>, DevTreeError> {
+    let Some(node) = chosen(dtb)? else {
+        return Ok(None);
+    };
+    let (Some(start), Some(end)) = (
+        find_prop(&node, "linux,initrd-start")?,
+        find_prop(&node, "linux,initrd-end")?,
+    ) else {
+        return Ok(None);
+    };
+    Ok(Some((read_addr(&start)?, read_addr(&end)?)))
+}
@@ -0,0 +1,73 @@
+//! Framebuffer interface, implemented by the display drivers
+//!
+//! Clients draw straight into the pixels, which are shared with the display, then tell the
+//! driver which part changed with [`Framebuffer::flush`]. Pixels are 32-bit `0x00RRGGBB`.
+
+use kernel_api::KError;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels from the start of a row to the start of the next one
+    pub stride: u32,
+}
+
+impl FbInfo {
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The part of `self` inside `other`, empty if they don't overlap
+    pub fn intersect(&self, other: Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        Rect::new(x, y, right.saturating_sub(x), bottom.saturating_sub(y))
+    }
+}
+
+pub trait Framebuffer {
+    fn info(&self) -> FbInfo;
+
+    /// The pixels, row by row, `stride` pixels apart
+    fn pixels(&mut self) -> &mut [u32];
+
+    /// Makes what was drawn in `rect` visible
+    fn flush(&mut self, rect: Rect) -> Result<(), KError>;
+}
+
+/// Fills `rect` with `color`, without flushing it
+pub fn fill_rect(fb: &mut dyn Framebuffer, rect: Rect, color: u32) {
+    let info = fb.info();
+    let rect = rect.intersect(info.bounds());
+    let pixels = fb.pixels();
+    for y in rect.y..rect.y + rect.height {
+        let start = (y * info.stride + rect.x) as usize;
+        pixels[start..start + rect.width as usize].fill(color);
+    }
+}
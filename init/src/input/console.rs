@@ -0,0 +1,46 @@
+//! Typing on the console with an input device's keyboard
+//!
+//! The kernel console only reads the UART, so the keys are translated here and fed to it with
+//! [`console_input`]. Whoever reads the console has to pump the keyboard while waiting for a
+//! line, so it puts the console in `NonBlock` mode, see the shell.
+
+use crate::input::keymap::{Keyboard, Layout, MAX_SEQUENCE};
+use crate::input::service::{InputService, Subscription};
+use crate::input::EV_KEY;
+use crate::utils::console_input;
+use kernel_api::KError;
+
+pub struct ConsoleInput {
+    subscription: Subscription,
+    keyboard: Keyboard,
+}
+
+impl ConsoleInput {
+    pub fn new(service: &mut InputService, layout: &'static Layout) -> Result<Self, KError> {
+        let subscription = service.subscribe(&[EV_KEY])?;
+        Ok(Self {
+            subscription,
+            keyboard: Keyboard::new(layout),
+        })
+    }
+
+    pub fn keyboard_mut(&mut self) -> &mut Keyboard {
+        &mut self.keyboard
+    }
+
+    /// Waits for keys for at most `timeout_ns` nanoseconds, and types the ones that came
+    pub fn pump(&mut self, service: &mut InputService, timeout_ns: u64) -> Result<(), KError> {
+        let mut next = service.wait(&self.subscription, timeout_ns)?;
+        while let Some(event) = next {
+            if event.event_type == EV_KEY {
+                let mut sequence = [0u8; MAX_SEQUENCE];
+                let len = self.keyboard.key(event.code, event.value, &mut sequence);
+                if len != 0 {
+                    console_input(&sequence[..len])?;
+                }
+            }
+            next = service.next_event(&self.subscription);
+        }
+        Ok(())
+    }
+}
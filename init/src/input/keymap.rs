@@ -0,0 +1,177 @@
+//! Keyboard layouts: turning key events into the characters the console expects
+//!
+//! Layouts map the Linux key codes of the main block and the keypad, which are the first 84,
+//! to a character without and with shift. The keys beyond that the console knows, the arrows
+//! and the editing block, send the same escape sequences as a VT100-style terminal. The keypad
+//! always types digits, there's no num lock.
+
+/// Key codes covered by the layout tables
+const LAYOUT_KEYS: usize = 84;
+/// The longest sequence a single key sends
+pub const MAX_SEQUENCE: usize = 4;
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_KPENTER: u16 = 96;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_KPSLASH: u16 = 98;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_HOME: u16 = 102;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_END: u16 = 107;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+const KEY_INSERT: u16 = 110;
+const KEY_DELETE: u16 = 111;
+
+/// Modifier bits
+const SHIFT_LEFT: u8 = 1 << 0;
+const SHIFT_RIGHT: u8 = 1 << 1;
+const CTRL_LEFT: u8 = 1 << 2;
+const CTRL_RIGHT: u8 = 1 << 3;
+const ALT_LEFT: u8 = 1 << 4;
+const ALT_RIGHT: u8 = 1 << 5;
+
+pub struct Layout {
+    pub name: &'static str,
+    /// The character of each key code, 0 if it has none
+    normal: [u8; LAYOUT_KEYS],
+    shifted: [u8; LAYOUT_KEYS],
+}
+
+pub static US: Layout = Layout {
+    name: "us",
+    normal: *b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 \
+        \0\0\0\0\0\0\0\0\0\0\0\0\x00789-456+1230.",
+    shifted: *b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 \
+        \0\0\0\0\0\0\0\0\0\0\0\0\x00789-456+1230.",
+};
+
+pub static DVORAK: Layout = Layout {
+    name: "dvorak",
+    normal: *b"\0\x1b1234567890[]\x7f\t',.pyfgcrl/=\r\0aoeuidhtns-`\0\\;qjkxbmwvz\0*\0 \
+        \0\0\0\0\0\0\0\0\0\0\0\0\x00789-456+1230.",
+    shifted: *b"\0\x1b!@#$%^&*(){}\x7f\t\"<>PYFGCRL?+\r\0AOEUIDHTNS_~\0|:QJKXBMWVZ\0*\0 \
+        \0\0\0\0\0\0\0\0\0\0\0\0\x00789-456+1230.",
+};
+
+pub static LAYOUTS: [&Layout; 2] = [&US, &DVORAK];
+
+pub fn find_layout(name: &str) -> Option<&'static Layout> {
+    LAYOUTS.iter().copied().find(|layout| layout.name == name)
+}
+
+/// Tracks the modifiers and translates the keys pressed
+pub struct Keyboard {
+    layout: &'static Layout,
+    modifiers: u8,
+    caps_lock: bool,
+}
+
+impl Keyboard {
+    pub fn new(layout: &'static Layout) -> Self {
+        Self {
+            layout,
+            modifiers: 0,
+            caps_lock: false,
+        }
+    }
+
+    pub fn layout(&self) -> &'static Layout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: &'static Layout) {
+        self.layout = layout;
+    }
+
+    /// Handles the `EV_KEY` event of `code`, `value` being 0 for a release, 1 for a press and 2
+    /// for a repeat. Writes what it types to `out` and returns its length.
+    pub fn key(&mut self, code: u16, value: i32, out: &mut [u8; MAX_SEQUENCE]) -> usize {
+        let modifier = match code {
+            KEY_LEFTSHIFT => SHIFT_LEFT,
+            KEY_RIGHTSHIFT => SHIFT_RIGHT,
+            KEY_LEFTCTRL => CTRL_LEFT,
+            KEY_RIGHTCTRL => CTRL_RIGHT,
+            KEY_LEFTALT => ALT_LEFT,
+            KEY_RIGHTALT => ALT_RIGHT,
+            _ => 0,
+        };
+        if modifier != 0 {
+            match value {
+                0 => self.modifiers &= !modifier,
+                _ => self.modifiers |= modifier,
+            }
+            return 0;
+        }
+        if value == 0 {
+            return 0;
+        }
+        if code == KEY_CAPSLOCK {
+            if value == 1 {
+                self.caps_lock = !self.caps_lock;
+            }
+            return 0;
+        }
+
+        let sequence: &[u8] = match code {
+            KEY_UP => b"\x1b[A",
+            KEY_DOWN => b"\x1b[B",
+            KEY_RIGHT => b"\x1b[C",
+            KEY_LEFT => b"\x1b[D",
+            KEY_HOME => b"\x1b[H",
+            KEY_END => b"\x1b[F",
+            KEY_INSERT => b"\x1b[2~",
+            KEY_DELETE => b"\x1b[3~",
+            KEY_PAGEUP => b"\x1b[5~",
+            KEY_PAGEDOWN => b"\x1b[6~",
+            _ => b"",
+        };
+        if !sequence.is_empty() {
+            out[..sequence.len()].copy_from_slice(sequence);
+            return sequence.len();
+        }
+
+        let Some(ch) = self.char(code) else {
+            return 0;
+        };
+        let mut len = 0;
+        if self.modifiers & (ALT_LEFT | ALT_RIGHT) != 0 {
+            out[len] = 0x1b;
+            len += 1;
+        }
+        out[len] = match self.modifiers & (CTRL_LEFT | CTRL_RIGHT) != 0 {
+            true if ch.is_ascii_alphabetic() || (b'@'..=b'_').contains(&ch) => ch & 0x1f,
+            _ => ch,
+        };
+        len + 1
+    }
+
+    /// The character of `code` with the current shift and caps lock state
+    fn char(&self, code: u16) -> Option<u8> {
+        let code = code as usize;
+        let ch = match code {
+            _ if code == KEY_KPENTER as usize => b'\r',
+            _ if code == KEY_KPSLASH as usize => b'/',
+            0..LAYOUT_KEYS => {
+                let normal = self.layout.normal[code];
+                let mut shift = self.modifiers & (SHIFT_LEFT | SHIFT_RIGHT) != 0;
+                if self.caps_lock && normal.is_ascii_lowercase() {
+                    shift = !shift;
+                }
+                match shift {
+                    true => self.layout.shifted[code],
+                    false => normal,
+                }
+            }
+            _ => 0,
+        };
+        (ch != 0).then_some(ch)
+    }
+}
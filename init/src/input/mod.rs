@@ -0,0 +1,24 @@
+//! Input: the events of all input devices, published to the clients that subscribed to them,
+//! and keyboard layouts to turn key presses into text for the console
+
+pub mod console;
+pub mod keymap;
+pub mod service;
+
+pub use virtio::input::{EV_ABS, EV_KEY, EV_REL, EV_SYN};
+
+/// Event types clients can subscribe to
+pub const EVENT_TYPES: [u16; 3] = [EV_KEY, EV_REL, EV_ABS];
+
+pub fn type_name(event_type: u16) -> &'static str {
+    match event_type {
+        EV_SYN => "SYN",
+        EV_KEY => "KEY",
+        EV_REL => "REL",
+        EV_ABS => "ABS",
+        virtio::input::EV_MSC => "MSC",
+        virtio::input::EV_LED => "LED",
+        virtio::input::EV_REP => "REP",
+        _ => "?",
+    }
+}
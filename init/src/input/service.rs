@@ -0,0 +1,168 @@
+//! The input service: reads the events of every input device and hands each one to all the
+//! clients that subscribed to its type
+//!
+//! There's no IPC yet, so for now clients call it directly, like the shell does; subscribing,
+//! reading and waiting map one-to-one to the messages of an input interface. Every subscriber
+//! gets its own queue, so a slow one only loses its own events.
+
+use crate::drv::virtio_input::{VirtioInputDevice, MAX_DEVICES};
+use crate::input::EV_SYN;
+use crate::net::buffer::PageBox;
+use crate::time;
+use kernel_api::clock::ClockId;
+use kernel_api::KError;
+
+pub const MAX_SUBSCRIBERS: usize = 4;
+/// Events queued per subscriber, past this new ones are dropped
+const QUEUE_LEN: usize = 64;
+/// Only one device's interrupt can be waited for, the others are checked this often
+const POLL_INTERVAL_NS: u64 = 10_000_000;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Event {
+    /// Which device sent it, an index into [`InputService::devices`]
+    pub device: usize,
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// A client's subscription, to read its events with
+pub struct Subscription(usize);
+
+#[derive(Copy, Clone)]
+struct Subscriber {
+    /// One bit per event type, `EV_SYN` is always included
+    types: u32,
+    queue: [Event; QUEUE_LEN],
+    start: usize,
+    len: usize,
+    dropped: u64,
+}
+
+impl Subscriber {
+    fn push(&mut self, event: Event) {
+        if self.len == QUEUE_LEN {
+            self.dropped += 1;
+            return;
+        }
+        self.queue[(self.start + self.len) % QUEUE_LEN] = event;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.queue[self.start];
+        self.start = (self.start + 1) % QUEUE_LEN;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+pub struct InputService {
+    devices: [Option<VirtioInputDevice>; MAX_DEVICES],
+    subscribers: PageBox<[Option<Subscriber>; MAX_SUBSCRIBERS]>,
+}
+
+impl InputService {
+    pub fn new(devices: [Option<VirtioInputDevice>; MAX_DEVICES]) -> Result<Self, KError> {
+        Ok(Self {
+            devices,
+            subscribers: PageBox::new([None; MAX_SUBSCRIBERS])?,
+        })
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (usize, &VirtioInputDevice)> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter_map(|(i, dev)| dev.as_ref().map(|dev| (i, dev)))
+    }
+
+    /// Starts queueing the events of `types`, from all devices
+    pub fn subscribe(&mut self, types: &[u16]) -> Result<Subscription, KError> {
+        let mask = types
+            .iter()
+            .try_fold(1u32 << EV_SYN, |mask, &event_type| {
+                (event_type < u32::BITS as u16).then_some(mask | 1 << event_type)
+            })
+            .ok_or(KError::InvalidArgument)?;
+        let slot = self
+            .subscribers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(KError::OOM)?;
+        self.subscribers[slot] = Some(Subscriber {
+            types: mask,
+            queue: [Event::default(); QUEUE_LEN],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        });
+        Ok(Subscription(slot))
+    }
+
+    pub fn unsubscribe(&mut self, subscription: Subscription) {
+        self.subscribers[subscription.0] = None;
+    }
+
+    /// Moves the events the devices have to the queues of the subscribers
+    pub fn poll(&mut self) -> Result<(), KError> {
+        for (i, dev) in self.devices.iter_mut().enumerate() {
+            let Some(dev) = dev else {
+                continue;
+            };
+            while let Some(event) = dev.pop_event()? {
+                let event = Event {
+                    device: i,
+                    event_type: event.event_type,
+                    code: event.code,
+                    value: event.value,
+                };
+                for subscriber in self.subscribers.iter_mut().flatten() {
+                    if event.event_type < u32::BITS as u16
+                        && subscriber.types & (1 << event.event_type) != 0
+                    {
+                        subscriber.push(event);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the next queued event of `subscription`, without waiting
+    pub fn next_event(&mut self, subscription: &Subscription) -> Option<Event> {
+        self.subscribers[subscription.0].as_mut()?.pop()
+    }
+
+    /// How many events `subscription` lost because its queue was full
+    pub fn dropped(&self, subscription: &Subscription) -> u64 {
+        self.subscribers[subscription.0].map_or(0, |subscriber| subscriber.dropped)
+    }
+
+    /// Waits for the next event of `subscription`, for at most `timeout_ns` nanoseconds
+    pub fn wait(
+        &mut self,
+        subscription: &Subscription,
+        timeout_ns: u64,
+    ) -> Result<Option<Event>, KError> {
+        let deadline = time::now(ClockId::Monotonic).saturating_add(timeout_ns);
+        loop {
+            self.poll()?;
+            if let Some(event) = self.next_event(subscription) {
+                return Ok(Some(event));
+            }
+            let left = deadline.saturating_sub(time::now(ClockId::Monotonic));
+            if left == 0 {
+                return Ok(None);
+            }
+            let Some((_, dev)) = self.devices().next() else {
+                return Ok(None);
+            };
+            dev.wait(left.min(POLL_INTERVAL_NS))?;
+        }
+    }
+}
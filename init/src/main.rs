@@ -1,13 +1,48 @@
 #![no_std]
 #![no_main]
 
+mod archive;
+mod block;
 mod drv;
+mod dtb;
+mod framebuffer;
+mod input;
+mod net;
+mod p9;
+mod pci;
+mod shell;
+mod time;
 pub(crate) mod utils;
 
-use crate::drv::GicAndTimer;
+use crate::archive::Archive;
+use crate::block::BLOCK_SIZE;
+use crate::drv::arm_gic::GicAndTimer;
+use crate::drv::fw_cfg::FwCfg;
+use crate::drv::gpio_keys::GpioKeys;
+use crate::drv::initrd::Initrd;
+use crate::drv::nvme::NvmeController;
+use crate::drv::pl011::Pl011;
+use crate::drv::pl031::Pl031;
+use crate::drv::pl061::Pl061;
+use crate::drv::psci::Psci;
+use crate::drv::ramfb::RamFb;
+use crate::drv::virtio::for_each_device;
+use crate::drv::virtio_blk::VirtioBlockDevice;
+use crate::drv::virtio_console::VirtioConsoleDevice;
+use crate::drv::virtio_gpu::VirtioGpuDevice;
+use crate::drv::virtio_input::VirtioInputDevice;
+use crate::drv::virtio_net::VirtioNetDevice;
+use crate::drv::virtio_rng::VirtioRngDevice;
+use crate::framebuffer::Framebuffer;
+use crate::input::console::ConsoleInput;
+use crate::input::keymap;
+use crate::input::service::InputService;
+use crate::net::service::NetService;
+use crate::pci::host::PciBus;
+use crate::shell::Shutdown;
 use crate::utils::{
-    download_more_ram, dump_hex_slice, exit, mem_map, mem_unmap, phy_map, sleep_sec,
-    FmtWriteAdapter,
+    clock_set, download_more_ram, dump_hex_slice, exit, mem_map, mem_unmap, phy_map, set_cmdline,
+    sleep_sec, FmtWriteAdapter,
 };
 use core::fmt::Write;
 use core::panic::PanicInfo;
@@ -15,7 +50,11 @@ use core::ptr::slice_from_raw_parts;
 use fdt_rs::base::DevTree;
 use fdt_rs::error::DevTreeError;
 use fdt_rs::prelude::{FallibleIterator, PropReader};
-use kernel_api::{MemMapFlags, PhyMapFlags};
+use kernel_api::clock::{ClockId, NSEC_PER_SEC};
+use kernel_api::cmdline::{BootOptions, OptionError};
+use kernel_api::datetime::DateTime;
+use kernel_api::{KError, MemMapFlags, PhyMapFlags};
+use virtio::blk::SECTOR_SIZE;
 
 fn map_dtb() -> Result<DevTree<'static>, DevTreeError> {
     unsafe {
@@ -41,20 +80,10 @@ fn map_dtb() -> Result<DevTree<'static>, DevTreeError> {
 
 fn find_mem_nodes(dtb: &DevTree) -> Result<(), DevTreeError> {
     let mut node_iter = dtb.nodes();
-    let mut bootargs = None;
     let mut mem = None;
     while let Some(node) = node_iter.next()? {
         let node_name = node.name()?;
-        if node_name == "chosen" {
-            let mut prop_iter = node.props();
-            while let Some(prop) = prop_iter.next()? {
-                if prop.name()? == "bootargs" {
-                    bootargs = prop.iter_str().next()?;
-                    break;
-                }
-                // println!("  {}: {:?}", prop.name()?, prop.iter_str());
-            }
-        } else if node_name.starts_with("memory@") {
+        if node_name.starts_with("memory@") {
             let mut prop_iter = node.props();
             while let Some(prop) = prop_iter.next()? {
                 if prop.name()? == "reg" {
@@ -65,11 +94,6 @@ fn find_mem_nodes(dtb: &DevTree) -> Result<(), DevTreeError> {
         }
     }
 
-    if let Some(bootargs) = bootargs {
-        println!("Boot args: {:?}", bootargs);
-    } else {
-        println!("No boot args");
-    }
     let mem = mem.expect("device tree did not contain memory node");
     println!("RAM: 0p{:x} ({} bytes)", mem.0, mem.1);
 
@@ -79,6 +103,49 @@ fn find_mem_nodes(dtb: &DevTree) -> Result<(), DevTreeError> {
     Ok(())
 }
 
+fn print_fw_cfg(fw_cfg: &FwCfg) -> Result<(), KError> {
+    println!(
+        "fw_cfg files ({}):",
+        if fw_cfg.has_dma() { "DMA" } else { "no DMA" }
+    );
+    for file in fw_cfg.files()? {
+        let file = file?;
+        println!("  0x{:04x} {:>8} {}", file.select, file.size, file.name());
+    }
+
+    let mut cmdline_buf = [0u8; 256];
+    match fw_cfg.cmdline(&mut cmdline_buf)? {
+        Some(cmdline) => println!("fw_cfg command line: {:?}", cmdline),
+        None => println!("fw_cfg has no command line"),
+    }
+    match fw_cfg.initrd_size()? {
+        0 => println!("fw_cfg has no initrd"),
+        size => {
+            let mut head = [0u8; 16];
+            fw_cfg.read_initrd(0, &mut head)?;
+            println!("fw_cfg initrd: {} bytes, starting with:", size);
+            dump_hex_slice(&head);
+        }
+    }
+    Ok(())
+}
+
+fn print_virtio_devices(dtb: &DevTree) -> Result<(), DevTreeError> {
+    println!("Virtio devices:");
+    for_each_device(dtb, |device| {
+        let transport = &device.transport;
+        println!(
+            "  0p{:x}: {:?} (version {}, vendor 0x{:x}, IRQ {})",
+            device.phy_addr,
+            transport.device_type(),
+            transport.version(),
+            transport.vendor_id(),
+            transport.interrupt_id()
+        );
+        true
+    })
+}
+
 fn main() {
     println!("Hello from usermode!");
 
@@ -89,9 +156,43 @@ fn main() {
     );
     dump_hex_slice(&dtb.buf()[..32]);
 
+    // Boot options, the kernel gets them too once the initrd is safe from memory poisoning
+    let bootargs = dtb::bootargs(&dtb)
+        .expect("Failed to parse device tree")
+        .unwrap_or("");
+    println!("Boot args: {:?}", bootargs);
+    let options = BootOptions::parse(bootargs, |err| match err {
+        OptionError::Unknown(arg) => println!("Unknown boot argument: {:?}", arg.key),
+        OptionError::InvalidValue(arg) => {
+            println!("Invalid boot argument: {}={:?}", arg.key, arg.value)
+        }
+    });
+
     // Find all memory nodes
     find_mem_nodes(&dtb).expect("Failed to parse device tree");
 
+    // Claim the initrd before anything else gets allocated over it
+    let initrd = Initrd::find_and_init(&dtb).expect("Failed to parse device tree");
+    // `overwrite_free_pages` poisons every free page as soon as the kernel applies it
+    set_cmdline(bootargs).expect("Failed to pass the command line to the kernel");
+    let initrd_files = initrd.as_ref().and_then(|initrd| {
+        println!(
+            "Initrd: 0p{:x} ({} bytes)",
+            initrd.phy_addr(),
+            initrd.data().len()
+        );
+        let archive = Archive::new(initrd.data());
+        match &archive {
+            Some(archive) => println!(
+                "Initrd is a {:?} archive with {} entries",
+                archive.format(),
+                archive.entries().count()
+            ),
+            None => println!("Initrd is not an archive"),
+        }
+        archive
+    });
+
     // Allocate 10 MB
     println!("Allocating big buffer using newly discovered memory");
     let buf = unsafe { mem_map(1024 * 1024 * 10, MemMapFlags::ReadWrite) }.unwrap();
@@ -100,8 +201,258 @@ fn main() {
     // Find all devices
     let _gic_and_timer = GicAndTimer::find_and_init(&dtb).expect("Failed to parse device tree");
 
+    // Seed the wall clock from the RTC
+    let rtc = Pl031::find_and_init(&dtb).expect("Failed to parse device tree");
+    if let Some(rtc) = &rtc {
+        let rtc_time = rtc.time();
+        clock_set(ClockId::Realtime, rtc_time as u64 * NSEC_PER_SEC)
+            .expect("Failed to set the realtime clock");
+        println!("RTC: {}", DateTime::from_unix(rtc_time as i64));
+    } else {
+        println!("No RTC found, wall-clock time is unknown");
+    }
+
+    let psci = Psci::find_and_init(&dtb).expect("Failed to parse device tree");
+    if let Some(psci) = &psci {
+        let (major, minor) = psci.version();
+        println!("PSCI {major}.{minor} over {}", psci.method());
+    }
+
+    let fw_cfg = FwCfg::find_and_init(&dtb).expect("Failed to parse device tree");
+    let mut ramfb = None;
+    if let Some(fw_cfg) = &fw_cfg {
+        print_fw_cfg(fw_cfg).expect("Failed to read fw_cfg");
+        // The display works as soon as fw_cfg does
+        match RamFb::find_and_init(fw_cfg) {
+            Ok(Some(fb)) => {
+                let info = fb.info();
+                println!(
+                    "RAM framebuffer at 0p{:x}: {}x{}",
+                    fb.phy_addr(),
+                    info.width,
+                    info.height
+                );
+                if let Err(err) = fb.attach_console() {
+                    println!("Failed to start the framebuffer console: {err:?}");
+                }
+                ramfb = Some(fb);
+            }
+            Ok(None) => {}
+            Err(err) => println!("Failed to set up the RAM framebuffer: {err:?}"),
+        }
+    }
+
+    print_virtio_devices(&dtb).expect("Failed to parse device tree");
+    // Seed the kernel's entropy pool, otherwise it has to gather interrupt jitter for a while
+    if let Some(mut rng) =
+        VirtioRngDevice::find_and_init(&dtb).expect("Failed to parse device tree")
+    {
+        match rng.feed_kernel() {
+            Ok(()) => println!(
+                "Seeded the entropy pool from virtio-rng at 0p{:x}",
+                rng.phy_addr()
+            ),
+            Err(err) => println!("Failed to read virtio-rng: {err:?}"),
+        }
+    }
+
+    let disk = VirtioBlockDevice::find_and_init(&dtb).expect("Failed to parse device tree");
+    if let Some(disk) = &disk {
+        let dev = disk.device();
+        println!(
+            "Disk at 0p{:x}: {} sectors ({} MiB), block size {}{}{}{}",
+            disk.phy_addr(),
+            dev.capacity(),
+            dev.capacity() * SECTOR_SIZE as u64 / (1024 * 1024),
+            dev.block_size(),
+            if dev.read_only() { ", read-only" } else { "" },
+            if dev.supports_flush() { ", flush" } else { "" },
+            if dev.supports_discard() {
+                ", discard"
+            } else {
+                ""
+            },
+        );
+    }
+
+    let mut vconsole =
+        VirtioConsoleDevice::find_and_init(&dtb).expect("Failed to parse device tree");
+    if let Some(vconsole) = &mut vconsole {
+        println!(
+            "Virtio console at 0p{:x}{}, ports:",
+            vconsole.phy_addr(),
+            if vconsole.is_multiport() {
+                " (multiport)"
+            } else {
+                ""
+            }
+        );
+        vconsole
+            .for_each_port(|port| println!("  {}: {:?}", port.id, port.name))
+            .expect("Failed to read the virtio console ports");
+    }
+
+    let gpu = VirtioGpuDevice::find_and_init(&dtb).expect("Failed to parse device tree");
+    if let Some(gpu) = &gpu {
+        let info = gpu.info();
+        println!(
+            "Virtio GPU at 0p{:x}: {}x{} framebuffer on scanout {}",
+            gpu.phy_addr(),
+            info.width,
+            info.height,
+            gpu.scanout()
+        );
+    }
+
+    let mut pci = PciBus::find_and_init(&dtb).expect("Failed to parse device tree");
+    let mut nvme = None;
+    if let Some(pci) = &mut pci {
+        println!(
+            "PCIe host bridge at 0p{:x}: {} functions",
+            pci.phy_addr(),
+            pci.functions().count()
+        );
+        match NvmeController::find_and_init(pci) {
+            Ok(Some(controller)) => {
+                let (major, minor, _) = controller.version();
+                println!(
+                    "NVMe controller at {}: {:?} (serial {:?}, firmware {:?}), NVMe {major}.{minor}",
+                    controller.device().address(),
+                    controller.model(),
+                    controller.serial(),
+                    controller.firmware(),
+                );
+                for ns in controller.namespaces() {
+                    println!(
+                        "  nvme0n{}: {} blocks of {} bytes ({} MiB)",
+                        ns.id,
+                        ns.block_count() * BLOCK_SIZE as u64 / ns.lba_size() as u64,
+                        ns.lba_size(),
+                        ns.block_count() * BLOCK_SIZE as u64 / (1024 * 1024),
+                    );
+                }
+                nvme = Some(controller);
+            }
+            Ok(None) => {}
+            Err(err) => println!("Failed to start the NVMe controller: {err:?}"),
+        }
+    }
+
+    let gpio = Pl061::find_and_init(&dtb).expect("Failed to parse device tree");
+    let mut gpio_keys = None;
+    if let Some(gpio) = &gpio {
+        println!("GPIO controller at 0p{:x}", gpio.phy_addr());
+        gpio_keys = GpioKeys::find_and_init(&dtb, gpio).expect("Failed to parse device tree");
+        for key in gpio_keys.iter().flat_map(GpioKeys::keys) {
+            println!("  pin {}: {:?}, key {}", key.pin, key.label(), key.code);
+        }
+    }
+
+    let input_devices =
+        VirtioInputDevice::find_and_init_all(&dtb).expect("Failed to parse device tree");
+    let mut input = None;
+    let mut console_input = None;
+    if input_devices.iter().any(Option::is_some) {
+        let mut service =
+            InputService::new(input_devices).expect("Failed to start the input service");
+        let mut keyboard = false;
+        for (i, dev) in service.devices() {
+            print!(
+                "Input device {i} at 0p{:x}: {:?}, events",
+                dev.phy_addr(),
+                dev.name()
+            );
+            for event_type in input::EVENT_TYPES.iter().filter(|&&t| dev.supports(t)) {
+                print!(" {}", input::type_name(*event_type));
+            }
+            println!();
+            keyboard |= dev.supports(input::EV_KEY);
+        }
+        // Keys typed on it go to the console too
+        if keyboard {
+            match ConsoleInput::new(&mut service, &keymap::US) {
+                Ok(console) => console_input = Some(console),
+                Err(err) => println!("Failed to attach the keyboard to the console: {err:?}"),
+            }
+        }
+        input = Some(service);
+    }
+
+    let mut net = VirtioNetDevice::find_and_init(&dtb)
+        .expect("Failed to parse device tree")
+        .map(|dev| NetService::new(dev).expect("Failed to set up the network stack"));
+    if let Some(net) = &mut net {
+        let dev = net.device();
+        println!(
+            "Network card at 0p{:x}: {}, link {}",
+            dev.phy_addr(),
+            net.stack().mac(),
+            if dev.link_up() { "up" } else { "down" },
+        );
+        match net.dhcp(shell::DHCP_TIMEOUT_NS) {
+            Ok(lease) => shell::print_lease(&lease),
+            Err(err) => println!("DHCP failed: {err}, the network has no address"),
+        }
+    }
+
+    let rootfs = p9::Client::find_and_init(&dtb, "rootfs").expect("Failed to parse device tree");
+    if rootfs.is_some() {
+        println!("Mounted the 9P share \"rootfs\" at {}", shell::ROOTFS_MOUNT);
+    }
+
+    // A second UART, if there's one, is for debugging with GDB
+    if Pl011::find_and_init_gdb_stub(&dtb, options.console)
+        .expect("Failed to parse device tree")
+        .is_some()
+    {
+        println!("GDB stub listening on the second UART");
+    }
+
+    // Switch the console to the UART described in the DTB, which also enables input
+    let console = Pl011::find_and_init(&dtb, options.console).expect("Failed to parse device tree");
+    if console.is_some() {
+        if let Some(motd) = initrd_files.and_then(|files| files.find("etc/motd")) {
+            print!("{}", core::str::from_utf8(motd.data).unwrap_or(""));
+        }
+        let ctx = shell::Context {
+            initrd,
+            initrd_files,
+            rtc,
+            disk,
+            vconsole,
+            gpu,
+            ramfb,
+            input,
+            console_input,
+            gpio,
+            gpio_keys,
+            pci,
+            nvme,
+            net,
+            rootfs,
+            init_script: options.init,
+            shutdown: None,
+        };
+        let shutdown = shell::run(ctx).expect("Console failed");
+        if let Some(psci) = &psci {
+            let err = match shutdown {
+                Shutdown::PowerOff => psci.power_off(),
+                Shutdown::Reboot => psci.reboot(),
+            };
+            println!("PSCI failed: {err:?}");
+        }
+        println!("System halted");
+        loop {
+            sleep_sec(3600);
+        }
+    }
+
     loop {
-        println!("Current time: {} ms", GicAndTimer::current_time_ms());
+        println!(
+            "Current time: {} ms ({})",
+            time::now(ClockId::Boot) / 1_000_000,
+            DateTime::from_unix_nanos(time::now(ClockId::Realtime))
+        );
         sleep_sec(1);
     }
 }
@@ -116,5 +467,6 @@ pub extern "C" fn _start() -> ! {
 #[panic_handler]
 fn rust_panic(info: &PanicInfo) -> ! {
     let _ = write!(FmtWriteAdapter, "Panic: {}\n", info.message());
-    loop {}
+    // The kernel can't go on without init, it handles that like its own panics
+    unsafe { exit(1) }
 }
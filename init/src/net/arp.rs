@@ -0,0 +1,117 @@
+//! ARP, for finding the hardware address of neighbours (RFC 826)
+
+use crate::net::{Ipv4Addr, MacAddr};
+
+pub const PACKET_LEN: usize = 28;
+
+const HTYPE_ETHERNET: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+pub const OP_REQUEST: u16 = 1;
+pub const OP_REPLY: u16 = 2;
+
+/// How long answers are trusted
+const ENTRY_LIFETIME_NS: u64 = 60_000_000_000;
+const CACHE_SIZE: usize = 16;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Packet {
+    pub op: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Packet {
+    /// Parses an Ethernet/IPv4 ARP packet, anything else is ignored
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..PACKET_LEN)?;
+        let field = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+        if field(0) != HTYPE_ETHERNET || field(2) != PTYPE_IPV4 || data[4] != 6 || data[5] != 4 {
+            return None;
+        }
+        let mac = |offset: usize| MacAddr(data[offset..offset + 6].try_into().unwrap());
+        let ip = |offset: usize| Ipv4Addr(data[offset..offset + 4].try_into().unwrap());
+        Some(Self {
+            op: field(6),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+        buf[2..4].copy_from_slice(&PTYPE_IPV4.to_be_bytes());
+        buf[4] = 6;
+        buf[5] = 4;
+        buf[6..8].copy_from_slice(&self.op.to_be_bytes());
+        buf[8..14].copy_from_slice(&self.sender_mac.0);
+        buf[14..18].copy_from_slice(&self.sender_ip.0);
+        buf[18..24].copy_from_slice(&self.target_mac.0);
+        buf[24..28].copy_from_slice(&self.target_ip.0);
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Entry {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    /// When the entry was last confirmed
+    pub updated: u64,
+}
+
+pub struct ArpCache {
+    entries: [Option<Entry>; CACHE_SIZE],
+}
+
+impl ArpCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; CACHE_SIZE],
+        }
+    }
+
+    pub fn lookup(&self, ip: Ipv4Addr, now: u64) -> Option<MacAddr> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|entry| entry.ip == ip && now < entry.updated + ENTRY_LIFETIME_NS)
+            .map(|entry| entry.mac)
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        self.entries.iter().flatten().any(|entry| entry.ip == ip)
+    }
+
+    /// Adds or refreshes an entry, evicting the oldest one if the cache is full
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr, now: u64) {
+        let slot = match self
+            .entries
+            .iter()
+            .position(|e| e.is_some_and(|e| e.ip == ip))
+        {
+            Some(slot) => slot,
+            None => match self.entries.iter().position(Option::is_none) {
+                Some(slot) => slot,
+                None => (0..CACHE_SIZE)
+                    .min_by_key(|&i| self.entries[i].map_or(0, |entry| entry.updated))
+                    .unwrap_or(0),
+            },
+        };
+        self.entries[slot] = Some(Entry {
+            ip,
+            mac,
+            updated: now,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries = [None; CACHE_SIZE];
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().flatten()
+    }
+}
@@ -0,0 +1,157 @@
+//! Memory for the stack: init's stack is far too small for frames and socket buffers, so they
+//! get pages of their own. The kernel doesn't free unmapped memory yet, so socket buffers are
+//! recycled rather than given back.
+
+use crate::utils::{mem_map, mem_unmap};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use kernel_api::{KError, MemMapFlags};
+
+/// Zeroed pages, unmapped on drop
+pub struct Pages {
+    base: NonNull<u8>,
+    len: usize,
+}
+
+impl Pages {
+    pub fn new(len: usize) -> Result<Self, KError> {
+        let base = unsafe { mem_map(len, MemMapFlags::ReadWrite) }? as *mut u8;
+        let base = NonNull::new(base).ok_or(KError::OOM)?;
+        unsafe { base.as_ptr().write_bytes(0, len) };
+        Ok(Self { base, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.base.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.base.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        let _ = unsafe { mem_unmap(self.base.as_ptr() as *const (), self.len) };
+    }
+}
+
+/// A value in pages of its own, for structures too big for the stack
+pub struct PageBox<T> {
+    pages: Pages,
+    _value: PhantomData<T>,
+}
+
+impl<T> PageBox<T> {
+    pub fn new(value: T) -> Result<Self, KError> {
+        let pages = Pages::new(size_of::<T>().max(1))?;
+        unsafe { (pages.base.as_ptr() as *mut T).write(value) };
+        Ok(Self {
+            pages,
+            _value: PhantomData,
+        })
+    }
+}
+
+impl<T> Deref for PageBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(self.pages.base.as_ptr() as *const T) }
+    }
+}
+
+impl<T> DerefMut for PageBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(self.pages.base.as_ptr() as *mut T) }
+    }
+}
+
+impl<T> Drop for PageBox<T> {
+    fn drop(&mut self) {
+        unsafe { (self.pages.base.as_ptr() as *mut T).drop_in_place() };
+    }
+}
+
+/// A byte FIFO, e.g. a TCP send or receive buffer
+pub struct ByteRing {
+    memory: Pages,
+    head: usize,
+    len: usize,
+}
+
+impl ByteRing {
+    pub fn new(capacity: usize) -> Result<Self, KError> {
+        Ok(Self {
+            memory: Pages::new(capacity)?,
+            head: 0,
+            len: 0,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn free(&self) -> usize {
+        self.capacity() - self.len
+    }
+
+    /// Appends as much of `data` as fits, returns how much that was
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let count = data.len().min(self.free());
+        let capacity = self.capacity();
+        let tail = (self.head + self.len) % capacity;
+        let first = count.min(capacity - tail);
+        let memory = self.memory.as_mut_slice();
+        memory[tail..tail + first].copy_from_slice(&data[..first]);
+        memory[..count - first].copy_from_slice(&data[first..count]);
+        self.len += count;
+        count
+    }
+
+    /// Copies bytes starting `offset` bytes into the ring to `buf`, without consuming them.
+    /// Returns how many were copied.
+    pub fn peek(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len.saturating_sub(offset));
+        let capacity = self.capacity();
+        let start = (self.head + offset) % capacity;
+        let first = count.min(capacity - start);
+        let memory = self.memory.as_slice();
+        buf[..first].copy_from_slice(&memory[start..start + first]);
+        buf[first..count].copy_from_slice(&memory[..count - first]);
+        count
+    }
+
+    /// Drops the first `count` bytes
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.head = (self.head + count) % self.capacity();
+        self.len -= count;
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Takes bytes from the front into `buf`, returns how many
+    pub fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let count = self.peek(0, buf);
+        self.consume(count);
+        count
+    }
+}
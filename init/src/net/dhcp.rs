@@ -0,0 +1,393 @@
+//! DHCPv4 client (RFC 2131)
+//!
+//! The client gets an address, a default gateway and name servers, configures the stack with
+//! them, and keeps the lease alive: it renews with the server that gave it at T1, with any server
+//! at T2, and starts over when the lease runs out. Like the TCP sockets, it only acts when polled.
+
+use crate::net::ring::FrameRing;
+use crate::net::stack::{SocketHandle, Stack};
+use crate::net::{Error, Ipv4Addr, MacAddr};
+use crate::utils::get_random;
+use kernel_api::GetRandomFlags;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Where the options start, after the fixed BOOTP fields and the cookie
+const OPTIONS_OFFSET: usize = 240;
+/// BOOTP relays may drop anything shorter
+const MIN_MESSAGE_LEN: usize = 300;
+const MAX_MESSAGE_LEN: usize = 576;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+/// Retransmissions start after this and double up to [`MAX_RETRY_NS`]
+const INITIAL_RETRY_NS: u64 = 2 * NS_PER_SEC;
+const MAX_RETRY_NS: u64 = 16 * NS_PER_SEC;
+/// Requests sent for an offer before going back to discovering
+const MAX_REQUESTS: u32 = 4;
+/// How often to retry renewing or rebinding
+const RENEW_RETRY_NS: u64 = 60 * NS_PER_SEC;
+/// Used when the server doesn't say how long the lease is
+const DEFAULT_LEASE_SECS: u32 = 3600;
+
+pub const MAX_NAME_SERVERS: usize = 2;
+
+/// What the server gave us
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub name_servers: [Option<Ipv4Addr>; MAX_NAME_SERVERS],
+    pub server: Ipv4Addr,
+    pub lease_secs: u32,
+    /// When the lease was last acknowledged
+    pub acquired_at: u64,
+}
+
+impl Lease {
+    pub fn expires_at(&self) -> u64 {
+        self.acquired_at + self.lease_secs as u64 * NS_PER_SEC
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    /// Looking for a server
+    Selecting,
+    /// Asking the server that made an offer for it
+    Requesting,
+    Bound,
+    /// Asking the server that gave the lease for more time
+    Renewing,
+    /// Asking any server for more time
+    Rebinding,
+}
+
+/// A received message, the fields and options the client cares about
+#[derive(Copy, Clone, Debug, Default)]
+struct Reply {
+    message_type: u8,
+    your_ip: Ipv4Addr,
+    subnet_mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    name_servers: [Option<Ipv4Addr>; MAX_NAME_SERVERS],
+    server_id: Option<Ipv4Addr>,
+    lease_secs: Option<u32>,
+    renewal_secs: Option<u32>,
+    rebinding_secs: Option<u32>,
+}
+
+impl Reply {
+    /// Parses a reply to the transaction `xid` for `mac`, anything else is ignored
+    fn parse(data: &[u8], xid: u32, mac: MacAddr) -> Option<Self> {
+        if data.len() < OPTIONS_OFFSET
+            || data[0] != OP_REPLY
+            || data[4..8] != xid.to_be_bytes()
+            || data[28..34] != mac.0
+            || data[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut reply = Reply {
+            your_ip: Ipv4Addr(data[16..20].try_into().unwrap()),
+            ..Default::default()
+        };
+        let addr = |value: &[u8]| value.get(..4).map(|v| Ipv4Addr(v.try_into().unwrap()));
+        let secs = |value: &[u8]| {
+            value
+                .get(..4)
+                .map(|v| u32::from_be_bytes(v.try_into().unwrap()))
+        };
+        let mut options = &data[OPTIONS_OFFSET..];
+        while let Some(&code) = options.first() {
+            match code {
+                OPTION_END => break,
+                OPTION_PAD => {
+                    options = &options[1..];
+                    continue;
+                }
+                _ => {}
+            }
+            let len = *options.get(1)? as usize;
+            let value = options.get(2..2 + len)?;
+            match code {
+                OPTION_MESSAGE_TYPE => reply.message_type = *value.first()?,
+                OPTION_SUBNET_MASK => reply.subnet_mask = addr(value),
+                OPTION_ROUTER => reply.router = addr(value),
+                OPTION_DNS => {
+                    for (server, bytes) in reply.name_servers.iter_mut().zip(value.chunks_exact(4))
+                    {
+                        *server = addr(bytes);
+                    }
+                }
+                OPTION_SERVER_ID => reply.server_id = addr(value),
+                OPTION_LEASE_TIME => reply.lease_secs = secs(value),
+                OPTION_RENEWAL_TIME => reply.renewal_secs = secs(value),
+                OPTION_REBINDING_TIME => reply.rebinding_secs = secs(value),
+                _ => {}
+            }
+            options = &options[2 + len..];
+        }
+        Some(reply)
+    }
+}
+
+/// The prefix length of a netmask, e.g. 24 for 255.255.255.0
+fn mask_prefix_len(mask: Ipv4Addr) -> u8 {
+    mask.to_u32().leading_ones() as u8
+}
+
+fn random_xid() -> u32 {
+    let mut bytes = [0u8; 4];
+    let _ = get_random(&mut bytes, GetRandomFlags::empty());
+    u32::from_ne_bytes(bytes)
+}
+
+pub struct DhcpClient {
+    mac: MacAddr,
+    socket: SocketHandle,
+    state: State,
+    xid: u32,
+    /// The offer being requested, and the server that made it
+    offered: Ipv4Addr,
+    offer_server: Ipv4Addr,
+    lease: Option<Lease>,
+    /// T1 and T2, as times
+    renew_at: u64,
+    rebind_at: u64,
+    next_send: u64,
+    retry_ns: u64,
+    requests: u32,
+}
+
+impl DhcpClient {
+    /// Opens the client port and starts looking for a server
+    pub fn new(stack: &mut Stack) -> Result<Self, Error> {
+        let socket = stack.udp_bind(CLIENT_PORT)?;
+        Ok(Self {
+            mac: stack.mac(),
+            socket,
+            state: State::Selecting,
+            xid: random_xid(),
+            offered: Ipv4Addr::UNSPECIFIED,
+            offer_server: Ipv4Addr::UNSPECIFIED,
+            lease: None,
+            renew_at: 0,
+            rebind_at: 0,
+            next_send: 0,
+            retry_ns: INITIAL_RETRY_NS,
+            requests: 0,
+        })
+    }
+
+    /// The current lease, while there's one
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// Drops the lease and starts over, e.g. after moving to another network
+    pub fn restart(&mut self, stack: &mut Stack) {
+        if self.lease.take().is_some() {
+            stack.configure(Ipv4Addr::UNSPECIFIED, 0, None);
+        }
+        self.xid = random_xid();
+        self.enter(State::Selecting, 0);
+    }
+
+    fn enter(&mut self, state: State, now: u64) {
+        self.state = state;
+        self.next_send = now;
+        self.retry_ns = INITIAL_RETRY_NS;
+        self.requests = 0;
+    }
+
+    /// Handles the server's messages and the timers. Returns when it should run again.
+    pub fn poll(&mut self, stack: &mut Stack, tx: &FrameRing, now: u64) -> Option<u64> {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        while let Ok((len, _, src_port)) = stack.udp_recv_from(self.socket, &mut buf) {
+            let reply = match src_port {
+                SERVER_PORT => Reply::parse(&buf[..len.min(buf.len())], self.xid, self.mac),
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                self.handle_reply(stack, &reply, now);
+            }
+        }
+
+        if let Some(lease) = self.lease {
+            if now >= lease.expires_at() {
+                self.restart(stack);
+            } else if self.state == State::Bound && now >= self.renew_at {
+                self.xid = random_xid();
+                self.enter(State::Renewing, now);
+            } else if self.state == State::Renewing && now >= self.rebind_at {
+                self.enter(State::Rebinding, now);
+            }
+        }
+        if self.state == State::Bound {
+            return Some(self.renew_at);
+        }
+        if now >= self.next_send {
+            self.send(stack, tx, now);
+        }
+        let next = Some(self.next_send);
+        match self.lease {
+            Some(lease) => next.min(Some(lease.expires_at())),
+            None => next,
+        }
+    }
+
+    fn handle_reply(&mut self, stack: &mut Stack, reply: &Reply, now: u64) {
+        match (self.state, reply.message_type) {
+            (State::Selecting, DHCPOFFER) => {
+                let Some(server) = reply.server_id else {
+                    return;
+                };
+                self.offered = reply.your_ip;
+                self.offer_server = server;
+                self.enter(State::Requesting, now);
+            }
+            (State::Requesting | State::Renewing | State::Rebinding, DHCPACK) => {
+                self.bind(stack, reply, now);
+            }
+            (State::Requesting | State::Renewing | State::Rebinding, DHCPNAK) => {
+                self.restart(stack);
+            }
+            _ => {}
+        }
+    }
+
+    fn bind(&mut self, stack: &mut Stack, reply: &Reply, now: u64) {
+        let lease_secs = reply.lease_secs.unwrap_or(DEFAULT_LEASE_SECS);
+        let server = match self.state {
+            State::Requesting => self.offer_server,
+            _ => reply
+                .server_id
+                .or(self.lease.map(|lease| lease.server))
+                .unwrap_or_default(),
+        };
+        let lease = Lease {
+            ip: reply.your_ip,
+            prefix_len: reply.subnet_mask.map_or(24, mask_prefix_len),
+            gateway: reply.router,
+            name_servers: reply.name_servers,
+            server,
+            lease_secs,
+            acquired_at: now,
+        };
+        let changed = self.lease.is_none_or(|old| {
+            (old.ip, old.prefix_len, old.gateway) != (lease.ip, lease.prefix_len, lease.gateway)
+        });
+        if changed {
+            // Reconfiguring drops the ARP cache and routes, don't do it on every renewal
+            stack.configure(lease.ip, lease.prefix_len, lease.gateway);
+        }
+        let secs_ns = |secs: u32| secs as u64 * NS_PER_SEC;
+        self.renew_at = now + secs_ns(reply.renewal_secs.unwrap_or(lease_secs / 2));
+        self.rebind_at = now + secs_ns(reply.rebinding_secs.unwrap_or(lease_secs / 8 * 7));
+        self.lease = Some(lease);
+        self.state = State::Bound;
+    }
+
+    fn send(&mut self, stack: &mut Stack, tx: &FrameRing, now: u64) {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let (message_type, client_ip, requested, server_id, dst) = match self.state {
+            State::Selecting => (DHCPDISCOVER, None, None, None, Ipv4Addr::BROADCAST),
+            State::Requesting => {
+                if self.requests == MAX_REQUESTS {
+                    self.restart(stack);
+                    return self.send(stack, tx, now);
+                }
+                self.requests += 1;
+                (
+                    DHCPREQUEST,
+                    None,
+                    Some(self.offered),
+                    Some(self.offer_server),
+                    Ipv4Addr::BROADCAST,
+                )
+            }
+            State::Renewing | State::Rebinding => {
+                let Some(lease) = self.lease else {
+                    return;
+                };
+                let dst = match self.state {
+                    State::Renewing => lease.server,
+                    _ => Ipv4Addr::BROADCAST,
+                };
+                (DHCPREQUEST, Some(lease.ip), None, None, dst)
+            }
+            State::Bound => return,
+        };
+
+        buf[0] = OP_REQUEST;
+        buf[1] = HTYPE_ETHERNET;
+        buf[2] = self.mac.0.len() as u8;
+        buf[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        if let Some(ip) = client_ip {
+            buf[12..16].copy_from_slice(&ip.0);
+        }
+        buf[28..34].copy_from_slice(&self.mac.0);
+        buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+        let mut len = OPTIONS_OFFSET;
+        let mut option = |code: u8, value: &[u8]| {
+            buf[len] = code;
+            buf[len + 1] = value.len() as u8;
+            buf[len + 2..len + 2 + value.len()].copy_from_slice(value);
+            len += 2 + value.len();
+        };
+        option(OPTION_MESSAGE_TYPE, &[message_type]);
+        if let Some(ip) = requested {
+            option(OPTION_REQUESTED_IP, &ip.0);
+        }
+        if let Some(server) = server_id {
+            option(OPTION_SERVER_ID, &server.0);
+        }
+        option(
+            OPTION_PARAMETERS,
+            &[
+                OPTION_SUBNET_MASK,
+                OPTION_ROUTER,
+                OPTION_DNS,
+                OPTION_LEASE_TIME,
+                OPTION_RENEWAL_TIME,
+                OPTION_REBINDING_TIME,
+            ],
+        );
+        buf[len] = OPTION_END;
+        let len = (len + 1).max(MIN_MESSAGE_LEN);
+
+        let _ = stack.udp_send_to(tx, now, self.socket, dst, SERVER_PORT, &buf[..len]);
+        match self.state {
+            State::Renewing | State::Rebinding => self.next_send = now + RENEW_RETRY_NS,
+            _ => {
+                self.next_send = now + self.retry_ns;
+                self.retry_ns = (self.retry_ns * 2).min(MAX_RETRY_NS);
+            }
+        }
+    }
+}
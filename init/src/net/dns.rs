@@ -0,0 +1,324 @@
+//! Stub DNS resolver (RFC 1035), with a cache
+//!
+//! The resolver only builds queries and makes sense of the answers, the network service sends
+//! them to the name servers. It asks for the addresses of a name and relies on the server for
+//! recursion. Answers are cached for as long as their TTL says, names that don't exist for a
+//! little while too.
+
+use crate::net::{Error, IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::utils::get_random;
+use kernel_api::GetRandomFlags;
+
+pub const SERVER_PORT: u16 = 53;
+/// Queries for a single name always fit, answers are truncated past this
+pub const MAX_MESSAGE_LEN: usize = 512;
+pub const MAX_NAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+const HEADER_LEN: usize = 12;
+
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const RCODE_MASK: u16 = 0xf;
+const RCODE_NAME_ERROR: u16 = 3;
+const CLASS_IN: u16 = 1;
+
+/// Addresses kept per name
+pub const MAX_ADDRS: usize = 4;
+pub const MAX_SERVERS: usize = 2;
+const CACHE_SIZE: usize = 16;
+const NS_PER_SEC: u64 = 1_000_000_000;
+/// Longest time an answer is cached, whatever its TTL
+const MAX_TTL_SECS: u32 = 3600;
+/// How long names that don't exist are remembered
+const NEGATIVE_TTL_SECS: u32 = 30;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordType {
+    A = 1,
+    Aaaa = 28,
+}
+
+impl RecordType {
+    fn addr_len(self) -> usize {
+        match self {
+            RecordType::A => 4,
+            RecordType::Aaaa => 16,
+        }
+    }
+}
+
+/// The addresses a name resolved to
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Addresses {
+    addrs: [Option<IpAddr>; MAX_ADDRS],
+}
+
+impl Addresses {
+    pub fn iter(&self) -> impl Iterator<Item = &IpAddr> {
+        self.addrs.iter().flatten()
+    }
+
+    pub fn first_v4(&self) -> Option<Ipv4Addr> {
+        self.iter().find_map(|addr| match addr {
+            IpAddr::V4(addr) => Some(*addr),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    fn push(&mut self, addr: IpAddr) {
+        if let Some(slot) = self.addrs.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(addr);
+        }
+    }
+}
+
+/// A name, without the trailing dot and in lowercase so lookups ignore case
+#[derive(Copy, Clone)]
+struct Name {
+    bytes: [u8; MAX_NAME_LEN],
+    len: usize,
+}
+
+impl Name {
+    fn new(name: &str) -> Result<Self, Error> {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        let valid_label = |label: &str| {
+            (1..=MAX_LABEL_LEN).contains(&label.len())
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        };
+        if name.len() > MAX_NAME_LEN || !name.split('.').all(valid_label) {
+            return Err(Error::InvalidArgument);
+        }
+        let mut bytes = [0u8; MAX_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        bytes[..name.len()].make_ascii_lowercase();
+        Ok(Self {
+            bytes,
+            len: name.len(),
+        })
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Writes the name as DNS labels, returns their length
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for label in self.as_bytes().split(|b| *b == b'.') {
+            buf[len] = label.len() as u8;
+            buf[len + 1..len + 1 + label.len()].copy_from_slice(label);
+            len += 1 + label.len();
+        }
+        buf[len] = 0;
+        len + 1
+    }
+}
+
+/// Skips the name at `offset`, returns the offset right after it
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            // A pointer to a name elsewhere ends this one
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len if len > MAX_LABEL_LEN => return None,
+            len => offset += 1 + len,
+        }
+    }
+}
+
+fn u16_at(message: &[u8], offset: usize) -> Option<u16> {
+    let bytes = message.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(message: &[u8], offset: usize) -> Option<u32> {
+    let bytes = message.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[derive(Copy, Clone)]
+struct CacheEntry {
+    name: Name,
+    kind: RecordType,
+    result: Result<Addresses, Error>,
+    expires: u64,
+}
+
+/// A query waiting for its answer
+#[derive(Copy, Clone)]
+pub struct Query {
+    id: u16,
+    name: Name,
+    kind: RecordType,
+}
+
+pub struct Resolver {
+    servers: [Option<Ipv4Addr>; MAX_SERVERS],
+    cache: [Option<CacheEntry>; CACHE_SIZE],
+}
+
+impl Resolver {
+    pub const fn new() -> Self {
+        Self {
+            servers: [None; MAX_SERVERS],
+            cache: [None; CACHE_SIZE],
+        }
+    }
+
+    pub fn servers(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.servers.iter().flatten().copied()
+    }
+
+    /// Replaces the name servers, forgetting what the old ones said if they changed
+    pub fn set_servers(&mut self, servers: [Option<Ipv4Addr>; MAX_SERVERS]) {
+        if servers != self.servers {
+            self.servers = servers;
+            self.clear_cache();
+        }
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.cache = [None; CACHE_SIZE];
+    }
+
+    /// The cached answer for `name`, if there's one that hasn't expired
+    pub fn lookup(
+        &self,
+        name: &str,
+        kind: RecordType,
+        now: u64,
+    ) -> Option<Result<Addresses, Error>> {
+        let name = Name::new(name).ok()?;
+        self.cache
+            .iter()
+            .flatten()
+            .find(|entry| {
+                entry.kind == kind
+                    && entry.name.as_bytes() == name.as_bytes()
+                    && entry.expires > now
+            })
+            .map(|entry| entry.result)
+    }
+
+    /// Writes a query for the `kind` addresses of `name` to `buf`, returns its length and the
+    /// query to match the answer with
+    pub fn write_query(
+        &self,
+        name: &str,
+        kind: RecordType,
+        buf: &mut [u8],
+    ) -> Result<(usize, Query), Error> {
+        let name = Name::new(name)?;
+        let mut id = [0u8; 2];
+        get_random(&mut id, GetRandomFlags::empty())?;
+        let query = Query {
+            id: u16::from_ne_bytes(id),
+            name,
+            kind,
+        };
+        buf[..HEADER_LEN].fill(0);
+        buf[0..2].copy_from_slice(&query.id.to_be_bytes());
+        buf[2..4].copy_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+        // One question
+        buf[4..6].copy_from_slice(&1u16.to_be_bytes());
+        let mut len = HEADER_LEN + name.encode(&mut buf[HEADER_LEN..]);
+        buf[len..len + 2].copy_from_slice(&(kind as u16).to_be_bytes());
+        buf[len + 2..len + 4].copy_from_slice(&CLASS_IN.to_be_bytes());
+        len += 4;
+        Ok((len, query))
+    }
+
+    /// Makes sense of a response to `query` and caches it. Returns `None` if the message isn't
+    /// an answer to the query.
+    pub fn handle_response(
+        &mut self,
+        query: &Query,
+        message: &[u8],
+        now: u64,
+    ) -> Option<Result<Addresses, Error>> {
+        let flags = u16_at(message, 2)?;
+        if u16_at(message, 0)? != query.id || flags & FLAG_RESPONSE == 0 {
+            return None;
+        }
+        // The question comes back as it was asked
+        let mut question = [0u8; MAX_NAME_LEN + 2];
+        let question_len = query.name.encode(&mut question);
+        let asked = message.get(HEADER_LEN..HEADER_LEN + question_len)?;
+        if u16_at(message, 4)? != 1 || !asked.eq_ignore_ascii_case(&question[..question_len]) {
+            return None;
+        }
+
+        let result = match flags & RCODE_MASK {
+            0 => Self::parse_answers(query, message, HEADER_LEN + question_len + 4),
+            RCODE_NAME_ERROR => Some((Err(Error::NameNotFound), NEGATIVE_TTL_SECS)),
+            _ => None,
+        };
+        let Some((result, ttl)) = result else {
+            // Not worth caching, another server may do better
+            return Some(Err(Error::ServerFailure));
+        };
+        self.insert(
+            query,
+            result,
+            now + ttl.min(MAX_TTL_SECS) as u64 * NS_PER_SEC,
+        );
+        Some(result)
+    }
+
+    /// Collects the addresses in the answer section, returns them with the lowest TTL. Aliases
+    /// are skipped, their records come along with them.
+    fn parse_answers(
+        query: &Query,
+        message: &[u8],
+        mut offset: usize,
+    ) -> Option<(Result<Addresses, Error>, u32)> {
+        let count = u16_at(message, 6)?;
+        let mut addrs = Addresses::default();
+        let mut ttl = MAX_TTL_SECS;
+        for _ in 0..count {
+            offset = skip_name(message, offset)?;
+            let kind = u16_at(message, offset)?;
+            let class = u16_at(message, offset + 2)?;
+            let record_ttl = u32_at(message, offset + 4)?;
+            let len = u16_at(message, offset + 8)? as usize;
+            let data = message.get(offset + 10..offset + 10 + len)?;
+            offset += 10 + len;
+            if kind != query.kind as u16 || class != CLASS_IN || len != query.kind.addr_len() {
+                continue;
+            }
+            addrs.push(match query.kind {
+                RecordType::A => IpAddr::V4(Ipv4Addr(data.try_into().unwrap())),
+                RecordType::Aaaa => IpAddr::V6(Ipv6Addr(data.try_into().unwrap())),
+            });
+            ttl = ttl.min(record_ttl);
+        }
+        if addrs.iter().next().is_none() {
+            return Some((Err(Error::NameNotFound), NEGATIVE_TTL_SECS));
+        }
+        Some((Ok(addrs), ttl))
+    }
+
+    fn insert(&mut self, query: &Query, result: Result<Addresses, Error>, expires: u64) {
+        let same = |entry: &CacheEntry| {
+            entry.kind == query.kind && entry.name.as_bytes() == query.name.as_bytes()
+        };
+        // The same name, else a free slot, else the one expiring first
+        let slot = match self.cache.iter().position(|e| e.as_ref().is_some_and(same)) {
+            Some(slot) => slot,
+            None => (0..CACHE_SIZE)
+                .min_by_key(|&i| self.cache[i].map_or(0, |entry| entry.expires))
+                .unwrap_or(0),
+        };
+        self.cache[slot] = Some(CacheEntry {
+            name: query.name,
+            kind: query.kind,
+            result,
+            expires,
+        });
+    }
+}
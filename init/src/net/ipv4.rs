@@ -0,0 +1,134 @@
+//! IPv4 headers and the routing table (RFC 791)
+//!
+//! Fragments are dropped rather than reassembled, and packets are never sent fragmented: the
+//! stack keeps its segments within the MTU.
+
+use crate::net::{checksum, Ipv4Addr};
+
+pub const HEADER_LEN: usize = 20;
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+const DEFAULT_TTL: u8 = 64;
+/// Don't fragment
+const FLAG_DF: u16 = 1 << 14;
+const FLAG_MF: u16 = 1 << 13;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+const ROUTES: usize = 8;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Header {
+    pub header_len: usize,
+    pub total_len: usize,
+    pub protocol: u8,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+}
+
+impl Header {
+    /// Parses and checks a header, `data` may have padding after the packet
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let fixed = data.get(..HEADER_LEN)?;
+        let header_len = (fixed[0] & 0xf) as usize * 4;
+        if fixed[0] >> 4 != 4 || header_len < HEADER_LEN {
+            return None;
+        }
+        let total_len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        if total_len < header_len || total_len > data.len() {
+            return None;
+        }
+        if checksum(&data[..header_len]) != 0 {
+            return None;
+        }
+        let flags = u16::from_be_bytes([fixed[6], fixed[7]]);
+        if flags & FLAG_MF != 0 || flags & FRAGMENT_OFFSET != 0 {
+            return None;
+        }
+        Some(Self {
+            header_len,
+            total_len,
+            protocol: fixed[9],
+            src: Ipv4Addr(fixed[12..16].try_into().unwrap()),
+            dst: Ipv4Addr(fixed[16..20].try_into().unwrap()),
+        })
+    }
+}
+
+/// Writes a header without options for a packet carrying `payload_len` bytes
+pub fn write_header(
+    buf: &mut [u8],
+    id: u16,
+    protocol: u8,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    payload_len: usize,
+) {
+    let header = &mut buf[..HEADER_LEN];
+    header[0] = 0x45;
+    header[1] = 0;
+    header[2..4].copy_from_slice(&((HEADER_LEN + payload_len) as u16).to_be_bytes());
+    header[4..6].copy_from_slice(&id.to_be_bytes());
+    header[6..8].copy_from_slice(&FLAG_DF.to_be_bytes());
+    header[8] = DEFAULT_TTL;
+    header[9] = protocol;
+    header[10..12].fill(0);
+    header[12..16].copy_from_slice(&src.0);
+    header[16..20].copy_from_slice(&dst.0);
+    let sum = checksum(header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Route {
+    pub dest: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Where to send packets, directly to the destination without one
+    pub gateway: Option<Ipv4Addr>,
+}
+
+pub struct RoutingTable {
+    routes: [Option<Route>; ROUTES],
+}
+
+impl RoutingTable {
+    pub const fn new() -> Self {
+        Self {
+            routes: [None; ROUTES],
+        }
+    }
+
+    /// Adds a route, replacing the one for the same prefix if there's one
+    pub fn add(&mut self, route: Route) -> bool {
+        let same_prefix = |r: &Option<Route>| {
+            r.is_some_and(|r| r.dest == route.dest && r.prefix_len == route.prefix_len)
+        };
+        let slot = match self.routes.iter().position(same_prefix) {
+            Some(slot) => slot,
+            None => match self.routes.iter().position(Option::is_none) {
+                Some(slot) => slot,
+                None => return false,
+            },
+        };
+        self.routes[slot] = Some(route);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.routes = [None; ROUTES];
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.iter().flatten()
+    }
+
+    /// The next hop towards `dst`, using the most specific route
+    pub fn next_hop(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        let route = self
+            .routes()
+            .filter(|route| dst.same_prefix(route.dest, route.prefix_len))
+            .max_by_key(|route| route.prefix_len)?;
+        Some(route.gateway.unwrap_or(dst))
+    }
+}
@@ -0,0 +1,249 @@
+//! Networking: a small TCP/IP stack over the frame rings of the network card
+//!
+//! [`stack::Stack`] is the protocol logic, it only ever sees frames. [`service::NetService`]
+//! drives it with the card and offers blocking, socket-like calls on top.
+
+pub mod arp;
+pub mod buffer;
+pub mod dhcp;
+pub mod dns;
+pub mod ipv4;
+pub mod ring;
+pub mod service;
+pub mod stack;
+pub mod tcp;
+pub mod udp;
+
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+use kernel_api::KError;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+}
+
+impl Display for MacAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(addr: u32) -> Self {
+        Self(addr.to_be_bytes())
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Self::UNSPECIFIED
+    }
+
+    /// Whether `self` and `other` share the first `prefix_len` bits
+    pub fn same_prefix(self, other: Ipv4Addr, prefix_len: u8) -> bool {
+        let mask = prefix_mask(prefix_len);
+        self.to_u32() & mask == other.to_u32() & mask
+    }
+}
+
+/// The netmask of a prefix length, e.g. 0xffffff00 for 24
+pub fn prefix_mask(prefix_len: u8) -> u32 {
+    match prefix_len {
+        0 => 0,
+        len => u32::MAX << (32 - len.min(32) as u32),
+    }
+}
+
+impl Display for Ipv4Addr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut addr = [0u8; 4];
+        let mut parts = s.split('.');
+        for byte in &mut addr {
+            *byte = parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or(Error::InvalidArgument)?;
+        }
+        match parts.next() {
+            Some(_) => Err(Error::InvalidArgument),
+            None => Ok(Self(addr)),
+        }
+    }
+}
+
+/// An IPv6 address. The stack doesn't speak IPv6, but names resolve to these too.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Ipv6Addr(pub [u8; 16]);
+
+impl Ipv6Addr {
+    pub fn segments(&self) -> [u16; 8] {
+        let mut segments = [0u16; 8];
+        for (segment, bytes) in segments.iter_mut().zip(self.0.chunks_exact(2)) {
+            *segment = u16::from_be_bytes([bytes[0], bytes[1]]);
+        }
+        segments
+    }
+}
+
+impl Display for Ipv6Addr {
+    /// Formats the address as RFC 5952 recommends: the longest run of zeroes becomes `::`
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let segments = self.segments();
+        let (mut zeroes_at, mut zeroes_len) = (0, 0);
+        let mut i = 0;
+        while i < segments.len() {
+            let len = segments[i..].iter().take_while(|s| **s == 0).count();
+            if len > zeroes_len {
+                (zeroes_at, zeroes_len) = (i, len);
+            }
+            i += len.max(1);
+        }
+        if zeroes_len < 2 {
+            zeroes_len = 0;
+        }
+        let write_all = |f: &mut Formatter<'_>, segments: &[u16]| -> core::fmt::Result {
+            for (i, segment) in segments.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ":")?;
+                }
+                write!(f, "{segment:x}")?;
+            }
+            Ok(())
+        };
+        if zeroes_len == 0 {
+            return write_all(f, &segments);
+        }
+        write_all(f, &segments[..zeroes_at])?;
+        write!(f, "::")?;
+        write_all(f, &segments[zeroes_at + zeroes_len..])
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IpAddr {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+}
+
+impl Display for IpAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            IpAddr::V4(addr) => addr.fmt(f),
+            IpAddr::V6(addr) => addr.fmt(f),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The card failed
+    Device(virtio::Error),
+    Kernel(KError),
+    /// Nothing to do without blocking
+    WouldBlock,
+    TimedOut,
+    InvalidArgument,
+    /// The port is taken by another socket
+    AddrInUse,
+    TooManySockets,
+    /// There's no route to the destination, or no address to send from
+    NoRoute,
+    NotConnected,
+    ConnectionRefused,
+    ConnectionReset,
+    /// The socket was shut down for this direction
+    Closed,
+    /// The name doesn't exist, or has no address of the kind asked for
+    NameNotFound,
+    /// The name server failed, or answered something that makes no sense
+    ServerFailure,
+    /// There's no name server to ask
+    NoNameServer,
+}
+
+impl From<virtio::Error> for Error {
+    fn from(err: virtio::Error) -> Self {
+        Error::Device(err)
+    }
+}
+
+impl From<KError> for Error {
+    fn from(err: KError) -> Self {
+        Error::Kernel(err)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Device(err) => write!(f, "network card: {err}"),
+            Error::Kernel(err) => write!(f, "{err:?}"),
+            Error::WouldBlock => write!(f, "would block"),
+            Error::TimedOut => write!(f, "timed out"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::AddrInUse => write!(f, "address in use"),
+            Error::TooManySockets => write!(f, "too many sockets"),
+            Error::NoRoute => write!(f, "no route to host"),
+            Error::NotConnected => write!(f, "not connected"),
+            Error::ConnectionRefused => write!(f, "connection refused"),
+            Error::ConnectionReset => write!(f, "connection reset"),
+            Error::Closed => write!(f, "closed"),
+            Error::NameNotFound => write!(f, "name not found"),
+            Error::ServerFailure => write!(f, "name server failure"),
+            Error::NoNameServer => write!(f, "no name server"),
+        }
+    }
+}
+
+/// Adds `data` to a ones' complement sum, as 16-bit big-endian words
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Folds a sum from [`checksum_add`] into 16 bits, without inverting it
+pub fn checksum_fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// The Internet checksum of `data`
+pub fn checksum(data: &[u8]) -> u16 {
+    !checksum_fold(checksum_add(0, data))
+}
+
+/// The sum of the IPv4 pseudo-header TCP and UDP checksums cover
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let sum = checksum_add(0, &src.0);
+    let sum = checksum_add(sum, &dst.0);
+    sum + protocol as u32 + len as u32
+}
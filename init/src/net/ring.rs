@@ -0,0 +1,153 @@
+//! Single-producer, single-consumer rings of Ethernet frames
+//!
+//! A ring lives in its own pages, so it can be shared with whichever process runs the stack: the
+//! driver produces on the receive ring and consumes the transmit ring, the stack the other way
+//! around. Each side only ever writes its own index, which is published after the slot it
+//! covers.
+//!
+//! The first slot holds the indexes, the others hold a frame each:
+//!
+//! ```text
+//! 0  head u32, the next slot to fill, written by the producer
+//! 4  tail u32, the next slot to empty, written by the consumer
+//! ```
+//!
+//! ```text
+//! 0  len u16
+//! 2  checksum kind u16: 0 none, 1 valid, 2 partial
+//! 4  checksum start u16
+//! 6  checksum offset u16
+//! 8  the frame
+//! ```
+
+use crate::utils::mem_map;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+use kernel_api::{KError, MemMapFlags};
+
+/// The largest frame, with a VLAN tag and without the FCS
+pub const MAX_FRAME_LEN: usize = 1518;
+
+const SLOT_SIZE: usize = 2048;
+const SLOT_FRAME: usize = 8;
+const RING_SIZE: usize = 64 * 1024;
+/// The first slot is the header
+pub const RING_SLOTS: usize = RING_SIZE / SLOT_SIZE - 1;
+
+const HEAD: usize = 0;
+const TAIL: usize = 4;
+
+const CSUM_NONE: u16 = 0;
+const CSUM_VALID: u16 = 1;
+const CSUM_PARTIAL: u16 = 2;
+
+/// The checksum state of a frame. Received frames say whether the card checked them; frames to
+/// send say whether the card should fill one in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FrameChecksum {
+    None,
+    Valid,
+    /// The Internet checksum of everything from `start` to the end of the frame is missing, and
+    /// goes at `start + offset`
+    Partial {
+        start: u16,
+        offset: u16,
+    },
+}
+
+pub struct FrameRing {
+    base: NonNull<u8>,
+}
+
+impl FrameRing {
+    /// Allocates an empty ring
+    pub fn new() -> Result<Self, KError> {
+        let base = unsafe { mem_map(RING_SIZE, MemMapFlags::ReadWrite) }? as *mut u8;
+        let base = NonNull::new(base).ok_or(KError::OOM)?;
+        let ring = Self { base };
+        ring.index(HEAD).store(0, Ordering::Relaxed);
+        ring.index(TAIL).store(0, Ordering::Relaxed);
+        Ok(ring)
+    }
+
+    fn index(&self, offset: usize) -> &AtomicU32 {
+        unsafe { AtomicU32::from_ptr(self.base.as_ptr().add(offset) as *mut u32) }
+    }
+
+    /// The memory of the slot for index `index`
+    fn slot(&self, index: u32) -> *mut u8 {
+        let slot = 1 + index as usize % RING_SLOTS;
+        unsafe { self.base.as_ptr().add(slot * SLOT_SIZE) }
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.index(HEAD).load(Ordering::Acquire);
+        let tail = self.index(TAIL).load(Ordering::Acquire);
+        head.wrapping_sub(tail) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == RING_SLOTS
+    }
+
+    /// Adds a frame, returns false if the ring is full. Only the producer may call this.
+    pub fn push(&self, frame: &[u8], checksum: FrameChecksum) -> Result<bool, KError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(KError::InvalidArgument);
+        }
+        if self.is_full() {
+            return Ok(false);
+        }
+        let head = self.index(HEAD).load(Ordering::Relaxed);
+        let (kind, start, offset) = match checksum {
+            FrameChecksum::None => (CSUM_NONE, 0, 0),
+            FrameChecksum::Valid => (CSUM_VALID, 0, 0),
+            FrameChecksum::Partial { start, offset } => (CSUM_PARTIAL, start, offset),
+        };
+        let mut header = [0u8; SLOT_FRAME];
+        header[0..2].copy_from_slice(&(frame.len() as u16).to_le_bytes());
+        header[2..4].copy_from_slice(&kind.to_le_bytes());
+        header[4..6].copy_from_slice(&start.to_le_bytes());
+        header[6..8].copy_from_slice(&offset.to_le_bytes());
+        let slot = self.slot(head);
+        unsafe {
+            core::ptr::copy_nonoverlapping(header.as_ptr(), slot, SLOT_FRAME);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), slot.add(SLOT_FRAME), frame.len());
+        }
+        self.index(HEAD)
+            .store(head.wrapping_add(1), Ordering::Release);
+        Ok(true)
+    }
+
+    /// Takes the oldest frame, copying it to `buf`, which should be [`MAX_FRAME_LEN`] long.
+    /// Frames that don't fit are truncated, the length is that of the whole frame. Only the
+    /// consumer may call this.
+    pub fn pop(&self, buf: &mut [u8]) -> Option<(usize, FrameChecksum)> {
+        if self.is_empty() {
+            return None;
+        }
+        let tail = self.index(TAIL).load(Ordering::Relaxed);
+        let slot = self.slot(tail);
+        let mut header = [0u8; SLOT_FRAME];
+        unsafe { core::ptr::copy_nonoverlapping(slot, header.as_mut_ptr(), SLOT_FRAME) };
+        let field = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let len = (field(0) as usize).min(MAX_FRAME_LEN);
+        let checksum = match field(2) {
+            CSUM_VALID => FrameChecksum::Valid,
+            CSUM_PARTIAL => FrameChecksum::Partial {
+                start: field(4),
+                offset: field(6),
+            },
+            _ => FrameChecksum::None,
+        };
+        let copied = len.min(buf.len());
+        unsafe { core::ptr::copy_nonoverlapping(slot.add(SLOT_FRAME), buf.as_mut_ptr(), copied) };
+        self.index(TAIL)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Some((len, checksum))
+    }
+}
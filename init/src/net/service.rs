@@ -0,0 +1,278 @@
+//! The network service: the stack driven by the card, with blocking socket calls
+//!
+//! This is what clients get to talk to. There's no IPC yet, so for now the shell calls it
+//! directly; the calls map one-to-one to the messages a socket interface would have.
+
+use crate::drv::virtio_net::VirtioNetDevice;
+use crate::net::buffer::PageBox;
+use crate::net::dhcp::{DhcpClient, Lease};
+use crate::net::dns::{self, Addresses, RecordType, Resolver};
+use crate::net::stack::{SocketHandle, Stack};
+use crate::net::{Error, Ipv4Addr, MacAddr};
+use crate::time;
+use kernel_api::clock::ClockId;
+
+/// Data sent with each echo request
+const PING_LEN: usize = 56;
+/// How long to wait for each name server, and how many times to ask them all
+const DNS_TIMEOUT_NS: u64 = 2_000_000_000;
+const DNS_ATTEMPTS: usize = 2;
+
+fn now() -> u64 {
+    time::now(ClockId::Monotonic)
+}
+
+pub struct NetService {
+    dev: VirtioNetDevice,
+    stack: PageBox<Stack>,
+    /// Keeps the address leased, once started
+    dhcp: Option<DhcpClient>,
+    resolver: PageBox<Resolver>,
+}
+
+impl NetService {
+    pub fn new(dev: VirtioNetDevice) -> Result<Self, Error> {
+        let stack = PageBox::new(Stack::new(MacAddr(dev.mac()))?)?;
+        let resolver = PageBox::new(Resolver::new())?;
+        Ok(Self {
+            dev,
+            stack,
+            dhcp: None,
+            resolver,
+        })
+    }
+
+    pub fn device(&self) -> &VirtioNetDevice {
+        &self.dev
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    pub fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    /// The address leased over DHCP, if there's one
+    pub fn lease(&self) -> Option<&Lease> {
+        self.dhcp.as_ref().and_then(DhcpClient::lease)
+    }
+
+    /// Moves frames and runs the stack's timers, without blocking. Returns when the stack
+    /// needs to run again at the latest.
+    pub fn poll(&mut self) -> Result<Option<u64>, Error> {
+        self.dev.pump()?;
+        let now = now();
+        let mut deadline = self.stack.poll(self.dev.rx_ring(), self.dev.tx_ring(), now);
+        if let Some(dhcp) = &mut self.dhcp {
+            let dhcp_deadline = dhcp.poll(&mut self.stack, self.dev.tx_ring(), now);
+            deadline = match (deadline, dhcp_deadline) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            if let Some(lease) = dhcp.lease() {
+                self.resolver.set_servers(lease.name_servers);
+            }
+        }
+        self.dev.pump()?;
+        Ok(deadline)
+    }
+
+    /// Runs the stack until `f` stops returning `WouldBlock`, or for at most `timeout_ns`
+    pub fn run_until<T>(
+        &mut self,
+        timeout_ns: u64,
+        mut f: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let deadline = now().saturating_add(timeout_ns);
+        loop {
+            self.poll()?;
+            let result = f(self);
+            // Send what `f` queued right away
+            let timer = self.poll()?;
+            match result {
+                Err(Error::WouldBlock) => {}
+                result => return result,
+            }
+            let now = now();
+            if now >= deadline {
+                return Err(Error::TimedOut);
+            }
+            let wake_at = timer.map_or(deadline, |timer| timer.min(deadline));
+            self.dev.wait(wake_at.saturating_sub(now).max(1))?;
+        }
+    }
+
+    /// Sends an echo request and waits for the reply, returns the round-trip time
+    pub fn ping(&mut self, dst: Ipv4Addr, seq: u16, timeout_ns: u64) -> Result<u64, Error> {
+        let sent_at = now();
+        self.stack
+            .ping(self.dev.tx_ring(), sent_at, dst, seq, PING_LEN)?;
+        self.run_until(timeout_ns, |net| net.stack.take_echo_reply(dst, seq))?;
+        Ok(now() - sent_at)
+    }
+
+    pub fn udp_bind(&mut self, port: u16) -> Result<SocketHandle, Error> {
+        self.stack.udp_bind(port)
+    }
+
+    pub fn udp_send_to(
+        &mut self,
+        socket: SocketHandle,
+        dst: Ipv4Addr,
+        dst_port: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.stack
+            .udp_send_to(self.dev.tx_ring(), now(), socket, dst, dst_port, data)?;
+        self.poll().map(|_| ())
+    }
+
+    /// Waits for a datagram, returns its length, source address and port
+    pub fn udp_recv_from(
+        &mut self,
+        socket: SocketHandle,
+        buf: &mut [u8],
+        timeout_ns: u64,
+    ) -> Result<(usize, Ipv4Addr, u16), Error> {
+        self.run_until(timeout_ns, |net| net.stack.udp_recv_from(socket, buf))
+    }
+
+    /// Opens a connection, closing the socket again if that fails
+    pub fn tcp_connect(
+        &mut self,
+        dst: Ipv4Addr,
+        dst_port: u16,
+        timeout_ns: u64,
+    ) -> Result<SocketHandle, Error> {
+        let socket = self.stack.tcp_connect(dst, dst_port)?;
+        match self.run_until(timeout_ns, |net| net.stack.tcp_connected(socket)) {
+            Ok(()) => Ok(socket),
+            Err(err) => {
+                self.close(socket)?;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn tcp_listen(&mut self, port: u16) -> Result<SocketHandle, Error> {
+        self.stack.tcp_listen(port)
+    }
+
+    pub fn tcp_accept(
+        &mut self,
+        listener: SocketHandle,
+        timeout_ns: u64,
+    ) -> Result<SocketHandle, Error> {
+        self.run_until(timeout_ns, |net| net.stack.tcp_accept(listener))
+    }
+
+    /// Sends all of `data`, waiting for room in the send buffer
+    pub fn tcp_send(
+        &mut self,
+        socket: SocketHandle,
+        data: &[u8],
+        timeout_ns: u64,
+    ) -> Result<(), Error> {
+        let mut sent = 0;
+        while sent < data.len() {
+            sent += self.run_until(timeout_ns, |net| net.stack.tcp_send(socket, &data[sent..]))?;
+        }
+        Ok(())
+    }
+
+    /// Waits for data, returns 0 once the peer closed the connection
+    pub fn tcp_recv(
+        &mut self,
+        socket: SocketHandle,
+        buf: &mut [u8],
+        timeout_ns: u64,
+    ) -> Result<usize, Error> {
+        self.run_until(timeout_ns, |net| net.stack.tcp_recv(socket, buf))
+    }
+
+    /// Closes a socket, connections finish closing in the background
+    pub fn close(&mut self, socket: SocketHandle) -> Result<(), Error> {
+        self.stack.close(socket)?;
+        self.poll().map(|_| ())
+    }
+
+    /// Gets an address over DHCP, or a fresh one if there's a lease already. The client keeps
+    /// renewing the lease whenever the service runs.
+    pub fn dhcp(&mut self, timeout_ns: u64) -> Result<Lease, Error> {
+        match &mut self.dhcp {
+            Some(dhcp) => dhcp.restart(&mut self.stack),
+            None => self.dhcp = Some(DhcpClient::new(&mut self.stack)?),
+        }
+        self.run_until(timeout_ns, |net| match net.lease() {
+            Some(lease) => Ok(*lease),
+            None => Err(Error::WouldBlock),
+        })
+    }
+
+    /// Looks up the `kind` addresses of `name`, asking the name servers if they're not cached
+    pub fn resolve(&mut self, name: &str, kind: RecordType) -> Result<Addresses, Error> {
+        if let Some(result) = self.resolver.lookup(name, kind, now()) {
+            return result;
+        }
+        let mut servers = [None; dns::MAX_SERVERS];
+        for (slot, server) in servers.iter_mut().zip(self.resolver.servers()) {
+            *slot = Some(server);
+        }
+        if servers[0].is_none() {
+            return Err(Error::NoNameServer);
+        }
+        let socket = self.stack.udp_bind(0)?;
+        let mut result = Err(Error::TimedOut);
+        'attempts: for _ in 0..DNS_ATTEMPTS {
+            for server in servers.iter().flatten() {
+                result = self.query(socket, *server, name, kind);
+                match result {
+                    Err(Error::TimedOut | Error::ServerFailure) => {}
+                    _ => break 'attempts,
+                }
+            }
+        }
+        self.close(socket)?;
+        result
+    }
+
+    /// Asks `server` once, waits for its answer
+    fn query(
+        &mut self,
+        socket: SocketHandle,
+        server: Ipv4Addr,
+        name: &str,
+        kind: RecordType,
+    ) -> Result<Addresses, Error> {
+        let mut buf = [0u8; dns::MAX_MESSAGE_LEN];
+        let (len, query) = self.resolver.write_query(name, kind, &mut buf)?;
+        self.udp_send_to(socket, server, dns::SERVER_PORT, &buf[..len])?;
+        let deadline = now().saturating_add(DNS_TIMEOUT_NS);
+        loop {
+            let timeout = deadline.saturating_sub(now());
+            let (len, src, port) = self.udp_recv_from(socket, &mut buf, timeout)?;
+            if (src, port) != (server, dns::SERVER_PORT) {
+                continue;
+            }
+            let len = len.min(buf.len());
+            if let Some(result) = self.resolver.handle_response(&query, &buf[..len], now()) {
+                return result;
+            }
+        }
+    }
+
+    /// The IPv4 address of a host, given by name or as an address
+    pub fn resolve_ipv4(&mut self, host: &str) -> Result<Ipv4Addr, Error> {
+        if let Ok(addr) = host.parse() {
+            return Ok(addr);
+        }
+        let addrs = self.resolve(host, RecordType::A)?;
+        addrs.first_v4().ok_or(Error::NameNotFound)
+    }
+}
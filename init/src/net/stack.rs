@@ -0,0 +1,868 @@
+//! The protocol logic: Ethernet, ARP, IPv4, ICMP echo, UDP and TCP over a pair of frame rings
+//!
+//! Nothing here blocks or keeps time by itself: [`Stack::poll`] handles received frames and
+//! timers and returns when it next needs to run, socket calls return `WouldBlock` when they
+//! can't proceed yet. Outgoing TCP and UDP checksums are left to the card.
+
+use crate::net::arp::{self, ArpCache};
+use crate::net::buffer::{ByteRing, Pages};
+use crate::net::ipv4::{self, Route, RoutingTable, PROTO_ICMP, PROTO_TCP, PROTO_UDP};
+use crate::net::ring::{FrameChecksum, FrameRing, MAX_FRAME_LEN};
+use crate::net::tcp::{self, Segment, SegmentHeader, State, TcpSocket};
+use crate::net::udp::{self, UdpSocket};
+use crate::net::{
+    checksum, checksum_add, checksum_fold, prefix_mask, pseudo_header_sum, Error, Ipv4Addr, MacAddr,
+};
+use crate::utils::get_random;
+use kernel_api::GetRandomFlags;
+
+const ETH_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// Where transport headers start in the frames we build
+const PAYLOAD: usize = ETH_HEADER_LEN + ipv4::HEADER_LEN;
+/// The largest UDP payload that fits a frame
+pub const MAX_UDP_PAYLOAD: usize = 1500 - ipv4::HEADER_LEN - udp::HEADER_LEN;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_LEN: usize = 8;
+
+pub const MAX_SOCKETS: usize = 8;
+/// Connections waiting to be accepted, per listening socket
+const BACKLOG: usize = 2;
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// Packets waiting for their next hop's address, and how long they may wait
+const PENDING_PACKETS: usize = 4;
+const PENDING_TIMEOUT_NS: u64 = 3_000_000_000;
+const ARP_RETRY_NS: u64 = 1_000_000_000;
+
+const ECHO_REPLIES: usize = 4;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SocketHandle(usize);
+
+enum Socket {
+    Udp(UdpSocket),
+    Tcp(TcpSocket),
+}
+
+#[derive(Copy, Clone, Debug)]
+struct PendingPacket {
+    next_hop: Ipv4Addr,
+    len: usize,
+    checksum: FrameChecksum,
+    queued_at: u64,
+    arp_sent_at: u64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EchoReply {
+    pub src: Ipv4Addr,
+    pub id: u16,
+    pub seq: u16,
+}
+
+fn random_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    // The pool is seeded long before the network is up
+    let _ = get_random(&mut bytes, GetRandomFlags::empty());
+    u32::from_ne_bytes(bytes)
+}
+
+pub struct Stack {
+    mac: MacAddr,
+    ip: Ipv4Addr,
+    prefix_len: u8,
+    routes: RoutingTable,
+    arp: ArpCache,
+    sockets: [Option<Socket>; MAX_SOCKETS],
+    /// Buffers of closed sockets, to reuse
+    spare_buffers: [Option<ByteRing>; 2 * MAX_SOCKETS],
+    next_port: u16,
+    ip_id: u16,
+    pending: [Option<PendingPacket>; PENDING_PACKETS],
+    pending_frames: Pages,
+    /// The frame being built
+    frame: Pages,
+    echo_id: u16,
+    echo_replies: [Option<EchoReply>; ECHO_REPLIES],
+    next_echo_reply: usize,
+}
+
+impl Stack {
+    pub fn new(mac: MacAddr) -> Result<Self, Error> {
+        let random = random_u32();
+        let ports = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1;
+        Ok(Self {
+            mac,
+            ip: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            routes: RoutingTable::new(),
+            arp: ArpCache::new(),
+            sockets: [const { None }; MAX_SOCKETS],
+            spare_buffers: [const { None }; 2 * MAX_SOCKETS],
+            next_port: EPHEMERAL_PORTS.start() + (random as u16) % ports,
+            ip_id: (random >> 16) as u16,
+            pending: [None; PENDING_PACKETS],
+            pending_frames: Pages::new(PENDING_PACKETS * MAX_FRAME_LEN)?,
+            frame: Pages::new(MAX_FRAME_LEN)?,
+            echo_id: random_u32() as u16,
+            echo_replies: [None; ECHO_REPLIES],
+            next_echo_reply: 0,
+        })
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    /// Our address, unspecified until configured
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Sets our address, replacing the routes with the one for the local network and a default
+    /// route through `gateway`
+    pub fn configure(&mut self, ip: Ipv4Addr, prefix_len: u8, gateway: Option<Ipv4Addr>) {
+        self.ip = ip;
+        self.prefix_len = prefix_len.min(32);
+        self.routes.clear();
+        self.arp.clear();
+        if ip.is_unspecified() {
+            return;
+        }
+        let network = Ipv4Addr::from_u32(ip.to_u32() & prefix_mask(self.prefix_len));
+        self.routes.add(Route {
+            dest: network,
+            prefix_len: self.prefix_len,
+            gateway: None,
+        });
+        if let Some(gateway) = gateway {
+            self.routes.add(Route {
+                dest: Ipv4Addr::UNSPECIFIED,
+                prefix_len: 0,
+                gateway: Some(gateway),
+            });
+        }
+    }
+
+    /// Adds a route, returns false if the table is full
+    pub fn add_route(&mut self, route: Route) -> bool {
+        self.routes.add(route)
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.routes()
+    }
+
+    pub fn arp_entries(&self) -> impl Iterator<Item = &arp::Entry> {
+        self.arp.entries()
+    }
+
+    fn is_broadcast(&self, addr: Ipv4Addr) -> bool {
+        if addr == Ipv4Addr::BROADCAST {
+            return true;
+        }
+        let host_mask = !prefix_mask(self.prefix_len);
+        !self.ip.is_unspecified()
+            && self.prefix_len < 31
+            && addr.same_prefix(self.ip, self.prefix_len)
+            && addr.to_u32() & host_mask == host_mask
+    }
+
+    /// Handles the frames on `rx` and the timers. Returns when it should run again at the
+    /// latest, if anything is waiting for a timer.
+    pub fn poll(&mut self, rx: &FrameRing, tx: &FrameRing, now: u64) -> Option<u64> {
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        while let Some((len, checksum)) = rx.pop(&mut frame) {
+            let verified = matches!(
+                checksum,
+                FrameChecksum::Valid | FrameChecksum::Partial { .. }
+            );
+            self.handle_frame(tx, &frame[..len.min(MAX_FRAME_LEN)], verified, now);
+        }
+
+        let mut deadline: Option<u64> = None;
+        let mut earliest = |at: Option<u64>| {
+            if let Some(at) = at {
+                deadline = Some(deadline.map_or(at, |deadline| deadline.min(at)));
+            }
+        };
+        for index in 0..MAX_SOCKETS {
+            if let Some(Socket::Tcp(socket)) = &mut self.sockets[index] {
+                socket.on_timer(now);
+            }
+            self.flush_tcp(tx, index, now);
+            if let Some(Socket::Tcp(socket)) = &self.sockets[index] {
+                if socket.state() == State::Closed && socket.orphaned {
+                    self.free_socket(index);
+                } else {
+                    earliest(socket.deadline());
+                }
+            }
+        }
+        self.retry_pending(tx, now);
+        for packet in self.pending.iter().flatten() {
+            earliest(Some(packet.arp_sent_at + ARP_RETRY_NS));
+        }
+        deadline
+    }
+
+    fn handle_frame(&mut self, tx: &FrameRing, frame: &[u8], verified: bool, now: u64) {
+        if frame.len() < ETH_HEADER_LEN {
+            return;
+        }
+        let dst = MacAddr(frame[0..6].try_into().unwrap());
+        if dst != self.mac && dst != MacAddr::BROADCAST {
+            return;
+        }
+        let payload = &frame[ETH_HEADER_LEN..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => self.handle_arp(tx, payload, now),
+            ETHERTYPE_IPV4 => self.handle_ipv4(tx, payload, verified, now),
+            _ => {}
+        }
+    }
+
+    fn handle_arp(&mut self, tx: &FrameRing, data: &[u8], now: u64) {
+        let Some(packet) = arp::Packet::parse(data) else {
+            return;
+        };
+        if self.ip.is_unspecified() {
+            return;
+        }
+        let for_us = packet.target_ip == self.ip;
+        if for_us || self.arp.contains(packet.sender_ip) {
+            self.arp.insert(packet.sender_ip, packet.sender_mac, now);
+            self.flush_pending(tx, packet.sender_ip, packet.sender_mac);
+        }
+        if for_us && packet.op == arp::OP_REQUEST {
+            let reply = arp::Packet {
+                op: arp::OP_REPLY,
+                sender_mac: self.mac,
+                sender_ip: self.ip,
+                target_mac: packet.sender_mac,
+                target_ip: packet.sender_ip,
+            };
+            self.send_arp(tx, reply, packet.sender_mac);
+        }
+    }
+
+    fn send_arp(&mut self, tx: &FrameRing, packet: arp::Packet, dst: MacAddr) {
+        let frame = self.frame.as_mut_slice();
+        write_eth_header(frame, dst, self.mac, ETHERTYPE_ARP);
+        packet.write(&mut frame[ETH_HEADER_LEN..]);
+        let _ = tx.push(
+            &frame[..ETH_HEADER_LEN + arp::PACKET_LEN],
+            FrameChecksum::None,
+        );
+    }
+
+    fn send_arp_request(&mut self, tx: &FrameRing, ip: Ipv4Addr) {
+        let request = arp::Packet {
+            op: arp::OP_REQUEST,
+            sender_mac: self.mac,
+            sender_ip: self.ip,
+            target_mac: MacAddr::default(),
+            target_ip: ip,
+        };
+        self.send_arp(tx, request, MacAddr::BROADCAST);
+    }
+
+    /// Sends the packets that were waiting for `ip`'s address
+    fn flush_pending(&mut self, tx: &FrameRing, ip: Ipv4Addr, mac: MacAddr) {
+        for (slot, pending) in self.pending.iter_mut().enumerate() {
+            let Some(packet) = pending.filter(|packet| packet.next_hop == ip) else {
+                continue;
+            };
+            let offset = slot * MAX_FRAME_LEN;
+            let frame = &mut self.pending_frames.as_mut_slice()[offset..offset + packet.len];
+            frame[0..6].copy_from_slice(&mac.0);
+            let _ = tx.push(frame, packet.checksum);
+            *pending = None;
+        }
+    }
+
+    /// Asks again for addresses nobody answered for, gives up on packets after a while
+    fn retry_pending(&mut self, tx: &FrameRing, now: u64) {
+        for slot in 0..PENDING_PACKETS {
+            let Some(packet) = self.pending[slot] else {
+                continue;
+            };
+            if now >= packet.queued_at + PENDING_TIMEOUT_NS {
+                self.pending[slot] = None;
+            } else if now >= packet.arp_sent_at + ARP_RETRY_NS {
+                self.send_arp_request(tx, packet.next_hop);
+                for pending in self.pending.iter_mut().flatten() {
+                    if pending.next_hop == packet.next_hop {
+                        pending.arp_sent_at = now;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the frame being built, with an IPv4 packet carrying `payload_len` bytes already
+    /// written at [`PAYLOAD`]. If the next hop's address isn't known yet, the frame waits for it.
+    fn send_ip(
+        &mut self,
+        tx: &FrameRing,
+        now: u64,
+        dst: Ipv4Addr,
+        protocol: u8,
+        payload_len: usize,
+        checksum: FrameChecksum,
+    ) -> Result<(), Error> {
+        let (next_hop, dst_mac) = if self.is_broadcast(dst) {
+            (dst, Some(MacAddr::BROADCAST))
+        } else {
+            if self.ip.is_unspecified() {
+                return Err(Error::NoRoute);
+            }
+            let next_hop = self.routes.next_hop(dst).ok_or(Error::NoRoute)?;
+            (next_hop, self.arp.lookup(next_hop, now))
+        };
+
+        self.ip_id = self.ip_id.wrapping_add(1);
+        let len = PAYLOAD + payload_len;
+        let frame = &mut self.frame.as_mut_slice()[..len];
+        write_eth_header(frame, dst_mac.unwrap_or_default(), self.mac, ETHERTYPE_IPV4);
+        ipv4::write_header(
+            &mut frame[ETH_HEADER_LEN..],
+            self.ip_id,
+            protocol,
+            self.ip,
+            dst,
+            payload_len,
+        );
+        if dst_mac.is_some() {
+            // A full ring drops the frame, like a busy wire would
+            let _ = tx.push(frame, checksum);
+            return Ok(());
+        }
+
+        // Queue it, in place of the oldest packet if there's no room
+        let slot = match self.pending.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => (0..PENDING_PACKETS)
+                .min_by_key(|&i| self.pending[i].map_or(0, |packet| packet.queued_at))
+                .unwrap_or(0),
+        };
+        let already_asked = self
+            .pending
+            .iter()
+            .flatten()
+            .find(|packet| packet.next_hop == next_hop)
+            .map(|packet| packet.arp_sent_at);
+        let offset = slot * MAX_FRAME_LEN;
+        self.pending_frames.as_mut_slice()[offset..offset + len].copy_from_slice(frame);
+        self.pending[slot] = Some(PendingPacket {
+            next_hop,
+            len,
+            checksum,
+            queued_at: now,
+            arp_sent_at: already_asked.unwrap_or(now),
+        });
+        if already_asked.is_none() {
+            self.send_arp_request(tx, next_hop);
+        }
+        Ok(())
+    }
+
+    fn handle_ipv4(&mut self, tx: &FrameRing, data: &[u8], verified: bool, now: u64) {
+        let Some(header) = ipv4::Header::parse(data) else {
+            return;
+        };
+        // Until configured, take anything, DHCP answers may be sent to the address being offered
+        let for_us =
+            header.dst == self.ip || self.is_broadcast(header.dst) || self.ip.is_unspecified();
+        if !for_us {
+            return;
+        }
+        let payload = &data[header.header_len..header.total_len];
+        match header.protocol {
+            PROTO_ICMP => self.handle_icmp(tx, &header, payload, now),
+            PROTO_UDP => self.handle_udp(&header, payload, verified),
+            PROTO_TCP => self.handle_tcp(tx, &header, payload, verified, now),
+            _ => {}
+        }
+    }
+
+    fn handle_icmp(&mut self, tx: &FrameRing, header: &ipv4::Header, data: &[u8], now: u64) {
+        if data.len() < ICMP_HEADER_LEN || checksum(data) != 0 {
+            return;
+        }
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        let seq = u16::from_be_bytes([data[6], data[7]]);
+        match data[0] {
+            ICMP_ECHO_REQUEST if header.dst == self.ip => {
+                let reply = &mut self.frame.as_mut_slice()[PAYLOAD..PAYLOAD + data.len()];
+                reply.copy_from_slice(data);
+                reply[0] = ICMP_ECHO_REPLY;
+                reply[2..4].fill(0);
+                let sum = checksum(reply);
+                reply[2..4].copy_from_slice(&sum.to_be_bytes());
+                let _ = self.send_ip(
+                    tx,
+                    now,
+                    header.src,
+                    PROTO_ICMP,
+                    data.len(),
+                    FrameChecksum::None,
+                );
+            }
+            ICMP_ECHO_REPLY if id == self.echo_id => {
+                self.echo_replies[self.next_echo_reply] = Some(EchoReply {
+                    src: header.src,
+                    id,
+                    seq,
+                });
+                self.next_echo_reply = (self.next_echo_reply + 1) % ECHO_REPLIES;
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether a TCP or UDP checksum checks out
+    fn transport_checksum_ok(header: &ipv4::Header, data: &[u8]) -> bool {
+        let sum = pseudo_header_sum(header.src, header.dst, header.protocol, data.len());
+        checksum_fold(checksum_add(sum, data)) == 0xffff
+    }
+
+    fn handle_udp(&mut self, header: &ipv4::Header, data: &[u8], verified: bool) {
+        if data.len() < udp::HEADER_LEN {
+            return;
+        }
+        let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+        if len < udp::HEADER_LEN || len > data.len() {
+            return;
+        }
+        let data = &data[..len];
+        let has_checksum = data[6..8] != [0, 0];
+        if !verified && has_checksum && !Self::transport_checksum_ok(header, data) {
+            return;
+        }
+        let src_port = u16::from_be_bytes([data[0], data[1]]);
+        let dst_port = u16::from_be_bytes([data[2], data[3]]);
+        let socket = self
+            .sockets
+            .iter_mut()
+            .flatten()
+            .find_map(|socket| match socket {
+                Socket::Udp(socket) if socket.local_port == dst_port => Some(socket),
+                _ => None,
+            });
+        if let Some(socket) = socket {
+            socket.deliver(header.src, src_port, &data[udp::HEADER_LEN..]);
+        }
+    }
+
+    fn handle_tcp(
+        &mut self,
+        tx: &FrameRing,
+        header: &ipv4::Header,
+        data: &[u8],
+        verified: bool,
+        now: u64,
+    ) {
+        if header.dst != self.ip || (!verified && !Self::transport_checksum_ok(header, data)) {
+            return;
+        }
+        let Some((segment, payload)) = SegmentHeader::parse(data) else {
+            return;
+        };
+
+        let connection = self.sockets.iter().position(|socket| {
+            matches!(socket, Some(Socket::Tcp(socket))
+                if !matches!(socket.state(), State::Closed | State::Listen)
+                    && socket.local_port == segment.dst_port
+                    && socket.remote == header.src
+                    && socket.remote_port == segment.src_port)
+        });
+        if let Some(index) = connection {
+            let Some(Socket::Tcp(socket)) = &mut self.sockets[index] else {
+                return;
+            };
+            if let Some(reset) = socket.on_segment(&segment, payload, now) {
+                self.send_tcp(
+                    tx,
+                    now,
+                    header.src,
+                    segment.dst_port,
+                    segment.src_port,
+                    &reset,
+                    None,
+                );
+            }
+            self.flush_tcp(tx, index, now);
+            return;
+        }
+
+        let listener = self.sockets.iter().position(|socket| {
+            matches!(socket, Some(Socket::Tcp(socket))
+                if socket.state() == State::Listen && socket.local_port == segment.dst_port)
+        });
+        let is_syn =
+            segment.flags & (tcp::FLAG_SYN | tcp::FLAG_ACK | tcp::FLAG_RST) == tcp::FLAG_SYN;
+        if let (Some(listener), true) = (listener, is_syn) {
+            // With a full backlog, the peer will try again
+            if self.backlog(listener) < BACKLOG {
+                if let Ok(index) = self.accept_syn(listener, header.src, &segment) {
+                    self.flush_tcp(tx, index, now);
+                }
+            }
+            return;
+        }
+        if let Some(reset) = Segment::reset_for(&segment, payload.len()) {
+            self.send_tcp(
+                tx,
+                now,
+                header.src,
+                segment.dst_port,
+                segment.src_port,
+                &reset,
+                None,
+            );
+        }
+    }
+
+    /// Connections from `listener` that weren't accepted yet
+    fn backlog(&self, listener: usize) -> usize {
+        self.sockets
+            .iter()
+            .filter(|socket| {
+                matches!(socket, Some(Socket::Tcp(socket)) if socket.listener == Some(listener))
+            })
+            .count()
+    }
+
+    fn accept_syn(
+        &mut self,
+        listener: usize,
+        src: Ipv4Addr,
+        syn: &SegmentHeader,
+    ) -> Result<usize, Error> {
+        let index = self.free_slot()?;
+        let (rx, tx) = self.take_tcp_buffers()?;
+        let socket = TcpSocket::accept(listener, src, syn, random_u32(), rx, tx);
+        self.sockets[index] = Some(Socket::Tcp(socket));
+        Ok(index)
+    }
+
+    /// Sends a TCP segment, with data from the send buffer of the socket at `data_from`
+    #[allow(clippy::too_many_arguments)]
+    fn send_tcp(
+        &mut self,
+        tx: &FrameRing,
+        now: u64,
+        dst: Ipv4Addr,
+        src_port: u16,
+        dst_port: u16,
+        segment: &Segment,
+        data_from: Option<usize>,
+    ) {
+        let buf = &mut self.frame.as_mut_slice()[PAYLOAD..];
+        let header_len = segment.write_header(buf, src_port, dst_port);
+        let data = &mut buf[header_len..header_len + segment.data_len];
+        if let Some(Some(Socket::Tcp(socket))) = data_from.map(|index| &self.sockets[index]) {
+            socket.peek_tx(segment.data_offset, data);
+        }
+        let len = header_len + segment.data_len;
+        let sum = checksum_fold(pseudo_header_sum(self.ip, dst, PROTO_TCP, len));
+        buf[16..18].copy_from_slice(&sum.to_be_bytes());
+        let checksum = FrameChecksum::Partial {
+            start: PAYLOAD as u16,
+            offset: 16,
+        };
+        let _ = self.send_ip(tx, now, dst, PROTO_TCP, len, checksum);
+    }
+
+    /// Sends whatever the TCP socket at `index` has to send
+    fn flush_tcp(&mut self, tx: &FrameRing, index: usize, now: u64) {
+        while !tx.is_full() {
+            let Some(Socket::Tcp(socket)) = &mut self.sockets[index] else {
+                return;
+            };
+            let Some(segment) = socket.next_segment(now) else {
+                return;
+            };
+            let (dst, src_port, dst_port) = (socket.remote, socket.local_port, socket.remote_port);
+            self.send_tcp(tx, now, dst, src_port, dst_port, &segment, Some(index));
+        }
+    }
+
+    fn free_slot(&self) -> Result<usize, Error> {
+        self.sockets
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::TooManySockets)
+    }
+
+    /// An empty buffer, recycled if possible
+    fn take_buffer(&mut self, capacity: usize) -> Result<ByteRing, Error> {
+        let spare = self
+            .spare_buffers
+            .iter_mut()
+            .find(|buffer| buffer.as_ref().is_some_and(|b| b.capacity() == capacity));
+        if let Some(mut buffer) = spare.and_then(Option::take) {
+            buffer.clear();
+            return Ok(buffer);
+        }
+        Ok(ByteRing::new(capacity)?)
+    }
+
+    fn take_tcp_buffers(&mut self) -> Result<(ByteRing, ByteRing), Error> {
+        let rx = self.take_buffer(tcp::BUFFER_SIZE)?;
+        let tx = match self.take_buffer(tcp::BUFFER_SIZE) {
+            Ok(tx) => tx,
+            Err(err) => {
+                self.give_buffer(rx);
+                return Err(err);
+            }
+        };
+        Ok((rx, tx))
+    }
+
+    fn give_buffer(&mut self, buffer: ByteRing) {
+        if let Some(slot) = self.spare_buffers.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(buffer);
+        }
+    }
+
+    fn free_socket(&mut self, index: usize) {
+        match self.sockets[index].take() {
+            Some(Socket::Udp(socket)) => self.give_buffer(socket.into_buffer()),
+            Some(Socket::Tcp(socket)) => {
+                let (rx, tx) = socket.into_buffers();
+                self.give_buffer(rx);
+                self.give_buffer(tx);
+            }
+            None => {}
+        }
+    }
+
+    fn port_in_use(&self, port: u16) -> bool {
+        self.sockets.iter().flatten().any(|socket| match socket {
+            Socket::Udp(socket) => socket.local_port == port,
+            Socket::Tcp(socket) => socket.local_port == port,
+        })
+    }
+
+    /// `port`, or a free ephemeral port for 0
+    fn pick_port(&mut self, port: u16) -> Result<u16, Error> {
+        if port != 0 {
+            return match self.port_in_use(port) {
+                true => Err(Error::AddrInUse),
+                false => Ok(port),
+            };
+        }
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_port;
+            self.next_port = match port {
+                65535 => *EPHEMERAL_PORTS.start(),
+                port => port + 1,
+            };
+            if !self.port_in_use(port) {
+                return Ok(port);
+            }
+        }
+        Err(Error::AddrInUse)
+    }
+
+    fn udp(&mut self, handle: SocketHandle) -> Result<&mut UdpSocket, Error> {
+        match self.sockets.get_mut(handle.0) {
+            Some(Some(Socket::Udp(socket))) => Ok(socket),
+            _ => Err(Error::InvalidArgument),
+        }
+    }
+
+    fn tcp(&mut self, handle: SocketHandle) -> Result<&mut TcpSocket, Error> {
+        match self.sockets.get_mut(handle.0) {
+            Some(Some(Socket::Tcp(socket))) if socket.listener.is_none() => Ok(socket),
+            _ => Err(Error::InvalidArgument),
+        }
+    }
+
+    /// Opens a UDP socket on `port`, or on an ephemeral port for 0
+    pub fn udp_bind(&mut self, port: u16) -> Result<SocketHandle, Error> {
+        let index = self.free_slot()?;
+        let port = self.pick_port(port)?;
+        let rx = self.take_buffer(udp::RX_BUFFER_SIZE)?;
+        self.sockets[index] = Some(Socket::Udp(UdpSocket::new(port, rx)));
+        Ok(SocketHandle(index))
+    }
+
+    pub fn udp_send_to(
+        &mut self,
+        tx: &FrameRing,
+        now: u64,
+        handle: SocketHandle,
+        dst: Ipv4Addr,
+        dst_port: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if data.len() > MAX_UDP_PAYLOAD {
+            return Err(Error::InvalidArgument);
+        }
+        let src_port = self.udp(handle)?.local_port;
+        let len = udp::HEADER_LEN + data.len();
+        let sum = checksum_fold(pseudo_header_sum(self.ip, dst, PROTO_UDP, len));
+        let buf = &mut self.frame.as_mut_slice()[PAYLOAD..PAYLOAD + len];
+        buf[0..2].copy_from_slice(&src_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        buf[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        buf[6..8].copy_from_slice(&sum.to_be_bytes());
+        buf[udp::HEADER_LEN..].copy_from_slice(data);
+        let checksum = FrameChecksum::Partial {
+            start: PAYLOAD as u16,
+            offset: 6,
+        };
+        self.send_ip(tx, now, dst, PROTO_UDP, len, checksum)
+    }
+
+    /// Takes a received datagram, returns its length, source address and port
+    pub fn udp_recv_from(
+        &mut self,
+        handle: SocketHandle,
+        buf: &mut [u8],
+    ) -> Result<(usize, Ipv4Addr, u16), Error> {
+        self.udp(handle)?.recv_from(buf)
+    }
+
+    /// Starts opening a connection, see [`Self::tcp_connected`]
+    pub fn tcp_connect(&mut self, dst: Ipv4Addr, dst_port: u16) -> Result<SocketHandle, Error> {
+        if self.ip.is_unspecified() || self.routes.next_hop(dst).is_none() {
+            return Err(Error::NoRoute);
+        }
+        let index = self.free_slot()?;
+        let port = self.pick_port(0)?;
+        let (rx, tx) = self.take_tcp_buffers()?;
+        let socket = TcpSocket::connect(port, dst, dst_port, random_u32(), rx, tx);
+        self.sockets[index] = Some(Socket::Tcp(socket));
+        Ok(SocketHandle(index))
+    }
+
+    /// Whether the connection is open, `WouldBlock` while it's being opened
+    pub fn tcp_connected(&mut self, handle: SocketHandle) -> Result<(), Error> {
+        let socket = self.tcp(handle)?;
+        match socket.state() {
+            State::SynSent | State::SynReceived => Err(Error::WouldBlock),
+            _ if socket.is_connected() => Ok(()),
+            _ => Err(socket.error().unwrap_or(Error::NotConnected)),
+        }
+    }
+
+    pub fn tcp_listen(&mut self, port: u16) -> Result<SocketHandle, Error> {
+        if port == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let index = self.free_slot()?;
+        let port = self.pick_port(port)?;
+        let (rx, tx) = self.take_tcp_buffers()?;
+        self.sockets[index] = Some(Socket::Tcp(TcpSocket::listen(port, rx, tx)));
+        Ok(SocketHandle(index))
+    }
+
+    /// Takes an established connection from a listening socket
+    pub fn tcp_accept(&mut self, listener: SocketHandle) -> Result<SocketHandle, Error> {
+        if self.tcp(listener)?.state() != State::Listen {
+            return Err(Error::InvalidArgument);
+        }
+        for (index, socket) in self.sockets.iter_mut().enumerate() {
+            let Some(Socket::Tcp(socket)) = socket else {
+                continue;
+            };
+            if socket.listener == Some(listener.0)
+                && !matches!(socket.state(), State::SynReceived | State::Closed)
+            {
+                socket.listener = None;
+                return Ok(SocketHandle(index));
+            }
+        }
+        Err(Error::WouldBlock)
+    }
+
+    pub fn tcp_send(&mut self, handle: SocketHandle, data: &[u8]) -> Result<usize, Error> {
+        self.tcp(handle)?.send(data)
+    }
+
+    pub fn tcp_recv(&mut self, handle: SocketHandle, buf: &mut [u8]) -> Result<usize, Error> {
+        self.tcp(handle)?.recv(buf)
+    }
+
+    /// Closes a socket. TCP connections still send what's buffered and close gracefully, and go
+    /// away once they're closed.
+    pub fn close(&mut self, handle: SocketHandle) -> Result<(), Error> {
+        let state = match self.sockets.get_mut(handle.0) {
+            Some(Some(Socket::Udp(_))) => None,
+            Some(Some(Socket::Tcp(socket))) if socket.listener.is_none() => {
+                socket.close();
+                socket.orphaned = true;
+                Some(socket.state())
+            }
+            _ => return Err(Error::InvalidArgument),
+        };
+        if matches!(state, None | Some(State::Closed)) {
+            self.free_socket(handle.0);
+        }
+        // Connections nobody accepted go with their listener, the peer gets reset when it
+        // sends again
+        for socket in self.sockets.iter_mut().flatten() {
+            let Socket::Tcp(socket) = socket else {
+                continue;
+            };
+            if socket.listener == Some(handle.0) {
+                socket.listener = None;
+                socket.orphaned = true;
+                socket.abort();
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends an ICMP echo request with `len` bytes of data, see [`Self::take_echo_reply`]
+    pub fn ping(
+        &mut self,
+        tx: &FrameRing,
+        now: u64,
+        dst: Ipv4Addr,
+        seq: u16,
+        len: usize,
+    ) -> Result<(), Error> {
+        let len = ICMP_HEADER_LEN + len.min(MAX_UDP_PAYLOAD);
+        let buf = &mut self.frame.as_mut_slice()[PAYLOAD..PAYLOAD + len];
+        buf[0] = ICMP_ECHO_REQUEST;
+        buf[1] = 0;
+        buf[2..4].fill(0);
+        buf[4..6].copy_from_slice(&self.echo_id.to_be_bytes());
+        buf[6..8].copy_from_slice(&seq.to_be_bytes());
+        for (i, byte) in buf[ICMP_HEADER_LEN..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let sum = checksum(buf);
+        buf[2..4].copy_from_slice(&sum.to_be_bytes());
+        self.send_ip(tx, now, dst, PROTO_ICMP, len, FrameChecksum::None)
+    }
+
+    /// Takes the reply to the echo request `seq` sent to `src`, `WouldBlock` if there's none
+    pub fn take_echo_reply(&mut self, src: Ipv4Addr, seq: u16) -> Result<EchoReply, Error> {
+        let reply = self
+            .echo_replies
+            .iter_mut()
+            .find(|reply| reply.is_some_and(|reply| reply.src == src && reply.seq == seq))
+            .and_then(Option::take);
+        reply.ok_or(Error::WouldBlock)
+    }
+}
+
+fn write_eth_header(frame: &mut [u8], dst: MacAddr, src: MacAddr, ethertype: u16) {
+    frame[0..6].copy_from_slice(&dst.0);
+    frame[6..12].copy_from_slice(&src.0);
+    frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+}
@@ -0,0 +1,712 @@
+//! TCP connections (RFC 9293)
+//!
+//! Sent data stays in the send buffer until it's acknowledged. When the retransmission timer
+//! fires, sending starts over from the oldest unacknowledged byte (go-back-N), with the timeout
+//! estimated as in RFC 6298. Segments arriving out of order are dropped and acknowledged again,
+//! the peer sends them anew. The only option is the MSS, so windows are limited to 64 KiB.
+//!
+//! A socket doesn't send anything by itself: the stack feeds it segments and timer ticks, and
+//! asks it what to send next with [`TcpSocket::next_segment`].
+
+use crate::net::buffer::ByteRing;
+use crate::net::{Error, Ipv4Addr};
+
+pub const HEADER_LEN: usize = 20;
+/// With the MSS option
+const SYN_HEADER_LEN: usize = 24;
+
+pub const FLAG_FIN: u8 = 1 << 0;
+pub const FLAG_SYN: u8 = 1 << 1;
+pub const FLAG_RST: u8 = 1 << 2;
+pub const FLAG_PSH: u8 = 1 << 3;
+pub const FLAG_ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Our MSS, for a 1500-byte MTU
+pub const MSS: u16 = 1460;
+/// The peer's MSS, when it doesn't say
+const DEFAULT_MSS: u16 = 536;
+
+/// Size of each of the send and receive buffers
+pub const BUFFER_SIZE: usize = 16 * 1024;
+
+const INITIAL_RTO_NS: u64 = 1_000_000_000;
+const MIN_RTO_NS: u64 = 200_000_000;
+const MAX_RTO_NS: u64 = 60_000_000_000;
+/// Retransmissions of the same data before giving up on the connection
+const MAX_RETRIES: u32 = 8;
+/// How long to linger in TIME-WAIT, much shorter than the 2 MSL of the RFC
+const TIME_WAIT_NS: u64 = 5_000_000_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// A received segment's header
+#[derive(Copy, Clone, Debug)]
+pub struct SegmentHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+}
+
+impl SegmentHeader {
+    /// Parses a segment, returns its header and its data
+    pub fn parse(data: &[u8]) -> Option<(Self, &[u8])> {
+        let fixed = data.get(..HEADER_LEN)?;
+        let header_len = (fixed[12] >> 4) as usize * 4;
+        if header_len < HEADER_LEN || header_len > data.len() {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_be_bytes([fixed[offset], fixed[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_be_bytes(fixed[offset..offset + 4].try_into().unwrap());
+
+        let mut mss = None;
+        let mut options = &data[HEADER_LEN..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+        let header = Self {
+            src_port: u16_at(0),
+            dst_port: u16_at(2),
+            seq: u32_at(4),
+            ack: u32_at(8),
+            flags: fixed[13],
+            window: u16_at(14),
+            mss,
+        };
+        Some((header, &data[header_len..]))
+    }
+
+    /// How much sequence space the segment takes, SYN and FIN count for one
+    pub fn seq_len(&self, data_len: usize) -> u32 {
+        data_len as u32 + (self.flags & FLAG_SYN != 0) as u32 + (self.flags & FLAG_FIN != 0) as u32
+    }
+}
+
+/// A segment to send. Its data is `data_len` bytes of the send buffer, starting `data_offset`
+/// bytes in.
+#[derive(Copy, Clone, Debug)]
+pub struct Segment {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub data_offset: usize,
+    pub data_len: usize,
+}
+
+impl Segment {
+    /// The reset answering `header`, for a segment that doesn't belong to any connection
+    pub fn reset_for(header: &SegmentHeader, data_len: usize) -> Option<Self> {
+        if header.flags & FLAG_RST != 0 {
+            return None;
+        }
+        let (seq, ack, flags) = if header.flags & FLAG_ACK != 0 {
+            (header.ack, 0, FLAG_RST)
+        } else {
+            let ack = header.seq.wrapping_add(header.seq_len(data_len));
+            (0, ack, FLAG_RST | FLAG_ACK)
+        };
+        Some(Self {
+            seq,
+            ack,
+            flags,
+            window: 0,
+            data_offset: 0,
+            data_len: 0,
+        })
+    }
+
+    /// Writes the header, with a zero checksum, returns its length
+    pub fn write_header(&self, buf: &mut [u8], src_port: u16, dst_port: u16) -> usize {
+        let header_len = match self.flags & FLAG_SYN {
+            0 => HEADER_LEN,
+            _ => SYN_HEADER_LEN,
+        };
+        buf[0..2].copy_from_slice(&src_port.to_be_bytes());
+        buf[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ack.to_be_bytes());
+        buf[12] = ((header_len / 4) as u8) << 4;
+        buf[13] = self.flags;
+        buf[14..16].copy_from_slice(&self.window.to_be_bytes());
+        buf[16..20].fill(0);
+        if header_len == SYN_HEADER_LEN {
+            buf[20] = OPTION_MSS;
+            buf[21] = 4;
+            buf[22..24].copy_from_slice(&MSS.to_be_bytes());
+        }
+        header_len
+    }
+}
+
+pub struct TcpSocket {
+    state: State,
+    pub local_port: u16,
+    pub remote: Ipv4Addr,
+    pub remote_port: u16,
+    /// The listening socket this connection came from, until it's accepted
+    pub listener: Option<usize>,
+    /// Whether the application closed its handle, the socket goes away once it's closed
+    pub orphaned: bool,
+
+    iss: u32,
+    /// Oldest unacknowledged, next to send, and highest sent sequence numbers
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32,
+    snd_wnd: u32,
+    /// The segment that last updated the window, so older ones don't
+    snd_wl1: u32,
+    snd_wl2: u32,
+    /// The peer's MSS
+    mss: u16,
+    rcv_nxt: u32,
+
+    rx: ByteRing,
+    tx: ByteRing,
+    fin_queued: bool,
+    fin_acked: bool,
+    fin_received: bool,
+    ack_pending: bool,
+
+    rto: u64,
+    srtt: Option<u64>,
+    rttvar: u64,
+    /// The segment being timed, and when it was sent
+    rtt_sample: Option<(u32, u64)>,
+    retransmit_at: Option<u64>,
+    retries: u32,
+    /// The next segment is a 1-byte probe of a zero window
+    probe: bool,
+    time_wait_until: u64,
+    error: Option<Error>,
+}
+
+impl TcpSocket {
+    fn new(local_port: u16, iss: u32, rx: ByteRing, tx: ByteRing) -> Self {
+        Self {
+            state: State::Closed,
+            local_port,
+            remote: Ipv4Addr::UNSPECIFIED,
+            remote_port: 0,
+            listener: None,
+            orphaned: false,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            mss: DEFAULT_MSS,
+            rcv_nxt: 0,
+            rx,
+            tx,
+            fin_queued: false,
+            fin_acked: false,
+            fin_received: false,
+            ack_pending: false,
+            rto: INITIAL_RTO_NS,
+            srtt: None,
+            rttvar: 0,
+            rtt_sample: None,
+            retransmit_at: None,
+            retries: 0,
+            probe: false,
+            time_wait_until: 0,
+            error: None,
+        }
+    }
+
+    /// A connection to open, the SYN goes out with the next segment. `rx` and `tx` should be
+    /// empty.
+    pub fn connect(
+        local_port: u16,
+        remote: Ipv4Addr,
+        remote_port: u16,
+        iss: u32,
+        rx: ByteRing,
+        tx: ByteRing,
+    ) -> Self {
+        let mut socket = Self::new(local_port, iss, rx, tx);
+        socket.state = State::SynSent;
+        socket.remote = remote;
+        socket.remote_port = remote_port;
+        socket
+    }
+
+    pub fn listen(local_port: u16, rx: ByteRing, tx: ByteRing) -> Self {
+        let mut socket = Self::new(local_port, 0, rx, tx);
+        socket.state = State::Listen;
+        socket
+    }
+
+    /// A connection for a SYN that arrived on `listener`
+    pub fn accept(
+        listener: usize,
+        remote: Ipv4Addr,
+        syn: &SegmentHeader,
+        iss: u32,
+        rx: ByteRing,
+        tx: ByteRing,
+    ) -> Self {
+        let mut socket = Self::new(syn.dst_port, iss, rx, tx);
+        socket.state = State::SynReceived;
+        socket.remote = remote;
+        socket.remote_port = syn.src_port;
+        socket.listener = Some(listener);
+        socket.rcv_nxt = syn.seq.wrapping_add(1);
+        socket.snd_wnd = syn.window as u32;
+        socket.snd_wl1 = syn.seq;
+        socket.mss = syn.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+        socket
+    }
+
+    /// Gives the buffers back, for another socket
+    pub fn into_buffers(self) -> (ByteRing, ByteRing) {
+        (self.rx, self.tx)
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Why the connection was closed, if it wasn't closed normally
+    pub fn error(&self) -> Option<Error> {
+        self.error
+    }
+
+    /// Whether data can flow both ways, or at least to the peer after it closed its side
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Established | State::CloseWait)
+    }
+
+    /// What the connection is waiting for, if anything
+    pub fn deadline(&self) -> Option<u64> {
+        match self.state {
+            State::TimeWait => Some(self.time_wait_until),
+            _ => self.retransmit_at,
+        }
+    }
+
+    /// Copies data from the send buffer, for [`Segment::data_offset`]
+    pub fn peek_tx(&self, offset: usize, buf: &mut [u8]) -> usize {
+        self.tx.peek(offset, buf)
+    }
+
+    fn rcv_window(&self) -> u16 {
+        self.rx.free().min(u16::MAX as usize) as u16
+    }
+
+    fn segment(&self, seq: u32, flags: u8) -> Segment {
+        Segment {
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.rcv_window(),
+            data_offset: 0,
+            data_len: 0,
+        }
+    }
+
+    fn close_with(&mut self, error: Option<Error>) {
+        self.state = State::Closed;
+        self.error = error;
+        self.retransmit_at = None;
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = State::TimeWait;
+        self.time_wait_until = now + TIME_WAIT_NS;
+        self.retransmit_at = None;
+    }
+
+    fn update_rto(&mut self, sample: u64) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(sample)) / 4;
+                self.srtt = Some((7 * srtt + sample) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(sample);
+        self.rto = (srtt + 4 * self.rttvar).clamp(MIN_RTO_NS, MAX_RTO_NS);
+    }
+
+    /// Marks sequence numbers up to `end` as sent, starting the timers
+    fn sent(&mut self, seq: u32, end: u32, now: u64) {
+        if self.rtt_sample.is_none() && self.retries == 0 {
+            self.rtt_sample = Some((seq, now));
+        }
+        self.snd_nxt = end;
+        if seq_lt(self.snd_max, end) {
+            self.snd_max = end;
+        }
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+        self.ack_pending = false;
+    }
+
+    /// Handles a segment for this connection, returns a reset to send if it calls for one
+    pub fn on_segment(&mut self, header: &SegmentHeader, data: &[u8], now: u64) -> Option<Segment> {
+        match self.state {
+            State::Closed | State::Listen => return None,
+            State::SynSent => return self.on_segment_syn_sent(header, now),
+            _ => {}
+        }
+
+        // Only a reset right at the expected sequence number is believed (RFC 5961)
+        if header.flags & FLAG_RST != 0 {
+            if header.seq == self.rcv_nxt {
+                self.close_with(Some(Error::ConnectionReset));
+            }
+            return None;
+        }
+        if header.flags & FLAG_SYN != 0 {
+            // The peer didn't get our SYN-ACK, send it again
+            if self.state == State::SynReceived && header.seq.wrapping_add(1) == self.rcv_nxt {
+                self.snd_nxt = self.iss;
+            } else {
+                self.ack_pending = true;
+            }
+            return None;
+        }
+        if header.flags & FLAG_ACK == 0 {
+            return None;
+        }
+
+        if self.state == State::SynReceived {
+            if header.ack != self.iss.wrapping_add(1) {
+                return Segment::reset_for(header, data.len());
+            }
+            self.state = State::Established;
+        }
+        self.on_ack(header, now);
+        if self.state == State::Closed {
+            return None;
+        }
+
+        // Trim what was already received, drop what comes after a gap
+        let mut data = data;
+        if header.seq != self.rcv_nxt {
+            let skip = self.rcv_nxt.wrapping_sub(header.seq) as usize;
+            if !seq_lt(header.seq, self.rcv_nxt) || skip > data.len() {
+                if header.seq_len(data.len()) > 0 {
+                    self.ack_pending = true;
+                }
+                return None;
+            }
+            data = &data[skip..];
+        }
+
+        if !data.is_empty() {
+            self.ack_pending = true;
+            if !matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            ) {
+                return None;
+            }
+            let accepted = self.rx.push(data);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+            if accepted < data.len() {
+                // The FIN comes after what didn't fit
+                return None;
+            }
+        }
+
+        if header.flags & FLAG_FIN != 0 && !self.fin_received {
+            self.fin_received = true;
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.ack_pending = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn on_segment_syn_sent(&mut self, header: &SegmentHeader, now: u64) -> Option<Segment> {
+        let ack_ok = header.ack == self.iss.wrapping_add(1);
+        if header.flags & FLAG_ACK != 0 && !ack_ok {
+            return Segment::reset_for(header, 0);
+        }
+        if header.flags & FLAG_RST != 0 {
+            if header.flags & FLAG_ACK != 0 {
+                self.close_with(Some(Error::ConnectionRefused));
+            }
+            return None;
+        }
+        if header.flags & FLAG_SYN == 0 {
+            return None;
+        }
+        self.rcv_nxt = header.seq.wrapping_add(1);
+        self.mss = header.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+        self.ack_pending = true;
+        if header.flags & FLAG_ACK != 0 {
+            self.state = State::Established;
+            self.snd_wl1 = header.seq.wrapping_sub(1);
+            self.on_ack(header, now);
+        } else {
+            // Both sides opened at once, answer with a SYN-ACK
+            self.state = State::SynReceived;
+            self.snd_wnd = header.window as u32;
+            self.snd_wl1 = header.seq;
+            self.snd_nxt = self.iss;
+        }
+        None
+    }
+
+    /// Takes what `header` acknowledges off the send buffer, and updates the window
+    fn on_ack(&mut self, header: &SegmentHeader, now: u64) {
+        if seq_lt(self.snd_max, header.ack) {
+            // Acknowledges something we never sent
+            self.ack_pending = true;
+            return;
+        }
+        if seq_lt(self.snd_una, header.ack) {
+            let mut acked = header.ack.wrapping_sub(self.snd_una) as usize;
+            // The SYN and FIN take sequence numbers but aren't in the buffer
+            if self.snd_una == self.iss {
+                acked -= 1;
+            }
+            let data_end = self.snd_una.wrapping_add(self.tx.len() as u32);
+            let fin_acked = self.fin_queued && header.ack == data_end.wrapping_add(1);
+            if fin_acked {
+                acked -= 1;
+            }
+            self.tx.consume(acked);
+            self.snd_una = header.ack;
+            if seq_lt(self.snd_nxt, header.ack) {
+                self.snd_nxt = header.ack;
+            }
+            if let Some((seq, sent_at)) = self.rtt_sample {
+                if seq_lt(seq, header.ack) {
+                    self.update_rto(now - sent_at);
+                    self.rtt_sample = None;
+                }
+            }
+            self.retries = 0;
+            self.retransmit_at = match self.snd_una == self.snd_max {
+                true => None,
+                false => Some(now + self.rto),
+            };
+            if fin_acked {
+                self.fin_acked = true;
+                match self.state {
+                    State::FinWait1 => self.state = State::FinWait2,
+                    State::Closing => self.enter_time_wait(now),
+                    State::LastAck => self.close_with(None),
+                    _ => {}
+                }
+            }
+        }
+        if seq_lt(self.snd_wl1, header.seq)
+            || (self.snd_wl1 == header.seq && seq_le(self.snd_wl2, header.ack))
+        {
+            self.snd_wnd = header.window as u32;
+            self.snd_wl1 = header.seq;
+            self.snd_wl2 = header.ack;
+        }
+    }
+
+    /// Handles the timers
+    pub fn on_timer(&mut self, now: u64) {
+        if self.state == State::TimeWait {
+            if now >= self.time_wait_until {
+                self.close_with(None);
+            }
+            return;
+        }
+        let Some(at) = self.retransmit_at else {
+            return;
+        };
+        if now < at {
+            return;
+        }
+        self.retransmit_at = None;
+        if self.snd_una == self.snd_max {
+            // Nothing in flight, the timer was waiting for a zero window to open
+            self.probe = true;
+            return;
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.close_with(Some(Error::TimedOut));
+            return;
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO_NS);
+        self.snd_nxt = self.snd_una;
+        self.rtt_sample = None;
+        self.probe = self.snd_wnd == 0;
+    }
+
+    /// The next segment to send, if there's one
+    pub fn next_segment(&mut self, now: u64) -> Option<Segment> {
+        match self.state {
+            State::Closed | State::Listen => return None,
+            State::SynSent | State::SynReceived => {
+                if self.snd_nxt == self.iss {
+                    let segment = match self.state {
+                        State::SynSent => Segment {
+                            ack: 0,
+                            ..self.segment(self.iss, FLAG_SYN)
+                        },
+                        _ => self.segment(self.iss, FLAG_SYN | FLAG_ACK),
+                    };
+                    self.sent(self.iss, self.iss.wrapping_add(1), now);
+                    return Some(segment);
+                }
+            }
+            State::TimeWait => {}
+            _ if !self.fin_acked => {
+                let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+                let unsent = (self.tx.len() as u32).saturating_sub(in_flight);
+                let mut usable = self.snd_wnd.saturating_sub(in_flight);
+                if usable == 0 && self.probe && in_flight == 0 {
+                    usable = 1;
+                }
+                if unsent > 0 && usable > 0 {
+                    self.probe = false;
+                    let len = unsent.min(usable).min(self.mss as u32);
+                    let segment = Segment {
+                        data_offset: in_flight as usize,
+                        data_len: len as usize,
+                        ..self.segment(self.snd_nxt, FLAG_ACK | FLAG_PSH)
+                    };
+                    self.sent(self.snd_nxt, self.snd_nxt.wrapping_add(len), now);
+                    return Some(segment);
+                }
+                let data_end = self.snd_una.wrapping_add(self.tx.len() as u32);
+                if self.fin_queued && self.snd_nxt == data_end {
+                    let segment = self.segment(self.snd_nxt, FLAG_FIN | FLAG_ACK);
+                    self.sent(self.snd_nxt, self.snd_nxt.wrapping_add(1), now);
+                    match self.state {
+                        State::Established => self.state = State::FinWait1,
+                        State::CloseWait => self.state = State::LastAck,
+                        _ => {}
+                    }
+                    return Some(segment);
+                }
+                if unsent > 0 && self.retransmit_at.is_none() {
+                    // Wait for the window to open, and probe it if it doesn't
+                    self.retransmit_at = Some(now + self.rto);
+                }
+            }
+            _ => {}
+        }
+        if self.ack_pending {
+            self.ack_pending = false;
+            return Some(self.segment(self.snd_nxt, FLAG_ACK));
+        }
+        None
+    }
+
+    /// Queues data, returns how much fit in the send buffer
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        match self.state {
+            State::Established | State::CloseWait if !self.fin_queued => {}
+            State::SynSent | State::SynReceived => return Err(Error::WouldBlock),
+            State::Listen => return Err(Error::NotConnected),
+            _ => return Err(Error::Closed),
+        }
+        match self.tx.push(data) {
+            0 if !data.is_empty() => Err(Error::WouldBlock),
+            len => Ok(len),
+        }
+    }
+
+    /// Takes received data, returns 0 once the peer closed its side
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if !self.rx.is_empty() {
+            let window = self.rcv_window();
+            let len = self.rx.pop(buf);
+            // Tell the peer if it was kept from sending
+            if window < self.mss && self.rcv_window() >= self.mss {
+                self.ack_pending = true;
+            }
+            return Ok(len);
+        }
+        if self.fin_received {
+            return Ok(0);
+        }
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        match self.state {
+            State::Closed | State::Listen => Err(Error::NotConnected),
+            _ => Err(Error::WouldBlock),
+        }
+    }
+
+    /// Closes our side once the send buffer is drained
+    pub fn close(&mut self) {
+        match self.state {
+            State::Listen | State::SynSent => self.close_with(None),
+            State::SynReceived | State::Established | State::CloseWait => self.fin_queued = true,
+            _ => {}
+        }
+    }
+
+    /// Drops the connection, returns the reset to send
+    pub fn abort(&mut self) -> Option<Segment> {
+        let reset = match self.state {
+            State::Closed | State::Listen | State::SynSent | State::TimeWait => None,
+            _ => Some(self.segment(self.snd_nxt, FLAG_RST | FLAG_ACK)),
+        };
+        self.close_with(Some(Error::ConnectionReset));
+        reset
+    }
+}
@@ -0,0 +1,56 @@
+//! UDP sockets (RFC 768)
+
+use crate::net::buffer::ByteRing;
+use crate::net::{Error, Ipv4Addr};
+
+pub const HEADER_LEN: usize = 8;
+
+/// Received datagrams are queued, each behind a header with its length, source address and port
+pub const RX_BUFFER_SIZE: usize = 8 * 1024;
+const RECORD_HEADER_LEN: usize = 8;
+
+pub struct UdpSocket {
+    pub local_port: u16,
+    rx: ByteRing,
+}
+
+impl UdpSocket {
+    /// Creates a socket queueing datagrams in `rx`, which should be empty
+    pub fn new(local_port: u16, rx: ByteRing) -> Self {
+        Self { local_port, rx }
+    }
+
+    /// Gives the buffer back, for another socket
+    pub fn into_buffer(self) -> ByteRing {
+        self.rx
+    }
+
+    /// Queues a received datagram, dropping it if there's no room
+    pub fn deliver(&mut self, src: Ipv4Addr, src_port: u16, data: &[u8]) {
+        if self.rx.free() < RECORD_HEADER_LEN + data.len() {
+            return;
+        }
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        header[2..4].copy_from_slice(&src_port.to_le_bytes());
+        header[4..8].copy_from_slice(&src.0);
+        self.rx.push(&header);
+        self.rx.push(data);
+    }
+
+    /// Takes the oldest datagram, returns its length, source address and port. Datagrams that
+    /// don't fit `buf` are truncated.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, Ipv4Addr, u16), Error> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        if self.rx.pop(&mut header) == 0 {
+            return Err(Error::WouldBlock);
+        }
+        let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let port = u16::from_le_bytes([header[2], header[3]]);
+        let src = Ipv4Addr(header[4..8].try_into().unwrap());
+        let copied = len.min(buf.len());
+        self.rx.pop(&mut buf[..copied]);
+        self.rx.consume(len - copied);
+        Ok((len, src, port))
+    }
+}
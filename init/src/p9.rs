@@ -0,0 +1,478 @@
+//! A 9P2000.L client, for the host directories QEMU shares with `-device virtio-9p-device`
+//!
+//! Requests are sent one at a time, so every message uses the same tag. Messages are built and
+//! parsed in place in the transport's DMA buffers.
+
+use crate::drv::virtio::{for_each_device, InitHal};
+use crate::println;
+use core::fmt::{Display, Formatter};
+use fdt_rs::base::DevTree;
+use fdt_rs::error::DevTreeError;
+use virtio::p9::Virtio9p;
+use virtio::DeviceType;
+
+const VERSION: &str = "9P2000.L";
+/// Largest message we negotiate, reads return at most this minus the headers
+pub const MSIZE: usize = 16 * 1024;
+
+/// Message types, requests are T-messages and responses R-messages
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TCLUNK: u8 = 120;
+
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+const TAG: u16 = 1;
+const ROOT_FID: u32 = 0;
+/// Most names a single walk can have
+const MAXWELEM: usize = 16;
+
+/// size[4] type[1] tag[2]
+const HEADER_LEN: usize = 7;
+/// Rread: count[4]
+const READ_OVERHEAD: usize = HEADER_LEN + 4;
+
+const O_RDONLY: u32 = 0;
+/// The basic fields of Tgetattr: mode, nlink, uid, gid, rdev, times, size and blocks
+const GETATTR_BASIC: u64 = 0x7ff;
+
+/// Linux errno values the server answers with
+const ENOENT: u32 = 2;
+const EACCES: u32 = 13;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const ENAMETOOLONG: u32 = 36;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    Virtio(virtio::Error),
+    /// The server failed the request with a Linux errno
+    Errno(u32),
+    /// The response is malformed or isn't the one we expected
+    InvalidResponse,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Virtio(err) => write!(f, "virtio: {err}"),
+            Error::Errno(ENOENT) => write!(f, "no such file or directory"),
+            Error::Errno(EACCES) => write!(f, "permission denied"),
+            Error::Errno(ENOTDIR) => write!(f, "not a directory"),
+            Error::Errno(EISDIR) => write!(f, "is a directory"),
+            Error::Errno(ENAMETOOLONG) => write!(f, "name too long"),
+            Error::Errno(errno) => write!(f, "errno {errno}"),
+            Error::InvalidResponse => write!(f, "invalid response"),
+        }
+    }
+}
+
+impl From<virtio::Error> for Error {
+    fn from(err: virtio::Error) -> Self {
+        Error::Virtio(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Stat {
+    /// The server's unique identifier for the file, like an inode number
+    pub ino: u64,
+    /// `st_mode`, with the same bits as [`crate::archive::S_IFMT`]
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub size: u64,
+    pub mtime_sec: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DirEntry<'a> {
+    /// `DT_DIR`, `DT_REG`, ... as in `readdir(3)`
+    pub entry_type: u8,
+    pub name: &'a str,
+}
+
+pub const DT_DIR: u8 = 4;
+pub const DT_LNK: u8 = 10;
+
+/// A file opened with [`Client::open`], has to be given back to [`Client::close`]
+#[derive(Debug)]
+pub struct File {
+    fid: u32,
+    /// Largest read the server does at once, 0 if unknown
+    iounit: u32,
+}
+
+/// Serializes a message into the request buffer
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8], msg_type: u8, tag: u16) -> Self {
+        let writer = Self {
+            buf,
+            len: HEADER_LEN,
+        };
+        writer.buf[4] = msg_type;
+        writer.buf[5..7].copy_from_slice(&tag.to_le_bytes());
+        writer
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<&mut Self, Error> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(Error::Errno(ENAMETOOLONG))?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(self)
+    }
+
+    fn u16(&mut self, value: u16) -> Result<&mut Self, Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<&mut Self, Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> Result<&mut Self, Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    /// Strings are a u16 length followed by UTF-8, without a terminator
+    fn str(&mut self, value: &str) -> Result<&mut Self, Error> {
+        let len = u16::try_from(value.len()).map_err(|_| Error::Errno(ENAMETOOLONG))?;
+        self.u16(len)?.bytes(value.as_bytes())
+    }
+
+    /// Writes the size field, returns the message length
+    fn finish(&mut self) -> usize {
+        self.buf[0..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        self.len
+    }
+}
+
+/// Parses a response, every getter fails with [`Error::InvalidResponse`] past the end
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(Error::InvalidResponse)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| Error::InvalidResponse)
+    }
+
+    /// Reads a qid, the server's identifier for a file: type[1] version[4] path[8]. Returns the
+    /// path, which is unique per file.
+    fn qid(&mut self) -> Result<u64, Error> {
+        self.bytes(5)?;
+        self.u64()
+    }
+}
+
+pub struct Client {
+    transport: Virtio9p<InitHal>,
+    msize: usize,
+    next_fid: u32,
+}
+
+impl Client {
+    /// Finds the 9P device exporting `mount_tag` and attaches to it. Failing to mount is
+    /// reported, but isn't an error since the directory is optional.
+    pub fn find_and_init(dtb: &DevTree, mount_tag: &str) -> Result<Option<Self>, DevTreeError> {
+        let mut transport = None;
+        for_each_device(dtb, |device| {
+            if device.transport.device_type() != DeviceType::NineP {
+                return true;
+            }
+            match Virtio9p::new(device.transport, MSIZE) {
+                Ok(dev) if dev.mount_tag() == mount_tag => {
+                    transport = Some(dev);
+                    false
+                }
+                Ok(_) => true,
+                Err(err) => {
+                    println!("Failed to initialize virtio-9p: {err}");
+                    true
+                }
+            }
+        })?;
+        let Some(transport) = transport else {
+            return Ok(None);
+        };
+        match Self::new(transport) {
+            Ok(client) => Ok(Some(client)),
+            Err(err) => {
+                println!("Failed to mount {mount_tag:?}: {err}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Negotiates the protocol version and attaches to the root of the export
+    pub fn new(transport: Virtio9p<InitHal>) -> Result<Self, Error> {
+        let mut client = Self {
+            msize: transport.msize().min(MSIZE),
+            transport,
+            next_fid: ROOT_FID + 1,
+        };
+
+        let msize = client.msize as u32;
+        let mut reader = client.call(TVERSION, NOTAG, |w| {
+            w.u32(msize)?.str(VERSION)?;
+            Ok(())
+        })?;
+        let msize = reader.u32()? as usize;
+        // The server answers "unknown" if it doesn't speak the version
+        if reader.str()? != VERSION {
+            return Err(Error::InvalidResponse);
+        }
+        client.msize = client.msize.min(msize);
+
+        client.call(TATTACH, TAG, |w| {
+            // Users don't matter with QEMU's mapped security model
+            w.u32(ROOT_FID)?.u32(NOFID)?.str("root")?.str("")?.u32(0)?;
+            Ok(())
+        })?;
+        Ok(client)
+    }
+
+    /// Sends a request written by `write` and checks the response, returns a reader positioned
+    /// after the response header
+    fn call(
+        &mut self,
+        msg_type: u8,
+        tag: u16,
+        write: impl FnOnce(&mut Writer) -> Result<(), Error>,
+    ) -> Result<Reader<'_>, Error> {
+        let msize = self.msize;
+        let mut writer = Writer::new(&mut self.transport.request_buf()[..msize], msg_type, tag);
+        write(&mut writer)?;
+        let len = writer.finish();
+        let response_len = self.transport.send(len)?;
+
+        let response = &self.transport.response()[..response_len.min(msize)];
+        let mut reader = Reader {
+            buf: response,
+            pos: 0,
+        };
+        let size = reader.u32()? as usize;
+        let response_type = reader.u8()?;
+        let response_tag = reader.u16()?;
+        if size < HEADER_LEN || size > response.len() || response_tag != tag {
+            return Err(Error::InvalidResponse);
+        }
+        reader.buf = &response[..size];
+        if response_type == RLERROR {
+            return Err(Error::Errno(reader.u32()?));
+        }
+        if response_type != msg_type + 1 {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(reader)
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        let fid = self.next_fid;
+        self.next_fid = self.next_fid.wrapping_add(1).max(ROOT_FID + 1);
+        fid
+    }
+
+    /// Walks from the root to `path`, returns a new fid for it
+    fn walk(&mut self, path: &str) -> Result<u32, Error> {
+        let fid = self.alloc_fid();
+        let mut names = path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".");
+
+        // The first walk clones the root, even with no names, the next ones continue from there
+        let mut from = ROOT_FID;
+        loop {
+            let mut batch = [""; MAXWELEM];
+            let mut count = 0;
+            for name in names.by_ref().take(MAXWELEM) {
+                batch[count] = name;
+                count += 1;
+            }
+            let result = self.call(TWALK, TAG, |w| {
+                w.u32(from)?.u32(fid)?.u16(count as u16)?;
+                for name in &batch[..count] {
+                    w.str(name)?;
+                }
+                Ok(())
+            });
+            // A partial walk succeeds but doesn't create the fid
+            let walked = match result {
+                Ok(mut reader) => reader.u16()? as usize,
+                Err(err) => {
+                    if from == fid {
+                        self.clunk(fid);
+                    }
+                    return Err(err);
+                }
+            };
+            if walked < count {
+                if from == fid {
+                    self.clunk(fid);
+                }
+                return Err(Error::Errno(ENOENT));
+            }
+            if count < MAXWELEM {
+                return Ok(fid);
+            }
+            from = fid;
+        }
+    }
+
+    /// Releases a fid, errors are ignored since the fid is gone either way
+    fn clunk(&mut self, fid: u32) {
+        let _ = self.call(TCLUNK, TAG, |w| {
+            w.u32(fid)?;
+            Ok(())
+        });
+    }
+
+    fn getattr(&mut self, fid: u32) -> Result<Stat, Error> {
+        let mut reader = self.call(TGETATTR, TAG, |w| {
+            w.u32(fid)?.u64(GETATTR_BASIC)?;
+            Ok(())
+        })?;
+        let _valid = reader.u64()?;
+        let ino = reader.qid()?;
+        let mode = reader.u32()?;
+        let uid = reader.u32()?;
+        let gid = reader.u32()?;
+        let nlink = reader.u64()?;
+        let _rdev = reader.u64()?;
+        let size = reader.u64()?;
+        let _blksize = reader.u64()?;
+        let _blocks = reader.u64()?;
+        let _atime = (reader.u64()?, reader.u64()?);
+        let mtime_sec = reader.u64()?;
+        Ok(Stat {
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            size,
+            mtime_sec,
+        })
+    }
+
+    pub fn stat(&mut self, path: &str) -> Result<Stat, Error> {
+        let fid = self.walk(path)?;
+        let stat = self.getattr(fid);
+        self.clunk(fid);
+        stat
+    }
+
+    /// Opens a file or a directory for reading
+    pub fn open(&mut self, path: &str) -> Result<File, Error> {
+        let fid = self.walk(path)?;
+        let result = self.call(TLOPEN, TAG, |w| {
+            w.u32(fid)?.u32(O_RDONLY)?;
+            Ok(())
+        });
+        let iounit = match result {
+            Ok(mut reader) => {
+                reader.qid()?;
+                reader.u32()?
+            }
+            Err(err) => {
+                self.clunk(fid);
+                return Err(err);
+            }
+        };
+        Ok(File { fid, iounit })
+    }
+
+    pub fn close(&mut self, file: File) {
+        self.clunk(file.fid);
+    }
+
+    /// Reads at `offset`, returns how many bytes were read, 0 at the end of the file
+    pub fn read(&mut self, file: &File, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut count = buf.len().min(self.msize - READ_OVERHEAD);
+        if file.iounit != 0 {
+            count = count.min(file.iounit as usize);
+        }
+        let mut reader = self.call(TREAD, TAG, |w| {
+            w.u32(file.fid)?.u64(offset)?.u32(count as u32)?;
+            Ok(())
+        })?;
+        let len = reader.u32()? as usize;
+        if len > count {
+            return Err(Error::InvalidResponse);
+        }
+        buf[..len].copy_from_slice(reader.bytes(len)?);
+        Ok(len)
+    }
+
+    /// Reads a batch of directory entries starting at the cookie `offset`, 0 for the first
+    /// batch, and calls `f` for each one. Returns the cookie for the next batch, or `None` at
+    /// the end of the directory.
+    pub fn readdir(
+        &mut self,
+        dir: &File,
+        offset: u64,
+        mut f: impl FnMut(DirEntry),
+    ) -> Result<Option<u64>, Error> {
+        let count = (self.msize - READ_OVERHEAD) as u32;
+        let mut reader = self.call(TREADDIR, TAG, |w| {
+            w.u32(dir.fid)?.u64(offset)?.u32(count)?;
+            Ok(())
+        })?;
+        let len = reader.u32()? as usize;
+        let mut entries = Reader {
+            buf: reader.bytes(len)?,
+            pos: 0,
+        };
+        let mut next = None;
+        while entries.pos < len {
+            entries.qid()?;
+            next = Some(entries.u64()?);
+            let entry_type = entries.u8()?;
+            let name = entries.str()?;
+            f(DirEntry { entry_type, name });
+        }
+        Ok(next)
+    }
+}
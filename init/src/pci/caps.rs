@@ -0,0 +1,180 @@
+//! The capability list in a function's config space
+//!
+//! Only the capabilities drivers care about are decoded: MSI, MSI-X, PCI Express, and the
+//! virtio-pci ones that tell where each structure of a virtio device is in its BARs.
+
+use crate::pci::{Config, CAPABILITIES_POINTER, STATUS, STATUS_CAPABILITIES};
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+/// Red Hat, whose vendor ID virtio devices use
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+/// More capabilities than fit in the config space, the list must be looping
+const MAX_CAPABILITIES: usize = 48;
+
+/// MSI message control bits
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_MASKABLE: u16 = 1 << 8;
+/// MSI-X message control bits
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_SIZE: u16 = 0x7ff;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Capability {
+    Msi {
+        offset: u8,
+        /// Vectors the function can send
+        vectors: u8,
+        is_64bit: bool,
+        maskable: bool,
+        enabled: bool,
+    },
+    MsiX {
+        offset: u8,
+        table_size: u16,
+        table_bar: u8,
+        table_offset: u32,
+        pba_bar: u8,
+        pba_offset: u32,
+        enabled: bool,
+    },
+    PciExpress {
+        offset: u8,
+        version: u8,
+        /// Endpoint, root port, switch port...
+        port_type: u8,
+    },
+    /// Where a virtio-pci structure is
+    Virtio {
+        offset: u8,
+        cfg_type: VirtioCfgType,
+        bar: u8,
+        bar_offset: u32,
+        length: u32,
+        /// For the notify structure, the distance between the queues' notify addresses
+        notify_multiplier: Option<u32>,
+    },
+    Other {
+        offset: u8,
+        id: u8,
+    },
+}
+
+/// The structures virtio-pci capabilities point to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VirtioCfgType {
+    Common,
+    Notify,
+    Isr,
+    Device,
+    PciConfig,
+    Other(u8),
+}
+
+impl From<u8> for VirtioCfgType {
+    fn from(cfg_type: u8) -> Self {
+        match cfg_type {
+            1 => Self::Common,
+            2 => Self::Notify,
+            3 => Self::Isr,
+            4 => Self::Device,
+            5 => Self::PciConfig,
+            _ => Self::Other(cfg_type),
+        }
+    }
+}
+
+/// Walks a function's capability list
+pub struct Capabilities {
+    config: Config,
+    vendor_id: u16,
+    next: u8,
+    left: usize,
+}
+
+impl Capabilities {
+    pub fn new(config: Config, vendor_id: u16) -> Self {
+        let has_list = config.read16(STATUS) & STATUS_CAPABILITIES != 0;
+        Self {
+            config,
+            vendor_id,
+            next: match has_list {
+                true => config.read8(CAPABILITIES_POINTER),
+                false => 0,
+            },
+            left: MAX_CAPABILITIES,
+        }
+    }
+
+    fn decode(&self, offset: u8) -> Capability {
+        let reg = offset as usize;
+        let config = &self.config;
+        match config.read8(reg) {
+            CAP_MSI => {
+                let control = config.read16(reg + 2);
+                Capability::Msi {
+                    offset,
+                    vectors: 1 << ((control >> 1) & 0x7).min(5),
+                    is_64bit: control & MSI_64BIT != 0,
+                    maskable: control & MSI_MASKABLE != 0,
+                    enabled: control & MSI_ENABLE != 0,
+                }
+            }
+            CAP_MSIX => {
+                let control = config.read16(reg + 2);
+                let table = config.read32(reg + 4);
+                let pba = config.read32(reg + 8);
+                Capability::MsiX {
+                    offset,
+                    table_size: (control & MSIX_TABLE_SIZE) + 1,
+                    table_bar: (table & 0x7) as u8,
+                    table_offset: table & !0x7,
+                    pba_bar: (pba & 0x7) as u8,
+                    pba_offset: pba & !0x7,
+                    enabled: control & MSIX_ENABLE != 0,
+                }
+            }
+            CAP_PCI_EXPRESS => {
+                let flags = config.read16(reg + 2);
+                Capability::PciExpress {
+                    offset,
+                    version: (flags & 0xf) as u8,
+                    port_type: ((flags >> 4) & 0xf) as u8,
+                }
+            }
+            CAP_VENDOR if self.vendor_id == VIRTIO_VENDOR_ID => {
+                let cfg_type = VirtioCfgType::from(config.read8(reg + 3));
+                Capability::Virtio {
+                    offset,
+                    cfg_type,
+                    bar: config.read8(reg + 4),
+                    bar_offset: config.read32(reg + 8),
+                    length: config.read32(reg + 12),
+                    notify_multiplier: (cfg_type == VirtioCfgType::Notify)
+                        .then(|| config.read32(reg + 16)),
+                }
+            }
+            id => Capability::Other { offset, id },
+        }
+    }
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // The bottom two bits are reserved, and the list lives after the standard header
+        let offset = self.next & !0x3;
+        if offset < 0x40 || self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        self.next = self.config.read8(offset as usize + 1);
+        Some(self.decode(offset))
+    }
+}
@@ -0,0 +1,169 @@
+//! The functions found on the bus, and the handle a driver gets by claiming one
+
+use crate::pci::caps::Capabilities;
+use crate::pci::{
+    Config, PciAddress, COMMAND, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE, COMMAND_IO,
+    COMMAND_MEMORY,
+};
+use crate::utils::{irq_wait_timeout, map_mmio, mem_unmap, PAGE_SIZE};
+use core::ops::Deref;
+use kernel_api::KError;
+
+/// Base address registers of a type 0 header
+pub const MAX_BARS: usize = 6;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BarKind {
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// A BAR, with the address it was assigned
+#[derive(Copy, Clone, Debug)]
+pub struct Bar {
+    pub kind: BarKind,
+    pub prefetchable: bool,
+    /// Where the CPU reaches it
+    pub phy_addr: u64,
+    pub size: u64,
+}
+
+/// What enumeration found out about a function
+#[derive(Copy, Clone)]
+pub struct PciFunction {
+    pub(super) address: PciAddress,
+    pub(super) config: Config,
+    pub(super) vendor_id: u16,
+    pub(super) device_id: u16,
+    pub(super) class_revision: u32,
+    /// The upper half of a 64-bit BAR is `None`, like unimplemented ones
+    pub(super) bars: [Option<Bar>; MAX_BARS],
+    /// The legacy INTx line, routed through the host bridge's `interrupt-map`
+    pub(super) interrupt_id: Option<u32>,
+}
+
+impl PciFunction {
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.device_id
+    }
+
+    /// Class, subclass and programming interface
+    pub fn class(&self) -> (u8, u8, u8) {
+        let [_, prog_if, subclass, class] = self.class_revision.to_le_bytes();
+        (class, subclass, prog_if)
+    }
+
+    pub fn bar(&self, index: usize) -> Option<&Bar> {
+        self.bars.get(index)?.as_ref()
+    }
+
+    pub fn bars(&self) -> impl Iterator<Item = (usize, &Bar)> {
+        self.bars
+            .iter()
+            .enumerate()
+            .filter_map(|(i, bar)| Some((i, bar.as_ref()?)))
+    }
+
+    /// The GIC interrupt ID of its INTx line, if it has one
+    pub fn interrupt_id(&self) -> Option<u32> {
+        self.interrupt_id
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(self.config, self.vendor_id)
+    }
+
+    pub fn read_config32(&self, reg: usize) -> u32 {
+        self.config.read32(reg)
+    }
+}
+
+/// A claimed function: only its owner maps its BARs and uses its interrupt. Get one from
+/// [`PciBus::claim`](crate::pci::host::PciBus::claim), and hand it back with
+/// [`PciBus::release`](crate::pci::host::PciBus::release).
+pub struct PciDevice {
+    function: PciFunction,
+    /// The page-aligned mapping of each BAR, 0 if not mapped yet
+    mappings: [usize; MAX_BARS],
+}
+
+impl PciDevice {
+    /// Makes the function decode its BARs, and lets it do DMA and raise its INTx interrupt
+    pub(super) fn enable(function: PciFunction) -> Self {
+        let mut command = function.config.read16(COMMAND) & !COMMAND_INTX_DISABLE;
+        for (_, bar) in function.bars() {
+            command |= match bar.kind {
+                BarKind::Io => COMMAND_IO,
+                BarKind::Memory32 | BarKind::Memory64 => COMMAND_MEMORY,
+            };
+        }
+        function
+            .config
+            .write16(COMMAND, command | COMMAND_BUS_MASTER);
+        Self {
+            function,
+            mappings: [0; MAX_BARS],
+        }
+    }
+
+    /// Stops the function's decoding and DMA and unmaps its BARs, before it's released
+    pub(super) fn disable(self) {
+        let config = self.function.config;
+        let command = config.read16(COMMAND);
+        config.write16(
+            COMMAND,
+            command & !(COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER) | COMMAND_INTX_DISABLE,
+        );
+        for (index, &mapping) in self.mappings.iter().enumerate() {
+            if mapping != 0 {
+                let _ = unsafe { mem_unmap(mapping as *const (), self.mapping_len(index)) };
+            }
+        }
+    }
+
+    /// The length of a BAR's mapping, in whole pages
+    fn mapping_len(&self, index: usize) -> usize {
+        let bar = self.function.bars[index].expect("BAR is mapped but doesn't exist");
+        (bar.phy_addr as usize % PAGE_SIZE + bar.size as usize).next_multiple_of(PAGE_SIZE)
+    }
+
+    /// Maps a BAR, I/O ones included since the host bridge turns them into MMIO. The mapping
+    /// lasts until the device is released.
+    pub fn map_bar(&mut self, index: usize) -> Result<*mut u8, KError> {
+        let bar = *self.function.bar(index).ok_or(KError::InvalidArgument)?;
+        let page_offset = bar.phy_addr as usize % PAGE_SIZE;
+        if self.mappings[index] == 0 {
+            let base = unsafe { map_mmio(bar.phy_addr as usize, bar.size as usize) }?;
+            self.mappings[index] = base as usize - page_offset;
+        }
+        Ok((self.mappings[index] + page_offset) as *mut u8)
+    }
+
+    /// Blocks until the function raises its interrupt, or for at most `timeout_ns`
+    /// nanoseconds. Returns whether it was raised, fails with `NoDevice` if it has none.
+    pub fn wait_interrupt(&self, timeout_ns: u64) -> Result<bool, KError> {
+        let interrupt_id = self.function.interrupt_id.ok_or(KError::NoDevice)?;
+        match irq_wait_timeout(interrupt_id, timeout_ns) {
+            Ok(()) => Ok(true),
+            Err(KError::TimedOut) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Deref for PciDevice {
+    type Target = PciFunction;
+
+    fn deref(&self) -> &PciFunction {
+        &self.function
+    }
+}
@@ -0,0 +1,571 @@
+//! The generic PCIe host bridge, `pci-host-ecam-generic`
+//!
+//! ECAM maps every function's config space in one window, bus by bus, 1 MiB each. Nothing
+//! assigns the BARs before us on QEMU's `virt` machine, so enumeration numbers the buses behind
+//! bridges and hands out addresses from the windows in the node's `ranges`. Legacy INTx lines
+//! are routed by `interrupt-map`, after swizzling them through the bridges on the way to the
+//! root bus.
+//!
+//! There's no handle system yet, so claiming a function hands out a [`PciDevice`] object that
+//! stands in for the handle: nobody else can claim the function until it's released.
+
+use crate::dtb;
+use crate::net::buffer::PageBox;
+use crate::pci::device::{Bar, BarKind, PciDevice, PciFunction, MAX_BARS};
+use crate::pci::{
+    Config, PciAddress, BAR0, CLASS_REVISION, COMMAND, COMMAND_BUS_MASTER, COMMAND_IO,
+    COMMAND_MEMORY, DEVICE_ID, HEADER_TYPE, INTERRUPT_PIN, VENDOR_ID,
+};
+use crate::utils::{map_mmio, PAGE_SIZE};
+use fdt_rs::base::{DevTree, DevTreeNode, DevTreeProp};
+use fdt_rs::error::DevTreeError;
+use fdt_rs::prelude::PropReader;
+use kernel_api::KError;
+
+pub const MAX_FUNCTIONS: usize = 32;
+/// Buses mapped from the ECAM window, the bridges past them are left closed
+const MAX_BUSES: usize = 16;
+const BUS_CONFIG_SIZE: usize = 1 << 20;
+const MAX_INTERRUPT_MAP: usize = 32;
+
+/// `HEADER_TYPE` bits
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_ENDPOINT: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// Registers of the type 1 header, for bridges
+const BRIDGE_BARS: usize = 2;
+const PRIMARY_BUS: usize = 0x18;
+const SECONDARY_BUS: usize = 0x19;
+const SUBORDINATE_BUS: usize = 0x1a;
+const IO_BASE: usize = 0x1c;
+const IO_LIMIT: usize = 0x1d;
+const MEMORY_BASE: usize = 0x20;
+const MEMORY_LIMIT: usize = 0x22;
+/// Prefetchable base and limit, as one register
+const PREFETCHABLE_BASE_LIMIT: usize = 0x24;
+const PREFETCHABLE_BASE_UPPER: usize = 0x28;
+const PREFETCHABLE_LIMIT_UPPER: usize = 0x2c;
+/// I/O base and limit upper halves, as one register
+const IO_UPPER: usize = 0x30;
+
+/// Bridges forward memory in 1 MiB granules and I/O in 4 KiB ones
+const BRIDGE_MEMORY_ALIGN: u64 = 1 << 20;
+const BRIDGE_IO_ALIGN: u64 = 1 << 12;
+
+/// BAR bits
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0x6;
+const BAR_TYPE_64: u32 = 0x4;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// A `ranges` entry: 3 cells of PCI address, 2 of CPU address, 2 of size
+const RANGE_CELLS: usize = 7;
+/// Address space, bits 25:24 of the first PCI address cell
+const SPACE_IO: u32 = 1;
+const SPACE_MEMORY32: u32 = 2;
+const SPACE_MEMORY64: u32 = 3;
+/// The legacy ISA ports, left alone in the I/O window
+const LEGACY_IO_END: u64 = 0x1000;
+
+/// An address window of the host bridge, allocated from the bottom up
+#[derive(Copy, Clone)]
+struct Window {
+    pci_base: u64,
+    cpu_base: u64,
+    size: u64,
+    /// The next free PCI address
+    next: u64,
+}
+
+impl Window {
+    fn new(pci_base: u64, cpu_base: u64, size: u64) -> Self {
+        Self {
+            pci_base,
+            cpu_base,
+            size,
+            next: pci_base,
+        }
+    }
+
+    /// Allocates `size` bytes aligned to `align`, returns their PCI and CPU addresses
+    fn alloc(&mut self, size: u64, align: u64) -> Option<(u64, u64)> {
+        let addr = self.next.checked_next_multiple_of(align)?;
+        let end = addr.checked_add(size)?;
+        if end > self.pci_base + self.size {
+            return None;
+        }
+        self.next = end;
+        Some((addr, self.cpu_base + (addr - self.pci_base)))
+    }
+
+    /// Moves to the next `align` boundary and returns it, for bridge windows
+    fn align(&mut self, align: u64) -> u64 {
+        self.next = self.next.next_multiple_of(align);
+        self.next
+    }
+}
+
+/// An `interrupt-map` entry, for the GIC
+#[derive(Copy, Clone)]
+struct InterruptRoute {
+    /// The device number bits of the first address cell
+    device: u32,
+    pin: u32,
+    interrupt_id: u32,
+}
+
+/// How the INTx lines of a bus reach the root bus: each bridge rotates them by the device
+/// number they come from
+#[derive(Copy, Clone)]
+struct Swizzle {
+    /// The root bus device they come out of, `None` on the root bus
+    root_device: Option<u8>,
+    rotation: u8,
+}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    function: PciFunction,
+    claimed: bool,
+}
+
+pub struct PciBus {
+    phy_addr: usize,
+    /// ECAM mapping of each bus from `first_bus`, null until it's first accessed
+    buses: [*mut u8; MAX_BUSES],
+    first_bus: u8,
+    last_bus: u8,
+    io: Option<Window>,
+    mem32: Option<Window>,
+    mem64: Option<Window>,
+    /// `interrupt-map-mask` of the device bits and the pin
+    interrupt_mask: (u32, u32),
+    interrupt_map: [Option<InterruptRoute>; MAX_INTERRUPT_MAP],
+    functions: PageBox<[Option<Slot>; MAX_FUNCTIONS]>,
+}
+
+impl PciBus {
+    /// Finds the host bridge and enumerates everything behind it
+    pub fn find_and_init(dtb: &DevTree) -> Result<Option<Self>, DevTreeError> {
+        let Some(node) = dtb::find_compatible(dtb, "pci-host-ecam-generic")? else {
+            return Ok(None);
+        };
+        let (phy_addr, len) = dtb::reg(&node)?.expect("PCIe node has no reg property");
+        let (first_bus, last_bus) = match dtb::find_prop(&node, "bus-range")? {
+            Some(prop) => (prop.u32(0)?, prop.u32(1)?),
+            None => (0, 0xff),
+        };
+        // The window may be smaller than the bus range says
+        let ecam_buses = (len / BUS_CONFIG_SIZE as u64).max(1) as u32;
+        let last_bus = last_bus.min(first_bus + ecam_buses - 1).min(0xff);
+
+        let mut bus = PciBus {
+            phy_addr: phy_addr as usize,
+            buses: [core::ptr::null_mut(); MAX_BUSES],
+            first_bus: first_bus as u8,
+            last_bus: last_bus as u8,
+            io: None,
+            mem32: None,
+            mem64: None,
+            interrupt_mask: (0, 0),
+            interrupt_map: [None; MAX_INTERRUPT_MAP],
+            functions: PageBox::new([None; MAX_FUNCTIONS])
+                .expect("Failed to allocate the PCI function table"),
+        };
+        if let Some(ranges) = dtb::find_prop(&node, "ranges")? {
+            bus.parse_ranges(&ranges)?;
+        }
+        bus.parse_interrupt_map(dtb, &node)?;
+
+        let mut next_bus = bus.first_bus as u16 + 1;
+        let root = Swizzle {
+            root_device: None,
+            rotation: 0,
+        };
+        bus.scan_bus(bus.first_bus, root, &mut next_bus);
+        Ok(Some(bus))
+    }
+
+    fn parse_ranges(&mut self, ranges: &DevTreeProp) -> Result<(), DevTreeError> {
+        for i in 0..ranges.length() / (4 * RANGE_CELLS) {
+            let cell = |n: usize| ranges.u32(i * RANGE_CELLS + n).map(|cell| cell as u64);
+            let space = (cell(0)? as u32 >> 24) & 0x3;
+            let pci_base = cell(1)? << 32 | cell(2)?;
+            let cpu_base = cell(3)? << 32 | cell(4)?;
+            let size = cell(5)? << 32 | cell(6)?;
+            let mut window = Window::new(pci_base, cpu_base, size);
+            match space {
+                SPACE_IO => {
+                    window.next = window.next.max(LEGACY_IO_END);
+                    self.io = Some(window);
+                }
+                SPACE_MEMORY32 => self.mem32 = Some(window),
+                SPACE_MEMORY64 => self.mem64 = Some(window),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the routes of `interrupt-map` that lead to the GIC. The child specifiers are 3
+    /// address cells and the pin, the parent's cell counts come from its node.
+    fn parse_interrupt_map(
+        &mut self,
+        dtb: &DevTree,
+        node: &DevTreeNode,
+    ) -> Result<(), DevTreeError> {
+        let (Some(map), Some(mask)) = (
+            dtb::find_prop(node, "interrupt-map")?,
+            dtb::find_prop(node, "interrupt-map-mask")?,
+        ) else {
+            return Ok(());
+        };
+        self.interrupt_mask = (mask.u32(0)?, mask.u32(3)?);
+
+        let cells = map.length() / 4;
+        let mut count = 0;
+        let mut i = 0;
+        // Phandle, #address-cells and #interrupt-cells of the last parent looked up
+        let mut parent = None;
+        while i + 5 <= cells && count < MAX_INTERRUPT_MAP {
+            let phandle = map.u32(i + 4)?;
+            let (address_cells, interrupt_cells) = match parent {
+                Some((cached, address_cells, interrupt_cells)) if cached == phandle => {
+                    (address_cells, interrupt_cells)
+                }
+                _ => {
+                    let Some(parent_node) = dtb::find_by_phandle(dtb, phandle)? else {
+                        break;
+                    };
+                    let address_cells = match dtb::find_prop(&parent_node, "#address-cells")? {
+                        Some(prop) => prop.u32(0)? as usize,
+                        None => 0,
+                    };
+                    let interrupt_cells = match dtb::find_prop(&parent_node, "#interrupt-cells")? {
+                        Some(prop) => prop.u32(0)? as usize,
+                        None => 1,
+                    };
+                    parent = Some((phandle, address_cells, interrupt_cells));
+                    (address_cells, interrupt_cells)
+                }
+            };
+            let specifier = i + 5 + address_cells;
+            if interrupt_cells >= 2 && specifier + interrupt_cells <= cells {
+                let interrupt_id =
+                    dtb::gic_interrupt_id(map.u32(specifier)?, map.u32(specifier + 1)?);
+                if let Some(interrupt_id) = interrupt_id {
+                    self.interrupt_map[count] = Some(InterruptRoute {
+                        device: map.u32(i)? & self.interrupt_mask.0,
+                        pin: map.u32(i + 3)? & self.interrupt_mask.1,
+                        interrupt_id,
+                    });
+                    count += 1;
+                }
+            }
+            i = specifier + interrupt_cells;
+        }
+        Ok(())
+    }
+
+    /// The GIC interrupt of `pin` (1 for INTA to 4 for INTD) of a root bus device
+    fn route_interrupt(&self, device: u8, pin: u8) -> Option<u32> {
+        let device = (device as u32) << 11 & self.interrupt_mask.0;
+        let pin = pin as u32 & self.interrupt_mask.1;
+        self.interrupt_map
+            .iter()
+            .flatten()
+            .find(|route| route.device == device && route.pin == pin)
+            .map(|route| route.interrupt_id)
+    }
+
+    /// The config space of a function, mapping its bus first if needed
+    fn config(&mut self, address: PciAddress) -> Option<Config> {
+        let index = address.bus.checked_sub(self.first_bus)? as usize;
+        if address.bus > self.last_bus || index >= MAX_BUSES {
+            return None;
+        }
+        if self.buses[index].is_null() {
+            let phy_addr = self.phy_addr + index * BUS_CONFIG_SIZE;
+            self.buses[index] =
+                unsafe { map_mmio(phy_addr, BUS_CONFIG_SIZE) }.expect("Failed to map PCIe ECAM");
+        }
+        let offset = (address.device as usize) << 15 | (address.function as usize) << 12;
+        Some(unsafe { Config::new(self.buses[index].add(offset)) })
+    }
+
+    fn scan_bus(&mut self, bus: u8, swizzle: Swizzle, next_bus: &mut u16) {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = PciAddress {
+                    bus,
+                    device,
+                    function,
+                };
+                let Some(config) = self.config(address) else {
+                    return;
+                };
+                if config.read16(VENDOR_ID) == 0xffff {
+                    // Function 0 is always there when the device is
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                let header_type = config.read8(HEADER_TYPE);
+                self.probe_function(
+                    address,
+                    config,
+                    header_type & HEADER_TYPE_MASK,
+                    swizzle,
+                    next_bus,
+                );
+                if function == 0 && header_type & HEADER_MULTIFUNCTION == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn probe_function(
+        &mut self,
+        address: PciAddress,
+        config: Config,
+        header_type: u8,
+        swizzle: Swizzle,
+        next_bus: &mut u16,
+    ) {
+        // No decoding while the BARs are sized and moved, drivers turn it on when they claim it
+        let command = config.read16(COMMAND) & !(COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+        config.write16(COMMAND, command);
+        let bar_count = match header_type {
+            HEADER_TYPE_ENDPOINT => MAX_BARS,
+            HEADER_TYPE_BRIDGE => BRIDGE_BARS,
+            _ => 0,
+        };
+        let bars = self.assign_bars(config, bar_count, swizzle.root_device.is_none());
+
+        let (root_device, rotation) = match swizzle.root_device {
+            None => (address.device, 0),
+            Some(root_device) => (root_device, (swizzle.rotation + address.device) % 4),
+        };
+        let interrupt_id = match config.read8(INTERRUPT_PIN) {
+            pin @ 1..=4 => self.route_interrupt(root_device, (pin - 1 + rotation) % 4 + 1),
+            _ => None,
+        };
+
+        let function = PciFunction {
+            address,
+            config,
+            vendor_id: config.read16(VENDOR_ID),
+            device_id: config.read16(DEVICE_ID),
+            class_revision: config.read32(CLASS_REVISION),
+            bars,
+            interrupt_id,
+        };
+        if let Some(slot) = self.functions.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(Slot {
+                function,
+                claimed: false,
+            });
+        }
+
+        if header_type == HEADER_TYPE_BRIDGE {
+            let swizzle = Swizzle {
+                root_device: Some(root_device),
+                rotation,
+            };
+            self.scan_bridge(config, address.bus, swizzle, next_bus);
+        }
+    }
+
+    /// Sizes the BARs and assigns them addresses. 64-bit BARs get 32-bit addresses when they
+    /// can, since bridges only forward the 32-bit window; on the root bus they fall back to the
+    /// 64-bit window.
+    fn assign_bars(
+        &mut self,
+        config: Config,
+        count: usize,
+        root_bus: bool,
+    ) -> [Option<Bar>; MAX_BARS] {
+        let mut bars = [None; MAX_BARS];
+        let mut index = 0;
+        while index < count {
+            let reg = BAR0 + index * 4;
+            let low = config.read32(reg);
+            config.write32(reg, u32::MAX);
+            let low_mask = config.read32(reg);
+            config.write32(reg, low);
+
+            let kind = match (low & BAR_IO != 0, low & BAR_TYPE_MASK) {
+                (true, _) => BarKind::Io,
+                (false, BAR_TYPE_64) if index + 1 < count => BarKind::Memory64,
+                (false, _) => BarKind::Memory32,
+            };
+            let flags = match kind {
+                BarKind::Io => 0x3,
+                BarKind::Memory32 | BarKind::Memory64 => 0xf,
+            };
+            // Upper bits that don't exist read as 0, as if the BAR were that big
+            let mut mask = 0xffff_ffff_0000_0000 | (low_mask & !flags) as u64;
+            if kind == BarKind::Io {
+                mask |= 0xffff_0000;
+            }
+            if kind == BarKind::Memory64 {
+                let high = config.read32(reg + 4);
+                config.write32(reg + 4, u32::MAX);
+                mask = (config.read32(reg + 4) as u64) << 32 | mask & 0xffff_ffff;
+                config.write32(reg + 4, high);
+            }
+            let size = (!mask).wrapping_add(1);
+
+            if low_mask & !flags != 0 && size != 0 {
+                if let Some((pci_addr, phy_addr)) = self.alloc(kind, size, root_bus) {
+                    config.write32(reg, pci_addr as u32);
+                    if kind == BarKind::Memory64 {
+                        config.write32(reg + 4, (pci_addr >> 32) as u32);
+                    }
+                    bars[index] = Some(Bar {
+                        kind,
+                        prefetchable: kind != BarKind::Io && low & BAR_PREFETCHABLE != 0,
+                        phy_addr,
+                        size,
+                    });
+                }
+            }
+            index += match kind {
+                BarKind::Memory64 => 2,
+                BarKind::Io | BarKind::Memory32 => 1,
+            };
+        }
+        bars
+    }
+
+    /// Allocates the addresses of a BAR, returns its PCI and CPU addresses. Memory BARs get
+    /// pages of their own, so mapping one doesn't expose another.
+    fn alloc(&mut self, kind: BarKind, size: u64, root_bus: bool) -> Option<(u64, u64)> {
+        let align = match kind {
+            BarKind::Io => size,
+            BarKind::Memory32 | BarKind::Memory64 => size.max(PAGE_SIZE as u64),
+        };
+        match kind {
+            BarKind::Io => self.io.as_mut()?.alloc(size, align),
+            BarKind::Memory32 => self.mem32.as_mut()?.alloc(size, align),
+            BarKind::Memory64 => {
+                let low = self.mem32.as_mut().and_then(|mem| mem.alloc(size, align));
+                match (low, root_bus) {
+                    (None, true) => self.mem64.as_mut()?.alloc(size, align),
+                    (low, _) => low,
+                }
+            }
+        }
+    }
+
+    /// Numbers the bus behind a bridge, enumerates it, and opens the bridge's windows around
+    /// what its side got
+    fn scan_bridge(&mut self, config: Config, bus: u8, swizzle: Swizzle, next_bus: &mut u16) {
+        let index = (*next_bus - self.first_bus as u16) as usize;
+        if *next_bus > self.last_bus as u16 || index >= MAX_BUSES {
+            return;
+        }
+        let secondary = *next_bus as u8;
+        *next_bus += 1;
+        // Everything up to 0xff is forwarded while the subordinate buses are numbered
+        config.write8(PRIMARY_BUS, bus);
+        config.write8(SECONDARY_BUS, secondary);
+        config.write8(SUBORDINATE_BUS, 0xff);
+
+        let memory_start = self
+            .mem32
+            .as_mut()
+            .map(|mem| mem.align(BRIDGE_MEMORY_ALIGN));
+        let io_start = self.io.as_mut().map(|io| io.align(BRIDGE_IO_ALIGN));
+        self.scan_bus(secondary, swizzle, next_bus);
+        let memory_end = self
+            .mem32
+            .as_mut()
+            .map(|mem| mem.align(BRIDGE_MEMORY_ALIGN));
+        let io_end = self.io.as_mut().map(|io| io.align(BRIDGE_IO_ALIGN));
+        config.write8(SUBORDINATE_BUS, (*next_bus - 1) as u8);
+
+        // A window is closed by a base above its limit
+        let (base, limit) = match (memory_start, memory_end) {
+            (Some(start), Some(end)) if end > start => (start, end - 1),
+            _ => (BRIDGE_MEMORY_ALIGN, 0),
+        };
+        config.write16(MEMORY_BASE, (base >> 16) as u16 & 0xfff0);
+        config.write16(MEMORY_LIMIT, (limit >> 16) as u16 & 0xfff0);
+        let (base, limit) = match (io_start, io_end) {
+            (Some(start), Some(end)) if end > start => (start, end - 1),
+            _ => (BRIDGE_IO_ALIGN, 0),
+        };
+        config.write8(IO_BASE, (base >> 8) as u8 & 0xf0);
+        config.write8(IO_LIMIT, (limit >> 8) as u8 & 0xf0);
+        config.write32(
+            IO_UPPER,
+            (limit >> 16) as u32 & 0xffff_0000 | (base >> 16) as u32 & 0xffff,
+        );
+        // Everything behind bridges is in the non-prefetchable window
+        config.write32(PREFETCHABLE_BASE_LIMIT, 0x0000_fff0);
+        config.write32(PREFETCHABLE_BASE_UPPER, 0);
+        config.write32(PREFETCHABLE_LIMIT_UPPER, 0);
+
+        let command = config.read16(COMMAND);
+        config.write16(
+            COMMAND,
+            command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        );
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    /// Every function found, claimed or not
+    pub fn functions(&self) -> impl Iterator<Item = &PciFunction> {
+        self.functions.iter().flatten().map(|slot| &slot.function)
+    }
+
+    pub fn function(&self, address: PciAddress) -> Option<&PciFunction> {
+        self.functions()
+            .find(|function| function.address() == address)
+    }
+
+    pub fn is_claimed(&self, address: PciAddress) -> bool {
+        self.functions
+            .iter()
+            .flatten()
+            .any(|slot| slot.claimed && slot.function.address() == address)
+    }
+
+    /// Takes ownership of a function and turns it on. Fails with `AlreadyExists` if someone
+    /// else has it.
+    pub fn claim(&mut self, address: PciAddress) -> Result<PciDevice, KError> {
+        let slot = self
+            .functions
+            .iter_mut()
+            .flatten()
+            .find(|slot| slot.function.address() == address)
+            .ok_or(KError::NoDevice)?;
+        if slot.claimed {
+            return Err(KError::AlreadyExists);
+        }
+        slot.claimed = true;
+        Ok(PciDevice::enable(slot.function))
+    }
+
+    /// Turns a claimed function off and lets others claim it
+    pub fn release(&mut self, device: PciDevice) {
+        let address = device.address();
+        device.disable();
+        if let Some(slot) = self
+            .functions
+            .iter_mut()
+            .flatten()
+            .find(|slot| slot.function.address() == address)
+        {
+            slot.claimed = false;
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! PCI: the functions behind the PCIe host bridge, found through its ECAM window
+//!
+//! The host bridge enumerates the buses and assigns the BARs from the windows in the DTB, see
+//! [`host`]. Drivers then claim a function, which gives them its BARs and its interrupt.
+
+pub mod caps;
+pub mod device;
+pub mod host;
+
+use core::fmt::{Display, Formatter};
+use core::str::FromStr;
+
+/// Config space registers of every header type
+pub const VENDOR_ID: usize = 0x00;
+pub const DEVICE_ID: usize = 0x02;
+pub const COMMAND: usize = 0x04;
+pub const STATUS: usize = 0x06;
+/// Revision in the low byte, then programming interface, subclass and class
+pub const CLASS_REVISION: usize = 0x08;
+pub const HEADER_TYPE: usize = 0x0e;
+pub const BAR0: usize = 0x10;
+pub const CAPABILITIES_POINTER: usize = 0x34;
+pub const INTERRUPT_PIN: usize = 0x3d;
+
+/// `COMMAND` bits
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// `STATUS` bit telling there's a capability list
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Size of a function's config space in the ECAM window
+pub const CONFIG_SIZE: usize = 0x1000;
+
+/// Where a function is: bus, device and function numbers
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Parses the `bus:device.function` form, in hex like `lspci`
+impl FromStr for PciAddress {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (bus, rest) = s.split_once(':').ok_or(())?;
+        let (device, function) = rest.split_once('.').ok_or(())?;
+        let address = PciAddress {
+            bus: u8::from_str_radix(bus, 16).map_err(|_| ())?,
+            device: u8::from_str_radix(device, 16).map_err(|_| ())?,
+            function: function.parse().map_err(|_| ())?,
+        };
+        match address.device < 32 && address.function < 8 {
+            true => Ok(address),
+            false => Err(()),
+        }
+    }
+}
+
+/// A function's config space, mapped from the ECAM window
+#[derive(Copy, Clone)]
+pub struct Config(*mut u8);
+
+impl Config {
+    /// # Safety
+    /// `base` must map the [`CONFIG_SIZE`] bytes of a function's config space.
+    pub unsafe fn new(base: *mut u8) -> Self {
+        Self(base)
+    }
+
+    pub fn read8(&self, reg: usize) -> u8 {
+        unsafe { self.0.add(reg % CONFIG_SIZE).read_volatile() }
+    }
+
+    pub fn read16(&self, reg: usize) -> u16 {
+        unsafe { (self.0.add(reg % CONFIG_SIZE) as *mut u16).read_volatile() }
+    }
+
+    pub fn read32(&self, reg: usize) -> u32 {
+        unsafe { (self.0.add(reg % CONFIG_SIZE) as *mut u32).read_volatile() }
+    }
+
+    pub fn write8(&self, reg: usize, val: u8) {
+        unsafe { self.0.add(reg % CONFIG_SIZE).write_volatile(val) }
+    }
+
+    pub fn write16(&self, reg: usize, val: u16) {
+        unsafe { (self.0.add(reg % CONFIG_SIZE) as *mut u16).write_volatile(val) }
+    }
+
+    pub fn write32(&self, reg: usize, val: u32) {
+        unsafe { (self.0.add(reg % CONFIG_SIZE) as *mut u32).write_volatile(val) }
+    }
+}
+
+/// A readable name for a class code, like `lspci`'s
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, _) => "Serial bus controller",
+        (0x0d, _) => "Wireless controller",
+        _ => "Unknown device",
+    }
+}
@@ -0,0 +1,1471 @@
+//! A tiny interactive shell on the console
+
+use crate::archive::{Archive, S_IFDIR, S_IFLNK, S_IFMT};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::drv::gpio_keys::{GpioKeys, KEY_POWER};
+use crate::drv::initrd::Initrd;
+use crate::drv::nvme::{NvmeController, MAX_NAMESPACES};
+use crate::drv::pl031::Pl031;
+use crate::drv::pl061::{Direction, Pl061, Trigger, PINS};
+use crate::drv::ramfb::RamFb;
+use crate::drv::virtio_blk::VirtioBlockDevice;
+use crate::drv::virtio_console::VirtioConsoleDevice;
+use crate::drv::virtio_gpu::VirtioGpuDevice;
+use crate::framebuffer::{fill_rect, Framebuffer, Rect};
+use crate::input::console::ConsoleInput;
+use crate::input::keymap::{self, LAYOUTS};
+use crate::input::service::InputService;
+use crate::input::{self, EV_ABS, EV_SYN};
+use crate::net::dhcp::Lease;
+use crate::net::dns::RecordType;
+use crate::net::ipv4::Route;
+use crate::net::service::NetService;
+use crate::net::stack::SocketHandle;
+use crate::net::{self, Ipv4Addr};
+use crate::p9;
+use crate::pci::caps::{Capability, VirtioCfgType};
+use crate::pci::device::BarKind;
+use crate::pci::host::PciBus;
+use crate::pci::{self, PciAddress};
+use crate::time;
+use crate::utils::{
+    clock_set, console_read, console_set_mode, console_write, dump_hex_slice, get_random, log_read,
+    log_set_console_level, log_set_level, sleep_sec,
+};
+use crate::{print, println};
+use kernel_api::clock::{ClockId, NSEC_PER_SEC};
+use kernel_api::datetime::DateTime;
+use kernel_api::klog::{Level, Records, MAX_RECORD_LEN};
+use kernel_api::{ConsoleFlags, GetRandomFlags, KError};
+
+/// How long reading a line waits for keys and buttons before checking the console again
+const EVENT_POLL_NS: u64 = 10_000_000;
+
+/// Reads a line from the console, without the trailing newline. Gives up with an empty line
+/// once a shutdown is requested, or when the line doesn't fit in `buf`.
+fn read_line<'a>(ctx: &mut Context, buf: &'a mut [u8]) -> Result<&'a str, KError> {
+    let mut len = 0;
+    let mut too_long = false;
+    loop {
+        // Drop the start of a line that's too long, up to its newline, rather than run its pieces
+        if len == buf.len() {
+            too_long = true;
+            len = 0;
+        }
+        match console_read(&mut buf[len..]) {
+            Ok(count) => len += count,
+            // The console doesn't wait when there are other events to handle
+            Err(KError::WouldBlock) => {
+                wait_events(ctx)?;
+                if ctx.shutdown.is_some() {
+                    return Ok("");
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+        if buf[len - 1] == b'\n' {
+            len -= 1;
+            break;
+        }
+    }
+    if too_long {
+        println!("Line too long, {} characters at most", buf.len() - 1);
+        return Ok("");
+    }
+    Ok(core::str::from_utf8(&buf[..len]).unwrap_or(""))
+}
+
+/// Waits a little for keys and for the power button
+fn wait_events(ctx: &mut Context) -> Result<(), KError> {
+    if let (Some(input), Some(console_input)) = (&mut ctx.input, &mut ctx.console_input) {
+        console_input.pump(input, EVENT_POLL_NS)?;
+    } else if let (Some(gpio), Some(_)) = (&ctx.gpio, &ctx.gpio_keys) {
+        gpio.wait(EVENT_POLL_NS)?;
+    }
+    let (Some(gpio), Some(gpio_keys)) = (&ctx.gpio, &mut ctx.gpio_keys) else {
+        return Ok(());
+    };
+    let mut power = false;
+    gpio_keys.poll(gpio, |key, pressed| {
+        power |= pressed && key.code == KEY_POWER;
+    });
+    // Nothing else handles the interrupts of pins set up with `gpio`
+    gpio.clear(gpio.pending());
+    if power {
+        println!();
+        println!("Power button pressed");
+        ctx.shutdown = Some(Shutdown::PowerOff);
+    }
+    Ok(())
+}
+
+/// Where paths go to the 9P share instead of the initrd
+pub const ROOTFS_MOUNT: &str = "/rootfs";
+
+/// The path inside the 9P share, if `path` is under [`ROOTFS_MOUNT`]
+fn rootfs_path(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix(ROOTFS_MOUNT)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Shutdown {
+    PowerOff,
+    Reboot,
+}
+
+/// What the commands can work with, found while booting
+pub struct Context {
+    pub initrd: Option<Initrd>,
+    /// The files in the initrd, if it's an archive
+    pub initrd_files: Option<Archive<'static>>,
+    /// The real-time clock, kept in step when the wall clock is set
+    pub rtc: Option<Pl031>,
+    /// The first virtio block device
+    pub disk: Option<VirtioBlockDevice>,
+    /// Extra console channels, besides the PL011
+    pub vconsole: Option<VirtioConsoleDevice>,
+    /// The first virtio GPU, as a framebuffer
+    pub gpu: Option<VirtioGpuDevice>,
+    /// QEMU's RAM framebuffer, used when there's no GPU
+    pub ramfb: Option<RamFb>,
+    /// The events of the virtio input devices
+    pub input: Option<InputService>,
+    /// Types the keys of the input devices on the console
+    pub console_input: Option<ConsoleInput>,
+    /// The GPIO controller
+    pub gpio: Option<Pl061>,
+    /// Buttons on the GPIO pins, like the power button
+    pub gpio_keys: Option<GpioKeys>,
+    /// The functions behind the PCIe host bridge
+    pub pci: Option<PciBus>,
+    /// The NVMe controller, claimed from the PCI bus
+    pub nvme: Option<NvmeController>,
+    /// The TCP/IP stack, on the first virtio network card
+    pub net: Option<NetService>,
+    /// The host directory shared over 9P, under [`ROOTFS_MOUNT`]
+    pub rootfs: Option<p9::Client>,
+    /// Commands to run before the prompt, from the `init` boot option
+    pub init_script: Option<&'static str>,
+    /// Set by the power button, `shutdown` and `reboot`, stops the shell
+    pub shutdown: Option<Shutdown>,
+}
+
+/// Runs commands until a shutdown is requested, and returns which one
+pub fn run(mut ctx: Context) -> Result<Shutdown, KError> {
+    if let Some(path) = ctx.init_script {
+        run_script(&mut ctx, path)?;
+    }
+
+    // Keys and buttons are handled while waiting for a line, so reads mustn't block
+    if ctx.console_input.is_some() || ctx.gpio_keys.is_some() {
+        console_set_mode(ConsoleFlags::Canonical | ConsoleFlags::Echo | ConsoleFlags::NonBlock);
+    }
+
+    println!("Type 'help' for a list of commands");
+    let mut line_buf = [0u8; 256];
+    let shutdown = loop {
+        if let Some(shutdown) = ctx.shutdown {
+            break shutdown;
+        }
+        console_write(b"boldos> ")?;
+        let line = read_line(&mut ctx, &mut line_buf)?;
+        execute(&mut ctx, line)?;
+    };
+    prepare_shutdown(&mut ctx);
+    Ok(shutdown)
+}
+
+/// Gets the devices ready for the power to go: the disk's write cache is flushed and the console
+/// goes back to blocking reads
+fn prepare_shutdown(ctx: &mut Context) {
+    println!("Shutting down");
+    if let Some(disk) = &mut ctx.disk {
+        if let Err(err) = disk.flush() {
+            println!("Failed to flush the disk: {err:?}");
+        }
+    }
+    if let Some(nvme) = &mut ctx.nvme {
+        if let Err(err) = nvme.shutdown() {
+            println!("Failed to shut the NVMe controller down: {err:?}");
+        }
+    }
+    console_set_mode(ConsoleFlags::Canonical | ConsoleFlags::Echo);
+}
+
+/// Runs the commands of a script from the initrd, one per line, skipping `#` comments
+fn run_script(ctx: &mut Context, path: &str) -> Result<(), KError> {
+    let Some(script) = ctx.initrd_files.and_then(|files| files.find(path)) else {
+        println!("Init script {path:?} not found in the initrd");
+        return Ok(());
+    };
+    let Ok(script) = core::str::from_utf8(script.data) else {
+        println!("Init script {path:?} is not text");
+        return Ok(());
+    };
+    for line in script.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("{path}: {line}");
+        execute(ctx, line)?;
+        if ctx.shutdown.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn execute(ctx: &mut Context, line: &str) -> Result<(), KError> {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        None => {}
+        Some("help") => {
+            println!("Commands:");
+            println!("  help         Show this message");
+            println!("  echo [ARGS]  Print the arguments");
+            println!("  clear        Clear the screen");
+            println!("  time         Show the uptime and the wall-clock time");
+            println!("  date [-s YYYY-MM-DDTHH:MM:SS]");
+            println!("               Show the date, or set it and the RTC");
+            println!("  alarm SECS   Wait for the RTC alarm to go off in SECS seconds");
+            println!("  shutdown     Flush the disk and power off, like the power button");
+            println!("  reboot       Flush the disk and reboot");
+            println!("  sleep SECS   Sleep for the given number of seconds");
+            println!("  dmesg        Show (and drain) the kernel log");
+            println!("  random [N]   Print N random bytes from the kernel, 32 by default");
+            println!("  loglevel LEVEL [TAG]");
+            println!("               Set which kernel log records are kept, for TAG or all");
+            println!("  conlevel LEVEL");
+            println!("               Set which kernel log records are echoed to the console");
+            println!("  ls [DIR]     List the files in the initrd, or in /rootfs");
+            println!("  cat FILE     Print a file from the initrd, or from /rootfs");
+            println!("  stat FILE    Show the attributes of a file");
+            println!("  vports       List the virtio console ports");
+            println!("  vread PORT   Wait for data on a virtio console port and print it");
+            println!("  vwrite PORT TEXT");
+            println!("               Write a line to a virtio console port");
+            println!("  ifconfig [IP/PREFIX [GW]]");
+            println!("               Show the network card and its address, or set it");
+            println!("  route [add DEST/PREFIX [GW]]");
+            println!("               Show the routing table, or add a route");
+            println!("  arp          Show the ARP cache");
+            println!("  dhcp         Get a new address lease");
+            println!("  host NAME    Look up the IPv4 and IPv6 addresses of a name");
+            println!("  ping HOST [COUNT]");
+            println!("               Send ICMP echo requests, 4 by default");
+            println!("  nc HOST PORT [TEXT]");
+            println!("               Connect over TCP, send a line and print the answer");
+            println!("  nc -l PORT   Accept a TCP connection and echo what it sends");
+            println!("  nc -u HOST PORT TEXT");
+            println!("               Send TEXT in a UDP datagram and print the answer");
+            println!("  input        List the input devices and what they report");
+            println!("  evtest [SECS]");
+            println!("               Print the input events for SECS seconds, 10 by default");
+            println!("  keymap [LAYOUT]");
+            println!("               Show the keyboard layout, or switch to another");
+            println!("  gpio [PIN in|high|low|irq TRIGGER]");
+            println!("               Show the GPIO pins, or set one up; TRIGGER is rising,");
+            println!("               falling, both, high, low or off");
+            println!("  lspci        List the PCI functions, their BARs and capabilities");
+            println!("  pcidump ADDR [BAR]");
+            println!("               Dump a PCI function's config space, or the start of a BAR");
+            println!("  fb           Show the framebuffer's size");
+            println!("  fbfill RRGGBB [X Y W H]");
+            println!("               Fill the framebuffer, or a rectangle of it, with a colour");
+            println!("  fbtest       Draw a test pattern on the framebuffer");
+            println!("  lsblk        List the block devices");
+            println!("  readblk DEV LBA");
+            println!("               Dump a block of a device");
+            println!("  writeblk DEV LBA TEXT");
+            println!("               Write TEXT to a block of a device, padded with zeroes");
+            println!("  sync DEV     Flush the device's write cache");
+            println!("  discard DEV LBA COUNT");
+            println!("               Tell the device COUNT blocks are unused");
+        }
+        Some("clear") => {
+            console_write(b"\x1b[H\x1b[2J")?;
+        }
+        Some("echo") => {
+            for arg in args {
+                print!("{arg} ");
+            }
+            println!();
+        }
+        Some("shutdown") => ctx.shutdown = Some(Shutdown::PowerOff),
+        Some("reboot") => ctx.shutdown = Some(Shutdown::Reboot),
+        Some("time") => {
+            println!(
+                "Uptime: {} ms, now: {}",
+                time::now(ClockId::Boot) / 1_000_000,
+                DateTime::from_unix_nanos(time::now(ClockId::Realtime))
+            );
+        }
+        Some("date") => match (args.next(), args.next().map(str::parse::<DateTime>)) {
+            (None, _) => println!(
+                "{}",
+                DateTime::from_unix_nanos(time::now(ClockId::Realtime))
+            ),
+            (Some("-s"), Some(Ok(date))) => set_date(ctx, date),
+            _ => println!("Usage: date [-s YYYY-MM-DDTHH:MM:SS]"),
+        },
+        Some("alarm") => match args.next().map(str::parse::<u32>) {
+            Some(Ok(secs)) if secs > 0 => alarm(ctx, secs),
+            _ => println!("Usage: alarm SECS"),
+        },
+        Some("sleep") => match args.next().map(str::parse::<u64>) {
+            Some(Ok(secs)) => sleep_sec(secs),
+            _ => println!("Usage: sleep SECS"),
+        },
+        Some("dmesg") => dmesg()?,
+        Some("random") => match args.next().map_or(Ok(32), str::parse::<usize>) {
+            Ok(len) if len <= 256 => {
+                let mut buf = [0u8; 256];
+                match get_random(&mut buf[..len], GetRandomFlags::empty()) {
+                    Ok(()) => dump_hex_slice(&buf[..len]),
+                    Err(err) => println!("Failed to get random bytes: {err:?}"),
+                }
+            }
+            _ => println!("Usage: random [N], N up to 256"),
+        },
+        Some("loglevel") => match args.next().map(Level::from_name) {
+            Some(Some(level)) => log_set_level(args.next().unwrap_or(""), level)?,
+            _ => println!("Usage: loglevel error|warn|info|debug|trace [TAG]"),
+        },
+        Some("conlevel") => match args.next().map(Level::from_name) {
+            Some(Some(level)) => {
+                let prev_level = log_set_console_level(level);
+                println!("Console log level: {:?} -> {:?}", prev_level, level);
+            }
+            _ => println!("Usage: conlevel error|warn|info|debug|trace"),
+        },
+        Some("ls") => ls(ctx, args.next().unwrap_or("")),
+        Some("cat") => match args.next() {
+            Some(path) => cat(ctx, path)?,
+            None => println!("Usage: cat FILE"),
+        },
+        Some("stat") => match args.next() {
+            Some(path) => stat(ctx, path),
+            None => println!("Usage: stat FILE"),
+        },
+        Some("vports") => vports(ctx),
+        Some("vread") => match args.next() {
+            Some(port) => vread(ctx, port),
+            None => println!("Usage: vread PORT"),
+        },
+        Some("vwrite") => match args.next() {
+            Some(port) => {
+                let text = line.splitn(3, char::is_whitespace).nth(2).unwrap_or("");
+                vwrite(ctx, port, text)
+            }
+            None => println!("Usage: vwrite PORT TEXT"),
+        },
+        Some("input") => input_devices(ctx),
+        Some("evtest") => match args.next().map(str::parse::<u64>) {
+            None => evtest(ctx, 10),
+            Some(Ok(secs)) => evtest(ctx, secs),
+            Some(Err(_)) => println!("Usage: evtest [SECS]"),
+        },
+        Some("keymap") => set_keymap(ctx, args.next()),
+        Some("gpio") => match (args.next().map(str::parse::<u8>), args.next(), args.next()) {
+            (None, _, _) => gpio_pins(ctx),
+            (Some(Ok(pin)), Some("in"), None) => {
+                with_gpio(ctx, |gpio| gpio.set_direction(pin, Direction::Input))
+            }
+            (Some(Ok(pin)), Some(level @ ("high" | "low")), None) => with_gpio(ctx, |gpio| {
+                gpio.set_value(pin, level == "high")?;
+                gpio.set_direction(pin, Direction::Output)
+            }),
+            (Some(Ok(pin)), Some("irq"), Some(trigger)) => match parse_trigger(trigger) {
+                Some(trigger) => with_gpio(ctx, |gpio| gpio.set_interrupt(pin, trigger)),
+                None => println!("Unknown trigger {trigger:?}"),
+            },
+            _ => println!("Usage: gpio [PIN in|high|low|irq TRIGGER]"),
+        },
+        Some("lspci") => lspci(ctx),
+        Some("pcidump") => match (
+            args.next().map(str::parse::<PciAddress>),
+            args.next().map(str::parse::<usize>),
+        ) {
+            (Some(Ok(address)), None) => pci_dump_config(ctx, address),
+            (Some(Ok(address)), Some(Ok(bar))) => pci_dump_bar(ctx, address, bar),
+            _ => println!("Usage: pcidump BUS:DEV.FN [BAR]"),
+        },
+        Some("ifconfig") => match (args.next(), args.next()) {
+            (None, _) => ifconfig(ctx),
+            (Some(addr), gateway) => match (parse_prefix(addr), gateway.map(str::parse)) {
+                (Some((ip, prefix_len)), None) => set_address(ctx, ip, prefix_len, None),
+                (Some((ip, prefix_len)), Some(Ok(gateway))) => {
+                    set_address(ctx, ip, prefix_len, Some(gateway))
+                }
+                _ => println!("Usage: ifconfig [IP/PREFIX [GW]]"),
+            },
+        },
+        Some("route") => match (args.next(), args.next(), args.next()) {
+            (None, _, _) => routes(ctx),
+            (Some("add"), Some(dest), gateway) => {
+                match (parse_prefix(dest), gateway.map(str::parse)) {
+                    (Some((dest, prefix_len)), None) => add_route(ctx, dest, prefix_len, None),
+                    (Some((dest, prefix_len)), Some(Ok(gateway))) => {
+                        add_route(ctx, dest, prefix_len, Some(gateway))
+                    }
+                    _ => println!("Usage: route [add DEST/PREFIX [GW]]"),
+                }
+            }
+            _ => println!("Usage: route [add DEST/PREFIX [GW]]"),
+        },
+        Some("arp") => arp(ctx),
+        Some("dhcp") => dhcp(ctx),
+        Some("host") => match args.next() {
+            Some(name) => host(ctx, name),
+            None => println!("Usage: host NAME"),
+        },
+        Some("ping") => match (args.next(), args.next().map_or(Ok(4), str::parse::<u16>)) {
+            (Some(host), Ok(count)) => ping(ctx, host, count),
+            _ => println!("Usage: ping HOST [COUNT]"),
+        },
+        Some("nc") => match args.next() {
+            Some("-l") => match args.next().map(str::parse::<u16>) {
+                Some(Ok(port)) => nc_listen(ctx, port),
+                _ => println!("Usage: nc -l PORT"),
+            },
+            Some("-u") => match (args.next(), args.next().map(str::parse::<u16>)) {
+                (Some(host), Some(Ok(port))) => {
+                    let text = line.splitn(5, char::is_whitespace).nth(4).unwrap_or("");
+                    nc_udp(ctx, host, port, text)
+                }
+                _ => println!("Usage: nc -u HOST PORT TEXT"),
+            },
+            host => match (host, args.next().map(str::parse::<u16>)) {
+                (Some(host), Some(Ok(port))) => {
+                    let text = line.splitn(4, char::is_whitespace).nth(3).unwrap_or("");
+                    nc(ctx, host, port, text)
+                }
+                _ => println!("Usage: nc HOST PORT [TEXT]"),
+            },
+        },
+        Some("fb") => with_framebuffer(ctx, |fb| {
+            let info = fb.info();
+            println!(
+                "{}x{}, stride {} pixels",
+                info.width, info.height, info.stride
+            );
+            Ok(())
+        }),
+        Some("fbfill") => {
+            let color = args.next().map(|color| u32::from_str_radix(color, 16));
+            let mut coords = [0u32; 4];
+            let mut parsed = 0;
+            for (coord, arg) in coords.iter_mut().zip(&mut args) {
+                match arg.parse() {
+                    Ok(value) => *coord = value,
+                    Err(_) => break,
+                }
+                parsed += 1;
+            }
+            match (color, parsed) {
+                (Some(Ok(color)), 0) => fb_fill(ctx, None, color),
+                (Some(Ok(color)), 4) => {
+                    let [x, y, width, height] = coords;
+                    fb_fill(ctx, Some(Rect::new(x, y, width, height)), color)
+                }
+                _ => println!("Usage: fbfill RRGGBB [X Y W H]"),
+            }
+        }
+        Some("fbtest") => fb_test(ctx),
+        Some("lsblk") => lsblk(ctx),
+        Some("readblk") => match (args.next(), args.next().map(str::parse::<u64>)) {
+            (Some(dev), Some(Ok(lba))) => read_block(ctx, dev, lba),
+            _ => println!("Usage: readblk DEV LBA"),
+        },
+        Some("writeblk") => match (args.next(), args.next().map(str::parse::<u64>)) {
+            (Some(dev), Some(Ok(lba))) => {
+                // Keep the spacing of the text, rather than joining the words
+                let text = line.splitn(4, char::is_whitespace).nth(3).unwrap_or("");
+                write_block(ctx, dev, lba, text.as_bytes())
+            }
+            _ => println!("Usage: writeblk DEV LBA TEXT"),
+        },
+        Some("sync") => match args.next() {
+            Some(dev) => with_block_device(ctx, dev, |dev| dev.flush()),
+            None => println!("Usage: sync DEV"),
+        },
+        Some("discard") => match (
+            args.next(),
+            args.next().map(str::parse::<u64>),
+            args.next().map(str::parse::<u64>),
+        ) {
+            (Some(dev), Some(Ok(lba)), Some(Ok(count))) => {
+                with_block_device(ctx, dev, |dev| dev.discard(lba, count))
+            }
+            _ => println!("Usage: discard DEV LBA COUNT"),
+        },
+        Some(cmd) => println!("Unknown command: {cmd:?}"),
+    }
+    Ok(())
+}
+
+fn dmesg() -> Result<(), KError> {
+    let mut buf = [0u8; 4 * MAX_RECORD_LEN];
+    loop {
+        let len = log_read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        for record in Records::new(&buf[..len]) {
+            println!("{}", record);
+        }
+    }
+}
+
+fn ls(ctx: &mut Context, dir: &str) {
+    if let Some(dir) = rootfs_path(dir) {
+        return with_rootfs(ctx, |rootfs| ls_rootfs(rootfs, dir));
+    }
+    let Some(files) = &ctx.initrd_files else {
+        println!("No initrd archive");
+        return;
+    };
+    let dir = dir.trim_matches('/');
+    for entry in files.entries().filter(|entry| entry.is_in_dir(dir)) {
+        let kind = if entry.is_dir() { 'd' } else { '-' };
+        println!("{kind} {:>8} {}", entry.data.len(), entry);
+    }
+}
+
+fn cat(ctx: &mut Context, path: &str) -> Result<(), KError> {
+    if let Some(path) = rootfs_path(path) {
+        with_rootfs(ctx, |rootfs| cat_rootfs(rootfs, path));
+        return Ok(());
+    }
+    let Some(files) = &ctx.initrd_files else {
+        println!("No initrd archive");
+        return Ok(());
+    };
+    match files.find(path) {
+        Some(entry) if entry.is_file() => {
+            console_write(entry.data)?;
+        }
+        Some(_) => println!("Not a file: {path}"),
+        None => println!("File not found: {path}"),
+    }
+    Ok(())
+}
+
+fn stat(ctx: &mut Context, path: &str) {
+    if let Some(path) = rootfs_path(path) {
+        return with_rootfs(ctx, |rootfs| {
+            let stat = rootfs.stat(path)?;
+            println!(
+                "{} mode {:o}, {} bytes, {} links, uid {} gid {}, inode {}",
+                file_kind(stat.mode),
+                stat.mode & !S_IFMT,
+                stat.size,
+                stat.nlink,
+                stat.uid,
+                stat.gid,
+                stat.ino
+            );
+            println!("  modified {}", DateTime::from_unix(stat.mtime_sec as i64));
+            Ok(())
+        });
+    }
+    let Some(files) = &ctx.initrd_files else {
+        println!("No initrd archive");
+        return;
+    };
+    match files.find(path) {
+        Some(entry) => println!(
+            "{} mode {:o}, {} bytes",
+            file_kind(entry.mode),
+            entry.mode & !S_IFMT,
+            entry.data.len()
+        ),
+        None => println!("File not found: {path}"),
+    }
+}
+
+fn file_kind(mode: u32) -> &'static str {
+    match mode & S_IFMT {
+        S_IFDIR => "directory",
+        S_IFLNK => "symlink",
+        _ => "file",
+    }
+}
+
+/// Runs `f` on the 9P share, reporting errors
+fn with_rootfs(ctx: &mut Context, f: impl FnOnce(&mut p9::Client) -> Result<(), p9::Error>) {
+    let Some(rootfs) = &mut ctx.rootfs else {
+        println!("Nothing mounted at {ROOTFS_MOUNT}");
+        return;
+    };
+    if let Err(err) = f(rootfs) {
+        println!("{ROOTFS_MOUNT}: {err}");
+    }
+}
+
+fn ls_rootfs(rootfs: &mut p9::Client, dir: &str) -> Result<(), p9::Error> {
+    let dir = rootfs.open(dir)?;
+    let mut offset = Some(0);
+    let mut result = Ok(());
+    while let Some(cookie) = offset {
+        match rootfs.readdir(&dir, cookie, |entry| {
+            if entry.name == "." || entry.name == ".." {
+                return;
+            }
+            let kind = match entry.entry_type {
+                p9::DT_DIR => 'd',
+                p9::DT_LNK => 'l',
+                _ => '-',
+            };
+            println!("{kind} {}", entry.name);
+        }) {
+            Ok(next) => offset = next,
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    rootfs.close(dir);
+    result
+}
+
+fn cat_rootfs(rootfs: &mut p9::Client, path: &str) -> Result<(), p9::Error> {
+    let file = rootfs.open(path)?;
+    let mut buf = [0u8; 512];
+    let mut offset = 0;
+    let result = loop {
+        match rootfs.read(&file, offset, &mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(len) => {
+                let _ = console_write(&buf[..len]);
+                offset += len as u64;
+            }
+            Err(err) => break Err(err),
+        }
+    };
+    rootfs.close(file);
+    result
+}
+
+/// Runs `f` on the virtio console, reporting errors
+fn with_vconsole(
+    ctx: &mut Context,
+    f: impl FnOnce(&mut VirtioConsoleDevice) -> Result<(), virtio::Error>,
+) {
+    let Some(vconsole) = &mut ctx.vconsole else {
+        println!("No virtio console");
+        return;
+    };
+    if let Err(err) = f(vconsole) {
+        println!("Virtio console: {err}");
+    }
+}
+
+fn vports(ctx: &mut Context) {
+    with_vconsole(ctx, |vconsole| {
+        vconsole.for_each_port(|port| {
+            println!(
+                "{:>2} {:<32} {}{}",
+                port.id,
+                if port.name.is_empty() { "-" } else { port.name },
+                if port.host_connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                },
+                if port.is_console { ", console" } else { "" }
+            );
+        })
+    });
+}
+
+fn vread(ctx: &mut Context, port: &str) {
+    with_vconsole(ctx, |vconsole| {
+        let port = vconsole.port(port)?;
+        let mut buf = [0u8; 256];
+        let len = vconsole.read(port, &mut buf)?;
+        let _ = console_write(&buf[..len]);
+        println!();
+        Ok(())
+    });
+}
+
+fn vwrite(ctx: &mut Context, port: &str, text: &str) {
+    with_vconsole(ctx, |vconsole| {
+        let port = vconsole.port(port)?;
+        vconsole.write(port, text.as_bytes())?;
+        vconsole.write(port, b"\n")
+    });
+}
+
+/// Parses an address with a prefix length, like 10.0.2.15/24
+fn parse_prefix(s: &str) -> Option<(Ipv4Addr, u8)> {
+    let (addr, prefix_len) = s.split_once('/')?;
+    let prefix_len = prefix_len.parse().ok().filter(|len| *len <= 32)?;
+    Some((addr.parse().ok()?, prefix_len))
+}
+
+/// Runs `f` on the network service, reporting errors
+fn with_net(ctx: &mut Context, f: impl FnOnce(&mut NetService) -> Result<(), net::Error>) {
+    let Some(net) = &mut ctx.net else {
+        println!("No network card");
+        return;
+    };
+    if let Err(err) = f(net) {
+        println!("Network: {err}");
+    }
+}
+
+fn ifconfig(ctx: &mut Context) {
+    with_net(ctx, |net| {
+        // Catch up with what arrived since the last time
+        net.poll()?;
+        let dev = net.device();
+        let stats = dev.stats();
+        println!(
+            "MAC {}, link {}",
+            net.stack().mac(),
+            if dev.link_up() { "up" } else { "down" }
+        );
+        if net.stack().ip().is_unspecified() {
+            println!("No address");
+        } else {
+            println!("IP {}/{}", net.stack().ip(), net.stack().prefix_len());
+        }
+        if let Some(lease) = net.lease() {
+            let now = time::now(ClockId::Monotonic);
+            println!(
+                "Leased from {} for {} s more",
+                lease.server,
+                lease.expires_at().saturating_sub(now) / 1_000_000_000
+            );
+        }
+        for server in net.resolver().servers() {
+            println!("Name server {server}");
+        }
+        println!(
+            "Checksum offload: tx {}, rx {}",
+            if dev.device().supports_tx_checksum() {
+                "on"
+            } else {
+                "off"
+            },
+            if dev.device().supports_rx_checksum() {
+                "on"
+            } else {
+                "off"
+            }
+        );
+        println!(
+            "RX {} frames ({} queued), TX {} frames",
+            stats.rx_frames,
+            dev.rx_ring().len(),
+            stats.tx_frames
+        );
+        Ok(())
+    });
+}
+
+fn set_address(ctx: &mut Context, ip: Ipv4Addr, prefix_len: u8, gateway: Option<Ipv4Addr>) {
+    with_net(ctx, |net| {
+        net.stack_mut().configure(ip, prefix_len, gateway);
+        Ok(())
+    });
+}
+
+fn routes(ctx: &mut Context) {
+    with_net(ctx, |net| {
+        for route in net.stack().routes() {
+            match route.gateway {
+                Some(gateway) => println!("{}/{} via {gateway}", route.dest, route.prefix_len),
+                None => println!("{}/{} direct", route.dest, route.prefix_len),
+            }
+        }
+        Ok(())
+    });
+}
+
+fn add_route(ctx: &mut Context, dest: Ipv4Addr, prefix_len: u8, gateway: Option<Ipv4Addr>) {
+    with_net(ctx, |net| {
+        let route = Route {
+            dest,
+            prefix_len,
+            gateway,
+        };
+        if !net.stack_mut().add_route(route) {
+            println!("The routing table is full");
+        }
+        Ok(())
+    });
+}
+
+fn arp(ctx: &mut Context) {
+    with_net(ctx, |net| {
+        net.poll()?;
+        let now = time::now(ClockId::Monotonic);
+        for entry in net.stack().arp_entries() {
+            println!(
+                "{} at {}, {} s ago",
+                entry.ip,
+                entry.mac,
+                (now - entry.updated) / 1_000_000_000
+            );
+        }
+        Ok(())
+    });
+}
+
+/// How long to wait for a lease, at boot and for the `dhcp` command
+pub const DHCP_TIMEOUT_NS: u64 = 10_000_000_000;
+
+fn dhcp(ctx: &mut Context) {
+    with_net(ctx, |net| {
+        let lease = net.dhcp(DHCP_TIMEOUT_NS)?;
+        print_lease(&lease);
+        Ok(())
+    });
+}
+
+/// Prints what DHCP gave
+pub fn print_lease(lease: &Lease) {
+    print!("Leased {}/{}", lease.ip, lease.prefix_len);
+    if let Some(gateway) = lease.gateway {
+        print!(" via {gateway}");
+    }
+    for server in lease.name_servers.iter().flatten() {
+        print!(", name server {server}");
+    }
+    println!(" for {} s", lease.lease_secs);
+}
+
+fn host(ctx: &mut Context, name: &str) {
+    with_net(ctx, |net| {
+        let mut found = false;
+        for kind in [RecordType::A, RecordType::Aaaa] {
+            match net.resolve(name, kind) {
+                Ok(addrs) => {
+                    for addr in addrs.iter() {
+                        println!("{name} has address {addr}");
+                    }
+                    found = true;
+                }
+                Err(net::Error::NameNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if !found {
+            println!("{name} not found");
+        }
+        Ok(())
+    });
+}
+
+const PING_TIMEOUT_NS: u64 = 1_000_000_000;
+
+fn ping(ctx: &mut Context, host: &str, count: u16) {
+    with_net(ctx, |net| {
+        let host = net.resolve_ipv4(host)?;
+        let mut received = 0;
+        for seq in 0..count {
+            if seq > 0 {
+                sleep_sec(1);
+            }
+            match net.ping(host, seq, PING_TIMEOUT_NS) {
+                Ok(rtt) => {
+                    println!(
+                        "Reply from {host}: seq={seq} time={}.{:03} ms",
+                        rtt / 1_000_000,
+                        rtt / 1_000 % 1_000
+                    );
+                    received += 1;
+                }
+                Err(net::Error::TimedOut) => println!("No reply from {host}: seq={seq}"),
+                Err(err) => return Err(err),
+            }
+        }
+        println!("{count} sent, {received} received");
+        Ok(())
+    });
+}
+
+const CONNECT_TIMEOUT_NS: u64 = 10_000_000_000;
+/// How long `nc` waits for more data before giving up
+const NC_IDLE_TIMEOUT_NS: u64 = 5_000_000_000;
+/// How long `nc -l` waits for a connection, or for data on it
+const NC_LISTEN_TIMEOUT_NS: u64 = 60_000_000_000;
+
+fn nc(ctx: &mut Context, host: &str, port: u16, text: &str) {
+    with_net(ctx, |net| {
+        let host = net.resolve_ipv4(host)?;
+        let socket = net.tcp_connect(host, port, CONNECT_TIMEOUT_NS)?;
+        let result = nc_session(net, socket, text);
+        net.close(socket)?;
+        result
+    });
+}
+
+fn nc_session(net: &mut NetService, socket: SocketHandle, text: &str) -> Result<(), net::Error> {
+    if !text.is_empty() {
+        net.tcp_send(socket, text.as_bytes(), NC_IDLE_TIMEOUT_NS)?;
+        net.tcp_send(socket, b"\r\n", NC_IDLE_TIMEOUT_NS)?;
+    }
+    let mut buf = [0u8; 512];
+    loop {
+        match net.tcp_recv(socket, &mut buf, NC_IDLE_TIMEOUT_NS) {
+            Ok(0) | Err(net::Error::TimedOut) => return Ok(()),
+            Ok(len) => {
+                let _ = console_write(&buf[..len]);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn nc_listen(ctx: &mut Context, port: u16) {
+    with_net(ctx, |net| {
+        let listener = net.tcp_listen(port)?;
+        println!("Listening on port {port}");
+        let result = net.tcp_accept(listener, NC_LISTEN_TIMEOUT_NS);
+        net.close(listener)?;
+        let socket = result?;
+        let result = echo(net, socket);
+        net.close(socket)?;
+        result
+    });
+}
+
+fn nc_udp(ctx: &mut Context, host: &str, port: u16, text: &str) {
+    with_net(ctx, |net| {
+        let host = net.resolve_ipv4(host)?;
+        // Port 0 picks an ephemeral port
+        let socket = net.udp_bind(0)?;
+        let result = nc_udp_exchange(net, socket, host, port, text);
+        net.close(socket)?;
+        result
+    });
+}
+
+fn nc_udp_exchange(
+    net: &mut NetService,
+    socket: SocketHandle,
+    host: Ipv4Addr,
+    port: u16,
+    text: &str,
+) -> Result<(), net::Error> {
+    net.udp_send_to(socket, host, port, text.as_bytes())?;
+    let mut buf = [0u8; 512];
+    match net.udp_recv_from(socket, &mut buf, NC_IDLE_TIMEOUT_NS) {
+        Ok((len, src, src_port)) => {
+            println!("From {src}:{src_port}:");
+            let _ = console_write(&buf[..len.min(buf.len())]);
+            println!();
+            Ok(())
+        }
+        Err(net::Error::TimedOut) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Sends back what the peer sends, until it closes the connection
+fn echo(net: &mut NetService, socket: SocketHandle) -> Result<(), net::Error> {
+    let mut buf = [0u8; 512];
+    loop {
+        match net.tcp_recv(socket, &mut buf, NC_LISTEN_TIMEOUT_NS)? {
+            0 => return Ok(()),
+            len => {
+                let _ = console_write(&buf[..len]);
+                net.tcp_send(socket, &buf[..len], NC_LISTEN_TIMEOUT_NS)?;
+            }
+        }
+    }
+}
+
+/// Runs `f` on the input service, reporting errors
+fn with_input(ctx: &mut Context, f: impl FnOnce(&mut InputService) -> Result<(), KError>) {
+    let Some(input) = &mut ctx.input else {
+        println!("No input devices");
+        return;
+    };
+    if let Err(err) = f(input) {
+        println!("Input: {err:?}");
+    }
+}
+
+fn input_devices(ctx: &mut Context) {
+    with_input(ctx, |input| {
+        for (i, dev) in input.devices() {
+            println!("{i}: {:?} at 0p{:x}", dev.name(), dev.phy_addr());
+            for event_type in input::EVENT_TYPES {
+                let codes = dev.event_codes(event_type);
+                if codes.is_empty() {
+                    continue;
+                }
+                println!(
+                    "   {}: {} codes",
+                    input::type_name(event_type),
+                    codes.iter().count()
+                );
+                if event_type != EV_ABS {
+                    continue;
+                }
+                for axis in codes.iter() {
+                    if let Some(info) = dev.abs_info(axis) {
+                        println!(
+                            "     axis {axis}: {}..{}, fuzz {}, flat {}, resolution {}",
+                            info.min, info.max, info.fuzz, info.flat, info.res
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    });
+}
+
+fn evtest(ctx: &mut Context, secs: u64) {
+    with_input(ctx, |input| {
+        let subscription = input.subscribe(&input::EVENT_TYPES)?;
+        println!("Printing input events for {secs} seconds");
+        let deadline = time::now(ClockId::Monotonic).saturating_add(secs * 1_000_000_000);
+        let result = loop {
+            let left = deadline.saturating_sub(time::now(ClockId::Monotonic));
+            match input.wait(&subscription, left) {
+                Ok(Some(event)) if event.event_type == EV_SYN => println!("-- {}", event.device),
+                Ok(Some(event)) => println!(
+                    "   {} {} code {} value {}",
+                    event.device,
+                    input::type_name(event.event_type),
+                    event.code,
+                    event.value
+                ),
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        let dropped = input.dropped(&subscription);
+        if dropped != 0 {
+            println!("{dropped} events were dropped");
+        }
+        input.unsubscribe(subscription);
+        result
+    });
+}
+
+fn set_keymap(ctx: &mut Context, name: Option<&str>) {
+    let Some(console_input) = &mut ctx.console_input else {
+        println!("No keyboard");
+        return;
+    };
+    let keyboard = console_input.keyboard_mut();
+    match name.map(keymap::find_layout) {
+        None => {
+            print!("Layout: {}, available:", keyboard.layout().name);
+            for layout in LAYOUTS {
+                print!(" {}", layout.name);
+            }
+            println!();
+        }
+        Some(Some(layout)) => keyboard.set_layout(layout),
+        Some(None) => println!("Unknown layout {:?}", name.unwrap_or("")),
+    }
+}
+
+/// Runs `f` on the GPIO controller, reporting errors
+fn with_gpio(ctx: &Context, f: impl FnOnce(&Pl061) -> Result<(), KError>) {
+    let Some(gpio) = &ctx.gpio else {
+        println!("No GPIO controller");
+        return;
+    };
+    if let Err(err) = f(gpio) {
+        println!("GPIO: {err:?}");
+    }
+}
+
+fn trigger_name(trigger: Option<Trigger>) -> &'static str {
+    match trigger {
+        Some(Trigger::RisingEdge) => "rising",
+        Some(Trigger::FallingEdge) => "falling",
+        Some(Trigger::BothEdges) => "both",
+        Some(Trigger::HighLevel) => "high",
+        Some(Trigger::LowLevel) => "low",
+        None => "off",
+    }
+}
+
+/// The trigger named `name`, `Some(None)` for "off"
+fn parse_trigger(name: &str) -> Option<Option<Trigger>> {
+    [
+        None,
+        Some(Trigger::RisingEdge),
+        Some(Trigger::FallingEdge),
+        Some(Trigger::BothEdges),
+        Some(Trigger::HighLevel),
+        Some(Trigger::LowLevel),
+    ]
+    .into_iter()
+    .find(|&trigger| trigger_name(trigger) == name)
+}
+
+fn gpio_pins(ctx: &mut Context) {
+    let gpio_keys = &ctx.gpio_keys;
+    with_gpio(ctx, |gpio| {
+        let raw_pending = gpio.raw_pending();
+        for pin in 0..PINS {
+            print!(
+                "{pin}: {:<3} {:<4} irq {:<7}{}",
+                match gpio.direction(pin)? {
+                    Direction::Input => "in",
+                    Direction::Output => "out",
+                },
+                if gpio.value(pin)? { "high" } else { "low" },
+                trigger_name(gpio.interrupt(pin)?),
+                if raw_pending & 1 << pin != 0 {
+                    " pending"
+                } else {
+                    ""
+                },
+            );
+            match gpio_keys.as_ref().and_then(|keys| keys.key(pin)) {
+                Some(key) => println!(
+                    "  key {} {:?}{}",
+                    key.code,
+                    key.label(),
+                    if key.pressed() { ", pressed" } else { "" }
+                ),
+                None => println!(),
+            }
+        }
+        Ok(())
+    });
+}
+
+/// Runs `f` on the PCI bus, reporting errors
+fn with_pci(ctx: &mut Context, f: impl FnOnce(&mut PciBus) -> Result<(), KError>) {
+    let Some(pci) = &mut ctx.pci else {
+        println!("No PCIe host bridge");
+        return;
+    };
+    if let Err(err) = f(pci) {
+        println!("PCI: {err:?}");
+    }
+}
+
+fn bar_kind_name(kind: BarKind) -> &'static str {
+    match kind {
+        BarKind::Io => "I/O",
+        BarKind::Memory32 => "memory, 32-bit",
+        BarKind::Memory64 => "memory, 64-bit",
+    }
+}
+
+fn print_capability(capability: &Capability) {
+    match *capability {
+        Capability::Msi {
+            offset,
+            vectors,
+            is_64bit,
+            maskable,
+            enabled,
+        } => println!(
+            "    [{offset:02x}] MSI: {vectors} vectors{}{}{}",
+            if is_64bit { ", 64-bit" } else { "" },
+            if maskable { ", maskable" } else { "" },
+            if enabled { ", enabled" } else { "" },
+        ),
+        Capability::MsiX {
+            offset,
+            table_size,
+            table_bar,
+            table_offset,
+            pba_bar,
+            pba_offset,
+            enabled,
+        } => println!(
+            "    [{offset:02x}] MSI-X: {table_size} vectors, table BAR{table_bar}+0x{table_offset:x}, \
+             PBA BAR{pba_bar}+0x{pba_offset:x}{}",
+            if enabled { ", enabled" } else { "" },
+        ),
+        Capability::PciExpress {
+            offset,
+            version,
+            port_type,
+        } => println!("    [{offset:02x}] PCI Express v{version}, port type {port_type}"),
+        Capability::Virtio {
+            offset,
+            cfg_type,
+            bar,
+            bar_offset,
+            length,
+            notify_multiplier,
+        } => {
+            let name = match cfg_type {
+                VirtioCfgType::Common => "common",
+                VirtioCfgType::Notify => "notify",
+                VirtioCfgType::Isr => "ISR",
+                VirtioCfgType::Device => "device",
+                VirtioCfgType::PciConfig => "PCI config",
+                VirtioCfgType::Other(_) => "unknown",
+            };
+            print!("    [{offset:02x}] Virtio {name}: BAR{bar}+0x{bar_offset:x}, {length} bytes");
+            match notify_multiplier {
+                Some(multiplier) => println!(", multiplier {multiplier}"),
+                None => println!(),
+            }
+        }
+        Capability::Other { offset, id } => println!("    [{offset:02x}] ID 0x{id:02x}"),
+    }
+}
+
+fn lspci(ctx: &mut Context) {
+    with_pci(ctx, |pci| {
+        for function in pci.functions() {
+            let (class, subclass, prog_if) = function.class();
+            print!(
+                "{} {:04x}:{:04x} {} [{class:02x}{subclass:02x}{prog_if:02x}]",
+                function.address(),
+                function.vendor_id(),
+                function.device_id(),
+                pci::class_name(class, subclass),
+            );
+            if let Some(interrupt_id) = function.interrupt_id() {
+                print!(", IRQ {interrupt_id}");
+            }
+            if pci.is_claimed(function.address()) {
+                print!(", claimed");
+            }
+            println!();
+            for (index, bar) in function.bars() {
+                println!(
+                    "    BAR{index}: 0p{:x}, {} bytes ({}{})",
+                    bar.phy_addr,
+                    bar.size,
+                    bar_kind_name(bar.kind),
+                    if bar.prefetchable {
+                        ", prefetchable"
+                    } else {
+                        ""
+                    },
+                );
+            }
+            for capability in function.capabilities() {
+                print_capability(&capability);
+            }
+        }
+        Ok(())
+    });
+}
+
+fn pci_dump_config(ctx: &mut Context, address: PciAddress) {
+    with_pci(ctx, |pci| {
+        let function = pci.function(address).ok_or(KError::NoDevice)?;
+        let mut config = [0u8; 256];
+        for (reg, chunk) in config.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&function.read_config32(reg * 4).to_le_bytes());
+        }
+        dump_hex_slice(&config);
+        Ok(())
+    });
+}
+
+/// Claims the function for the time it takes to dump the start of a BAR
+fn pci_dump_bar(ctx: &mut Context, address: PciAddress, index: usize) {
+    with_pci(ctx, |pci| {
+        let mut device = pci.claim(address)?;
+        let result = device.map_bar(index).map(|base| {
+            let len = device.bar(index).map_or(0, |bar| bar.size).min(64) as usize;
+            let mut data = [0u8; 64];
+            for (i, chunk) in data[..len].chunks_exact_mut(4).enumerate() {
+                let val = unsafe { (base as *const u32).add(i).read_volatile() };
+                chunk.copy_from_slice(&val.to_le_bytes());
+            }
+            dump_hex_slice(&data[..len]);
+        });
+        pci.release(device);
+        result
+    });
+}
+
+/// The framebuffer of the display, if there's one
+fn framebuffer(ctx: &mut Context) -> Option<&mut dyn Framebuffer> {
+    match (&mut ctx.gpu, &mut ctx.ramfb) {
+        (Some(gpu), _) => Some(gpu as &mut dyn Framebuffer),
+        (None, Some(ramfb)) => Some(ramfb as &mut dyn Framebuffer),
+        (None, None) => None,
+    }
+}
+
+/// Sets the wall clock, and the RTC so that the date survives a reboot
+fn set_date(ctx: &mut Context, date: DateTime) {
+    let Ok(secs) = u64::try_from(date.to_unix()) else {
+        println!("Dates before 1970 can't be set");
+        return;
+    };
+    if let Err(err) = clock_set(ClockId::Realtime, secs * NSEC_PER_SEC) {
+        println!("Failed to set the clock: {err:?}");
+        return;
+    }
+    if let Some(rtc) = &ctx.rtc {
+        match u32::try_from(secs) {
+            Ok(secs) => rtc.set_time(secs),
+            Err(_) => println!("The RTC counts up to 2106 only, it was left alone"),
+        }
+    }
+    println!("{date}");
+}
+
+/// Programs the RTC alarm and blocks until its interrupt fires
+fn alarm(ctx: &mut Context, secs: u32) {
+    let Some(rtc) = &ctx.rtc else {
+        println!("No RTC");
+        return;
+    };
+    // The RTC stops counting at 2106, an alarm past that would never go off
+    let Some(at) = rtc.time().checked_add(secs) else {
+        println!("The alarm would be past the end of the RTC's range");
+        return;
+    };
+    rtc.set_alarm(at);
+    println!("Alarm set for {}", DateTime::from_unix(at as i64));
+    match rtc.wait_alarm() {
+        Ok(()) => println!("Alarm! It's {}", DateTime::from_unix(rtc.time() as i64)),
+        Err(err) => println!("Failed to wait for the alarm: {err:?}"),
+    }
+}
+
+/// Runs `f` on the framebuffer, reporting errors
+fn with_framebuffer(ctx: &mut Context, f: impl FnOnce(&mut dyn Framebuffer) -> Result<(), KError>) {
+    match framebuffer(ctx) {
+        Some(fb) => {
+            if let Err(err) = f(fb) {
+                println!("Framebuffer: {err:?}");
+            }
+        }
+        None => println!("No framebuffer"),
+    }
+}
+
+fn fb_fill(ctx: &mut Context, rect: Option<Rect>, color: u32) {
+    with_framebuffer(ctx, |fb| {
+        let rect = rect.unwrap_or(fb.info().bounds());
+        fill_rect(fb, rect, color);
+        fb.flush(rect)
+    });
+}
+
+/// Colour bars over a grey ramp, with a white border
+fn fb_test(ctx: &mut Context) {
+    const BARS: [u32; 8] = [
+        0xffffff, 0xffff00, 0x00ffff, 0x00ff00, 0xff00ff, 0xff0000, 0x0000ff, 0x000000,
+    ];
+    with_framebuffer(ctx, |fb| {
+        let info = fb.info();
+        let bar_width = info.width / BARS.len() as u32;
+        let bars_height = info.height * 2 / 3;
+        for (i, color) in BARS.iter().enumerate() {
+            let rect = Rect::new(i as u32 * bar_width, 0, bar_width, bars_height);
+            fill_rect(fb, rect, *color);
+        }
+        let pixels = fb.pixels();
+        for y in bars_height..info.height {
+            let row = &mut pixels[(y * info.stride) as usize..][..info.width as usize];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let level = (x as u32 * 255 / info.width.max(1)) & 0xff;
+                *pixel = level << 16 | level << 8 | level;
+            }
+        }
+        let (width, height) = (info.width, info.height);
+        for border in [
+            Rect::new(0, 0, width, 1),
+            Rect::new(0, height - 1, width, 1),
+            Rect::new(0, 0, 1, height),
+            Rect::new(width - 1, 0, 1, height),
+        ] {
+            fill_rect(fb, border, 0xffffff);
+        }
+        fb.flush(info.bounds())
+    });
+}
+
+/// Calls `f` on a block device by name: `initrd`, `vda` for the virtio disk, and `nvme0nN` for
+/// namespace N of the NVMe controller. Returns `None` if there's no such device.
+fn visit_block_device<R>(
+    ctx: &mut Context,
+    name: &str,
+    f: impl FnOnce(&mut dyn BlockDevice) -> R,
+) -> Option<R> {
+    match name {
+        "initrd" => ctx.initrd.as_mut().map(|dev| f(dev)),
+        "vda" => ctx.disk.as_mut().map(|dev| f(dev)),
+        _ => {
+            let nsid = name.strip_prefix("nvme0n")?.parse().ok()?;
+            let mut disk = ctx.nvme.as_mut()?.disk(nsid)?;
+            Some(f(&mut disk))
+        }
+    }
+}
+
+/// Prints the size and mode of a block device, after its name
+fn print_block_device(dev: &mut dyn BlockDevice) {
+    let blocks = dev.block_count();
+    let mode = if dev.read_only() { "ro" } else { "rw" };
+    println!(
+        "{blocks:>10} blocks {:>8} KiB {mode}",
+        blocks * BLOCK_SIZE as u64 / 1024
+    );
+}
+
+fn lsblk(ctx: &mut Context) {
+    for name in ["initrd", "vda"] {
+        visit_block_device(ctx, name, |dev| {
+            print!("{name:<8} ");
+            print_block_device(dev)
+        });
+    }
+    let Some(nvme) = &mut ctx.nvme else {
+        return;
+    };
+    let mut nsids = [0u32; MAX_NAMESPACES];
+    for (nsid, ns) in nsids.iter_mut().zip(nvme.namespaces()) {
+        *nsid = ns.id;
+    }
+    for nsid in nsids.into_iter().take_while(|&nsid| nsid != 0) {
+        if let Some(mut disk) = nvme.disk(nsid) {
+            print!("nvme0n{nsid:<2} ");
+            print_block_device(&mut disk);
+        }
+    }
+}
+
+/// Runs `f` on a block device, reporting errors
+fn with_block_device(
+    ctx: &mut Context,
+    name: &str,
+    f: impl FnOnce(&mut dyn BlockDevice) -> Result<(), KError>,
+) {
+    match visit_block_device(ctx, name, f) {
+        Some(Ok(())) => {}
+        Some(Err(err)) => println!("{name}: {err:?}"),
+        None => println!("No block device {name:?}"),
+    }
+}
+
+fn read_block(ctx: &mut Context, name: &str, lba: u64) {
+    with_block_device(ctx, name, |dev| {
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read_blocks(lba, &mut block)?;
+        dump_hex_slice(&block);
+        Ok(())
+    });
+}
+
+fn write_block(ctx: &mut Context, name: &str, lba: u64, data: &[u8]) {
+    with_block_device(ctx, name, |dev| {
+        let mut block = [0u8; BLOCK_SIZE];
+        let len = data.len().min(BLOCK_SIZE);
+        block[..len].copy_from_slice(&data[..len]);
+        dev.write_blocks(lba, &block)
+    });
+}
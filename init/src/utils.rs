@@ -1,7 +1,14 @@
 use core::arch::asm;
-use kernel_api::{kernel_device, KError, MemMapFlags, PhyMapFlags, Syscall};
+use kernel_api::clock::ClockId;
+use kernel_api::klog::Level;
+use kernel_api::{
+    kernel_device, ConsoleFlags, GetRandomFlags, KError, MemMapFlags, PhyMapFlags, PsciFunction,
+    Syscall,
+};
 use num_enum::FromPrimitive;
 
+pub const PAGE_SIZE: usize = 4096;
+
 pub unsafe fn exit(code: u32) -> ! {
     unsafe {
         asm!(
@@ -48,6 +55,15 @@ pub unsafe fn phy_map(
     }
 }
 
+/// Maps a device's MMIO registers, which don't have to be page-aligned
+pub unsafe fn map_mmio(phy_addr: usize, len: usize) -> Result<*mut u8, KError> {
+    let page_offset = phy_addr % PAGE_SIZE;
+    let map_len = (page_offset + len).next_multiple_of(PAGE_SIZE);
+    let flags = PhyMapFlags::ReadWrite | PhyMapFlags::DeviceMem;
+    let base = unsafe { phy_map(phy_addr - page_offset, map_len, flags) }?;
+    Ok(unsafe { (base as *mut u8).add(page_offset) })
+}
+
 pub unsafe fn mem_map(len: usize, flags: MemMapFlags) -> Result<*const (), KError> {
     let mut virt_addr: u64;
     unsafe {
@@ -67,6 +83,27 @@ pub unsafe fn mem_map(len: usize, flags: MemMapFlags) -> Result<*const (), KErro
     }
 }
 
+/// Allocates zeroed, physically contiguous memory that a device can access directly. Returns the
+/// virtual and the physical address of the buffer.
+pub unsafe fn dma_alloc(len: usize) -> Result<(*mut u8, usize), KError> {
+    let mut virt_addr: u64;
+    let mut phy_addr: u64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") len as u64,
+        in("x8") Syscall::DmaAlloc as u64,
+        lateout("x0") virt_addr,
+        lateout("x1") phy_addr,
+        );
+    }
+    if (virt_addr as i64) < 0 {
+        Err(KError::from_primitive(virt_addr as i32))
+    } else {
+        Ok((virt_addr as _, phy_addr as usize))
+    }
+}
+
 pub unsafe fn mem_unmap(virt_addr: *const (), len: usize) -> Result<(), KError> {
     let mut res: i64;
     unsafe {
@@ -104,6 +141,85 @@ pub unsafe fn download_more_ram(phy_addr: usize, len: usize) -> Result<(), KErro
     }
 }
 
+/// Keeps the kernel from allocating a region of RAM that's already in use, like the initrd
+pub fn reserve_memory(phy_addr: usize, len: usize) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") phy_addr as u64,
+        in("x1") len as u64,
+        in("x8") Syscall::ReserveMemory as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Hands the boot command line to the kernel, which applies the options that concern it
+pub fn set_cmdline(cmdline: &str) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") cmdline.as_ptr() as u64,
+        in("x1") cmdline.len() as u64,
+        in("x8") Syscall::SetCmdline as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Fills `buf` from the kernel's entropy pool, waiting for it to be seeded unless `flags` say
+/// otherwise
+pub fn get_random(buf: &mut [u8], flags: GetRandomFlags) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") buf.as_mut_ptr() as u64,
+        in("x1") buf.len() as u64,
+        in("x2") flags.bits(),
+        in("x8") Syscall::GetRandom as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Mixes `data` into the kernel's entropy pool, crediting it with `credit_bits` of entropy
+pub fn add_entropy(data: &[u8], credit_bits: u32) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") data.as_ptr() as u64,
+        in("x1") data.len() as u64,
+        in("x2") credit_bits as u64,
+        in("x8") Syscall::AddEntropy as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
 pub unsafe fn load_kernel_device<T: kernel_device::KernelDeviceId + Sized>(
     req: &T,
 ) -> Result<(), KError> {
@@ -137,6 +253,223 @@ pub fn sleep_sec(sec: u64) {
     }
 }
 
+#[allow(dead_code)]
+pub fn clock_get(clock: ClockId) -> Result<u64, KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") u32::from(clock) as u64,
+        in("x8") Syscall::ClockGet as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(res as u64)
+    }
+}
+
+pub fn clock_set(clock: ClockId, ns: u64) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") u32::from(clock) as u64,
+        in("x1") ns,
+        in("x8") Syscall::ClockSet as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Blocks until the given interrupt fires. The interrupt stays masked until the next call, so
+/// the device's interrupt condition should be cleared before waiting again.
+pub fn irq_wait(interrupt_id: u32) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") interrupt_id as u64,
+        in("x8") Syscall::IrqWait as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Like [`irq_wait`], but gives up with `TimedOut` after `timeout_ns` nanoseconds
+pub fn irq_wait_timeout(interrupt_id: u32, timeout_ns: u64) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") interrupt_id as u64,
+        in("x1") timeout_ns,
+        in("x8") Syscall::IrqWaitTimeout as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Blocks until console input is available, unless the console is in `NonBlock` mode. In
+/// canonical mode, reads at most one line.
+pub fn console_read(buf: &mut [u8]) -> Result<usize, KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") buf.as_mut_ptr() as u64,
+        in("x1") buf.len() as u64,
+        in("x8") Syscall::ConsoleRead as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+pub fn console_write(buf: &[u8]) -> Result<usize, KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") buf.as_ptr() as u64,
+        in("x1") buf.len() as u64,
+        in("x8") Syscall::ConsoleWrite as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Feeds `buf` to the console's input, as if it was typed on the UART
+pub fn console_input(buf: &[u8]) -> Result<usize, KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") buf.as_ptr() as u64,
+        in("x1") buf.len() as u64,
+        in("x8") Syscall::ConsoleInput as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Sets the console mode, returning the previous one
+pub fn console_set_mode(flags: ConsoleFlags) -> ConsoleFlags {
+    let prev_flags: u64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") flags.bits(),
+        in("x8") Syscall::ConsoleSetMode as u64,
+        lateout("x0") prev_flags,
+        );
+    }
+    ConsoleFlags::from_bits_truncate(prev_flags)
+}
+
+/// Calls a PSCI function through the kernel, which only init is allowed to. Only `Version`
+/// returns on success.
+pub fn psci_call(function: PsciFunction) -> Result<u64, KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") u32::from(function) as u64,
+        in("x8") Syscall::Psci as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(res as u64)
+    }
+}
+
+/// Drains kernel log records into `buf`, see [`kernel_api::klog::Records`] to parse them
+pub fn log_read(buf: &mut [u8]) -> Result<usize, KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") buf.as_mut_ptr() as u64,
+        in("x1") buf.len() as u64,
+        in("x8") Syscall::LogRead as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Sets the maximum level of kernel log records kept for `tag`, or for all tags if it's empty
+pub fn log_set_level(tag: &str, level: Level) -> Result<(), KError> {
+    let mut res: i64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") tag.as_ptr() as u64,
+        in("x1") tag.len() as u64,
+        in("x2") u8::from(level) as u64,
+        in("x8") Syscall::LogSetLevel as u64,
+        lateout("x0") res,
+        );
+    }
+    if res < 0 {
+        Err(KError::from_primitive(res as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Sets the maximum level of kernel log records echoed to the console, returning the previous one
+pub fn log_set_console_level(level: Level) -> Level {
+    let res: u64;
+    unsafe {
+        asm!(
+        "svc #0",
+        in("x0") u8::from(level) as u64,
+        in("x8") Syscall::LogSetConsoleLevel as u64,
+        lateout("x0") res,
+        );
+    }
+    Level::try_from(res as u8).unwrap_or(level)
+}
+
 pub(crate) struct FmtWriteAdapter;
 
 impl core::fmt::Write for FmtWriteAdapter {
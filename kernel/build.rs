@@ -3,9 +3,14 @@ use std::process::Command;
 use walkdir::WalkDir;
 
 fn main() {
-    for entry in WalkDir::new("../init")
+    // Init and the crates it depends on
+    for entry in ["../init", "../virtio"]
         .into_iter()
-        .filter_entry(|e| e.file_name() != "target")
+        .flat_map(|dir| {
+            WalkDir::new(dir)
+                .into_iter()
+                .filter_entry(|e| e.file_name() != "target")
+        })
         .map(|e| e.expect("Failed to read init binary directory"))
         .filter(|e| e.file_type().is_file())
     {
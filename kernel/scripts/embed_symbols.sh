@@ -0,0 +1,23 @@
+#!/usr/bin/env bash
+# Patches the kernel's own symbol table into its reserved `.ksyms` section, so that panics and
+# exceptions print symbolized backtraces. Safe to run multiple times on the same file.
+
+set -e
+
+KERNEL="$1"
+SCRIPTS_PATH="$(dirname "$0")"
+TMP_DIR="$(mktemp -d)"
+trap 'rm -rf "$TMP_DIR"' EXIT
+
+llvm-objcopy --dump-section .ksyms="$TMP_DIR/old.bin" "$KERNEL"
+SECTION_SIZE=$(stat -c %s "$TMP_DIR/old.bin")
+
+"$SCRIPTS_PATH/symbols.sh" "$KERNEL" >"$TMP_DIR/ksyms.bin"
+if [ "$(stat -c %s "$TMP_DIR/ksyms.bin")" -ge "$SECTION_SIZE" ]; then
+  echo "Kernel symbol table doesn't fit in .ksyms ($SECTION_SIZE bytes), increase it in linker.lds" >&2
+  exit 1
+fi
+# Zero padding terminates the table
+truncate -s "$SECTION_SIZE" "$TMP_DIR/ksyms.bin"
+
+llvm-objcopy --update-section .ksyms="$TMP_DIR/ksyms.bin" "$KERNEL"
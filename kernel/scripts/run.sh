@@ -5,6 +5,21 @@ KERNEL="$(realpath "$1")"
 cd "$ROOT_PATH" || exit
 shift
 
+./scripts/embed_symbols.sh "$KERNEL" || exit
+
+# Pack ../initrd into a tar archive, init reads files from it
+tar --format=ustar --owner=0 --group=0 -cf ./initrd.bin -C ../initrd . || exit
+
+# Attach ./disk.img as a virtio block device if there's one, e.g. made with `truncate -s 64M`
+DISK_ARGS=()
+if [ -f ./disk.img ]; then
+  DISK_ARGS=(-drive if=none,file=./disk.img,format=raw,id=disk -device virtio-blk-device,drive=disk)
+fi
+# Same for ./nvme.img, as an NVMe controller on the PCIe bus
+if [ -f ./nvme.img ]; then
+  DISK_ARGS+=(-drive if=none,file=./nvme.img,format=raw,id=nvme -device nvme,drive=nvme,serial=boldos)
+fi
+
 MEM=256M
 CPU_CORES=4
 CPU_TYPE=cortex-a72
@@ -12,7 +27,17 @@ CPU_TYPE=cortex-a72
 qemu-system-aarch64 \
   -machine virt -cpu $CPU_TYPE -smp $CPU_CORES -m $MEM \
   -nographic \
-  -kernel "$KERNEL" -append "placeholder kernel params" -initrd "./initrd.bin" \
+  -serial mon:stdio -serial tcp::1235,server=on,wait=off \
+  -kernel "$KERNEL" -append "loglevel=info init=etc/rc" -initrd "./initrd.bin" \
   -fsdev local,path=../rootfs,security_model=mapped-xattr,id=rootfs,readonly=on,multidevs=forbid \
   -device virtio-9p-device,fsdev=rootfs,mount_tag=rootfs \
+  -device virtio-rng-device \
+  -device ramfb \
+  -device virtio-gpu-device \
+  -device virtio-keyboard-device -device virtio-tablet-device \
+  -device virtio-serial-device \
+  -chardev socket,id=debugport,host=127.0.0.1,port=1236,server=on,wait=off \
+  -device virtserialport,chardev=debugport,name=org.boldos.debug \
+  -netdev user,id=net0,hostfwd=tcp:127.0.0.1:5555-:7 -device virtio-net-device,netdev=net0 \
+  "${DISK_ARGS[@]}" \
   -gdb tcp::1234 "$@"
@@ -0,0 +1,18 @@
+#!/usr/bin/env bash
+# Prints the function symbol table of an ELF file, for symbolizing backtraces at runtime:
+# one "<hex address> <hex size> <demangled name>" line per function, sorted by address. The size
+# is 0 for symbols that don't have one, like labels in assembly.
+
+llvm-nm -n -S -C --defined-only "$1" |
+  sed -nE 's/^([0-9a-f]+) ([0-9a-f]+) [tT] (.*)$/\1 \2 \3/p; s/^([0-9a-f]+) [tT] (.*)$/\1 0 \2/p' |
+  # The linker script's physical addresses of sections aren't where any code runs
+  sed -E '/ _[a-z_]+_phys$/d' |
+  sed -E '
+    s/ \(\.llvm\.[0-9]+\)$//
+    s/::h[0-9a-f]{16}$//
+    s/\$LT\$/</g; s/\$GT\$/>/g; s/\$RF\$/\&/g; s/\$BP\$/*/g
+    s/\$LP\$/(/g; s/\$RP\$/)/g; s/\$C\$/,/g; s/\$u20\$/ /g
+    s/\$u27\$/'"'"'/g; s/\$u5b\$/[/g; s/\$u5d\$/]/g; s/\$u7b\$/{/g; s/\$u7d\$/}/g
+    s/\.\./::/g
+    s/( |::)_</\1</g
+  '
@@ -0,0 +1,100 @@
+//! Stack unwinding by walking the frame pointer (x29) chain
+//!
+//! Every function pushes a frame record of `[previous x29, x30 (return address)]` and points x29
+//! at it, which the targets guarantee with `"frame-pointer": "always"`.
+
+use crate::aarch64::usermode;
+use crate::println;
+use crate::symbols::SymbolTable;
+use core::arch::asm;
+
+const MAX_FRAMES: usize = 32;
+
+/// Calls `f` with the return address of every frame record, starting from `fp`. Frame records
+/// must be within `stack` and grow towards higher addresses, so a corrupt chain can't fault.
+fn walk(
+    mut fp: usize,
+    stack: (usize, usize),
+    read_u64: impl Fn(usize) -> u64,
+    mut f: impl FnMut(usize),
+) {
+    for _ in 0..MAX_FRAMES {
+        if !fp.is_multiple_of(8) || fp < stack.0 || fp + 16 > stack.1 {
+            return;
+        }
+        let next_fp = read_u64(fp) as usize;
+        let return_addr = read_u64(fp + 8) as usize;
+        if return_addr == 0 {
+            return;
+        }
+        f(return_addr);
+        if next_fp <= fp {
+            return;
+        }
+        fp = next_fp;
+    }
+}
+
+fn print_frame(table: SymbolTable, index: usize, addr: usize) {
+    println!("  #{index:<2} {}", table.symbolize(addr));
+}
+
+/// Return addresses point after the call, look up the call instruction itself instead
+fn call_site(return_addr: usize) -> usize {
+    return_addr.saturating_sub(4)
+}
+
+fn kernel_stack() -> (usize, usize) {
+    extern "C" {
+        static _initstack_start: u8;
+        static _initstack_end: u8;
+    }
+    (
+        &raw const _initstack_start as usize,
+        &raw const _initstack_end as usize,
+    )
+}
+
+/// Prints a backtrace of kernel code, starting at `pc` with the frame pointer `fp`
+pub fn print_kernel(pc: usize, fp: usize) {
+    let table = SymbolTable::kernel();
+    println!("Kernel backtrace:");
+    print_frame(table, 0, pc);
+    let mut index = 1;
+    walk(
+        fp,
+        kernel_stack(),
+        |addr| unsafe { (addr as *const u64).read() },
+        |return_addr| {
+            print_frame(table, index, call_site(return_addr));
+            index += 1;
+        },
+    );
+}
+
+/// Prints a backtrace of the calling kernel function
+#[inline(always)]
+pub fn print_current() {
+    let (pc, fp): (usize, usize);
+    unsafe {
+        asm!("adr {}, .", "mov {}, x29", out(reg) pc, out(reg) fp, options(nomem, nostack));
+    }
+    print_kernel(pc, fp);
+}
+
+/// Prints a backtrace of the init thread, starting at `pc` with the frame pointer `fp`
+pub fn print_user(pc: usize, fp: usize) {
+    let table = SymbolTable::init();
+    println!("Usermode backtrace:");
+    print_frame(table, 0, pc);
+    let mut index = 1;
+    walk(
+        fp,
+        usermode::stack_range(),
+        |addr| unsafe { usermode::read_user_u64(addr) },
+        |return_addr| {
+            print_frame(table, index, call_site(return_addr));
+            index += 1;
+        },
+    );
+}
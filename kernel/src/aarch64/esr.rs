@@ -0,0 +1,151 @@
+//! Decoding of the Exception Syndrome Register (`ESR_EL1`)
+
+use core::fmt::{Display, Formatter};
+
+/// A raw `ESR_EL1` value, displayed as e.g. "data abort from EL0, translation fault level 3, write"
+#[derive(Copy, Clone, Debug)]
+pub struct Esr(pub u64);
+
+pub const EC_SVC64: u64 = 0x15;
+pub const EC_IABT_LOWER: u64 = 0x20;
+pub const EC_IABT_CURRENT: u64 = 0x21;
+pub const EC_DABT_LOWER: u64 = 0x24;
+pub const EC_DABT_CURRENT: u64 = 0x25;
+pub const EC_BREAKPOINT_LOWER: u64 = 0x30;
+pub const EC_BREAKPOINT_CURRENT: u64 = 0x31;
+pub const EC_SOFTWARE_STEP_LOWER: u64 = 0x32;
+pub const EC_SOFTWARE_STEP_CURRENT: u64 = 0x33;
+pub const EC_WATCHPOINT_LOWER: u64 = 0x34;
+pub const EC_WATCHPOINT_CURRENT: u64 = 0x35;
+pub const EC_BRK64: u64 = 0x3C;
+
+/// Write not Read, for data aborts
+const ISS_ABORT_WNR: u64 = 1 << 6;
+/// Cache maintenance, for data aborts
+const ISS_ABORT_CM: u64 = 1 << 8;
+/// FAR not Valid, for aborts
+const ISS_ABORT_FNV: u64 = 1 << 10;
+/// Instruction Syndrome Valid, for data aborts
+const ISS_ABORT_ISV: u64 = 1 << 24;
+
+impl Esr {
+    /// Exception class
+    pub const fn ec(self) -> u64 {
+        (self.0 >> 26) & 0x3f
+    }
+
+    /// Instruction specific syndrome
+    pub const fn iss(self) -> u64 {
+        self.0 & 0x1ff_ffff
+    }
+
+    pub const fn is_abort(self) -> bool {
+        matches!(
+            self.ec(),
+            EC_IABT_LOWER | EC_IABT_CURRENT | EC_DABT_LOWER | EC_DABT_CURRENT
+        )
+    }
+
+    /// Whether FAR_EL1 holds the faulting address
+    pub const fn far_valid(self) -> bool {
+        self.is_abort() && self.iss() & ISS_ABORT_FNV == 0
+            || matches!(self.ec(), EC_WATCHPOINT_LOWER | EC_WATCHPOINT_CURRENT)
+            // PC alignment fault
+            || self.ec() == 0x22
+    }
+
+    const fn class_name(self) -> &'static str {
+        match self.ec() {
+            0x00 => "unknown reason (undefined instruction?)",
+            0x01 => "trapped WFI/WFE",
+            0x07 => "trapped SIMD/FP access",
+            0x0E => "illegal execution state",
+            0x11 | EC_SVC64 => "SVC",
+            0x18 => "trapped MSR/MRS/system instruction",
+            EC_IABT_LOWER | EC_IABT_CURRENT => "instruction abort",
+            0x22 => "PC alignment fault",
+            EC_DABT_LOWER | EC_DABT_CURRENT => "data abort",
+            0x26 => "SP alignment fault",
+            0x28 | 0x2C => "floating point exception",
+            0x2F => "SError",
+            EC_BREAKPOINT_LOWER | EC_BREAKPOINT_CURRENT => "breakpoint",
+            EC_SOFTWARE_STEP_LOWER | EC_SOFTWARE_STEP_CURRENT => "software step",
+            EC_WATCHPOINT_LOWER | EC_WATCHPOINT_CURRENT => "watchpoint",
+            0x38 | EC_BRK64 => "BRK instruction",
+            _ => "reserved exception class",
+        }
+    }
+
+    /// Whether the exception class is only taken from a lower exception level
+    const fn taken_from_el0(self) -> Option<bool> {
+        match self.ec() {
+            EC_IABT_LOWER
+            | EC_DABT_LOWER
+            | EC_BREAKPOINT_LOWER
+            | EC_SOFTWARE_STEP_LOWER
+            | EC_WATCHPOINT_LOWER => Some(true),
+            EC_IABT_CURRENT
+            | EC_DABT_CURRENT
+            | EC_BREAKPOINT_CURRENT
+            | EC_SOFTWARE_STEP_CURRENT
+            | EC_WATCHPOINT_CURRENT => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Describes the data/instruction fault status code of an abort
+fn fmt_fault_status(f: &mut Formatter<'_>, fsc: u64) -> core::fmt::Result {
+    let level = fsc & 0b11;
+    match fsc {
+        0b000000..=0b000011 => write!(f, "address size fault level {level}"),
+        0b000100..=0b000111 => write!(f, "translation fault level {level}"),
+        0b001000..=0b001011 => write!(f, "access flag fault level {level}"),
+        0b001100..=0b001111 => write!(f, "permission fault level {level}"),
+        0b010000 => write!(f, "synchronous external abort"),
+        0b010100..=0b010111 => write!(f, "synchronous external abort on table walk level {level}"),
+        0b011000 => write!(f, "synchronous parity/ECC error"),
+        0b100001 => write!(f, "alignment fault"),
+        0b110000 => write!(f, "TLB conflict abort"),
+        0b110001 => write!(f, "unsupported atomic hardware update"),
+        _ => write!(f, "fault status 0x{fsc:x}"),
+    }
+}
+
+impl Display for Esr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.class_name())?;
+        match self.taken_from_el0() {
+            Some(true) => write!(f, " from EL0")?,
+            Some(false) => write!(f, " from EL1")?,
+            None => {}
+        }
+
+        let iss = self.iss();
+        match self.ec() {
+            EC_IABT_LOWER | EC_IABT_CURRENT => {
+                write!(f, ", ")?;
+                fmt_fault_status(f, iss & 0x3f)?;
+            }
+            EC_DABT_LOWER | EC_DABT_CURRENT => {
+                write!(f, ", ")?;
+                fmt_fault_status(f, iss & 0x3f)?;
+                if iss & ISS_ABORT_CM != 0 {
+                    write!(f, ", cache maintenance")?;
+                } else if iss & ISS_ABORT_WNR != 0 {
+                    write!(f, ", write")?;
+                } else {
+                    write!(f, ", read")?;
+                }
+                if iss & ISS_ABORT_ISV != 0 {
+                    let size = 1 << ((iss >> 22) & 0b11);
+                    let reg = (iss >> 16) & 0x1f;
+                    write!(f, " of {size} bytes (x{reg})")?;
+                }
+            }
+            0x11 | EC_SVC64 | 0x38 | EC_BRK64 => write!(f, " #{}", iss & 0xffff)?,
+            _ => {}
+        }
+        write!(f, " (ESR 0x{:08x})", self.0)
+    }
+}
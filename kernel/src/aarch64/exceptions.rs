@@ -1,4 +1,8 @@
-use crate::aarch64::usermode::handle_syscall;
+use crate::aarch64::backtrace;
+use crate::aarch64::esr::Esr;
+use crate::aarch64::gdbstub;
+use crate::aarch64::usermode::{self, handle_syscall};
+use crate::symbols::SymbolTable;
 use crate::{print, println};
 use aarch64_cpu::registers::{SPSel, ESR_EL1, FAR_EL1};
 use core::arch::asm;
@@ -8,8 +12,12 @@ use tock_registers::interfaces::Readable;
 pub unsafe fn exception_handler(etype: u64, esr: u64, elr: u64, spsr: u64, far: u64) -> ! {
     println!("Exception SPSel: {}", SPSel.read(SPSel::SP));
     panic!(
-        "Exception:\netype=0x{:x} esr=0x{:x} elr=0x{:x} spsr=0x{:x} far=0x{:x}",
-        etype, esr, elr, spsr, far
+        "Exception:\netype=0x{:x} {} elr=0x{:x} spsr=0x{:x} far=0x{:x}",
+        etype,
+        Esr(esr),
+        elr,
+        spsr,
+        far
     );
 }
 
@@ -34,27 +42,54 @@ pub struct ExceptionContext {
 
 #[no_mangle]
 pub unsafe extern "C" fn exception_handler2(e: &mut ExceptionContext) {
+    // SPSR_EL1.M is 0 (EL0t) for exceptions taken from usermode
+    let from_user = e.spsr & 0xf == 0;
+    if from_user {
+        usermode::set_user_context(e);
+    }
+
     if ESR_EL1.get() == 0x56000000 {
         handle_syscall(e);
         return;
     }
 
+    let esr = Esr(ESR_EL1.get());
+    if gdbstub::handle_debug_exception(e, esr) {
+        return;
+    }
+    let symbols = if from_user {
+        SymbolTable::init()
+    } else {
+        SymbolTable::kernel()
+    };
+
     println!("-------------------------------------------");
-    // let sp = (e as *const ExceptionContext as *const u8)
-    //     .offset(size_of::<ExceptionContext>() as isize) as *const u64;
+    println!(
+        "Unhandled exception from {}: {}",
+        if from_user { "usermode" } else { "kernel" },
+        esr
+    );
+    if esr.far_valid() {
+        println!("Address accessed: 0x{:x}", FAR_EL1.get());
+    }
+    println!("PC: {}", symbols.symbolize(e.pc as usize));
+    println!("LR: {}", symbols.symbolize(e.lr as usize));
+    if from_user {
+        println!("SP: 0x{:016x}", e.sp);
+    }
+    println!("SPSR: 0x{:x}", e.spsr);
     println!("Registers:");
-    for reg in e.gpr {
-        print!("{:016x} ", reg);
+    for (i, reg) in e.gpr.iter().enumerate() {
+        print!("x{:<2} {:016x}", i, reg);
+        print!("{}", if i % 4 == 3 { "\n" } else { "  " });
     }
     println!();
-    println!("Exception reason: 0x{:x}", ESR_EL1.get());
-    println!("FAR (Address accessed): 0x{:x}", FAR_EL1.get());
-    println!("PC: 0x{:x}", e.pc);
-    println!("LR: 0x{:x}", e.lr);
-    println!("SP: 0x{:016x}", e.sp);
-    println!("SPSR: 0x{:x}", e.spsr);
+    if from_user {
+        backtrace::print_user(e.pc as usize, e.gpr[29] as usize);
+    } else {
+        backtrace::print_kernel(e.pc as usize, e.gpr[29] as usize);
+    }
     println!("-------------------------------------------");
-    // print_stacktrace(e);
 
     loop {
         asm!("wfi");
@@ -62,6 +97,9 @@ pub unsafe extern "C" fn exception_handler2(e: &mut ExceptionContext) {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn irq_handler(_e: &mut ExceptionContext) {
-    crate::drv::arm_gic::handle_irq();
+pub unsafe extern "C" fn irq_handler(e: &mut ExceptionContext) {
+    if e.spsr & 0xf == 0 {
+        usermode::set_user_context(e);
+    }
+    crate::drv::arm_gic::handle_irq(e);
 }
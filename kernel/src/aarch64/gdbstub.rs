@@ -0,0 +1,755 @@
+//! GDB remote serial protocol stub, listening on a dedicated PL011
+//!
+//! The whole system stops while the stub talks to GDB: when a breakpoint (`BRK`) or a single
+//! step (`MDSCR_EL1.SS`) exception is taken, or when GDB sends a packet or Ctrl-C while the
+//! system is running. The kernel and the init thread are reported as separate GDB threads.
+//!
+//! `scripts/run.sh` connects the second UART to TCP port 1235 (QEMU 10+ adds it to the virt
+//! machine), so attach with `target remote localhost:1235`.
+
+use crate::aarch64::esr::{Esr, EC_BRK64, EC_SOFTWARE_STEP_CURRENT, EC_SOFTWARE_STEP_LOWER};
+use crate::aarch64::exceptions::ExceptionContext;
+use crate::aarch64::interrupts::IrqMutex;
+use crate::aarch64::usermode;
+use crate::drv::arm_gic;
+use crate::drv::pl011::Pl011;
+use crate::page_alloc::PhyAddr;
+use crate::{get_msr, info, set_msr};
+use core::arch::asm;
+use core::fmt::Write;
+use core::ptr::{read_volatile, write_volatile};
+
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+
+/// `BRK #0`
+const BRK_INSTRUCTION: u32 = 0xd420_0000;
+
+/// Software step enable
+const MDSCR_SS: u64 = 1 << 0;
+/// Enables debug exceptions (e.g. software step) at EL1
+const MDSCR_KDE: u64 = 1 << 13;
+/// Software step pending, restored to PSTATE.SS on exception return
+const SPSR_SS: u64 = 1 << 21;
+/// Debug exceptions mask
+const SPSR_D: u64 = 1 << 9;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const KERNEL_TID: u64 = 1;
+const INIT_TID: u64 = 2;
+
+/// Register numbers of the `org.gnu.gdb.aarch64.core` feature
+const REG_LR: usize = 30;
+const REG_SP: usize = 31;
+const REG_PC: usize = 32;
+const REG_CPSR: usize = 33;
+const REG_COUNT: usize = 34;
+
+/// Only the core registers, the kernel doesn't support the FPU
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>aarch64</architecture>
+<feature name="org.gnu.gdb.aarch64.core">
+<reg name="x0" bitsize="64"/><reg name="x1" bitsize="64"/><reg name="x2" bitsize="64"/>
+<reg name="x3" bitsize="64"/><reg name="x4" bitsize="64"/><reg name="x5" bitsize="64"/>
+<reg name="x6" bitsize="64"/><reg name="x7" bitsize="64"/><reg name="x8" bitsize="64"/>
+<reg name="x9" bitsize="64"/><reg name="x10" bitsize="64"/><reg name="x11" bitsize="64"/>
+<reg name="x12" bitsize="64"/><reg name="x13" bitsize="64"/><reg name="x14" bitsize="64"/>
+<reg name="x15" bitsize="64"/><reg name="x16" bitsize="64"/><reg name="x17" bitsize="64"/>
+<reg name="x18" bitsize="64"/><reg name="x19" bitsize="64"/><reg name="x20" bitsize="64"/>
+<reg name="x21" bitsize="64"/><reg name="x22" bitsize="64"/><reg name="x23" bitsize="64"/>
+<reg name="x24" bitsize="64"/><reg name="x25" bitsize="64"/><reg name="x26" bitsize="64"/>
+<reg name="x27" bitsize="64"/><reg name="x28" bitsize="64"/><reg name="x29" bitsize="64"/>
+<reg name="x30" bitsize="64"/>
+<reg name="sp" bitsize="64" type="data_ptr"/>
+<reg name="pc" bitsize="64" type="code_ptr"/>
+<reg name="cpsr" bitsize="32"/>
+</feature>
+</target>
+"#;
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: usize,
+    original: u32,
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+/// Formats a reply packet into a fixed buffer, truncating what doesn't fit
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let _ = write!(self, "{byte:02x}");
+        }
+    }
+
+    /// Escapes characters that have a meaning in the packet framing, for `qXfer` replies
+    fn push_binary(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+                self.push(b'}');
+                self.push(byte ^ 0x20);
+            } else {
+                self.push(byte);
+            }
+        }
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+struct Stub {
+    uart: Option<Pl011>,
+    interrupt_id: u32,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// SPSR.D was cleared to single step kernel code, and must be set again
+    step_cleared_d: bool,
+    reply: Reply,
+}
+
+static STUB: IrqMutex<Stub> = IrqMutex::new(Stub {
+    uart: None,
+    interrupt_id: 0,
+    breakpoints: [None; MAX_BREAKPOINTS],
+    step_cleared_d: false,
+    reply: Reply {
+        buf: [0; PACKET_SIZE],
+        len: 0,
+    },
+});
+
+/// The packet being handled, separate from [`STUB`] so it can be borrowed while replying
+static PACKET: IrqMutex<[u8; PACKET_SIZE]> = IrqMutex::new([0; PACKET_SIZE]);
+
+/// Starts listening for GDB on the given UART
+///
+/// # Safety
+///
+/// The GIC must be initialized
+pub unsafe fn init(base: PhyAddr, clock_hz: u32, interrupt_id: u32) {
+    info!("gdb", "Listening for GDB on PL011 at {:?}", base);
+    let uart = Pl011::new(base.virt_dev_mut::<u8>() as usize);
+    uart.configure(clock_hz);
+    {
+        let mut stub = STUB.lock();
+        stub.uart = Some(uart);
+        stub.interrupt_id = interrupt_id;
+    }
+
+    // Unlock the OS lock, which blocks software step exceptions
+    asm!("msr oslar_el1, xzr", "isb", options(nomem, nostack));
+    arm_gic::enable_kernel_interrupt(interrupt_id);
+}
+
+pub fn interrupt_id() -> Option<u32> {
+    let stub = STUB.lock();
+    stub.uart.map(|_| stub.interrupt_id)
+}
+
+/// Handles GDB input while the system is running: Ctrl-C or a new packet stops it
+///
+/// # Safety
+///
+/// Must be called from the IRQ handler, with the interrupted context
+pub unsafe fn handle_irq(e: &mut ExceptionContext) {
+    let mut stub = STUB.lock();
+    let Some(uart) = stub.uart else {
+        return;
+    };
+    while let Some(ch) = uart.getc() {
+        match ch {
+            0x03 => {
+                uart.clear_rx_interrupts();
+                stub.stop(e, SIGINT, false);
+                return;
+            }
+            b'$' => {
+                uart.clear_rx_interrupts();
+                stub.stop(e, SIGINT, true);
+                return;
+            }
+            // Acks and line noise
+            _ => {}
+        }
+    }
+    uart.clear_rx_interrupts();
+}
+
+/// Handles breakpoint and single step exceptions, returns false if there's no debugger
+///
+/// # Safety
+///
+/// Must be called from the synchronous exception handler, with the interrupted context
+pub unsafe fn handle_debug_exception(e: &mut ExceptionContext, esr: Esr) -> bool {
+    let mut stub = STUB.lock();
+    if stub.uart.is_none() {
+        return false;
+    }
+    match esr.ec() {
+        EC_BRK64 => {
+            if !stub
+                .breakpoints
+                .iter()
+                .flatten()
+                .any(|b| b.addr == e.pc as usize)
+            {
+                // Compiled in, skip over it so continuing doesn't hit it again
+                e.pc += 4;
+            }
+            stub.stop(e, SIGTRAP, false);
+            true
+        }
+        EC_SOFTWARE_STEP_LOWER | EC_SOFTWARE_STEP_CURRENT => {
+            stub.stop(e, SIGTRAP, false);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn is_user(e: &ExceptionContext) -> bool {
+    // SPSR_EL1.M is 0 (EL0t) for exceptions taken from usermode
+    e.spsr & 0xf == 0
+}
+
+fn current_tid(e: &ExceptionContext) -> u64 {
+    if is_user(e) {
+        INIT_TID
+    } else {
+        KERNEL_TID
+    }
+}
+
+/// Finds the saved registers of a thread, `e` being the registers of the stopped one
+fn thread_context(e: &mut ExceptionContext, tid: u64) -> Option<*mut ExceptionContext> {
+    if tid == current_tid(e) {
+        Some(e as *mut _)
+    } else if tid == INIT_TID {
+        // Stopped in the kernel, while handling an exception from usermode
+        usermode::user_context()
+    } else {
+        None
+    }
+}
+
+/// Returns the value of a GDB register and its size in bytes
+fn read_register(context: &ExceptionContext, reg: usize) -> Option<(u64, usize)> {
+    Some(match reg {
+        0..=29 => (context.gpr[reg], 8),
+        REG_LR => (context.lr, 8),
+        REG_SP if is_user(context) => (context.sp, 8),
+        // The kernel stack pointer before the exception context was pushed
+        REG_SP => (
+            context as *const _ as u64 + size_of::<ExceptionContext>() as u64,
+            8,
+        ),
+        REG_PC => (context.pc, 8),
+        REG_CPSR => (context.spsr & 0xffff_ffff, 4),
+        _ => return None,
+    })
+}
+
+fn write_register(context: &mut ExceptionContext, reg: usize, value: u64) -> bool {
+    match reg {
+        0..=29 => context.gpr[reg] = value,
+        REG_LR => context.lr = value,
+        REG_SP if is_user(context) => context.sp = value,
+        REG_PC => context.pc = value,
+        REG_CPSR => context.spsr = value & 0xffff_ffff,
+        _ => return false,
+    }
+    true
+}
+
+/// Whether the kernel can access `addr` without faulting
+fn is_mapped(addr: usize, write: bool) -> bool {
+    unsafe {
+        if write {
+            asm!("at s1e1w, {}", in(reg) addr, options(nostack));
+        } else {
+            asm!("at s1e1r, {}", in(reg) addr, options(nostack));
+        }
+        asm!("isb", options(nomem, nostack));
+        // PAR_EL1.F is set if the translation faulted
+        get_msr!(par_el1) & 1 == 0
+    }
+}
+
+fn is_range_mapped(addr: usize, len: usize, write: bool) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    let mut page = addr & !0xfff;
+    while page < end {
+        if !is_mapped(page, write) {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+/// Makes written instructions visible to instruction fetches
+unsafe fn sync_icache(addr: usize, len: usize) {
+    for line in ((addr & !0xf)..addr + len).step_by(16) {
+        asm!("dc cvau, {0}", "dsb ish", "ic ivau, {0}", in(reg) line, options(nostack));
+    }
+    asm!("dsb ish", "isb", options(nostack));
+}
+
+unsafe fn write_u32(addr: usize, value: u32) {
+    for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+        write_volatile((addr + i) as *mut u8, byte);
+    }
+    sync_icache(addr, 4);
+}
+
+unsafe fn read_u32(addr: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = read_volatile((addr + i) as *const u8);
+    }
+    u32::from_le_bytes(bytes)
+}
+
+fn hex_digit(ch: u8) -> Option<u8> {
+    match ch {
+        b'0'..=b'9' => Some(ch - b'0'),
+        b'a'..=b'f' => Some(ch - b'a' + 10),
+        b'A'..=b'F' => Some(ch - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |acc, &ch| Some(acc << 4 | hex_digit(ch)? as u64))
+}
+
+/// Parses a thread ID, where 0 means any thread and -1 all threads
+fn parse_tid(s: &[u8]) -> Option<u64> {
+    if s == b"-1" {
+        return Some(0);
+    }
+    parse_hex(s)
+}
+
+/// Decodes hex pairs into `target`, returning the amount of bytes decoded
+fn decode_hex(s: &[u8], target: &mut [u8]) -> Option<usize> {
+    if !s.len().is_multiple_of(2) || s.len() / 2 > target.len() {
+        return None;
+    }
+    for (i, pair) in s.chunks(2).enumerate() {
+        target[i] = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(s.len() / 2)
+}
+
+/// Parses `addr,len`
+fn parse_addr_len(s: &[u8]) -> Option<(usize, usize)> {
+    let comma = s.iter().position(|&ch| ch == b',')?;
+    Some((
+        parse_hex(&s[..comma])? as usize,
+        parse_hex(&s[comma + 1..])? as usize,
+    ))
+}
+
+impl Stub {
+    fn uart(&self) -> Pl011 {
+        self.uart.expect("GDB stub is not initialized")
+    }
+
+    fn getc(&self) -> u8 {
+        let uart = self.uart();
+        loop {
+            if let Some(ch) = uart.getc() {
+                return ch;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Receives a packet into `packet`, returning its length. `started` is set if the leading
+    /// `$` was already received.
+    fn read_packet(&self, packet: &mut [u8; PACKET_SIZE], mut started: bool) -> usize {
+        loop {
+            while !started {
+                started = self.getc() == b'$';
+            }
+            started = false;
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            loop {
+                match self.getc() {
+                    b'#' => break,
+                    // A new packet, the previous one was cut off
+                    b'$' => {
+                        len = 0;
+                        checksum = 0;
+                    }
+                    ch => {
+                        if len < PACKET_SIZE {
+                            packet[len] = ch;
+                            len += 1;
+                        }
+                        checksum = checksum.wrapping_add(ch);
+                    }
+                }
+            }
+            let expected = [self.getc(), self.getc()];
+            if parse_hex(&expected) == Some(checksum as u64) && len < PACKET_SIZE {
+                self.uart().putc(b'+');
+                return len;
+            }
+            self.uart().putc(b'-');
+        }
+    }
+
+    /// Sends `self.reply`, returns true if GDB started a new packet instead of acking it
+    fn send_reply(&mut self) -> bool {
+        let uart = self.uart();
+        loop {
+            uart.putc(b'$');
+            let mut checksum = 0u8;
+            for &byte in &self.reply.buf[..self.reply.len] {
+                uart.putc(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+            uart.putc(b'#');
+            for digit in [checksum >> 4, checksum & 0xf] {
+                uart.putc(b"0123456789abcdef"[digit as usize]);
+            }
+            loop {
+                match self.getc() {
+                    b'+' => return false,
+                    b'-' => break,
+                    b'$' => return true,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn write_stop_reply(&mut self, e: &ExceptionContext, signal: u8) {
+        self.reply.len = 0;
+        let _ = write!(self.reply, "T{:02x}thread:{:x};", signal, current_tid(e));
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|b| b.addr == e.pc as usize)
+        {
+            let _ = write!(self.reply, "swbreak:;");
+        }
+    }
+
+    /// Talks to GDB until it resumes the system
+    unsafe fn stop(&mut self, e: &mut ExceptionContext, signal: u8, packet_started: bool) {
+        // Stop single stepping
+        set_msr!(mdscr_el1, get_msr!(mdscr_el1) & !(MDSCR_SS | MDSCR_KDE));
+        e.spsr &= !SPSR_SS;
+        if self.step_cleared_d {
+            e.spsr |= SPSR_D;
+            self.step_cleared_d = false;
+        }
+
+        let mut packet_started = packet_started;
+        if !packet_started {
+            // Tell GDB why we stopped, it's waiting for this after resuming
+            self.write_stop_reply(e, signal);
+            packet_started = self.send_reply();
+        }
+
+        let mut packet = PACKET.lock();
+        let mut selected_tid = current_tid(e);
+        loop {
+            let len = self.read_packet(&mut packet, packet_started);
+            self.reply.len = 0;
+            let resume = self.handle_packet(e, signal, &mut selected_tid, &packet[..len]);
+            if let Some(resume) = resume {
+                self.resume(e, resume);
+                return;
+            }
+            packet_started = self.send_reply();
+        }
+    }
+
+    unsafe fn resume(&mut self, e: &mut ExceptionContext, resume: Resume) {
+        if let Resume::Step = resume {
+            let mut mdscr = get_msr!(mdscr_el1) | MDSCR_SS;
+            if !is_user(e) {
+                mdscr |= MDSCR_KDE;
+                if e.spsr & SPSR_D != 0 {
+                    e.spsr &= !SPSR_D;
+                    self.step_cleared_d = true;
+                }
+            }
+            set_msr!(mdscr_el1, mdscr);
+            e.spsr |= SPSR_SS;
+        }
+    }
+
+    /// Handles a packet, writing the reply into `self.reply`. Returns how to resume the system
+    /// if the packet asked to.
+    unsafe fn handle_packet(
+        &mut self,
+        e: &mut ExceptionContext,
+        signal: u8,
+        selected_tid: &mut u64,
+        packet: &[u8],
+    ) -> Option<Resume> {
+        let (&command, args) = packet.split_first()?;
+
+        match command {
+            b'?' => self.write_stop_reply(e, signal),
+            b'q' => self.handle_query(e, args),
+            b'H' => {
+                // Hg/Hc select the thread for the following commands
+                match args.split_first().map(|(_, tid)| parse_tid(tid)) {
+                    Some(Some(0)) => *selected_tid = current_tid(e),
+                    Some(Some(tid)) if thread_context(e, tid).is_some() => *selected_tid = tid,
+                    _ => {
+                        let _ = write!(self.reply, "E01");
+                        return None;
+                    }
+                }
+                let _ = write!(self.reply, "OK");
+            }
+            b'T' => match parse_tid(args).and_then(|tid| thread_context(e, tid)) {
+                Some(_) => {
+                    let _ = write!(self.reply, "OK");
+                }
+                None => {
+                    let _ = write!(self.reply, "E01");
+                }
+            },
+            b'g' => match thread_context(e, *selected_tid) {
+                Some(context) => {
+                    for reg in 0..REG_COUNT {
+                        let (value, size) = read_register(&*context, reg)?;
+                        self.reply.push_hex(&value.to_le_bytes()[..size]);
+                    }
+                }
+                None => {
+                    let _ = write!(self.reply, "E01");
+                }
+            },
+            b'G' => match thread_context(e, *selected_tid) {
+                Some(context) => {
+                    let mut offset = 0;
+                    for reg in 0..REG_COUNT {
+                        let (_, size) = read_register(&*context, reg)?;
+                        let mut bytes = [0u8; 8];
+                        let Some(hex) = args.get(offset * 2..(offset + size) * 2) else {
+                            break;
+                        };
+                        decode_hex(hex, &mut bytes[..size])?;
+                        write_register(&mut *context, reg, u64::from_le_bytes(bytes));
+                        offset += size;
+                    }
+                    let _ = write!(self.reply, "OK");
+                }
+                None => {
+                    let _ = write!(self.reply, "E01");
+                }
+            },
+            b'p' => {
+                let reg = parse_hex(args)? as usize;
+                match thread_context(e, *selected_tid).and_then(|c| read_register(&*c, reg)) {
+                    Some((value, size)) => self.reply.push_hex(&value.to_le_bytes()[..size]),
+                    None => {
+                        let _ = write!(self.reply, "E01");
+                    }
+                }
+            }
+            b'P' => {
+                let eq = args.iter().position(|&ch| ch == b'=')?;
+                let reg = parse_hex(&args[..eq])? as usize;
+                let mut bytes = [0u8; 8];
+                decode_hex(&args[eq + 1..], &mut bytes)?;
+                let value = u64::from_le_bytes(bytes);
+                match thread_context(e, *selected_tid) {
+                    Some(context) if write_register(&mut *context, reg, value) => {
+                        let _ = write!(self.reply, "OK");
+                    }
+                    _ => {
+                        let _ = write!(self.reply, "E01");
+                    }
+                }
+            }
+            b'm' => {
+                let (addr, len) = parse_addr_len(args)?;
+                let len = len.min(PACKET_SIZE / 2 - 1);
+                if !is_range_mapped(addr, len, false) {
+                    let _ = write!(self.reply, "E14");
+                    return None;
+                }
+                for i in 0..len {
+                    let byte = read_volatile((addr + i) as *const u8);
+                    self.reply.push_hex(&[byte]);
+                }
+            }
+            b'M' => {
+                let colon = args.iter().position(|&ch| ch == b':')?;
+                let (addr, len) = parse_addr_len(&args[..colon])?;
+                let mut bytes = [0u8; PACKET_SIZE / 2];
+                // Data that doesn't fit in a packet is an error too, not an unknown command
+                if decode_hex(&args[colon + 1..], &mut bytes) != Some(len)
+                    || !is_range_mapped(addr, len, true)
+                {
+                    let _ = write!(self.reply, "E14");
+                    return None;
+                }
+                for (i, &byte) in bytes[..len].iter().enumerate() {
+                    write_volatile((addr + i) as *mut u8, byte);
+                }
+                sync_icache(addr, len);
+                let _ = write!(self.reply, "OK");
+            }
+            b'Z' | b'z' if args.starts_with(b"0,") => {
+                let (addr, _kind) = parse_addr_len(&args[2..])?;
+                let ok = if command == b'Z' {
+                    self.insert_breakpoint(addr)
+                } else {
+                    self.remove_breakpoint(addr)
+                };
+                let _ = write!(self.reply, "{}", if ok { "OK" } else { "E01" });
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    e.pc = addr;
+                }
+                return Some(if command == b's' {
+                    Resume::Step
+                } else {
+                    Resume::Continue
+                });
+            }
+            b'D' => {
+                // Detach: leave the system running without breakpoints
+                for i in 0..MAX_BREAKPOINTS {
+                    if let Some(breakpoint) = self.breakpoints[i] {
+                        self.remove_breakpoint(breakpoint.addr);
+                    }
+                }
+                let _ = write!(self.reply, "OK");
+                self.send_reply();
+                return Some(Resume::Continue);
+            }
+            // Unsupported, an empty reply tells GDB so
+            _ => {}
+        }
+        None
+    }
+
+    fn handle_query(&mut self, e: &mut ExceptionContext, args: &[u8]) {
+        if args.starts_with(b"Supported") {
+            let _ = write!(
+                self.reply,
+                "PacketSize={:x};qXfer:features:read+;qXfer:threads:read+;swbreak+",
+                PACKET_SIZE
+            );
+        } else if args == b"C" {
+            let _ = write!(self.reply, "QC{:x}", current_tid(e));
+        } else if args == b"fThreadInfo" {
+            let _ = write!(self.reply, "m{:x}", current_tid(e));
+            if usermode::user_context().is_some() && current_tid(e) != INIT_TID {
+                let _ = write!(self.reply, ",{:x}", INIT_TID);
+            }
+        } else if args == b"sThreadInfo" {
+            let _ = write!(self.reply, "l");
+        } else if args == b"Attached" {
+            let _ = write!(self.reply, "1");
+        } else if let Some(annex) = args.strip_prefix(b"Xfer:features:read:target.xml:") {
+            self.reply_xfer(TARGET_XML.as_bytes(), annex);
+        } else if let Some(annex) = args.strip_prefix(b"Xfer:threads:read::") {
+            let mut threads = Reply {
+                buf: [0; PACKET_SIZE],
+                len: 0,
+            };
+            let _ = write!(threads, "<?xml version=\"1.0\"?>\n<threads>\n");
+            if !is_user(e) {
+                let _ = writeln!(threads, "<thread id=\"{KERNEL_TID:x}\" name=\"kernel\"/>");
+            }
+            if is_user(e) || usermode::user_context().is_some() {
+                let _ = writeln!(threads, "<thread id=\"{INIT_TID:x}\" name=\"init\"/>");
+            }
+            let _ = writeln!(threads, "</threads>");
+            self.reply_xfer(&threads.buf[..threads.len], annex);
+        }
+    }
+
+    /// Replies to a `qXfer` read of `offset,length` from `data`
+    fn reply_xfer(&mut self, data: &[u8], annex: &[u8]) {
+        let Some((offset, len)) = parse_addr_len(annex) else {
+            let _ = write!(self.reply, "E00");
+            return;
+        };
+        let start = offset.min(data.len());
+        // Leave room for escaping
+        let end = (start + len.min(PACKET_SIZE / 2)).min(data.len());
+        self.reply.push(if end == data.len() { b'l' } else { b'm' });
+        self.reply.push_binary(&data[start..end]);
+    }
+
+    unsafe fn insert_breakpoint(&mut self, addr: usize) -> bool {
+        if self.breakpoints.iter().flatten().any(|b| b.addr == addr) {
+            return true;
+        }
+        if !addr.is_multiple_of(4) || !is_range_mapped(addr, 4, true) {
+            return false;
+        }
+        let Some(slot) = self.breakpoints.iter_mut().find(|b| b.is_none()) else {
+            return false;
+        };
+        *slot = Some(Breakpoint {
+            addr,
+            original: read_u32(addr),
+        });
+        write_u32(addr, BRK_INSTRUCTION);
+        true
+    }
+
+    unsafe fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        let Some(slot) = self
+            .breakpoints
+            .iter_mut()
+            .find(|b| b.is_some_and(|b| b.addr == addr))
+        else {
+            return false;
+        };
+        let breakpoint = slot.take().unwrap();
+        write_u32(breakpoint.addr, breakpoint.original);
+        true
+    }
+}
@@ -1,5 +1,6 @@
 use crate::set_msr_const;
 use aarch64_cpu::registers::DAIF;
+use core::arch::asm;
 use core::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
@@ -75,3 +76,18 @@ pub unsafe fn enable() {
 pub unsafe fn disable() {
     set_msr_const!(daifset, 2);
 }
+
+/// Sleeps until an interrupt arrives, and lets it be handled
+///
+/// Interrupts are masked while handling exceptions (e.g. syscalls), so `wfi` wakes up with the
+/// interrupt still pending, and we briefly unmask them to let the handler run.
+///
+/// # Safety
+///
+/// Interrupt handlers may run, so no `IrqGuard` may be held
+pub unsafe fn wait_for_interrupt() {
+    asm!("wfi");
+    enable();
+    asm!("isb");
+    disable();
+}
@@ -1,13 +1,14 @@
 use crate::page_alloc::{PageBox, PhyAddr, PAGE_SIZE};
 use aarch64_cpu::registers::{ReadWriteable, Writeable, VBAR_EL1};
-use aarch64_cpu::registers::{MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR0_EL1, TTBR1_EL1};
+use aarch64_cpu::registers::{
+    ID_AA64MMFR0_EL1, MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR0_EL1, TTBR1_EL1,
+};
 use core::arch::asm;
 use core::fmt::{Debug, Formatter};
 use tock_registers::interfaces::Readable;
 use zerocopy::FromZeros;
 
-#[allow(unused_imports)]
-use crate::println;
+use crate::trace;
 
 pub const PT_PAGE: u64 = 0b11;
 pub const PT_BLOCK: u64 = 0b01;
@@ -59,10 +60,12 @@ impl PageTable {
             // TODO: Doesn't handle oom
             let new_table = PageBox::leak(PageBox::<PageTable>::new_zeroed());
             let phy_addr = PhyAddr::from_virt(new_table);
-            #[cfg(feature = "log_mmu")]
-            println!(
-                "  mmu: Allocated PT at {:?}, storing in 0x{:x}[{}]",
-                phy_addr, &self.0 as *const u64 as u64, idx
+            trace!(
+                "mmu",
+                "Allocated PT at {:?}, storing in 0x{:x}[{}]",
+                phy_addr,
+                &self.0 as *const u64 as u64,
+                idx
             );
             self.0[idx] = phy_addr.0 as u64 | flags;
             new_table
@@ -87,8 +90,7 @@ impl PageTable {
             PT_ISH | // inner shareable
             PT_MEM; // normal memory
 
-        #[cfg(feature = "log_mmu")]
-        println!("  mmu: Mapping {:?} to 0x{:x}", paddr, vaddr);
+        trace!("mmu", "Mapping {:?} to 0x{:x}", paddr, vaddr);
         assert_eq!(PAGE_SIZE, 4096); // TODO
         assert!(vaddr < 0x8000000000);
         assert_eq!(vaddr % PAGE_SIZE, 0);
@@ -104,8 +106,7 @@ impl PageTable {
     }
 
     fn vunmap_single(&mut self, vaddr: usize) {
-        #[cfg(feature = "log_mmu")]
-        println!("  mmu: Unmapping 0x{:x}", vaddr);
+        trace!("mmu", "Unmapping 0x{:x}", vaddr);
         assert_eq!(PAGE_SIZE, 4096); // TODO
         assert!(vaddr < 0x8000000000);
         assert_eq!(vaddr % PAGE_SIZE, 0);
@@ -370,7 +371,9 @@ pub unsafe fn init() {
             + TCR_EL1::SH0::Inner
             + TCR_EL1::SH1::Inner
             + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-            + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable,
+            + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            // As wide as the CPU supports, PCIe windows are far above 4 GiB
+            + TCR_EL1::IPS.val(ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange)),
     );
 
     // Set translation table base registers
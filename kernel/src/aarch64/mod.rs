@@ -1,10 +1,16 @@
 use core::arch::global_asm;
 
+pub mod backtrace;
+pub mod esr;
 mod exceptions;
+pub mod gdbstub;
 pub mod interrupts;
 pub mod mmu;
+pub mod psci;
 pub mod usermode;
 
+pub use exceptions::ExceptionContext;
+
 global_asm!(include_str!("init.s"));
 
 #[macro_export]
@@ -0,0 +1,100 @@
+//! PSCI client, the firmware interface for turning CPUs and the machine off
+//!
+//! Calls are made with `hvc` or `smc`, depending on whether the hypervisor or the secure monitor
+//! implements PSCI, which init reads from the DTB's `psci` node. Only PSCI 0.2 and later are
+//! supported, they have standard function IDs and `SYSTEM_OFF`/`SYSTEM_RESET`.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, Ordering};
+use kernel_api::kernel_device::{PSCI_CONDUIT_HVC, PSCI_CONDUIT_SMC};
+use kernel_api::KError;
+
+/// Function IDs, SMC32 calling convention
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_OFF: u32 = 0x8400_0002;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+const SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// Return codes
+const NOT_SUPPORTED: i32 = -1;
+const INVALID_PARAMETERS: i32 = -2;
+const DENIED: i32 = -3;
+
+/// Before init loads the device
+const NO_CONDUIT: u32 = u32::MAX;
+
+static CONDUIT: AtomicU32 = AtomicU32::new(NO_CONDUIT);
+
+/// Starts making PSCI calls through `conduit`, returns the version of the firmware
+pub fn init(conduit: u32) -> Result<(u16, u16), KError> {
+    if conduit != PSCI_CONDUIT_HVC && conduit != PSCI_CONDUIT_SMC {
+        return Err(KError::InvalidArgument);
+    }
+    CONDUIT.store(conduit, Ordering::Relaxed);
+    let version = version();
+    if !matches!(version, Ok((major, minor)) if (major, minor) >= (0, 2)) {
+        CONDUIT.store(NO_CONDUIT, Ordering::Relaxed);
+        return Err(version.err().unwrap_or(KError::NotSupported));
+    }
+    version
+}
+
+/// Calls `function` with an argument, returns the result register
+fn call(function: u32, arg: u64) -> Result<u32, KError> {
+    let res: u64;
+    // The firmware may clobber x0-x17, x0-x3 are results
+    unsafe {
+        match CONDUIT.load(Ordering::Relaxed) {
+            PSCI_CONDUIT_HVC => asm!(
+                "hvc #0",
+                inlateout("x0") function as u64 => res,
+                inlateout("x1") arg => _,
+                lateout("x2") _, lateout("x3") _, lateout("x4") _, lateout("x5") _,
+                lateout("x6") _, lateout("x7") _, lateout("x8") _, lateout("x9") _,
+                lateout("x10") _, lateout("x11") _, lateout("x12") _, lateout("x13") _,
+                lateout("x14") _, lateout("x15") _, lateout("x16") _, lateout("x17") _,
+                options(nostack),
+            ),
+            // `smc #0`, which the assembler only takes with the `el3` feature
+            PSCI_CONDUIT_SMC => asm!(
+                ".inst 0xd4000003",
+                inlateout("x0") function as u64 => res,
+                inlateout("x1") arg => _,
+                lateout("x2") _, lateout("x3") _, lateout("x4") _, lateout("x5") _,
+                lateout("x6") _, lateout("x7") _, lateout("x8") _, lateout("x9") _,
+                lateout("x10") _, lateout("x11") _, lateout("x12") _, lateout("x13") _,
+                lateout("x14") _, lateout("x15") _, lateout("x16") _, lateout("x17") _,
+                options(nostack),
+            ),
+            _ => return Err(KError::NoDevice),
+        }
+    }
+    match res as i32 {
+        0.. => Ok(res as u32),
+        NOT_SUPPORTED => Err(KError::NotSupported),
+        INVALID_PARAMETERS => Err(KError::InvalidArgument),
+        DENIED => Err(KError::PermissionDenied),
+        _ => Err(KError::IoError),
+    }
+}
+
+/// The major and minor version of the firmware's PSCI
+pub fn version() -> Result<(u16, u16), KError> {
+    let version = call(PSCI_VERSION, 0)?;
+    Ok(((version >> 16) as u16, version as u16))
+}
+
+/// Turns the machine off, only returns if that failed
+pub fn system_off() -> KError {
+    call(SYSTEM_OFF, 0).err().unwrap_or(KError::IoError)
+}
+
+/// Reboots the machine, only returns if that failed
+pub fn system_reset() -> KError {
+    call(SYSTEM_RESET, 0).err().unwrap_or(KError::IoError)
+}
+
+/// Turns the calling CPU off, only returns if that failed
+pub fn cpu_off() -> KError {
+    call(CPU_OFF, 0).err().unwrap_or(KError::IoError)
+}
@@ -1,16 +1,28 @@
 use crate::aarch64::exceptions::ExceptionContext;
+use crate::aarch64::gdbstub;
+use crate::aarch64::interrupts;
 use crate::aarch64::mmu;
 use crate::aarch64::mmu::{tlb_flush, PageTable};
-use crate::drv::arm_gic::{timer_get_absolute_time_ms, timer_set_timeout};
-use crate::drv::qemu_console::puts;
-use crate::page_alloc::{add_memory_node, PageBox, PhyAddr, PAGE_ALLOC, PAGE_SIZE};
-use crate::{drv, page_alloc, println};
+use crate::aarch64::psci;
+use crate::clock;
+use crate::cmdline;
+use crate::drv::arm_gic::timer_set_timeout;
+use crate::drv::qemu_console::{self, puts};
+use crate::page_alloc::{add_memory_node, PageBox, PhyAddr, PhySlice, PAGE_ALLOC, PAGE_SIZE};
+use crate::{debug, drv, info, klog, page_alloc, random, warn};
 use aarch64_cpu::registers::{ELR_EL1, SPSR_EL1, SP_EL0, TTBR0_EL1};
 use core::arch::asm;
 use core::cell::UnsafeCell;
 use core::mem::{forget, MaybeUninit};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use kernel_api::clock::{ClockId, NSEC_PER_SEC, TIME_PAGE_ADDR};
 use kernel_api::kernel_device::KernelDeviceId;
-use kernel_api::{kernel_device, KError, MemMapFlags, PhyMapFlags, Syscall};
+use kernel_api::klog::MAX_TAG_LEN;
+use kernel_api::{
+    kernel_device, ConsoleFlags, GetRandomFlags, KError, MemMapFlags, PhyMapFlags, PsciFunction,
+    Syscall,
+};
 use tock_registers::interfaces::Writeable;
 use zerocopy::{FromZeros, IntoBytes};
 
@@ -30,6 +42,8 @@ struct Thread {
     sp: u64,
     /// Saved program status register
     spsr: u64,
+    /// Allowed to make privileged syscalls, like turning the machine off
+    privileged: bool,
 }
 
 impl Thread {
@@ -69,7 +83,6 @@ impl GlobalThread {
         self.0.get().write(MaybeUninit::new(thread));
     }
 
-    #[allow(dead_code)]
     unsafe fn as_ref(&self) -> &Thread {
         let inner = &*self.0.get();
         inner.assume_init_ref()
@@ -87,7 +100,7 @@ static INIT_BIN: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/init.bin"));
 static INIT_THREAD: GlobalThread = unsafe { GlobalThread::uninit() };
 
 pub unsafe fn start() {
-    println!(" user: Starting usermode");
+    info!("user", "Starting usermode");
 
     let mut code_slice = PAGE_ALLOC
         .lock()
@@ -97,6 +110,8 @@ pub unsafe fn start() {
 
     INIT_THREAD.init();
     let thread = INIT_THREAD.as_mut();
+    // Init runs the drivers, so it's in charge of the machine
+    thread.privileged = true;
 
     const PAGE_FLAGS: u64 = mmu::PT_RW_EL0 | // non-privileged
         mmu::PT_ISH | // inner shareable
@@ -120,6 +135,11 @@ pub unsafe fn start() {
             PAGE_FLAGS,
         );
     }
+    thread.page_table.vmap_at(
+        TIME_PAGE_ADDR,
+        clock::time_page_phys(),
+        mmu::PT_RO_EL0 | mmu::PT_ISH | mmu::PT_MEM,
+    );
 
     thread.enter();
 }
@@ -134,15 +154,57 @@ unsafe fn copy_from_user(user_pointer: usize, user_len: usize, target: &mut [u8]
     }
 }
 
+unsafe fn copy_to_user(user_pointer: usize, source: &[u8]) {
+    for (i, &byte) in source.iter().enumerate() {
+        asm!("sttrb {0:w}, [{1}]", in(reg) byte as u32, in(reg) user_pointer + i);
+    }
+}
+
+/// Reads from usermode memory with the permissions of EL0
+///
+/// # Safety
+///
+/// The address must be mapped in the current usermode address space
+pub unsafe fn read_user_u64(user_pointer: usize) -> u64 {
+    let value: u64;
+    asm!("ldtr {0}, [{1}]", out(reg) value, in(reg) user_pointer);
+    value
+}
+
+/// The registers of the init thread, saved when it entered the kernel
+static USER_CONTEXT: AtomicPtr<ExceptionContext> = AtomicPtr::new(null_mut());
+
+/// Records where the registers of the init thread were saved, called on every exception from
+/// usermode
+pub fn set_user_context(e: &mut ExceptionContext) {
+    USER_CONTEXT.store(e, Ordering::Relaxed);
+}
+
+/// The registers saved by the last exception from usermode. Only valid while the kernel handles
+/// that exception, which is always the case once usermode started.
+pub fn user_context() -> Option<*mut ExceptionContext> {
+    let context = USER_CONTEXT.load(Ordering::Relaxed);
+    (!context.is_null()).then_some(context)
+}
+
+/// The virtual address range of the init thread's stack
+pub fn stack_range() -> (usize, usize) {
+    (
+        (DEFAULT_SP - DEFAULT_STACK_SIZE) as usize,
+        DEFAULT_SP as usize,
+    )
+}
+
 pub unsafe fn handle_syscall(e: &mut ExceptionContext) {
     let Ok(syscall_num) = Syscall::try_from(e.gpr[8] as u32) else {
-        println!("Unknown syscall: {}", e.gpr[8]);
+        warn!("user", "Unknown syscall: {}", e.gpr[8]);
         e.gpr[0] = u64::MAX;
         return;
     };
     match syscall_num {
         Syscall::Exit => {
-            todo!("Syscall::Exit not implemented")
+            // There's nothing to run without init
+            panic!("Init exited with code {}", e.gpr[0] as u32);
         }
         Syscall::Log => {
             let mut buf = [0u8; 256];
@@ -192,6 +254,28 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext) {
             e.gpr[0] = thread.page_table.vmap(phy_addr, len as usize, page_flags) as u64;
             forget(page_slice); // Don't free the memory we just allocated
         }
+        Syscall::DmaAlloc => {
+            let len = e.gpr[0];
+            let thread = INIT_THREAD.as_mut();
+            if len == 0 {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            }
+
+            // Physically contiguous and zeroed, so a device can be pointed at it
+            let Some(page_slice) = PAGE_ALLOC
+                .lock()
+                .alloc_zeroed(len.div_ceil(PAGE_SIZE as u64) as usize)
+            else {
+                e.gpr[0] = KError::OOM.into();
+                return;
+            };
+            let phy_addr = PhyAddr::from_virt(page_slice.as_ptr());
+            let page_flags = mmu::PT_RW_EL0 | mmu::PT_ISH | mmu::PT_MEM;
+            e.gpr[0] = thread.page_table.vmap(phy_addr, len as usize, page_flags) as u64;
+            e.gpr[1] = phy_addr.0 as u64;
+            forget(page_slice); // Don't free the memory we just allocated
+        }
         Syscall::MemUnmap => {
             let virt_addr = e.gpr[0];
             let len = e.gpr[1];
@@ -208,6 +292,32 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext) {
             let len = e.gpr[1];
             add_memory_node(PhyAddr(phy_addr as usize), len as usize);
         }
+        Syscall::ReserveMemory => {
+            let region = PhySlice {
+                base: PhyAddr(e.gpr[0] as usize),
+                len: e.gpr[1] as usize,
+            };
+            e.gpr[0] = match page_alloc::reserve(region) {
+                Ok(()) => 0,
+                Err(err) => err.into(),
+            };
+        }
+        Syscall::SetCmdline => {
+            let ptr = e.gpr[0] as usize;
+            let len = e.gpr[1] as usize;
+            let mut buf = [0u8; 1024];
+            if len > buf.len() {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            }
+            copy_from_user(ptr, len, &mut buf[..len]);
+            let Ok(cmdline) = core::str::from_utf8(&buf[..len]) else {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            };
+            cmdline::apply(cmdline);
+            e.gpr[0] = 0;
+        }
         Syscall::LoadKernelDevice => {
             let ptr = e.gpr[0];
             let len = e.gpr[1];
@@ -221,7 +331,7 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext) {
                     "Invalid length for GicAndTimer"
                 );
                 copy_from_user(ptr as usize, len as usize, gic_and_timer.as_mut_bytes());
-                println!(" user: LoadKernelDevice: {:?}", gic_and_timer);
+                info!("user", "LoadKernelDevice: {:?}", gic_and_timer);
 
                 drv::arm_gic::timer_clear();
                 drv::arm_gic::init_gic(
@@ -232,6 +342,72 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext) {
 
                 e.gpr[0] = 0;
                 return;
+            } else if dev_id == kernel_device::GdbStub::ID as u64 {
+                let mut gdb_stub = kernel_device::GdbStub::new_zeroed();
+                if len != size_of_val(&gdb_stub) as u64 {
+                    e.gpr[0] = KError::InvalidArgument.into();
+                    return;
+                }
+                copy_from_user(ptr as usize, len as usize, gdb_stub.as_mut_bytes());
+                info!("user", "LoadKernelDevice: {:?}", gdb_stub);
+
+                gdbstub::init(
+                    PhyAddr(gdb_stub.base as usize),
+                    gdb_stub.clock_hz,
+                    gdb_stub.interrupt_id,
+                );
+
+                e.gpr[0] = 0;
+            } else if dev_id == kernel_device::Pl011::ID as u64 {
+                let mut pl011 = kernel_device::Pl011::new_zeroed();
+                if len != size_of_val(&pl011) as u64 {
+                    e.gpr[0] = KError::InvalidArgument.into();
+                    return;
+                }
+                copy_from_user(ptr as usize, len as usize, pl011.as_mut_bytes());
+                info!("user", "LoadKernelDevice: {:?}", pl011);
+
+                drv::pl011::init(
+                    PhyAddr(pl011.base as usize),
+                    pl011.clock_hz,
+                    pl011.interrupt_id,
+                );
+
+                e.gpr[0] = 0;
+            } else if dev_id == kernel_device::Framebuffer::ID as u64 {
+                let mut fb = kernel_device::Framebuffer::new_zeroed();
+                if len != size_of_val(&fb) as u64 {
+                    e.gpr[0] = KError::InvalidArgument.into();
+                    return;
+                }
+                copy_from_user(ptr as usize, len as usize, fb.as_mut_bytes());
+                info!("user", "LoadKernelDevice: {:?}", fb);
+
+                e.gpr[0] = match drv::fbcon::init(
+                    PhyAddr(fb.base as usize),
+                    fb.width,
+                    fb.height,
+                    fb.stride,
+                ) {
+                    Ok(()) => 0,
+                    Err(err) => err.into(),
+                };
+            } else if dev_id == kernel_device::Psci::ID as u64 {
+                let mut psci = kernel_device::Psci::new_zeroed();
+                if len != size_of_val(&psci) as u64 {
+                    e.gpr[0] = KError::InvalidArgument.into();
+                    return;
+                }
+                copy_from_user(ptr as usize, len as usize, psci.as_mut_bytes());
+                info!("user", "LoadKernelDevice: {:?}", psci);
+
+                e.gpr[0] = match psci::init(psci.conduit) {
+                    Ok((major, minor)) => {
+                        info!("kernel", "PSCI {major}.{minor}");
+                        0
+                    }
+                    Err(err) => err.into(),
+                };
             } else {
                 e.gpr[0] = KError::InvalidArgument.into();
                 return;
@@ -239,10 +415,11 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext) {
         }
         Syscall::SleepSec => {
             let sec = e.gpr[0];
-            let deadline: u64 = timer_get_absolute_time_ms() + sec * 1000;
-            println!(" user: Sleeping for {} seconds", sec);
+            let deadline =
+                clock::now(ClockId::Monotonic).saturating_add(sec.saturating_mul(NSEC_PER_SEC));
+            debug!("user", "Sleeping for {} seconds", sec);
             loop {
-                let sleep_left = deadline.saturating_sub(timer_get_absolute_time_ms());
+                let sleep_left = deadline.saturating_sub(clock::now(ClockId::Monotonic));
                 if sleep_left == 0 {
                     break;
                 }
@@ -251,5 +428,205 @@ pub unsafe fn handle_syscall(e: &mut ExceptionContext) {
             }
             e.gpr[0] = 0;
         }
+        Syscall::ClockGet => {
+            let Ok(clock_id) = ClockId::try_from(e.gpr[0] as u32) else {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            };
+            e.gpr[0] = clock::now(clock_id);
+        }
+        Syscall::ClockSet => {
+            let Ok(clock_id) = ClockId::try_from(e.gpr[0] as u32) else {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            };
+            e.gpr[0] = match clock::set(clock_id, e.gpr[1]) {
+                Ok(()) => 0,
+                Err(err) => err.into(),
+            };
+        }
+        Syscall::IrqWait => {
+            let interrupt_id = e.gpr[0] as u32;
+            e.gpr[0] = match drv::arm_gic::wait_user_interrupt(interrupt_id, None) {
+                Ok(()) => 0,
+                Err(err) => err.into(),
+            };
+        }
+        Syscall::IrqWaitTimeout => {
+            let interrupt_id = e.gpr[0] as u32;
+            let timeout_ns = e.gpr[1];
+            e.gpr[0] = match drv::arm_gic::wait_user_interrupt(interrupt_id, Some(timeout_ns)) {
+                Ok(()) => 0,
+                Err(err) => err.into(),
+            };
+        }
+        Syscall::ConsoleRead => {
+            let ptr = e.gpr[0];
+            let len = e.gpr[1];
+            let non_block = qemu_console::flags().contains(ConsoleFlags::NonBlock);
+            if drv::pl011::interrupt_id().is_none() && !non_block {
+                // Nothing will ever wake us up
+                e.gpr[0] = KError::NoDevice.into();
+                return;
+            }
+
+            let mut buf = [0u8; 256];
+            let max_len = len.min(buf.len() as u64) as usize;
+            let count = loop {
+                let count = qemu_console::read(&mut buf[..max_len]);
+                if count != 0 || max_len == 0 {
+                    break count;
+                }
+                if non_block {
+                    e.gpr[0] = KError::WouldBlock.into();
+                    return;
+                }
+                interrupts::wait_for_interrupt();
+            };
+            copy_to_user(ptr as usize, &buf[..count]);
+            e.gpr[0] = count as u64;
+        }
+        Syscall::ConsoleWrite => {
+            let ptr = e.gpr[0] as usize;
+            let len = e.gpr[1] as usize;
+            let mut buf = [0u8; 256];
+            for offset in (0..len).step_by(buf.len()) {
+                let chunk_len = (len - offset).min(buf.len());
+                copy_from_user(ptr + offset, chunk_len, &mut buf[..chunk_len]);
+                puts(&buf[..chunk_len]);
+            }
+            e.gpr[0] = len as u64;
+        }
+        Syscall::ConsoleInput => {
+            let ptr = e.gpr[0] as usize;
+            let len = e.gpr[1] as usize;
+            let mut buf = [0u8; 256];
+            for offset in (0..len).step_by(buf.len()) {
+                let chunk_len = (len - offset).min(buf.len());
+                copy_from_user(ptr + offset, chunk_len, &mut buf[..chunk_len]);
+                for &ch in &buf[..chunk_len] {
+                    qemu_console::push_input(ch);
+                }
+            }
+            e.gpr[0] = len as u64;
+        }
+        Syscall::Psci => {
+            if !INIT_THREAD.as_ref().privileged {
+                e.gpr[0] = KError::PermissionDenied.into();
+                return;
+            }
+            let Ok(function) = PsciFunction::try_from(e.gpr[0] as u32) else {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            };
+            e.gpr[0] = match function {
+                PsciFunction::Version => match psci::version() {
+                    Ok((major, minor)) => (major as u64) << 16 | minor as u64,
+                    Err(err) => err.into(),
+                },
+                PsciFunction::SystemOff => {
+                    info!("kernel", "Powering off");
+                    psci::system_off().into()
+                }
+                PsciFunction::SystemReset => {
+                    info!("kernel", "Rebooting");
+                    psci::system_reset().into()
+                }
+                PsciFunction::CpuOff => {
+                    info!("kernel", "Turning the CPU off");
+                    psci::cpu_off().into()
+                }
+            };
+        }
+        Syscall::ConsoleSetMode => {
+            let flags = ConsoleFlags::from_bits_truncate(e.gpr[0]);
+            e.gpr[0] = qemu_console::set_flags(flags).bits();
+        }
+        Syscall::LogRead => {
+            let ptr = e.gpr[0] as usize;
+            let len = e.gpr[1] as usize;
+            let mut buf = [0u8; 1024];
+            let mut total = 0;
+            let result = loop {
+                let chunk_len = (len - total).min(buf.len());
+                match klog::read(&mut buf[..chunk_len]) {
+                    Ok(0) => break Ok(total),
+                    Ok(count) => {
+                        copy_to_user(ptr + total, &buf[..count]);
+                        total += count;
+                    }
+                    // The next record doesn't fit in what's left of the user's buffer
+                    Err(_) if total != 0 => break Ok(total),
+                    Err(err) => break Err(err),
+                }
+            };
+            e.gpr[0] = match result {
+                Ok(count) => count as u64,
+                Err(err) => err.into(),
+            };
+        }
+        Syscall::LogSetLevel => {
+            let ptr = e.gpr[0];
+            let len = e.gpr[1] as usize;
+            let Ok(level) = klog::Level::try_from(e.gpr[2] as u8) else {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            };
+            let mut tag = [0u8; MAX_TAG_LEN];
+            if len > tag.len() {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            }
+            copy_from_user(ptr as usize, len, &mut tag[..len]);
+            // An empty tag sets the default level
+            let tag = if len == 0 { None } else { Some(&tag[..len]) };
+            e.gpr[0] = match klog::set_level(tag, level) {
+                Ok(()) => 0,
+                Err(err) => err.into(),
+            };
+        }
+        Syscall::GetRandom => {
+            let ptr = e.gpr[0] as usize;
+            let len = e.gpr[1] as usize;
+            let flags = GetRandomFlags::from_bits_truncate(e.gpr[2]);
+            if !random::is_seeded() {
+                if flags.contains(GetRandomFlags::NonBlock) {
+                    e.gpr[0] = KError::WouldBlock.into();
+                    return;
+                }
+                random::wait_until_seeded();
+            }
+            let mut buf = [0u8; 256];
+            for offset in (0..len).step_by(buf.len()) {
+                let chunk_len = (len - offset).min(buf.len());
+                random::try_fill(&mut buf[..chunk_len]);
+                copy_to_user(ptr + offset, &buf[..chunk_len]);
+            }
+            // Don't leave the output lying around on the stack
+            random::wipe(&mut buf);
+            e.gpr[0] = len as u64;
+        }
+        Syscall::AddEntropy => {
+            let ptr = e.gpr[0] as usize;
+            let len = e.gpr[1] as usize;
+            let credit_bits = e.gpr[2].min((len as u64).saturating_mul(8));
+            let credit_bits = u32::try_from(credit_bits).unwrap_or(u32::MAX);
+            let mut buf = [0u8; 256];
+            for offset in (0..len).step_by(buf.len()) {
+                let chunk_len = (len - offset).min(buf.len());
+                copy_from_user(ptr + offset, chunk_len, &mut buf[..chunk_len]);
+                random::add_entropy(&buf[..chunk_len], 0);
+            }
+            random::add_entropy(&[], credit_bits);
+            random::wipe(&mut buf);
+            e.gpr[0] = 0;
+        }
+        Syscall::LogSetConsoleLevel => {
+            let Ok(level) = klog::Level::try_from(e.gpr[0] as u8) else {
+                e.gpr[0] = KError::InvalidArgument.into();
+                return;
+            };
+            e.gpr[0] = u8::from(klog::set_console_level(level)) as u64;
+        }
     }
 }
@@ -0,0 +1,58 @@
+use crate::page_alloc::PhyAddr;
+use crate::{get_msr, set_msr};
+use core::sync::atomic::Ordering;
+use kernel_api::clock::{ClockId, TimePage};
+
+#[repr(align(4096))]
+struct AlignedTimePage(TimePage);
+
+/// Shared read-only with usermode, see [`kernel_api::clock::TIME_PAGE_ADDR`]
+static TIME_PAGE: AlignedTimePage = AlignedTimePage(TimePage::new());
+
+/// Reads the system counter
+pub fn counter() -> u64 {
+    unsafe {
+        // Don't let the counter be read speculatively before previous instructions
+        core::arch::asm!("isb", options(nomem, nostack));
+        get_msr!(cntpct_el0)
+    }
+}
+
+pub fn counter_freq() -> u64 {
+    unsafe { get_msr!(cntfrq_el0) }
+}
+
+/// # Safety
+///
+/// Must be called once, before usermode starts
+pub unsafe fn init() {
+    let boot_counter = counter();
+    TIME_PAGE.0.update(|page| {
+        page.counter_freq.store(counter_freq(), Ordering::Relaxed);
+        page.boot_counter.store(boot_counter, Ordering::Relaxed);
+    });
+
+    // Allow EL0 to read the physical counter (CNTKCTL_EL1.EL0PCTEN), for the time page
+    set_msr!(cntkctl_el1, 1);
+}
+
+/// Reads the given clock, in nanoseconds
+pub fn now(clock: ClockId) -> u64 {
+    TIME_PAGE.0.read(clock, counter)
+}
+
+/// Sets the given clock, in nanoseconds. Only the realtime clock can be set.
+pub fn set(clock: ClockId, ns: u64) -> Result<(), kernel_api::KError> {
+    if clock != ClockId::Realtime {
+        return Err(kernel_api::KError::InvalidArgument);
+    }
+    let offset = ns.wrapping_sub(now(ClockId::Monotonic));
+    TIME_PAGE.0.update(|page| {
+        page.realtime_offset_ns.store(offset, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+pub fn time_page_phys() -> PhyAddr {
+    PhyAddr::from_virt(&raw const TIME_PAGE)
+}
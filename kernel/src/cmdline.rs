@@ -0,0 +1,34 @@
+//! Boot options from the command line, which init passes on from the DTB
+
+use crate::{debug, info, klog, page_alloc, warn};
+use kernel_api::cmdline::{BootOptions, OptionError};
+
+/// Applies the options that concern the kernel, the others are left to init
+pub fn apply(cmdline: &str) {
+    info!("kernel", "Command line: {:?}", cmdline);
+    let options = BootOptions::parse(cmdline, |err| match err {
+        OptionError::Unknown(arg) => debug!("kernel", "Ignoring boot argument {:?}", arg.key),
+        OptionError::InvalidValue(arg) => {
+            warn!(
+                "kernel",
+                "Invalid boot argument {}={:?}", arg.key, arg.value
+            )
+        }
+    });
+
+    if let Some(level) = options.loglevel {
+        klog::set_console_level(level);
+    }
+    page_alloc::set_overwrite_free_pages(options.overwrite_free_pages);
+    if let Some(action) = options.panic {
+        crate::set_panic_action(action);
+    }
+    if let Some(cpus) = options.cpus {
+        if cpus > 1 {
+            warn!(
+                "kernel",
+                "cpus={cpus}: only the boot CPU is brought up for now"
+            );
+        }
+    }
+}
@@ -1,13 +1,28 @@
-use crate::{get_msr, set_msr};
-use crate::{page_alloc::PhyAddr, println};
+use crate::aarch64::gdbstub;
+use crate::aarch64::interrupts::{self, IrqMutex};
+use crate::aarch64::ExceptionContext;
+use crate::drv::pl011;
+use crate::page_alloc::PhyAddr;
+use crate::{clock, random, set_msr};
+use crate::{debug, info, warn};
 use core::ptr::{read_volatile, write_volatile};
+use kernel_api::clock::{ns_to_ticks, ClockId};
+use kernel_api::KError;
 
 static mut GICD_BASE: usize = 0;
 static mut GICC_BASE: usize = 0;
 
 const GICD_CTLR: usize = 0x000;
 const GICD_ISENABLER0: usize = 0x100;
+const GICD_ICENABLER0: usize = 0x180;
 const GICD_IPRIORITYR0: usize = 0x400;
+const GICD_ITARGETSR0: usize = 0x800;
+
+/// First shared peripheral interrupt, IDs below it are per-core (SGIs and PPIs)
+const SPI_OFFSET: u32 = 32;
+/// IDs from here on are reserved/special (1023 = spurious)
+const SPECIAL_INTERRUPTS_START: u32 = 1020;
+const DEFAULT_PRIORITY: u32 = 0xA0;
 
 const GICC_CTLR: usize = 0x000;
 const GICC_PMR: usize = 0x004;
@@ -22,43 +37,62 @@ unsafe fn mmio_read(base: usize, offset: usize) -> u32 {
     read_volatile(PhyAddr(base + offset).virt_dev::<u32>())
 }
 
-pub unsafe fn init_gic(gicd_base: usize, gicc_base: usize, timer_ppi_interrupt: u32) {
-    (&raw mut GICD_BASE).write(gicd_base);
-    (&raw mut GICC_BASE).write(gicc_base);
-    println!("  drv: Initializing ARM GIC");
-
-    // -- distributor setup --
-
-    // Disable distributor during configuration
-    mmio_write(gicd_base, GICD_CTLR, 0);
-
-    // Enable the timer PPI
-    let reg_idx = timer_ppi_interrupt as usize / 32;
+unsafe fn enable_interrupt(gicd_base: usize, interrupt_id: u32) {
+    let reg_idx = interrupt_id as usize / 32;
     let interrupt_enable_reg = GICD_ISENABLER0 + (reg_idx * 4);
-    let interrupt_enable_shift = timer_ppi_interrupt % 32;
-    let enable_bits = mmio_read(gicd_base, interrupt_enable_reg);
+    let interrupt_enable_shift = interrupt_id % 32;
+    mmio_write(gicd_base, interrupt_enable_reg, 1 << interrupt_enable_shift);
+}
+
+unsafe fn disable_interrupt(gicd_base: usize, interrupt_id: u32) {
+    let reg_idx = interrupt_id as usize / 32;
+    let interrupt_disable_reg = GICD_ICENABLER0 + (reg_idx * 4);
+    let interrupt_disable_shift = interrupt_id % 32;
     mmio_write(
         gicd_base,
-        interrupt_enable_reg,
-        enable_bits | (1 << interrupt_enable_shift),
+        interrupt_disable_reg,
+        1 << interrupt_disable_shift,
     );
+}
 
-    // Set priority for the timer PPI to a default value (e.g., 0xA0)
-    let reg_idx = timer_ppi_interrupt as usize / 4;
+unsafe fn set_priority(gicd_base: usize, interrupt_id: u32, new_priority: u32) {
+    let reg_idx = interrupt_id as usize / 4;
     let interrupt_priority_reg = GICD_IPRIORITYR0 + (reg_idx * 4);
-    let interrupt_priority_shift = (timer_ppi_interrupt % 4) * 8;
+    let interrupt_priority_shift = (interrupt_id % 4) * 8;
     let mut priority = mmio_read(gicd_base, interrupt_priority_reg);
     priority &= !(0xFF << interrupt_priority_shift); // Clear old priority
-    priority |= 0xA0 << interrupt_priority_shift; // Set new priority
+    priority |= new_priority << interrupt_priority_shift; // Set new priority
     mmio_write(gicd_base, interrupt_priority_reg, priority);
+}
+
+/// Routes a shared peripheral interrupt to the given CPU mask
+unsafe fn set_target(gicd_base: usize, interrupt_id: u32, cpu_mask: u8) {
+    let reg_idx = interrupt_id as usize / 4;
+    let interrupt_target_reg = GICD_ITARGETSR0 + (reg_idx * 4);
+    let interrupt_target_shift = (interrupt_id % 4) * 8;
+    let mut targets = mmio_read(gicd_base, interrupt_target_reg);
+    targets &= !(0xFF << interrupt_target_shift);
+    targets |= (cpu_mask as u32) << interrupt_target_shift;
+    mmio_write(gicd_base, interrupt_target_reg, targets);
+}
+
+pub unsafe fn init_gic(gicd_base: usize, gicc_base: usize, timer_ppi_interrupt: u32) {
+    (&raw mut GICD_BASE).write(gicd_base);
+    (&raw mut GICC_BASE).write(gicc_base);
+    info!("drv", "Initializing ARM GIC");
+
+    // -- distributor setup --
+
+    // Disable distributor during configuration
+    mmio_write(gicd_base, GICD_CTLR, 0);
+
+    // Enable the timer PPI, with a default priority
+    enable_interrupt(gicd_base, timer_ppi_interrupt);
+    set_priority(gicd_base, timer_ppi_interrupt, DEFAULT_PRIORITY);
 
     // Enable distributor (Group 1 / Non-Secure interrupts)
     mmio_write(gicd_base, GICD_CTLR, 1);
 
-    // -- timer setup --
-    // Enable EL0 timer access
-    set_msr!(cntkctl_el1, 1);
-
     // -- cpu interface setup --
     // TODO: per core
 
@@ -69,15 +103,33 @@ pub unsafe fn init_gic(gicd_base: usize, gicc_base: usize, timer_ppi_interrupt:
     mmio_write(gicc_base, GICC_CTLR, 1);
 }
 
-pub unsafe fn timer_set_timeout(ms: u64) {
+/// Whether init handed the GIC to the kernel yet, interrupts (and sleeping) only work after that
+pub fn is_initialized() -> bool {
+    unsafe { (&raw const GICC_BASE).read() != 0 }
+}
+
+/// Routes a shared peripheral interrupt to a kernel driver, see `handle_irq`
+pub unsafe fn enable_kernel_interrupt(interrupt_id: u32) {
+    let gicd_base = (&raw const GICD_BASE).read();
+    assert_ne!(
+        gicd_base, 0,
+        "GIC must be initialized before enabling interrupts"
+    );
+    // Usermode mustn't take it over
+    USER_INTERRUPTS.lock().reserve_for_kernel(interrupt_id);
+    set_priority(gicd_base, interrupt_id, DEFAULT_PRIORITY);
+    // TODO: per core
+    set_target(gicd_base, interrupt_id, 1);
+    enable_interrupt(gicd_base, interrupt_id);
+}
+
+pub unsafe fn timer_set_timeout(ns: u64) {
     let gicc_base = (&raw const GICC_BASE).read();
     assert_ne!(gicc_base, 0, "GIC must be initialized before sleeping");
 
-    // Read the system counter frequency (Hz)
-    let freq = get_msr!(cntfrq_el0);
-
-    // Write countdown value
-    set_msr!(cntp_tval_el0, (freq / 1000) * ms);
+    // Write countdown value, the register is a signed 32-bit value so long sleeps wake up early
+    let ticks = ns_to_ticks(ns, clock::counter_freq()).min(i32::MAX as u64);
+    set_msr!(cntp_tval_el0, ticks);
 
     // Enable timer, unmask interrupt (Bit 0 = 1, Bit 1 = 0)
     set_msr!(cntp_ctl_el0, 1);
@@ -89,30 +141,32 @@ pub unsafe fn timer_clear() {
     set_msr!(cntp_ctl_el0, 3);
 }
 
-pub fn timer_get_absolute_time_ms() -> u64 {
-    unsafe {
-        // Read the system counter frequency (Hz)
-        let freq = get_msr!(cntfrq_el0);
-
-        // Read the current system counter value
-        let ticks = get_msr!(cntpct_el0);
-
-        (ticks * 1000) / freq
-    }
-}
-
-pub unsafe fn handle_irq() {
+pub unsafe fn handle_irq(e: &mut ExceptionContext) {
     let gicc_base = (&raw const GICC_BASE).read();
 
     // Read Interrupt Acknowledge Register
     let iar = mmio_read(gicc_base, GICC_IAR);
     let interrupt_id = iar & 0x3FF; // Mask out CPU ID fields (bits 10-12)
+    if interrupt_id != 1023 {
+        random::add_interrupt_jitter();
+    }
 
     // Route the interrupt based on ID
     match interrupt_id {
+        id if Some(id) == pl011::interrupt_id() => {
+            pl011::handle_irq();
+        }
+        id if Some(id) == gdbstub::interrupt_id() => {
+            gdbstub::handle_irq(e);
+        }
+        id if USER_INTERRUPTS.lock().is_registered(id) => {
+            // Keep the line masked until the driver handles the device and waits again
+            disable_interrupt((&raw const GICD_BASE).read(), id);
+            USER_INTERRUPTS.lock().set_pending(id);
+        }
         30 => {
             // Non-Secure Physical Timer
-            println!("  irq: Timer Ticked!");
+            debug!("irq", "Timer Ticked!");
 
             // Clear the timer interrupt so it stops triggering
             timer_clear();
@@ -124,7 +178,7 @@ pub unsafe fn handle_irq() {
             return;
         }
         _ => {
-            println!("Unhandled interrupt ID: {interrupt_id}");
+            warn!("irq", "Unhandled interrupt ID: {interrupt_id}");
         }
     }
 
@@ -132,3 +186,112 @@ pub unsafe fn handle_irq() {
     // This tells the GIC we are done processing this priority layer.
     mmio_write(gicc_base, GICC_EOIR, iar);
 }
+
+const USER_INTERRUPT_CELLS: usize = (SPECIAL_INTERRUPTS_START as usize).div_ceil(64);
+
+/// Shared peripheral interrupts handled by usermode drivers
+struct UserInterrupts {
+    registered: [u64; USER_INTERRUPT_CELLS],
+    pending: [u64; USER_INTERRUPT_CELLS],
+    /// Handled by kernel drivers, usermode can't wait for them
+    kernel: [u64; USER_INTERRUPT_CELLS],
+}
+
+impl UserInterrupts {
+    const fn new() -> Self {
+        Self {
+            registered: [0; USER_INTERRUPT_CELLS],
+            pending: [0; USER_INTERRUPT_CELLS],
+            kernel: [0; USER_INTERRUPT_CELLS],
+        }
+    }
+
+    fn reserve_for_kernel(&mut self, interrupt_id: u32) {
+        let id = interrupt_id as usize;
+        if id < SPECIAL_INTERRUPTS_START as usize {
+            self.kernel[id / 64] |= 1 << (id % 64);
+        }
+    }
+
+    fn is_kernel(&self, interrupt_id: u32) -> bool {
+        let id = interrupt_id as usize;
+        id < SPECIAL_INTERRUPTS_START as usize && self.kernel[id / 64] & (1 << (id % 64)) != 0
+    }
+
+    fn is_registered(&self, interrupt_id: u32) -> bool {
+        let id = interrupt_id as usize;
+        id < SPECIAL_INTERRUPTS_START as usize && self.registered[id / 64] & (1 << (id % 64)) != 0
+    }
+
+    fn register(&mut self, interrupt_id: u32) {
+        let id = interrupt_id as usize;
+        self.registered[id / 64] |= 1 << (id % 64);
+    }
+
+    fn set_pending(&mut self, interrupt_id: u32) {
+        let id = interrupt_id as usize;
+        self.pending[id / 64] |= 1 << (id % 64);
+    }
+
+    /// Clears the pending bit, returning whether it was set
+    fn take_pending(&mut self, interrupt_id: u32) -> bool {
+        let id = interrupt_id as usize;
+        let was_pending = self.pending[id / 64] & (1 << (id % 64)) != 0;
+        self.pending[id / 64] &= !(1 << (id % 64));
+        was_pending
+    }
+}
+
+static USER_INTERRUPTS: IrqMutex<UserInterrupts> = IrqMutex::new(UserInterrupts::new());
+
+/// Blocks until the given shared peripheral interrupt fires, registering it for usermode on first use
+///
+/// The interrupt is masked when it fires, and only unmasked again by the next wait, which gives the
+/// driver a chance to clear the interrupt condition in the device. With a timeout, gives up with
+/// `TimedOut` after that many nanoseconds; the interrupt stays unmasked then, and is taken by the
+/// next wait if it fires in between. Interrupts of kernel drivers fail with `PermissionDenied`.
+pub unsafe fn wait_user_interrupt(
+    interrupt_id: u32,
+    timeout_ns: Option<u64>,
+) -> Result<(), KError> {
+    let gicd_base = (&raw const GICD_BASE).read();
+    if gicd_base == 0 || !(SPI_OFFSET..SPECIAL_INTERRUPTS_START).contains(&interrupt_id) {
+        return Err(KError::InvalidArgument);
+    }
+
+    {
+        let mut user_interrupts = USER_INTERRUPTS.lock();
+        if user_interrupts.is_kernel(interrupt_id)
+            || Some(interrupt_id) == pl011::interrupt_id()
+            || Some(interrupt_id) == gdbstub::interrupt_id()
+        {
+            return Err(KError::PermissionDenied);
+        }
+        if !user_interrupts.is_registered(interrupt_id) {
+            info!("drv", "Routing interrupt {interrupt_id} to usermode");
+            set_priority(gicd_base, interrupt_id, DEFAULT_PRIORITY);
+            // TODO: per core
+            set_target(gicd_base, interrupt_id, 1);
+            user_interrupts.register(interrupt_id);
+        }
+        if user_interrupts.take_pending(interrupt_id) {
+            return Ok(());
+        }
+    }
+
+    enable_interrupt(gicd_base, interrupt_id);
+    let deadline = timeout_ns.map(|ns| clock::now(ClockId::Monotonic).saturating_add(ns));
+    loop {
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_sub(clock::now(ClockId::Monotonic));
+            if left == 0 {
+                return Err(KError::TimedOut);
+            }
+            timer_set_timeout(left);
+        }
+        interrupts::wait_for_interrupt();
+        if USER_INTERRUPTS.lock().take_pending(interrupt_id) {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! Text console on a framebuffer, mirroring the UART console
+//!
+//! init hands over a framebuffer the display reads by itself, like QEMU's ramfb, with
+//! `LoadKernelDevice`. From then on everything [`qemu_console`](super::qemu_console) prints is
+//! drawn on it too, so the shell and kernel panics show up on screen without any help from
+//! usermode. It understands the common ANSI escape sequences: colours (16, 256 and 24-bit),
+//! cursor movement, erasing and hiding the cursor.
+
+use crate::aarch64::interrupts::IrqMutex;
+use crate::font;
+use crate::info;
+use crate::page_alloc::PhyAddr;
+use kernel_api::KError;
+
+const TAB_WIDTH: usize = 8;
+const MAX_PARAMS: usize = 16;
+
+/// Black, red, green, yellow, blue, magenta, cyan and white, then their bright versions
+const PALETTE: [u32; 16] = [
+    0x000000, 0xaa0000, 0x00aa00, 0xaa5500, 0x0000aa, 0xaa00aa, 0x00aaaa, 0xaaaaaa, 0x555555,
+    0xff5555, 0x55ff55, 0xffff55, 0x5555ff, 0xff55ff, 0x55ffff, 0xffffff,
+];
+const DEFAULT_FG: Color = Color::Palette(7);
+const DEFAULT_BG: Color = Color::Palette(0);
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Color {
+    Palette(u8),
+    Rgb(u32),
+}
+
+impl Color {
+    /// One of the 256 colours of `ESC[38;5;Nm`: the palette, a 6x6x6 cube, then 24 greys
+    fn from_index(index: u16) -> Self {
+        match index {
+            0..16 => Color::Palette(index as u8),
+            16..232 => {
+                let level = |i: u16| if i == 0 { 0 } else { 55 + 40 * i as u32 };
+                let i = index - 16;
+                Color::Rgb(level(i / 36) << 16 | level(i / 6 % 6) << 8 | level(i % 6))
+            }
+            _ => {
+                let grey = 8 + 10 * (index.min(255) - 232) as u32;
+                Color::Rgb(grey << 16 | grey << 8 | grey)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    Normal,
+    /// After an ESC
+    Escape,
+    /// In a control sequence, after `ESC [`
+    Csi,
+}
+
+struct FbCon {
+    pixels: *mut u32,
+    /// Pixels from the start of a row to the start of the next one
+    stride: usize,
+    cols: usize,
+    rows: usize,
+    x: usize,
+    y: usize,
+    /// A character was written to the last column, the next one goes on the next line
+    wrap_pending: bool,
+    saved: (usize, usize),
+    fg: Color,
+    bg: Color,
+    bold: bool,
+    reverse: bool,
+    cursor_visible: bool,
+    cursor_drawn: bool,
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    /// The sequence started with `?`, like `ESC[?25l`
+    private: bool,
+}
+
+unsafe impl Send for FbCon {}
+
+static FBCON: IrqMutex<Option<FbCon>> = IrqMutex::new(None);
+
+impl FbCon {
+    fn colors(&self) -> (u32, u32) {
+        let rgb = |color: Color, bright: bool| match color {
+            Color::Palette(i) if bright && i < 8 => PALETTE[i as usize + 8],
+            Color::Palette(i) => PALETTE[i as usize],
+            Color::Rgb(rgb) => rgb,
+        };
+        let fg = rgb(self.fg, self.bold);
+        let bg = rgb(self.bg, false);
+        if self.reverse {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+
+    fn pixel_row(&mut self, y: usize) -> *mut u32 {
+        unsafe { self.pixels.add(y * self.stride) }
+    }
+
+    fn draw_glyph(&mut self, ch: u8) {
+        let (fg, bg) = self.colors();
+        let glyph = font::glyph(ch);
+        for (dy, bits) in glyph.iter().enumerate() {
+            let row = self.pixel_row(self.y * font::HEIGHT + dy);
+            for dx in 0..font::WIDTH {
+                let color = if bits & (0x80 >> dx) != 0 { fg } else { bg };
+                unsafe { row.add(self.x * font::WIDTH + dx).write_volatile(color) };
+            }
+        }
+    }
+
+    /// Fills columns `start..end` of text row `row` with the background colour
+    fn erase(&mut self, row: usize, start: usize, end: usize) {
+        let (_, bg) = self.colors();
+        for dy in 0..font::HEIGHT {
+            let pixels = self.pixel_row(row * font::HEIGHT + dy);
+            for x in start * font::WIDTH..end * font::WIDTH {
+                unsafe { pixels.add(x).write_volatile(bg) };
+            }
+        }
+    }
+
+    fn erase_rows(&mut self, start: usize, end: usize) {
+        for row in start..end {
+            self.erase(row, 0, self.cols);
+        }
+    }
+
+    fn scroll_up(&mut self) {
+        let row_len = font::HEIGHT * self.stride;
+        unsafe {
+            core::ptr::copy(
+                self.pixels.add(row_len),
+                self.pixels,
+                (self.rows - 1) * row_len,
+            );
+        }
+        self.erase(self.rows - 1, 0, self.cols);
+    }
+
+    /// Inverts the cell under the cursor, a second call restores it
+    fn toggle_cursor(&mut self) {
+        for dy in 0..font::HEIGHT {
+            let row = self.pixel_row(self.y * font::HEIGHT + dy);
+            for dx in 0..font::WIDTH {
+                unsafe {
+                    let pixel = row.add(self.x * font::WIDTH + dx);
+                    pixel.write_volatile(pixel.read_volatile() ^ 0xffffff);
+                }
+            }
+        }
+        self.cursor_drawn = !self.cursor_drawn;
+    }
+
+    fn newline(&mut self) {
+        self.x = 0;
+        self.wrap_pending = false;
+        if self.y + 1 == self.rows {
+            self.scroll_up();
+        } else {
+            self.y += 1;
+        }
+    }
+
+    fn print(&mut self, ch: u8) {
+        if self.wrap_pending {
+            self.newline();
+        }
+        self.draw_glyph(ch);
+        if self.x + 1 == self.cols {
+            self.wrap_pending = true;
+        } else {
+            self.x += 1;
+        }
+    }
+
+    fn move_to(&mut self, x: usize, y: usize) {
+        self.x = x.min(self.cols - 1);
+        self.y = y.min(self.rows - 1);
+        self.wrap_pending = false;
+    }
+
+    fn reset(&mut self) {
+        self.fg = DEFAULT_FG;
+        self.bg = DEFAULT_BG;
+        self.bold = false;
+        self.reverse = false;
+        self.cursor_visible = true;
+        self.erase_rows(0, self.rows);
+        self.move_to(0, 0);
+    }
+
+    fn put(&mut self, ch: u8) {
+        match self.state {
+            State::Normal => match ch {
+                // The UART console leaves adding `\r` to the terminal, this is it
+                b'\n' | 0x0b | 0x0c => self.newline(),
+                b'\r' => self.move_to(0, self.y),
+                0x08 => self.move_to(self.x.saturating_sub(1), self.y),
+                b'\t' => self.move_to((self.x / TAB_WIDTH + 1) * TAB_WIDTH, self.y),
+                0x1b => self.state = State::Escape,
+                0x20..0x7f => self.print(ch),
+                // UTF-8 gets a placeholder per character, past the first byte
+                0xc0.. => self.print(0x7f),
+                _ => {}
+            },
+            State::Escape => {
+                self.state = State::Normal;
+                match ch {
+                    b'[' => {
+                        self.params = [0; MAX_PARAMS];
+                        self.param_count = 0;
+                        self.private = false;
+                        self.state = State::Csi;
+                    }
+                    b'7' => self.saved = (self.x, self.y),
+                    b'8' => self.move_to(self.saved.0, self.saved.1),
+                    b'c' => self.reset(),
+                    _ => {}
+                }
+            }
+            State::Csi => match ch {
+                b'0'..=b'9' => {
+                    self.param_count = self.param_count.max(1);
+                    let param = &mut self.params[self.param_count - 1];
+                    *param = param.saturating_mul(10).saturating_add((ch - b'0') as u16);
+                }
+                b';' => self.param_count = (self.param_count.max(1) + 1).min(MAX_PARAMS),
+                b'?' => self.private = true,
+                0x40..=0x7e => {
+                    self.state = State::Normal;
+                    self.csi(ch);
+                }
+                _ => {}
+            },
+        }
+    }
+
+    /// Parameter `i` of a control sequence, where 0 or a missing one means `default`
+    fn param(&self, i: usize, default: usize) -> usize {
+        match self.params[i] {
+            0 => default,
+            param => param as usize,
+        }
+    }
+
+    fn csi(&mut self, command: u8) {
+        let n = self.param(0, 1);
+        let (x, y) = (self.x, self.y);
+        match command {
+            b'A' => self.move_to(x, y.saturating_sub(n)),
+            b'B' => self.move_to(x, y + n),
+            b'C' => self.move_to(x + n, y),
+            b'D' => self.move_to(x.saturating_sub(n), y),
+            b'E' => self.move_to(0, y + n),
+            b'F' => self.move_to(0, y.saturating_sub(n)),
+            b'G' => self.move_to(n - 1, y),
+            b'd' => self.move_to(x, n - 1),
+            b'H' | b'f' => self.move_to(self.param(1, 1) - 1, n - 1),
+            b'J' => match self.params[0] {
+                0 => {
+                    self.erase(y, x, self.cols);
+                    self.erase_rows(y + 1, self.rows);
+                }
+                1 => {
+                    self.erase_rows(0, y);
+                    self.erase(y, 0, x + 1);
+                }
+                _ => self.erase_rows(0, self.rows),
+            },
+            b'K' => match self.params[0] {
+                0 => self.erase(y, x, self.cols),
+                1 => self.erase(y, 0, x + 1),
+                _ => self.erase(y, 0, self.cols),
+            },
+            b'm' => self.sgr(),
+            b's' => self.saved = (x, y),
+            b'u' => self.move_to(self.saved.0, self.saved.1),
+            b'h' | b'l' if self.private && self.params[0] == 25 => {
+                self.cursor_visible = command == b'h';
+            }
+            _ => {}
+        }
+    }
+
+    /// Select Graphic Rendition, `ESC[...m`
+    fn sgr(&mut self) {
+        let count = self.param_count.max(1);
+        let mut i = 0;
+        while i < count {
+            match self.params[i] {
+                0 => {
+                    self.fg = DEFAULT_FG;
+                    self.bg = DEFAULT_BG;
+                    self.bold = false;
+                    self.reverse = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                p @ 30..=37 => self.fg = Color::Palette((p - 30) as u8),
+                p @ 40..=47 => self.bg = Color::Palette((p - 40) as u8),
+                p @ 90..=97 => self.fg = Color::Palette((p - 90 + 8) as u8),
+                p @ 100..=107 => self.bg = Color::Palette((p - 100 + 8) as u8),
+                39 => self.fg = DEFAULT_FG,
+                49 => self.bg = DEFAULT_BG,
+                p @ (38 | 48) => {
+                    // `5;N` for the 256 colours, `2;R;G;B` for any other
+                    let color = match self.params.get(i + 1) {
+                        Some(&5) if i + 2 < count => {
+                            i += 2;
+                            Some(Color::from_index(self.params[i]))
+                        }
+                        Some(&2) if i + 4 < count => {
+                            let channel = |j: usize| self.params[i + j].min(255) as u32;
+                            let rgb = channel(2) << 16 | channel(3) << 8 | channel(4);
+                            i += 4;
+                            Some(Color::Rgb(rgb))
+                        }
+                        _ => None,
+                    };
+                    match (p, color) {
+                        (38, Some(color)) => self.fg = color,
+                        (_, Some(color)) => self.bg = color,
+                        (_, None) => {}
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Starts mirroring the console on the framebuffer at `base`, with 32-bit `0x00RRGGBB` pixels
+///
+/// # Safety
+///
+/// The framebuffer must be memory that nothing else uses, except for the display
+pub unsafe fn init(base: PhyAddr, width: u32, height: u32, stride: u32) -> Result<(), KError> {
+    let (width, height, stride) = (width as usize, height as usize, stride as usize);
+    if stride % size_of::<u32>() != 0 || stride < width * size_of::<u32>() {
+        return Err(KError::InvalidArgument);
+    }
+    let (cols, rows) = (width / font::WIDTH, height / font::HEIGHT);
+    if cols == 0 || rows == 0 {
+        return Err(KError::InvalidArgument);
+    }
+    info!(
+        "drv",
+        "Framebuffer console at {:?}: {}x{} pixels, {}x{} characters",
+        base,
+        width,
+        height,
+        cols,
+        rows
+    );
+
+    let mut fbcon = FbCon {
+        pixels: base.virt_mut(),
+        stride: stride / size_of::<u32>(),
+        cols,
+        rows,
+        x: 0,
+        y: 0,
+        wrap_pending: false,
+        saved: (0, 0),
+        fg: DEFAULT_FG,
+        bg: DEFAULT_BG,
+        bold: false,
+        reverse: false,
+        cursor_visible: true,
+        cursor_drawn: false,
+        state: State::Normal,
+        params: [0; MAX_PARAMS],
+        param_count: 0,
+        private: false,
+    };
+    fbcon.reset();
+    fbcon.toggle_cursor();
+    *FBCON.lock() = Some(fbcon);
+    Ok(())
+}
+
+/// Draws `s`, if there's a framebuffer console
+pub fn write(s: &[u8]) {
+    let mut fbcon = FBCON.lock();
+    let Some(fbcon) = fbcon.as_mut() else {
+        return;
+    };
+    if fbcon.cursor_drawn {
+        fbcon.toggle_cursor();
+    }
+    for &ch in s {
+        fbcon.put(ch);
+    }
+    if fbcon.cursor_visible {
+        fbcon.toggle_cursor();
+    }
+}
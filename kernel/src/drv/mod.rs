@@ -1,3 +1,4 @@
 pub mod arm_gic;
-pub mod fw_cfg;
+pub mod fbcon;
+pub mod pl011;
 pub mod qemu_console;
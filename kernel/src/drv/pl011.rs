@@ -0,0 +1,172 @@
+//! ARM PrimeCell PL011 UART
+//!
+//! The first UART is the console (see [`crate::drv::qemu_console`]), a second one can be used by
+//! the GDB stub.
+
+use crate::drv::{arm_gic, qemu_console};
+use crate::info;
+use crate::page_alloc::PhyAddr;
+use core::ptr::{read_volatile, write_volatile};
+
+/// Where QEMU's virt machine puts the first UART, used until the DTB tells us otherwise
+const EARLY_UART_PHYS: usize = 0x9000000;
+
+const UARTDR: usize = 0x00;
+const UARTFR: usize = 0x18;
+const UARTIBRD: usize = 0x24;
+const UARTFBRD: usize = 0x28;
+const UARTLCR_H: usize = 0x2C;
+const UARTCR: usize = 0x30;
+const UARTIFLS: usize = 0x34;
+const UARTIMSC: usize = 0x38;
+const UARTICR: usize = 0x44;
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+const LCR_H_FEN: u32 = 1 << 4;
+const LCR_H_WLEN_8: u32 = 0b11 << 5;
+
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+/// Receive interrupt (FIFO reached its trigger level)
+const INT_RX: u32 = 1 << 4;
+/// Receive timeout interrupt (FIFO has data, but not enough to trigger `INT_RX`)
+const INT_RT: u32 = 1 << 6;
+const INT_ALL: u32 = 0x7ff;
+
+/// Trigger the RX interrupt when the FIFO is 1/8 full, for responsive input
+const IFLS_RX_1_8: u32 = 0b000 << 3;
+const IFLS_TX_1_2: u32 = 0b010;
+
+const BAUD_RATE: u32 = 115200;
+
+#[derive(Copy, Clone)]
+pub struct Pl011 {
+    /// Virtual address of the registers
+    base: usize,
+}
+
+impl Pl011 {
+    /// # Safety
+    ///
+    /// `base` must be the mapped registers of a PL011
+    pub const unsafe fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    unsafe fn mmio_write(&self, offset: usize, val: u32) {
+        write_volatile((self.base + offset) as *mut u32, val);
+    }
+
+    unsafe fn mmio_read(&self, offset: usize) -> u32 {
+        read_volatile((self.base + offset) as *const u32)
+    }
+
+    pub fn putc(&self, ch: u8) {
+        unsafe {
+            // Wait for room in the TX FIFO
+            while self.mmio_read(UARTFR) & FR_TXFF != 0 {
+                core::hint::spin_loop();
+            }
+            self.mmio_write(UARTDR, ch as u32);
+        }
+    }
+
+    /// Reads a character from the RX FIFO, if there's one
+    pub fn getc(&self) -> Option<u8> {
+        unsafe {
+            if self.mmio_read(UARTFR) & FR_RXFE != 0 {
+                None
+            } else {
+                Some(self.mmio_read(UARTDR) as u8)
+            }
+        }
+    }
+
+    /// Waits until all pending output was sent
+    pub fn flush(&self) {
+        unsafe {
+            while self.mmio_read(UARTFR) & FR_BUSY != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Configures the UART for 8n1 at [`BAUD_RATE`], with receive interrupts enabled
+    pub fn configure(&self, clock_hz: u32) {
+        unsafe {
+            // Disable the UART while configuring it
+            self.mmio_write(UARTCR, 0);
+
+            // Divisor = clock / (16 * baud), with 6 fractional bits
+            let divisor_x64 =
+                ((clock_hz as u64 * 4 + BAUD_RATE as u64 / 2) / BAUD_RATE as u64) as u32;
+            self.mmio_write(UARTIBRD, divisor_x64 >> 6);
+            self.mmio_write(UARTFBRD, divisor_x64 & 0x3f);
+            self.mmio_write(UARTLCR_H, LCR_H_FEN | LCR_H_WLEN_8);
+
+            self.mmio_write(UARTIFLS, IFLS_RX_1_8 | IFLS_TX_1_2);
+            self.mmio_write(UARTICR, INT_ALL);
+            self.mmio_write(UARTIMSC, INT_RX | INT_RT);
+
+            self.mmio_write(UARTCR, CR_UARTEN | CR_TXE | CR_RXE);
+        }
+    }
+
+    pub fn clear_rx_interrupts(&self) {
+        unsafe { self.mmio_write(UARTICR, INT_RX | INT_RT) };
+    }
+}
+
+static mut CONSOLE_UART: Pl011 = unsafe { Pl011::new(EARLY_UART_PHYS) };
+static mut INTERRUPT_ID: Option<u32> = None;
+
+fn console_uart() -> Pl011 {
+    unsafe { (&raw const CONSOLE_UART).read() }
+}
+
+pub fn putc(ch: u8) {
+    console_uart().putc(ch);
+}
+
+/// Switches to the high-mem device mapping of the early UART
+pub fn eject_lowmem() {
+    unsafe {
+        (&raw mut CONSOLE_UART).write(Pl011::new(
+            PhyAddr(EARLY_UART_PHYS).virt_dev_mut::<u8>() as usize
+        ));
+    }
+}
+
+/// Reconfigures the console UART found in the DTB, and enables receive interrupts
+///
+/// # Safety
+///
+/// The GIC must be initialized
+pub unsafe fn init(base: PhyAddr, clock_hz: u32, interrupt_id: u32) {
+    // Let pending output drain before switching UARTs
+    console_uart().flush();
+    (&raw mut CONSOLE_UART).write(Pl011::new(base.virt_dev_mut::<u8>() as usize));
+    info!("drv", "Initializing PL011 UART at {:?}", base);
+
+    console_uart().configure(clock_hz);
+
+    (&raw mut INTERRUPT_ID).write(Some(interrupt_id));
+    arm_gic::enable_kernel_interrupt(interrupt_id);
+}
+
+pub fn interrupt_id() -> Option<u32> {
+    unsafe { (&raw const INTERRUPT_ID).read() }
+}
+
+pub unsafe fn handle_irq() {
+    let uart = console_uart();
+    while let Some(ch) = uart.getc() {
+        qemu_console::push_input(ch);
+    }
+    uart.clear_rx_interrupts();
+}
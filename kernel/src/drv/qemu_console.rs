@@ -1,17 +1,166 @@
+use crate::aarch64::interrupts::IrqMutex;
+use crate::drv::{fbcon, pl011};
 use core::mem::size_of;
+use kernel_api::ConsoleFlags;
 
-static mut UART0_ADDR: *mut u8 = 0x9000000 as *mut u8;
+const READY_BUF_SIZE: usize = 1024;
+const LINE_BUF_SIZE: usize = 256;
 
-pub fn putc(ch: u8) {
-    unsafe {
-        UART0_ADDR.write_volatile(ch);
+struct Console {
+    flags: ConsoleFlags,
+    /// Input that can be read, as a ring buffer
+    ready: [u8; READY_BUF_SIZE],
+    ready_start: usize,
+    ready_len: usize,
+    /// The line being edited, in canonical mode
+    line: [u8; LINE_BUF_SIZE],
+    line_len: usize,
+}
+
+impl Console {
+    const fn new() -> Self {
+        Self {
+            flags: ConsoleFlags::Canonical.union(ConsoleFlags::Echo),
+            ready: [0; READY_BUF_SIZE],
+            ready_start: 0,
+            ready_len: 0,
+            line: [0; LINE_BUF_SIZE],
+            line_len: 0,
+        }
+    }
+
+    /// Returns false if the buffer is full
+    fn push_ready(&mut self, ch: u8) -> bool {
+        if self.ready_len == READY_BUF_SIZE {
+            return false;
+        }
+        self.ready[(self.ready_start + self.ready_len) % READY_BUF_SIZE] = ch;
+        self.ready_len += 1;
+        true
+    }
+
+    fn pop_ready(&mut self) -> Option<u8> {
+        if self.ready_len == 0 {
+            return None;
+        }
+        let ch = self.ready[self.ready_start];
+        self.ready_start = (self.ready_start + 1) % READY_BUF_SIZE;
+        self.ready_len -= 1;
+        Some(ch)
     }
 }
 
+static CONSOLE: IrqMutex<Console> = IrqMutex::new(Console::new());
+
+pub fn putc(ch: u8) {
+    pl011::putc(ch);
+    fbcon::write(&[ch]);
+}
+
 pub fn puts(s: &[u8]) {
+    let crlf = CONSOLE.lock().flags.contains(ConsoleFlags::Crlf);
     for &ch in s {
-        putc(ch);
+        if crlf && ch == b'\n' {
+            pl011::putc(b'\r');
+        }
+        pl011::putc(ch);
+    }
+    fbcon::write(s);
+}
+
+pub fn flags() -> ConsoleFlags {
+    CONSOLE.lock().flags
+}
+
+/// Sets the console mode, returning the previous one
+pub fn set_flags(flags: ConsoleFlags) -> ConsoleFlags {
+    let mut console = CONSOLE.lock();
+    let prev_flags = console.flags;
+    console.flags = flags;
+    if !flags.contains(ConsoleFlags::Canonical) {
+        // Don't strand a partially edited line when leaving canonical mode
+        let line_len = console.line_len;
+        for i in 0..line_len {
+            let ch = console.line[i];
+            console.push_ready(ch);
+        }
+        console.line_len = 0;
+    }
+    prev_flags
+}
+
+/// Handles a character received by the UART, or typed on another input device
+pub fn push_input(ch: u8) {
+    let mut echo = [0u8; 3];
+    let mut echo_len = 0;
+    let echo_enabled;
+    {
+        let mut console = CONSOLE.lock();
+        echo_enabled = console.flags.contains(ConsoleFlags::Echo);
+        if !console.flags.contains(ConsoleFlags::Canonical) {
+            if console.push_ready(ch) {
+                echo[0] = ch;
+                echo_len = 1;
+            }
+        } else {
+            match ch {
+                b'\r' | b'\n' => {
+                    // Commit the line, if there's room for all of it
+                    let line_len = console.line_len;
+                    if console.ready_len + line_len < READY_BUF_SIZE {
+                        for i in 0..line_len {
+                            let ch = console.line[i];
+                            console.push_ready(ch);
+                        }
+                        console.push_ready(b'\n');
+                        console.line_len = 0;
+                        echo[..2].copy_from_slice(b"\r\n");
+                        echo_len = 2;
+                    }
+                }
+                // Backspace / delete
+                0x08 | 0x7f => {
+                    if console.line_len > 0 {
+                        console.line_len -= 1;
+                        echo.copy_from_slice(b"\x08 \x08");
+                        echo_len = 3;
+                    }
+                }
+                _ => {
+                    if console.line_len < LINE_BUF_SIZE {
+                        let line_len = console.line_len;
+                        console.line[line_len] = ch;
+                        console.line_len += 1;
+                        echo[0] = ch;
+                        echo_len = 1;
+                    }
+                }
+            }
+        }
+    }
+    if echo_enabled {
+        for &ch in &echo[..echo_len] {
+            putc(ch);
+        }
+    }
+}
+
+/// Reads available input without blocking. In canonical mode, stops after the end of a line.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut console = CONSOLE.lock();
+    let canonical = console.flags.contains(ConsoleFlags::Canonical);
+    let mut count = 0;
+    while count < buf.len() {
+        let Some(ch) = console.pop_ready() else {
+            break;
+        };
+        buf[count] = ch;
+        count += 1;
+        if canonical && ch == b'\n' {
+            break;
+        }
     }
+    count
 }
 
 pub(crate) struct FmtWriteAdapter;
@@ -77,8 +226,5 @@ pub fn dump_hex_slice(val: &[u8]) {
 }
 
 pub fn eject_lowmem() {
-    unsafe {
-        let new_value = (UART0_ADDR as u64) | 0xffff_ff00_0000_0000;
-        UART0_ADDR = new_value as *mut u8;
-    }
+    pl011::eject_lowmem();
 }
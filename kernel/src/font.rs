@@ -0,0 +1,66 @@
+//! The 8x16 bitmap font of the framebuffer console
+//!
+//! The glyphs are drawn in `font8x16.txt`, rows of 16 characters from `' '` up to DEL, which
+//! doubles as the glyph for everything the font lacks. `#` is a set pixel and `.` a clear one,
+//! lines starting with `;` are comments. It's decoded when compiling, to a byte per glyph row
+//! with the leftmost pixel in the highest bit.
+
+pub const WIDTH: usize = 8;
+pub const HEIGHT: usize = 16;
+
+const FIRST: u8 = b' ';
+const COUNT: usize = 96;
+const GLYPHS_PER_LINE: usize = 16;
+
+static GLYPHS: [[u8; HEIGHT]; COUNT] = decode(include_bytes!("font8x16.txt"));
+
+const fn decode(src: &[u8]) -> [[u8; HEIGHT]; COUNT] {
+    let mut glyphs = [[0u8; HEIGHT]; COUNT];
+    let mut line = 0;
+    let mut pos = 0;
+    while pos < src.len() {
+        let start = pos;
+        while pos < src.len() && src[pos] != b'\n' {
+            pos += 1;
+        }
+        let len = pos - start;
+        pos += 1;
+        if len == 0 || src[start] == b';' {
+            continue;
+        }
+        assert!(
+            len == GLYPHS_PER_LINE * (WIDTH + 1) - 1,
+            "Font line has the wrong length"
+        );
+        let row = line % HEIGHT;
+        let mut i = 0;
+        while i < GLYPHS_PER_LINE {
+            let mut bits = 0u8;
+            let mut x = 0;
+            while x < WIDTH {
+                match src[start + i * (WIDTH + 1) + x] {
+                    b'#' => bits |= 0x80 >> x,
+                    b'.' => {}
+                    _ => panic!("Font pixels must be '#' or '.'"),
+                }
+                x += 1;
+            }
+            glyphs[line / HEIGHT * GLYPHS_PER_LINE + i][row] = bits;
+            i += 1;
+        }
+        line += 1;
+    }
+    assert!(
+        line == COUNT / GLYPHS_PER_LINE * HEIGHT,
+        "Font is incomplete"
+    );
+    glyphs
+}
+
+/// The rows of the glyph for `ch`, top to bottom
+pub fn glyph(ch: u8) -> &'static [u8; HEIGHT] {
+    match ch {
+        FIRST..=0x7e => &GLYPHS[(ch - FIRST) as usize],
+        _ => &GLYPHS[COUNT - 1],
+    }
+}
@@ -0,0 +1,262 @@
+//! Kernel log: leveled and tagged records, kept in a ring buffer until usermode drains them with
+//! `LogRead`, and echoed to the console.
+//!
+//! Records are filtered by level, per tag, before they are even formatted, so verbose tags (e.g.
+//! `mmu` at [`Level::Trace`]) cost little when disabled.
+
+use crate::aarch64::interrupts::IrqMutex;
+use crate::clock;
+use crate::println;
+use core::fmt::Write;
+use kernel_api::clock::ticks_to_ns;
+use kernel_api::klog::{Record, RecordHeader, MAX_MESSAGE_LEN, MAX_TAG_LEN};
+use kernel_api::KError;
+use zerocopy::{FromZeros, IntoBytes};
+
+pub use kernel_api::klog::Level;
+
+const BUF_SIZE: usize = 16 * 1024;
+const MAX_TAG_FILTERS: usize = 16;
+
+const DEFAULT_LEVEL: Level = Level::Debug;
+const DEFAULT_CONSOLE_LEVEL: Level = Level::Info;
+
+#[derive(Copy, Clone)]
+struct TagFilter {
+    tag: [u8; MAX_TAG_LEN],
+    tag_len: usize,
+    level: Level,
+}
+
+impl TagFilter {
+    fn tag(&self) -> &[u8] {
+        &self.tag[..self.tag_len]
+    }
+}
+
+struct Log {
+    /// Serialized records (see [`kernel_api::klog`]), as a ring buffer
+    buf: [u8; BUF_SIZE],
+    start: usize,
+    len: usize,
+    /// Records above this level are dropped, unless their tag has a filter
+    default_level: Level,
+    filters: [Option<TagFilter>; MAX_TAG_FILTERS],
+    /// Records above this level are only kept in the ring buffer
+    console_level: Level,
+}
+
+impl Log {
+    const fn new() -> Self {
+        Self {
+            buf: [0; BUF_SIZE],
+            start: 0,
+            len: 0,
+            default_level: DEFAULT_LEVEL,
+            filters: [None; MAX_TAG_FILTERS],
+            console_level: DEFAULT_CONSOLE_LEVEL,
+        }
+    }
+
+    fn level_for(&self, tag: &str) -> Level {
+        self.filters
+            .iter()
+            .flatten()
+            .find(|filter| filter.tag() == tag.as_bytes())
+            .map_or(self.default_level, |filter| filter.level)
+    }
+
+    fn copy_out(&self, offset: usize, target: &mut [u8]) {
+        for (i, byte) in target.iter_mut().enumerate() {
+            *byte = self.buf[(self.start + offset + i) % BUF_SIZE];
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[(self.start + self.len) % BUF_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn peek_header(&self) -> Option<RecordHeader> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut header = RecordHeader::new_zeroed();
+        self.copy_out(0, header.as_mut_bytes());
+        Some(header)
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(header) = self.peek_header() {
+            let record_len = header.record_len();
+            self.start = (self.start + record_len) % BUF_SIZE;
+            self.len -= record_len;
+        }
+    }
+
+    fn push(&mut self, header: &RecordHeader, tag: &[u8], message: &[u8]) {
+        while self.len + header.record_len() > BUF_SIZE {
+            self.drop_oldest();
+        }
+        self.push_bytes(header.as_bytes());
+        self.push_bytes(tag);
+        self.push_bytes(message);
+    }
+}
+
+static LOG: IrqMutex<Log> = IrqMutex::new(Log::new());
+
+/// Formats into a fixed buffer, truncating what doesn't fit
+struct MessageBuf {
+    buf: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for MessageBuf {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut count = s.len().min(MAX_MESSAGE_LEN - self.len);
+        // Don't split a UTF-8 character
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Whether records of this level and tag are kept, used by [`log!`] to skip formatting
+pub fn enabled(level: Level, tag: &str) -> bool {
+    level <= LOG.lock().level_for(tag)
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, tag: &str, args: core::fmt::Arguments) {
+    let mut message = MessageBuf {
+        buf: [0; MAX_MESSAGE_LEN],
+        len: 0,
+    };
+    let _ = message.write_fmt(args);
+    let tag = &tag[..tag.len().min(MAX_TAG_LEN)];
+
+    let header = RecordHeader {
+        timestamp_ns: ticks_to_ns(clock::counter(), clock::counter_freq()),
+        level: level.into(),
+        tag_len: tag.len() as u8,
+        message_len: message.len as u16,
+        _padding: 0,
+    };
+    let console_level = {
+        let mut log = LOG.lock();
+        log.push(&header, tag.as_bytes(), &message.buf[..message.len]);
+        log.console_level
+    };
+
+    if level <= console_level {
+        let record = Record {
+            timestamp_ns: header.timestamp_ns,
+            level,
+            tag,
+            // Only whole characters were written
+            message: core::str::from_utf8(&message.buf[..message.len]).unwrap_or("<invalid>"),
+        };
+        println!("{}", record);
+    }
+}
+
+/// Moves whole records to `buf`, oldest first, returning the amount of bytes written
+pub fn read(buf: &mut [u8]) -> Result<usize, KError> {
+    let mut log = LOG.lock();
+    let mut count = 0;
+    while let Some(header) = log.peek_header() {
+        let record_len = header.record_len();
+        if count + record_len > buf.len() {
+            if count == 0 {
+                // Would never make progress
+                return Err(KError::InvalidArgument);
+            }
+            break;
+        }
+        log.copy_out(0, &mut buf[count..count + record_len]);
+        log.drop_oldest();
+        count += record_len;
+    }
+    Ok(count)
+}
+
+/// Sets the maximum level of records kept for `tag`, or for all tags without a filter if `None`
+pub fn set_level(tag: Option<&[u8]>, level: Level) -> Result<(), KError> {
+    let mut log = LOG.lock();
+    let Some(tag) = tag else {
+        log.default_level = level;
+        return Ok(());
+    };
+    if tag.len() > MAX_TAG_LEN {
+        return Err(KError::InvalidArgument);
+    }
+
+    let filters = &mut log.filters;
+    if let Some(filter) = filters.iter_mut().flatten().find(|f| f.tag() == tag) {
+        filter.level = level;
+        return Ok(());
+    }
+    let slot = filters
+        .iter_mut()
+        .find(|f| f.is_none())
+        .ok_or(KError::OOM)?;
+    let mut filter = TagFilter {
+        tag: [0; MAX_TAG_LEN],
+        tag_len: tag.len(),
+        level,
+    };
+    filter.tag[..tag.len()].copy_from_slice(tag);
+    *slot = Some(filter);
+    Ok(())
+}
+
+/// Sets the maximum level of records echoed to the console, returning the previous one
+pub fn set_console_level(level: Level) -> Level {
+    let mut log = LOG.lock();
+    let prev_level = log.console_level;
+    log.console_level = level;
+    prev_level
+}
+
+/// Logs a record with the given level and tag (subsystem), e.g. `log!(Level::Info, "drv", ...)`
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $tag:expr, $($arg:tt)*) => {{
+        let level = $level;
+        let tag = $tag;
+        if $crate::klog::enabled(level, tag) {
+            $crate::klog::_log(level, tag, format_args!($($arg)*));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($tag:expr, $($arg:tt)*) => ($crate::log!($crate::klog::Level::Error, $tag, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($tag:expr, $($arg:tt)*) => ($crate::log!($crate::klog::Level::Warn, $tag, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($tag:expr, $($arg:tt)*) => ($crate::log!($crate::klog::Level::Info, $tag, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($tag:expr, $($arg:tt)*) => ($crate::log!($crate::klog::Level::Debug, $tag, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($tag:expr, $($arg:tt)*) => ($crate::log!($crate::klog::Level::Trace, $tag, $($arg)*));
+}
@@ -3,16 +3,25 @@
 
 use crate::aarch64::interrupts;
 use crate::aarch64::mmu::eject_lowmem;
+use crate::aarch64::psci;
 use crate::page_alloc::PhyAddr;
 use aarch64::{mmu, usermode};
 use aarch64_cpu::registers::CurrentEL;
 use core::arch::asm;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU8, Ordering};
+use kernel_api::cmdline::PanicAction;
 use tock_registers::interfaces::Readable;
 
 pub mod aarch64;
+mod clock;
+mod cmdline;
 mod drv;
+mod font;
+pub mod klog;
 pub mod page_alloc;
+mod random;
+mod symbols;
 
 type InitFn = unsafe extern "C" fn() -> !;
 
@@ -39,11 +48,12 @@ pub unsafe extern "C" fn kmain_nommu() -> ! {
 pub unsafe extern "C" fn kmain() -> ! {
     eject_lowmem();
     println!("--- BoldOS ---");
-    println!("alloc: Initializing early allocator");
+    info!("alloc", "Initializing early allocator");
     page_alloc::init_early_heap();
+    clock::init();
     interrupts::enable();
     usermode::start();
-    println!("Sleeping forever");
+    info!("kernel", "Sleeping forever");
     loop {
         unsafe { asm!("wfi") }
     }
@@ -55,8 +65,24 @@ fn rust_panic(info: &PanicInfo) -> ! {
     if let Some(location) = info.location() {
         println!("location: {}", location);
     }
+    aarch64::backtrace::print_current();
 
+    let action = PANIC_ACTION.load(Ordering::Relaxed);
+    if action == PanicAction::Reboot as u8 {
+        println!("Rebooting");
+        println!("Failed to reboot: {:?}", psci::system_reset());
+    } else if action == PanicAction::PowerOff as u8 {
+        println!("Powering off");
+        println!("Failed to power off: {:?}", psci::system_off());
+    }
     loop {
         unsafe { asm!("wfi") }
     }
 }
+
+/// What a panic ends with, a [`PanicAction`] set by the `panic` boot option
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
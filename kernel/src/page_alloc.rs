@@ -1,11 +1,13 @@
 use crate::aarch64::interrupts::IrqMutex;
-use crate::{print, println};
+use crate::{print, println, trace};
 use core::fmt::{Debug, Formatter};
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
 use core::ptr::{drop_in_place, slice_from_raw_parts, slice_from_raw_parts_mut, write_bytes};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::{fmt, mem};
 use elain::Align;
+use kernel_api::KError;
 use zerocopy::FromZeros;
 
 pub const PAGE_SIZE: usize = 4096;
@@ -304,8 +306,9 @@ impl Drop for PageSlice {
     fn drop(&mut self) {
         unsafe {
             // Overwrite the page with poison
-            #[cfg(debug_assertions)]
-            write_bytes(self.buf as *mut u8, 0xa1, self.len);
+            if cfg!(debug_assertions) || overwrite_free_pages_enabled() {
+                write_bytes(self.buf as *mut u8, 0xa1, self.len);
+            }
 
             // Free the pages
             #[allow(static_mut_refs)]
@@ -329,9 +332,9 @@ impl<const SIZE: usize> BitmapPageAlloc<SIZE> {
 
     pub fn alloc(&mut self, page_count: usize) -> Option<PageSlice> {
         if let Some(page_num) = self.bitmap_alloc.alloc(page_count) {
-            #[cfg(feature = "log_alloc")]
-            println!(
-                "alloc: 0x{:x} - pages: {page_count}",
+            trace!(
+                "alloc",
+                "allocated 0x{:x} - pages: {page_count}",
                 self.ram_base + page_num * PAGE_SIZE
             );
             Some(PageSlice {
@@ -363,9 +366,30 @@ impl<const SIZE: usize> BitmapPageAlloc<SIZE> {
             .mark_allocated((addr - self.ram_base) / 4096, page_count);
     }
 
+    /// Marks pages as allocated, if they're all within RAM and free
+    pub fn reserve(&mut self, addr: usize, page_count: usize) -> Result<(), KError> {
+        if addr < self.ram_base
+            || addr + page_count * PAGE_SIZE
+                > self.ram_base + self.bitmap_alloc.bit_capacity() * PAGE_SIZE
+        {
+            return Err(KError::InvalidArgument);
+        }
+        let page_num = (addr - self.ram_base) / PAGE_SIZE;
+        if self
+            .bitmap_alloc
+            .iter()
+            .skip(page_num)
+            .take(page_count)
+            .any(|allocated| allocated)
+        {
+            return Err(KError::AlreadyExists);
+        }
+        self.bitmap_alloc.mark_allocated(page_num, page_count);
+        Ok(())
+    }
+
     pub fn free(&mut self, addr: usize, page_count: usize) {
-        #[cfg(feature = "log_alloc")]
-        println!("free: 0x{addr:x} - pages: {page_count}");
+        trace!("alloc", "freed 0x{addr:x} - pages: {page_count}");
         debug_assert!(addr % PAGE_SIZE == 0, "addr must be page-aligned");
         debug_assert!(addr >= self.ram_base, "addr was before RAM");
         debug_assert!(
@@ -377,7 +401,6 @@ impl<const SIZE: usize> BitmapPageAlloc<SIZE> {
             .free((addr - self.ram_base) / 4096, page_count);
     }
 
-    #[allow(dead_code)]
     pub fn overwrite_free_pages(&self) {
         let mut counter = 0;
         print!("Cleaning RAM: ");
@@ -444,10 +467,36 @@ impl<const SIZE: usize> BitmapPageAlloc<SIZE> {
     }
 }
 
+/// Whether free pages are overwritten with poison, see the `overwrite_free_pages` boot option
+static OVERWRITE_FREE_PAGES: AtomicBool = AtomicBool::new(false);
+
+fn overwrite_free_pages_enabled() -> bool {
+    OVERWRITE_FREE_PAGES.load(Ordering::Relaxed)
+}
+
+/// Enables poisoning free pages, starting with the ones that are free right now
+pub fn set_overwrite_free_pages(enabled: bool) {
+    let was_enabled = OVERWRITE_FREE_PAGES.swap(enabled, Ordering::Relaxed);
+    if enabled && !was_enabled {
+        PAGE_ALLOC.lock().overwrite_free_pages();
+    }
+}
+
 pub fn alloc(page_count: usize) -> PageSlice {
     PAGE_ALLOC.lock().alloc(page_count).expect("OOM")
 }
 
+/// Keeps the allocator away from a region of RAM that's already in use, like the initrd
+pub fn reserve(region: PhySlice) -> Result<(), KError> {
+    let start = region.base.0 / PAGE_SIZE * PAGE_SIZE;
+    let end = (region.base.0 + region.len).next_multiple_of(PAGE_SIZE);
+    trace!("alloc", "reserve: {:?}", region);
+    let virt_start = unsafe { PhyAddr(start).virt::<()>() } as usize;
+    PAGE_ALLOC
+        .lock()
+        .reserve(virt_start, (end - start) / PAGE_SIZE)
+}
+
 pub struct PageBox<T> {
     slice: PageSlice,
     _phantom_data: PhantomData<T>,
@@ -567,5 +616,9 @@ pub fn add_memory_node(phy_addr: PhyAddr, len: usize) {
         );
         // TODO: mark all pages currently mapped to usermode instead
         page_alloc.mark_allocated(dtb_region.0, (dtb_region.1 - dtb_region.0) / PAGE_SIZE);
+
+        if overwrite_free_pages_enabled() {
+            page_alloc.overwrite_free_pages();
+        }
     }
 }
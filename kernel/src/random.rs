@@ -0,0 +1,188 @@
+//! The entropy pool, a ChaCha20-based CSPRNG
+//!
+//! Entropy is absorbed into a 256-bit key: the input is XORed in, then the key is replaced by a
+//! ChaCha20 block of itself, whose feed-forward makes the mixing one-way. Output is ChaCha20
+//! keystream, after which the key is replaced again, so past output can't be recovered from the
+//! pool's state ("fast key erasure").
+//!
+//! Sources are usermode drivers, e.g. virtio-rng, and the jitter of interrupt timestamps. Output
+//! is only given out once enough entropy has been credited.
+
+use crate::aarch64::interrupts::{self, IrqMutex};
+use crate::clock;
+use crate::drv::arm_gic;
+use crate::info;
+
+/// Entropy to credit before the pool is considered seeded
+const SEED_BITS: u32 = 256;
+/// Interrupt timestamps are only worth a little: 1 bit for this many samples
+const JITTER_SAMPLES_PER_BIT: u32 = 64;
+/// How often to wake up while waiting to be seeded with nothing else going on
+const JITTER_INTERVAL_NS: u64 = 10_000;
+
+/// Nonces separating the uses of the key, so mixing and output never share a keystream
+const MIX_NONCE: u64 = 0;
+const OUTPUT_NONCE: u64 = 1;
+
+/// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// A ChaCha20 block, with the original 64-bit counter and 64-bit nonce
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+struct Pool {
+    key: [u32; 8],
+    /// Credited entropy, saturating at [`SEED_BITS`]
+    entropy_bits: u32,
+    /// Interrupt timestamps not mixed in yet
+    jitter: u64,
+    jitter_samples: u32,
+}
+
+impl Pool {
+    const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            entropy_bits: 0,
+            jitter: 0,
+            jitter_samples: 0,
+        }
+    }
+
+    fn is_seeded(&self) -> bool {
+        self.entropy_bits >= SEED_BITS
+    }
+
+    /// Mixes `data` into the key, 32 bytes at a time
+    fn absorb(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (i, bytes) in chunk.chunks(4).enumerate() {
+                let mut word = [0u8; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                self.key[i] ^= u32::from_le_bytes(word);
+            }
+            // The chunk length keeps inputs that differ only by trailing zeros apart
+            let block = chacha20_block(&self.key, chunk.len() as u64, MIX_NONCE);
+            self.key.copy_from_slice(&block[..8]);
+        }
+    }
+
+    fn credit(&mut self, bits: u32) {
+        let was_seeded = self.is_seeded();
+        self.entropy_bits = self.entropy_bits.saturating_add(bits).min(SEED_BITS);
+        if !was_seeded && self.is_seeded() {
+            info!("random", "Entropy pool seeded");
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        // Counter 0 is kept for the next key
+        for (counter, chunk) in buf.chunks_mut(64).enumerate() {
+            let block = chacha20_block(&self.key, counter as u64 + 1, OUTPUT_NONCE);
+            for (bytes, word) in chunk.chunks_mut(4).zip(block) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+        let block = chacha20_block(&self.key, 0, OUTPUT_NONCE);
+        self.key.copy_from_slice(&block[..8]);
+    }
+}
+
+static POOL: IrqMutex<Pool> = IrqMutex::new(Pool::new());
+
+/// Mixes `data` into the pool, crediting it with `credit_bits` of entropy
+pub fn add_entropy(data: &[u8], credit_bits: u32) {
+    let mut pool = POOL.lock();
+    pool.absorb(data);
+    pool.credit(credit_bits);
+}
+
+/// Records the time of an interrupt, called by the interrupt handler. Cheap, samples are only
+/// mixed in once enough were gathered.
+pub fn add_interrupt_jitter() {
+    let mut pool = POOL.lock();
+    pool.jitter = pool.jitter.rotate_left(7) ^ clock::counter();
+    pool.jitter_samples += 1;
+    if pool.jitter_samples == JITTER_SAMPLES_PER_BIT {
+        let jitter = pool.jitter;
+        pool.absorb(&jitter.to_le_bytes());
+        pool.credit(1);
+        pool.jitter_samples = 0;
+    }
+}
+
+/// Zeroes a buffer that held random bytes or entropy, with writes the compiler can't drop even
+/// though the buffer isn't read again
+pub fn wipe(buf: &mut [u8]) {
+    for byte in buf {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+}
+
+pub fn is_seeded() -> bool {
+    POOL.lock().is_seeded()
+}
+
+/// Fills `buf` with random bytes, or returns false if the pool isn't seeded yet
+pub fn try_fill(buf: &mut [u8]) -> bool {
+    let mut pool = POOL.lock();
+    if !pool.is_seeded() {
+        return false;
+    }
+    pool.fill(buf);
+    true
+}
+
+/// Blocks until the pool is seeded. Interrupts add jitter, so with nothing else feeding the pool
+/// this wakes up often to get some.
+pub fn wait_until_seeded() {
+    while !is_seeded() {
+        if arm_gic::is_initialized() {
+            unsafe {
+                arm_gic::timer_set_timeout(JITTER_INTERVAL_NS);
+                interrupts::wait_for_interrupt();
+            }
+        } else {
+            // No interrupts yet, the timing of a busy loop will have to do
+            for _ in 0..100 {
+                core::hint::spin_loop();
+            }
+            add_interrupt_jitter();
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Address to function name lookup, for backtraces
+//!
+//! Symbol tables are text, one `<hex address> <hex size> <name>` line per function sorted by
+//! address, as printed by `scripts/symbols.sh`. The kernel's own table is patched into the `.ksyms` section
+//! after linking, so it's empty when the kernel wasn't started through `scripts/run.sh`.
+
+use core::fmt::{Display, Formatter};
+
+static INIT_SYMBOLS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/init.syms"));
+
+#[derive(Copy, Clone)]
+pub struct SymbolTable(&'static [u8]);
+
+impl SymbolTable {
+    pub fn kernel() -> Self {
+        extern "C" {
+            static _ksyms_start: u8;
+            static _ksyms_end: u8;
+        }
+        let table = unsafe {
+            let start = &raw const _ksyms_start;
+            let len = &raw const _ksyms_end as usize - start as usize;
+            core::slice::from_raw_parts(start, len)
+        };
+        // The table is zero-padded to the size of the section
+        let len = table.iter().position(|&b| b == 0).unwrap_or(table.len());
+        Self(&table[..len])
+    }
+
+    pub fn init() -> Self {
+        Self(INIT_SYMBOLS)
+    }
+
+    /// Finds the function containing `addr`, returns its name and the offset into it. Symbols
+    /// without a size end where the next one starts, so nothing past the last one matches them.
+    pub fn lookup(&self, addr: usize) -> Option<(&'static str, usize)> {
+        let mut found = None;
+        for line in self.0.split(|&b| b == b'\n') {
+            let Some((sym_addr, size, name)) = parse_line(line) else {
+                continue;
+            };
+            if sym_addr > addr {
+                return found.map(|(name, offset, _)| (name, offset));
+            }
+            let offset = addr - sym_addr;
+            found = (size == 0 || offset < size).then_some((name, offset, size));
+        }
+        found
+            .filter(|&(_, _, size)| size != 0)
+            .map(|(name, offset, _)| (name, offset))
+    }
+
+    /// Wraps an address so it's displayed along with its symbol
+    pub fn symbolize(self, addr: usize) -> Symbolized {
+        Symbolized { table: self, addr }
+    }
+}
+
+fn parse_line(line: &'static [u8]) -> Option<(usize, usize, &'static str)> {
+    let line = core::str::from_utf8(line).ok()?;
+    let (addr, rest) = line.split_once(' ')?;
+    let (size, name) = rest.split_once(' ')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(size, 16).ok()?,
+        name,
+    ))
+}
+
+pub struct Symbolized {
+    table: SymbolTable,
+    addr: usize,
+}
+
+/// Formats like `0xffffff0040101234 (kernel::kmain+0x34)`
+impl Display for Symbolized {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "0x{:016x}", self.addr)?;
+        if let Some((name, offset)) = self.table.lookup(self.addr) {
+            write!(f, " ({name}+0x{offset:x})")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! Boot argument parsing, for the `/chosen/bootargs` command line given with QEMU's `-append`
+//!
+//! Arguments are separated by whitespace and are either flags (`overwrite_free_pages`) or
+//! `key=value` pairs. Values can be double-quoted to contain spaces (`init="/bin/sh -x"`).
+//! Everything after a lone `--` is left for the init program.
+
+use crate::klog::Level;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Arg<'a> {
+    pub key: &'a str,
+    /// `None` for flags, quotes are removed
+    pub value: Option<&'a str>,
+}
+
+/// Iterates over the arguments of a command line, up to `--`
+#[derive(Clone)]
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(cmdline: &'a str) -> Self {
+        Self { rest: cmdline }
+    }
+
+    /// Splits off the next whitespace-separated word, keeping quoted whitespace
+    fn next_word(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c.is_whitespace() && !in_quotes
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (word, rest) = rest.split_at(end);
+        self.rest = rest;
+        Some(word)
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = Arg<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let word = self.next_word()?;
+        if word == "--" {
+            self.rest = "";
+            return None;
+        }
+        Some(match word.split_once('=') {
+            Some((key, value)) => Arg {
+                key,
+                value: Some(unquote(value)),
+            },
+            None => Arg {
+                key: unquote(word),
+                value: None,
+            },
+        })
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .map(|value| value.strip_suffix('"').unwrap_or(value))
+        .unwrap_or(value)
+}
+
+/// The arguments after `--`, for the init program
+pub fn init_args(cmdline: &str) -> Args<'_> {
+    let mut args = Args::new(cmdline);
+    while let Some(word) = args.next_word() {
+        if word == "--" {
+            break;
+        }
+    }
+    args
+}
+
+/// What the kernel does after a panic
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum PanicAction {
+    /// Stop, keeping the machine on for a debugger
+    #[default]
+    Halt,
+    Reboot,
+    PowerOff,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum OptionError<'a> {
+    /// Not a boot option, which is fine if the argument is meant for someone else
+    Unknown(Arg<'a>),
+    /// A boot option with a missing or malformed value
+    InvalidValue(Arg<'a>),
+}
+
+/// The boot options understood by the kernel and init
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BootOptions<'a> {
+    /// `loglevel=N|NAME`: which kernel log records are echoed to the console, e.g. `loglevel=debug`
+    /// or `loglevel=3`
+    pub loglevel: Option<Level>,
+    /// `init=PATH`: a script in the initrd for the init shell to run at boot
+    pub init: Option<&'a str>,
+    /// `overwrite_free_pages`: poison free memory, to catch use-after-free and uninitialized reads
+    pub overwrite_free_pages: bool,
+    /// `cpus=N`: how many CPUs to bring up
+    pub cpus: Option<u32>,
+    /// `console=NAME`: which UART is the console, either a DTB node name like `pl011@9000000`
+    /// or `ttyAMAn` for the n-th PL011
+    pub console: Option<&'a str>,
+    /// `panic=halt|reboot|poweroff`: what the kernel does after a panic, e.g. `panic=poweroff`
+    /// so that automated runs end
+    pub panic: Option<PanicAction>,
+}
+
+impl<'a> BootOptions<'a> {
+    /// Parses the boot options, calling `on_error` for the arguments that aren't valid options.
+    /// Later arguments override earlier ones.
+    pub fn parse(cmdline: &'a str, mut on_error: impl FnMut(OptionError<'a>)) -> Self {
+        let mut options = Self::default();
+        for arg in Args::new(cmdline) {
+            if !options.apply(arg) {
+                on_error(if is_option(arg.key) {
+                    OptionError::InvalidValue(arg)
+                } else {
+                    OptionError::Unknown(arg)
+                });
+            }
+        }
+        options
+    }
+
+    /// Applies a single argument, returns false if it's not a valid option
+    fn apply(&mut self, arg: Arg<'a>) -> bool {
+        match (arg.key, arg.value) {
+            ("loglevel", Some(value)) => {
+                let level = match value.parse::<u8>() {
+                    Ok(number) => Level::try_from(number).ok(),
+                    Err(_) => Level::from_name(value),
+                };
+                self.loglevel = level.or(self.loglevel);
+                level.is_some()
+            }
+            ("init", Some(path)) if !path.is_empty() => {
+                self.init = Some(path);
+                true
+            }
+            ("overwrite_free_pages", value) => match value.map(parse_bool) {
+                None => {
+                    self.overwrite_free_pages = true;
+                    true
+                }
+                Some(Some(enabled)) => {
+                    self.overwrite_free_pages = enabled;
+                    true
+                }
+                Some(None) => false,
+            },
+            ("cpus", Some(value)) => match value.parse::<u32>() {
+                Ok(cpus) if cpus > 0 => {
+                    self.cpus = Some(cpus);
+                    true
+                }
+                _ => false,
+            },
+            ("console", Some(name)) if !name.is_empty() => {
+                // Options like the baud rate aren't supported, ignore them
+                self.console = Some(name.split(',').next().unwrap_or(name));
+                true
+            }
+            ("panic", Some(value)) => {
+                let action = match value {
+                    "halt" => Some(PanicAction::Halt),
+                    "reboot" => Some(PanicAction::Reboot),
+                    "poweroff" => Some(PanicAction::PowerOff),
+                    _ => None,
+                };
+                self.panic = action.or(self.panic);
+                action.is_some()
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_option(key: &str) -> bool {
+    matches!(
+        key,
+        "loglevel" | "init" | "overwrite_free_pages" | "cpus" | "console" | "panic"
+    )
+}
+
+/// Parses `1`/`0`, `on`/`off`, `yes`/`no` and `true`/`false`
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "on" | "yes" | "true" => Some(true),
+        "0" | "off" | "no" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flag(key: &str) -> Arg<'_> {
+        Arg { key, value: None }
+    }
+
+    fn pair<'a>(key: &'a str, value: &'a str) -> Arg<'a> {
+        Arg {
+            key,
+            value: Some(value),
+        }
+    }
+
+    /// Parses `cmdline`, counting the arguments rejected as unknown and as invalid
+    fn parse(cmdline: &str) -> (BootOptions<'_>, usize, usize) {
+        let (mut unknown, mut invalid) = (0, 0);
+        let options = BootOptions::parse(cmdline, |err| match err {
+            OptionError::Unknown(_) => unknown += 1,
+            OptionError::InvalidValue(_) => invalid += 1,
+        });
+        (options, unknown, invalid)
+    }
+
+    #[test]
+    fn flags_and_pairs() {
+        let args = Args::new("  quiet loglevel=3\tinit=etc/rc  ");
+        assert!(args.eq([flag("quiet"), pair("loglevel", "3"), pair("init", "etc/rc")]));
+        assert_eq!(Args::new("").count(), 0);
+        assert_eq!(Args::new(" \t ").count(), 0);
+    }
+
+    #[test]
+    fn quoted_values() {
+        let args = Args::new(r#"init="/bin/sh -x" "quoted flag" empty="""#);
+        assert!(args.eq([
+            pair("init", "/bin/sh -x"),
+            flag("quoted flag"),
+            pair("empty", ""),
+        ]));
+    }
+
+    #[test]
+    fn unterminated_quote_takes_the_rest() {
+        let args = Args::new(r#"loglevel=3 init="etc/rc cpus=2 -- x"#);
+        assert!(args.eq([pair("loglevel", "3"), pair("init", "etc/rc cpus=2 -- x")]));
+        assert_eq!(init_args(r#"init="a -- b"#).count(), 0);
+    }
+
+    #[test]
+    fn double_dash_splits_init_args() {
+        let cmdline = "loglevel=debug -- init=ignored -v path";
+        assert!(Args::new(cmdline).eq([pair("loglevel", "debug")]));
+        assert!(init_args(cmdline).eq([pair("init", "ignored"), flag("-v"), flag("path")]));
+        assert_eq!(init_args("loglevel=debug").count(), 0);
+        // Only a lone `--` counts, and not one that's quoted
+        assert!(Args::new(r#"--x "--" init="--""#).eq([
+            flag("--x"),
+            flag("--"),
+            pair("init", "--"),
+        ]));
+
+        let (options, unknown, invalid) = parse("cpus=2 -- cpus=0 bogus");
+        assert_eq!(options.cpus, Some(2));
+        assert_eq!((unknown, invalid), (0, 0));
+    }
+
+    #[test]
+    fn options() {
+        let (options, unknown, invalid) = parse(
+            "loglevel=warn init=etc/rc overwrite_free_pages cpus=4 console=pl011@9000000 \
+             panic=poweroff",
+        );
+        assert_eq!((unknown, invalid), (0, 0));
+        assert_eq!(options.loglevel, Some(Level::Warn));
+        assert_eq!(options.init, Some("etc/rc"));
+        assert!(options.overwrite_free_pages);
+        assert_eq!(options.cpus, Some(4));
+        assert_eq!(options.console, Some("pl011@9000000"));
+        assert_eq!(options.panic, Some(PanicAction::PowerOff));
+
+        let (options, ..) = parse("loglevel=4 overwrite_free_pages=on overwrite_free_pages=off");
+        assert_eq!(options.loglevel, Some(Level::Trace));
+        assert!(!options.overwrite_free_pages);
+    }
+
+    #[test]
+    fn console_drops_the_baud_rate() {
+        let (options, _, invalid) = parse("console=ttyAMA0,115200n8");
+        assert_eq!(options.console, Some("ttyAMA0"));
+        assert_eq!(invalid, 0);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let (options, unknown, invalid) = parse(
+            "cpus=0 cpus=x cpus loglevel=9 init= console= panic=explode overwrite_free_pages=2",
+        );
+        assert_eq!((unknown, invalid), (0, 8));
+        assert_eq!(options.cpus, None);
+        assert_eq!(options.loglevel, None);
+        assert_eq!(options.init, None);
+        assert_eq!(options.console, None);
+        assert_eq!(options.panic, None);
+        assert!(!options.overwrite_free_pages);
+
+        // An invalid value keeps the previous one
+        let (options, _, invalid) = parse("cpus=2 cpus=0 loglevel=info loglevel=loud");
+        assert_eq!(invalid, 2);
+        assert_eq!(options.cpus, Some(2));
+        assert_eq!(options.loglevel, Some(Level::Info));
+    }
+
+    #[test]
+    fn unknown_arguments_are_reported() {
+        let (options, unknown, invalid) = parse("quiet root=/dev/vda cpus=1");
+        assert_eq!((unknown, invalid), (2, 0));
+        assert_eq!(options.cpus, Some(1));
+    }
+}
@@ -0,0 +1,250 @@
+//! Calendar date/time conversions for unix timestamps (UTC, proleptic Gregorian calendar)
+
+use core::fmt::{Debug, Display, Formatter};
+use core::str::FromStr;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct DateTime {
+    pub year: i32,
+    /// 1..=12
+    pub month: u8,
+    /// 1..=31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl DateTime {
+    pub const UNIX_EPOCH: Self = Self::from_unix(0);
+
+    /// Converts seconds since the unix epoch to a calendar date
+    pub const fn from_unix(secs: i64) -> Self {
+        Self::from_unix_ns(secs, 0)
+    }
+
+    /// Converts nanoseconds since the unix epoch (e.g. `ClockId::Realtime`) to a calendar date
+    pub const fn from_unix_nanos(ns: u64) -> Self {
+        Self::from_unix_ns((ns / 1_000_000_000) as i64, (ns % 1_000_000_000) as u32)
+    }
+
+    const fn from_unix_ns(secs: i64, nanosecond: u32) -> Self {
+        let days = secs.div_euclid(SECS_PER_DAY);
+        let secs_of_day = secs.rem_euclid(SECS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            nanosecond,
+        }
+    }
+
+    /// Converts the calendar date to seconds since the unix epoch
+    pub const fn to_unix(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    pub const fn weekday(&self) -> Weekday {
+        // 1970-01-01 was a Thursday
+        match (days_from_civil(self.year, self.month, self.day) + 3).rem_euclid(7) {
+            0 => Weekday::Monday,
+            1 => Weekday::Tuesday,
+            2 => Weekday::Wednesday,
+            3 => Weekday::Thursday,
+            4 => Weekday::Friday,
+            5 => Weekday::Saturday,
+            _ => Weekday::Sunday,
+        }
+    }
+}
+
+/// Formats as ISO 8601, e.g. `2025-06-30T22:46:00Z`. Use `{:#}` to include milliseconds.
+impl Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )?;
+        if f.alternate() {
+            write!(f, ".{:03}", self.nanosecond / 1_000_000)?;
+        }
+        write!(f, "Z")
+    }
+}
+
+/// Parses the ISO 8601 form [`Display`] writes, without the milliseconds: the `Z` is optional
+impl FromStr for DateTime {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let s = s.strip_suffix('Z').unwrap_or(s);
+        let (date, time) = s.split_once('T').ok_or(())?;
+        let (year, rest) = date.split_once('-').ok_or(())?;
+        let (month, day) = rest.split_once('-').ok_or(())?;
+        let (hour, rest) = time.split_once(':').ok_or(())?;
+        let (minute, second) = rest.split_once(':').ok_or(())?;
+        let date_time = DateTime {
+            year: year.parse().map_err(|_| ())?,
+            month: month.parse().map_err(|_| ())?,
+            day: day.parse().map_err(|_| ())?,
+            hour: hour.parse().map_err(|_| ())?,
+            minute: minute.parse().map_err(|_| ())?,
+            second: second.parse().map_err(|_| ())?,
+            nanosecond: 0,
+        };
+        // Out of range fields, like February 30th, come back as another date
+        match DateTime::from_unix(date_time.to_unix()) == date_time {
+            true => Ok(date_time),
+            false => Err(()),
+        }
+    }
+}
+
+impl Debug for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+// Based on Howard Hinnant's `days_from_civil` and `civil_from_days`:
+// https://howardhinnant.github.io/date_algorithms.html
+
+/// Days since 1970-01-01
+const fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let year = (if month <= 2 { year - 1 } else { year }) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400; // [0, 399]
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1; // [0, 365]
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year; // [0, 146096]
+    era * 146097 + day_of_era - 719468
+}
+
+/// (year, month, day) from days since 1970-01-01
+const fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097; // [0, 146096]
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365; // [0, 399]
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100); // [0, 365]
+    let mp = (5 * day_of_year + 2) / 153; // [0, 11]
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8; // [1, 31]
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8; // [1, 12]
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn date(year: i32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            nanosecond: 0,
+        }
+    }
+
+    /// Checks both directions of the conversion, and the day of the week
+    fn check(secs: i64, expected: DateTime, weekday: Weekday) {
+        let date_time = DateTime::from_unix(secs);
+        assert_eq!(date_time, expected);
+        assert_eq!(date_time.to_unix(), secs);
+        assert_eq!(date_time.weekday(), weekday);
+    }
+
+    #[test]
+    fn epoch() {
+        check(0, date(1970, 1, 1, 0, 0, 0), Weekday::Thursday);
+        assert_eq!(DateTime::UNIX_EPOCH, date(1970, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn before_epoch() {
+        check(-1, date(1969, 12, 31, 23, 59, 59), Weekday::Wednesday);
+        check(-86_400, date(1969, 12, 31, 0, 0, 0), Weekday::Wednesday);
+    }
+
+    #[test]
+    fn leap_day() {
+        check(951_782_400, date(2000, 2, 29, 0, 0, 0), Weekday::Tuesday);
+        check(951_868_799, date(2000, 2, 29, 23, 59, 59), Weekday::Tuesday);
+        check(951_868_800, date(2000, 3, 1, 0, 0, 0), Weekday::Wednesday);
+    }
+
+    #[test]
+    fn century_that_is_not_a_leap_year() {
+        check(4_102_444_800, date(2100, 1, 1, 0, 0, 0), Weekday::Friday);
+        check(4_107_499_200, date(2100, 2, 28, 12, 0, 0), Weekday::Sunday);
+        check(4_107_542_400, date(2100, 3, 1, 0, 0, 0), Weekday::Monday);
+    }
+
+    #[test]
+    fn end_of_32_bit_time() {
+        check(
+            i32::MAX as i64,
+            date(2038, 1, 19, 3, 14, 7),
+            Weekday::Tuesday,
+        );
+        check(
+            i32::MAX as i64 + 1,
+            date(2038, 1, 19, 3, 14, 8),
+            Weekday::Tuesday,
+        );
+    }
+
+    #[test]
+    fn nanoseconds() {
+        let date_time = DateTime::from_unix_nanos(951_782_400_123_456_789);
+        assert_eq!(date_time.nanosecond, 123_456_789);
+        assert_eq!(date_time.to_unix(), 951_782_400);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "2000-02-29T12:34:56Z".parse(),
+            Ok(date(2000, 2, 29, 12, 34, 56))
+        );
+        assert_eq!("1970-01-01T00:00:00".parse(), Ok(DateTime::UNIX_EPOCH));
+        for invalid in [
+            "2100-02-29T00:00:00",
+            "2000-13-01T00:00:00",
+            "2000-00-10T00:00:00",
+            "2000-01-01T24:00:00",
+            "2000-01-01 00:00:00",
+            "2000-01-01",
+        ] {
+            assert_eq!(invalid.parse::<DateTime>(), Err(()), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Kernel log record format, shared by the kernel's log ring buffer and `LogRead` readers
+//!
+//! `LogRead` fills the buffer with whole records, each one is a [`RecordHeader`] followed by
+//! `tag_len` bytes of tag and `message_len` bytes of message (both UTF-8).
+
+use core::fmt::{Display, Formatter};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+pub const MAX_TAG_LEN: usize = 16;
+pub const MAX_MESSAGE_LEN: usize = 256;
+/// A `LogRead` buffer of this size always fits at least one record
+pub const MAX_RECORD_LEN: usize = size_of::<RecordHeader>() + MAX_TAG_LEN + MAX_MESSAGE_LEN;
+
+#[derive(TryFromPrimitive, IntoPrimitive, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// Parses a level name, case-insensitively, e.g. `"debug"`
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .into_iter()
+        .find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C)]
+pub struct RecordHeader {
+    /// Arch timer counter at the time of logging, in nanoseconds
+    pub timestamp_ns: u64,
+    pub level: u8,
+    pub tag_len: u8,
+    pub message_len: u16,
+    pub _padding: u32,
+}
+
+impl RecordHeader {
+    /// Length of the whole record, including this header
+    pub const fn record_len(&self) -> usize {
+        size_of::<Self>() + self.tag_len as usize + self.message_len as usize
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Record<'a> {
+    pub timestamp_ns: u64,
+    pub level: Level,
+    pub tag: &'a str,
+    pub message: &'a str,
+}
+
+/// Formats like dmesg, e.g. `[    1.234567] INFO  drv: Initializing ARM GIC`
+impl Display for Record<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] {:5} {}: {}",
+            self.timestamp_ns / 1_000_000_000,
+            self.timestamp_ns % 1_000_000_000 / 1000,
+            self.level.name(),
+            self.tag,
+            self.message
+        )
+    }
+}
+
+/// Iterates over the records in a buffer filled by `LogRead`, stops at the first malformed one
+pub struct Records<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    pub const fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (header, rest) = RecordHeader::read_from_prefix(self.buf).ok()?;
+        let tag_len = header.tag_len as usize;
+        let message_len = header.message_len as usize;
+        if rest.len() < tag_len + message_len {
+            return None;
+        }
+        let record = Record {
+            timestamp_ns: header.timestamp_ns,
+            level: Level::try_from(header.level).ok()?,
+            tag: core::str::from_utf8(&rest[..tag_len]).ok()?,
+            message: core::str::from_utf8(&rest[tag_len..tag_len + message_len]).ok()?,
+        };
+        self.buf = &rest[tag_len + message_len..];
+        Some(record)
+    }
+}
@@ -3,6 +3,10 @@ use bitflags::bitflags;
 use core::fmt::Debug;
 use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 
+pub mod cmdline;
+pub mod datetime;
+pub mod klog;
+
 #[derive(TryFromPrimitive, IntoPrimitive, Eq, PartialEq, Copy, Clone, Debug)]
 #[repr(u32)]
 pub enum Syscall {
@@ -14,6 +18,38 @@ pub enum Syscall {
     DownloadMoreRam = 5,
     LoadKernelDevice = 6,
     SleepSec = 7,
+    ClockGet = 8,
+    ClockSet = 9,
+    IrqWait = 10,
+    ConsoleRead = 11,
+    ConsoleWrite = 12,
+    ConsoleSetMode = 13,
+    LogRead = 14,
+    LogSetLevel = 15,
+    LogSetConsoleLevel = 16,
+    DmaAlloc = 17,
+    ReserveMemory = 18,
+    SetCmdline = 19,
+    GetRandom = 20,
+    AddEntropy = 21,
+    IrqWaitTimeout = 22,
+    ConsoleInput = 23,
+    /// Privileged, calls one of the [`PsciFunction`]s
+    Psci = 24,
+}
+
+/// The PSCI functions init can call through the `Psci` syscall. Only `Version` returns on
+/// success, with the major version in bits 31:16 and the minor one in bits 15:0.
+#[derive(TryFromPrimitive, IntoPrimitive, Eq, PartialEq, Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum PsciFunction {
+    Version = 0,
+    /// Turns the machine off
+    SystemOff = 1,
+    /// Reboots the machine
+    SystemReset = 2,
+    /// Turns the calling CPU off
+    CpuOff = 3,
 }
 
 #[derive(FromPrimitive, IntoPrimitive, Eq, PartialEq, Copy, Clone, Debug)]
@@ -24,6 +60,15 @@ pub enum KError {
     AlreadyExists = -1,
     OOM = -2,
     InvalidArgument = -3,
+    NoDevice = -4,
+    NotSupported = -5,
+    IoError = -6,
+    /// The operation would have to block, but wasn't allowed to
+    WouldBlock = -7,
+    /// The operation didn't complete in the time it was given
+    TimedOut = -8,
+    /// The caller isn't allowed to do that, e.g. a privileged syscall
+    PermissionDenied = -9,
 }
 
 impl Into<u64> for KError {
@@ -56,6 +101,193 @@ pub mod kernel_device {
     impl KernelDeviceId for GicAndTimer {
         const ID: u32 = 1;
     }
+
+    #[derive(Debug, FromBytes, IntoBytes)]
+    #[repr(C)]
+    pub struct Pl011 {
+        pub base: u64,
+        /// Frequency of the UART reference clock, for calculating the baud rate divisor
+        pub clock_hz: u32,
+        pub interrupt_id: u32,
+    }
+
+    impl KernelDeviceId for Pl011 {
+        const ID: u32 = 2;
+    }
+
+    /// A PL011 UART for the kernel's GDB stub
+    #[derive(Debug, FromBytes, IntoBytes)]
+    #[repr(C)]
+    pub struct GdbStub {
+        pub base: u64,
+        pub clock_hz: u32,
+        pub interrupt_id: u32,
+    }
+
+    impl KernelDeviceId for GdbStub {
+        const ID: u32 = 3;
+    }
+
+    /// A linear framebuffer that the display reads by itself, for the kernel's console
+    #[derive(Debug, FromBytes, IntoBytes)]
+    #[repr(C)]
+    pub struct Framebuffer {
+        /// Physical address of the pixels, which are 32-bit `0x00RRGGBB`
+        pub base: u64,
+        pub width: u32,
+        pub height: u32,
+        /// Bytes from the start of a row to the start of the next one
+        pub stride: u32,
+        pub _padding: u32,
+    }
+
+    impl KernelDeviceId for Framebuffer {
+        const ID: u32 = 4;
+    }
+
+    /// How PSCI calls reach the firmware, the `method` of the DTB's `psci` node
+    pub const PSCI_CONDUIT_HVC: u32 = 0;
+    pub const PSCI_CONDUIT_SMC: u32 = 1;
+
+    /// The PSCI firmware interface, version 0.2 or later, for power management
+    #[derive(Debug, FromBytes, IntoBytes)]
+    #[repr(C)]
+    pub struct Psci {
+        /// [`PSCI_CONDUIT_HVC`] or [`PSCI_CONDUIT_SMC`]
+        pub conduit: u32,
+        pub _padding: u32,
+    }
+
+    impl KernelDeviceId for Psci {
+        const ID: u32 = 5;
+    }
+}
+
+pub mod clock {
+    use core::sync::atomic::{AtomicU32, AtomicU64, Ordering, fence};
+    use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+    pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+    /// Where the kernel maps the read-only [`TimePage`] in every usermode address space
+    pub const TIME_PAGE_ADDR: usize = 0x7000000;
+
+    #[derive(TryFromPrimitive, IntoPrimitive, Eq, PartialEq, Copy, Clone, Debug)]
+    #[repr(u32)]
+    pub enum ClockId {
+        /// Never jumps, counts from an arbitrary point in the past (the counter reset)
+        Monotonic = 0,
+        /// Time since the kernel booted
+        Boot = 1,
+        /// Wall-clock time since the unix epoch, can be set with `ClockSet`
+        Realtime = 2,
+    }
+
+    /// Converts a counter value to nanoseconds, without overflowing for long uptimes
+    pub const fn ticks_to_ns(ticks: u64, freq: u64) -> u64 {
+        let secs = ticks / freq;
+        let rem = ticks % freq;
+        secs * NSEC_PER_SEC + (rem * NSEC_PER_SEC) / freq
+    }
+
+    /// Converts nanoseconds to a counter value, the inverse of [`ticks_to_ns`]. Saturates rather
+    /// than overflow for timeouts that would outlast the counter.
+    pub const fn ns_to_ticks(ns: u64, freq: u64) -> u64 {
+        let secs = ns / NSEC_PER_SEC;
+        let rem = ns % NSEC_PER_SEC;
+        secs.saturating_mul(freq)
+            .saturating_add((rem * freq) / NSEC_PER_SEC)
+    }
+
+    /// Clock parameters shared by the kernel with usermode, so time can be read without a syscall
+    ///
+    /// The kernel bumps `seq` to an odd value while updating the page, readers retry until they
+    /// observe the same even value before and after reading the other fields.
+    #[repr(C)]
+    pub struct TimePage {
+        pub seq: AtomicU32,
+        pub _padding: u32,
+        /// Frequency of the system counter (Hz)
+        pub counter_freq: AtomicU64,
+        /// Value of the system counter when the kernel booted
+        pub boot_counter: AtomicU64,
+        /// Added to the monotonic clock to get the wall-clock time (ns)
+        pub realtime_offset_ns: AtomicU64,
+    }
+
+    impl TimePage {
+        pub const fn new() -> Self {
+            Self {
+                seq: AtomicU32::new(0),
+                _padding: 0,
+                counter_freq: AtomicU64::new(0),
+                boot_counter: AtomicU64::new(0),
+                realtime_offset_ns: AtomicU64::new(0),
+            }
+        }
+
+        /// Reads `clock`, using `read_counter` to sample the system counter
+        pub fn read(&self, clock: ClockId, read_counter: impl Fn() -> u64) -> u64 {
+            loop {
+                let seq = self.seq.load(Ordering::Acquire);
+                if seq % 2 == 1 {
+                    core::hint::spin_loop();
+                    continue;
+                }
+                let freq = self.counter_freq.load(Ordering::Relaxed);
+                let boot_counter = self.boot_counter.load(Ordering::Relaxed);
+                let realtime_offset_ns = self.realtime_offset_ns.load(Ordering::Relaxed);
+                let counter = read_counter();
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) != seq {
+                    continue;
+                }
+
+                return match clock {
+                    ClockId::Monotonic => ticks_to_ns(counter, freq),
+                    ClockId::Boot => ticks_to_ns(counter.saturating_sub(boot_counter), freq),
+                    ClockId::Realtime => {
+                        ticks_to_ns(counter, freq).wrapping_add(realtime_offset_ns)
+                    }
+                };
+            }
+        }
+
+        /// Runs `f` with the sequence counter odd, so readers don't observe a partial update
+        ///
+        /// Must only be called by a single writer (the kernel) at a time
+        pub fn update(&self, f: impl FnOnce(&Self)) {
+            let seq = self.seq.load(Ordering::Relaxed);
+            self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+            fence(Ordering::Release);
+            f(self);
+            self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        }
+    }
+
+    impl Default for TimePage {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const FREQ: u64 = 62_500_000;
+
+        #[test]
+        fn conversions() {
+            assert_eq!(ns_to_ticks(1_500_000_000, FREQ), 93_750_000);
+            assert_eq!(ticks_to_ns(93_750_000, FREQ), 1_500_000_000);
+        }
+
+        #[test]
+        fn long_timeouts_saturate() {
+            assert_eq!(ns_to_ticks(u64::MAX, 4_000_000_000), u64::MAX);
+        }
+    }
 }
 
 bitflags! {
@@ -66,4 +298,20 @@ bitflags! {
     pub struct MemMapFlags: u64 {
         const ReadWrite = 1 << 0;
     }
+    #[derive(Copy, Clone, Debug)]
+    pub struct ConsoleFlags: u64 {
+        /// Buffer input until a newline, with backspace line editing
+        const Canonical = 1 << 0;
+        /// Echo received characters back to the console
+        const Echo = 1 << 1;
+        /// Translate "\n" to "\r\n" on output
+        const Crlf = 1 << 2;
+        /// Fail reads with `WouldBlock` instead of waiting for input
+        const NonBlock = 1 << 3;
+    }
+    #[derive(Copy, Clone, Debug)]
+    pub struct GetRandomFlags: u64 {
+        /// Fail with `WouldBlock` instead of waiting for the entropy pool to be seeded
+        const NonBlock = 1 << 0;
+    }
 }
@@ -0,0 +1,267 @@
+//! Block devices, see "Block Device" in the virtio specification
+//!
+//! Requests are asynchronous: [`VirtioBlk::submit`] queues one and returns right away, and
+//! completions are collected with [`VirtioBlk::poll`] or [`VirtioBlk::wait`], in whatever order
+//! the device finishes them. Data buffers must be DMA memory, given by physical address.
+
+use crate::mmio::MmioTransport;
+use crate::queue::VirtQueue;
+use crate::{Dma, Error, Hal};
+use core::marker::PhantomData;
+
+/// Sectors are always 512 bytes, whatever the device's block size
+pub const SECTOR_SIZE: usize = 512;
+
+/// Feature bits
+const F_SIZE_MAX: u64 = 1 << 1;
+const F_SEG_MAX: u64 = 1 << 2;
+const F_RO: u64 = 1 << 5;
+const F_BLK_SIZE: u64 = 1 << 6;
+const F_FLUSH: u64 = 1 << 9;
+const F_DISCARD: u64 = 1 << 13;
+
+/// Configuration space offsets
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SIZE_MAX: usize = 8;
+const CONFIG_SEG_MAX: usize = 12;
+const CONFIG_BLK_SIZE: usize = 20;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+
+/// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_DISCARD: u32 = 11;
+
+/// Request status
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// How many requests can be in flight, each takes up to 3 descriptors
+pub const MAX_REQUESTS: usize = 32;
+/// Reads and writes take their data in a single buffer
+const DATA_SEGMENTS: u32 = 1;
+const QUEUE_SIZE: u16 = 128;
+
+/// Per-request memory for the device: the header, the discard segment and the status byte
+const SLOT_SIZE: usize = 64;
+const SLOT_HEADER: usize = 0;
+const SLOT_SEGMENT: usize = 16;
+const SLOT_STATUS: usize = 32;
+const HEADER_LEN: usize = 16;
+const SEGMENT_LEN: usize = 16;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Request {
+    /// Reads sectors starting at `sector` into the `(physical address, length)` buffer
+    Read { sector: u64, buf: (usize, usize) },
+    /// Writes sectors starting at `sector` from the `(physical address, length)` buffer
+    Write { sector: u64, buf: (usize, usize) },
+    /// Makes previous writes durable
+    Flush,
+    /// Tells the device `count` sectors starting at `sector` are unused
+    Discard { sector: u64, count: u32 },
+}
+
+/// Identifies a submitted request when it completes
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RequestId(u16);
+
+pub struct VirtioBlk<H: Hal> {
+    transport: MmioTransport,
+    queue: VirtQueue,
+    features: u64,
+    capacity: u64,
+    block_size: u32,
+    /// The largest data segment, and how many a request can have
+    size_max: u32,
+    seg_max: u32,
+    /// Request slots, and the queue token of the request using each one
+    slots: Dma,
+    tokens: [Option<u16>; MAX_REQUESTS],
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> VirtioBlk<H> {
+    pub fn new(mut transport: MmioTransport) -> Result<Self, Error> {
+        let supported = F_SIZE_MAX | F_SEG_MAX | F_RO | F_BLK_SIZE | F_FLUSH | F_DISCARD;
+        let features = transport.begin_init(supported)?;
+        let queue = transport.setup_queue::<H>(0, QUEUE_SIZE)?;
+        let slots = Dma::new::<H>(SLOT_SIZE * MAX_REQUESTS)?;
+        transport.finish_init();
+
+        let capacity = transport.read_config::<u64>(CONFIG_CAPACITY);
+        let block_size = match features & F_BLK_SIZE {
+            0 => SECTOR_SIZE as u32,
+            _ => transport.read_config::<u32>(CONFIG_BLK_SIZE),
+        };
+        let size_max = match features & F_SIZE_MAX {
+            0 => u32::MAX,
+            _ => transport.read_config::<u32>(CONFIG_SIZE_MAX),
+        };
+        let seg_max = match features & F_SEG_MAX {
+            0 => u32::MAX,
+            _ => transport.read_config::<u32>(CONFIG_SEG_MAX),
+        };
+        Ok(Self {
+            transport,
+            queue,
+            features,
+            capacity,
+            block_size,
+            size_max,
+            seg_max,
+            slots,
+            tokens: [None; MAX_REQUESTS],
+            _hal: PhantomData,
+        })
+    }
+
+    /// Size of the device, in [`SECTOR_SIZE`] sectors
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The device's preferred block size, requests of that granularity are faster
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The largest read or write, in bytes: a whole number of sectors that fits in the device's
+    /// largest data segment. 0 if the device can't take a request's data segment at all.
+    pub fn max_transfer(&self) -> usize {
+        if self.seg_max < DATA_SEGMENTS {
+            return 0;
+        }
+        self.size_max as usize / SECTOR_SIZE * SECTOR_SIZE
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    pub fn supports_flush(&self) -> bool {
+        self.features & F_FLUSH != 0
+    }
+
+    pub fn supports_discard(&self) -> bool {
+        self.features & F_DISCARD != 0
+    }
+
+    /// The largest discard the device accepts, in sectors
+    pub fn max_discard_sectors(&self) -> u32 {
+        if !self.supports_discard() {
+            return 0;
+        }
+        self.transport
+            .read_config::<u32>(CONFIG_MAX_DISCARD_SECTORS)
+    }
+
+    /// How many more requests can be submitted before some have to complete
+    pub fn free_slots(&self) -> usize {
+        self.tokens.iter().filter(|token| token.is_none()).count()
+    }
+
+    fn check_range(&self, sector: u64, sectors: u64) -> Result<(), Error> {
+        match sector.checked_add(sectors) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(Error::InvalidArgument),
+        }
+    }
+
+    /// Queues a request and notifies the device, without waiting for it
+    pub fn submit(&mut self, request: Request) -> Result<RequestId, Error> {
+        let (request_type, sector) = match request {
+            Request::Read { sector, buf } | Request::Write { sector, buf } => {
+                let (_, len) = buf;
+                if len == 0 || !len.is_multiple_of(SECTOR_SIZE) || len > self.max_transfer() {
+                    return Err(Error::InvalidArgument);
+                }
+                self.check_range(sector, (len / SECTOR_SIZE) as u64)?;
+                match request {
+                    Request::Read { .. } => (T_IN, sector),
+                    _ if self.read_only() => return Err(Error::ReadOnly),
+                    _ => (T_OUT, sector),
+                }
+            }
+            Request::Flush if !self.supports_flush() => return Err(Error::Unsupported),
+            Request::Flush => (T_FLUSH, 0),
+            Request::Discard { .. } if !self.supports_discard() => {
+                return Err(Error::Unsupported);
+            }
+            Request::Discard { sector, count } => {
+                self.check_range(sector, count as u64)?;
+                (T_DISCARD, 0)
+            }
+        };
+        let slot = self
+            .tokens
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::QueueFull)?;
+
+        let offset = slot * SLOT_SIZE;
+        let memory = &mut self.slots.as_mut_slice()[offset..offset + SLOT_SIZE];
+        memory[SLOT_HEADER..SLOT_HEADER + 4].copy_from_slice(&request_type.to_le_bytes());
+        memory[SLOT_HEADER + 4..SLOT_HEADER + 8].fill(0);
+        memory[SLOT_HEADER + 8..SLOT_HEADER + 16].copy_from_slice(&sector.to_le_bytes());
+        // Poison the status, so a device that doesn't write it isn't taken for a success
+        memory[SLOT_STATUS] = 0xff;
+
+        let header = self.slots.range(offset + SLOT_HEADER, HEADER_LEN);
+        let status = self.slots.range(offset + SLOT_STATUS, 1);
+        let token = match request {
+            Request::Read { buf, .. } => self.queue.add(&[header], &[buf, status])?,
+            Request::Write { buf, .. } => self.queue.add(&[header, buf], &[status])?,
+            Request::Flush => self.queue.add(&[header], &[status])?,
+            Request::Discard { sector, count } => {
+                let memory = &mut self.slots.as_mut_slice()[offset..offset + SLOT_SIZE];
+                let segment = &mut memory[SLOT_SEGMENT..SLOT_SEGMENT + SEGMENT_LEN];
+                segment[0..8].copy_from_slice(&sector.to_le_bytes());
+                segment[8..12].copy_from_slice(&count.to_le_bytes());
+                segment[12..16].fill(0);
+                let segment = self.slots.range(offset + SLOT_SEGMENT, SEGMENT_LEN);
+                self.queue.add(&[header, segment], &[status])?
+            }
+        };
+        self.tokens[slot] = Some(token);
+        self.transport.notify(self.queue.index());
+        Ok(RequestId(token))
+    }
+
+    /// Takes a completed request, if there's one
+    pub fn poll(&mut self) -> Option<(RequestId, Result<(), Error>)> {
+        let (token, _) = self.queue.pop_used()?;
+        let Some(slot) = self.tokens.iter().position(|&t| t == Some(token)) else {
+            return Some((RequestId(token), Err(Error::InvalidResponse)));
+        };
+        self.tokens[slot] = None;
+        let result = match self.slots.as_slice()[slot * SLOT_SIZE + SLOT_STATUS] {
+            S_OK => Ok(()),
+            S_IOERR => Err(Error::IoError),
+            S_UNSUPP => Err(Error::Unsupported),
+            _ => Err(Error::InvalidResponse),
+        };
+        Some((RequestId(token), result))
+    }
+
+    /// Blocks until a request completes
+    pub fn wait(&mut self) -> Result<(RequestId, Result<(), Error>), Error> {
+        loop {
+            if let Some(completion) = self.poll() {
+                return Ok(completion);
+            }
+            self.transport.wait_used::<H>(&self.queue)?;
+        }
+    }
+
+    /// Submits a request and waits for it, other requests must not be in flight
+    pub fn execute(&mut self, request: Request) -> Result<(), Error> {
+        let id = self.submit(request)?;
+        match self.wait()? {
+            (done, result) if done == id => result,
+            _ => Err(Error::InvalidResponse),
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Consoles, see "Console Device" in the virtio specification
+//!
+//! With `VIRTIO_CONSOLE_F_MULTIPORT` the device has several ports, e.g. QEMU's `virtserialport`s,
+//! each with its own pair of queues. The device announces them on the control queue, along with
+//! their names and whether the host side is connected.
+
+use crate::mmio::MmioTransport;
+use crate::queue::VirtQueue;
+use crate::{Dma, Error, Hal};
+use core::marker::PhantomData;
+
+/// Feature bits
+const F_MULTIPORT: u64 = 1 << 1;
+
+/// Configuration space offsets
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+/// Control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const DEVICE_REMOVE: u16 = 2;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+/// Queues of the control channel, only with multiport
+const CONTROL_RX_QUEUE: u16 = 2;
+const CONTROL_TX_QUEUE: u16 = 3;
+
+/// Ports beyond this are refused
+pub const MAX_PORTS: usize = 4;
+pub const MAX_NAME_LEN: usize = 64;
+
+const QUEUE_SIZE: u16 = 16;
+const RX_BUF_SIZE: usize = 512;
+const TX_BUF_SIZE: usize = 4096;
+/// Control messages are id[4] event[2] value[2], followed by the name for `PORT_NAME`
+const CONTROL_HEADER_LEN: usize = 8;
+const CONTROL_BUF_SIZE: usize = CONTROL_HEADER_LEN + MAX_NAME_LEN;
+const CONTROL_BUFS: usize = 8;
+
+struct Port {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    rx_buf: Dma,
+    tx_buf: Dma,
+    /// Received bytes not read yet are `rx_buf[rx_pos..rx_len]`
+    rx_pos: usize,
+    rx_len: usize,
+    /// Whether `rx_buf` is with the device
+    rx_posted: bool,
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    /// The device announced the port
+    added: bool,
+    /// Something is connected on the host side
+    host_connected: bool,
+    is_console: bool,
+}
+
+impl Port {
+    fn new<H: Hal>(transport: &mut MmioTransport, id: usize) -> Result<Self, Error> {
+        // Port 0 uses queues 0 and 1, the others come after the control queues
+        let rx_queue = if id == 0 { 0 } else { 2 * id as u16 + 2 };
+        Ok(Self {
+            rx: transport.setup_queue::<H>(rx_queue, QUEUE_SIZE)?,
+            tx: transport.setup_queue::<H>(rx_queue + 1, QUEUE_SIZE)?,
+            rx_buf: Dma::new::<H>(RX_BUF_SIZE)?,
+            tx_buf: Dma::new::<H>(TX_BUF_SIZE)?,
+            rx_pos: 0,
+            rx_len: 0,
+            rx_posted: false,
+            name: [0; MAX_NAME_LEN],
+            name_len: 0,
+            added: false,
+            host_connected: false,
+            is_console: false,
+        })
+    }
+
+    fn has_data(&self) -> bool {
+        self.rx_pos < self.rx_len || self.rx.can_pop()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PortInfo<'a> {
+    pub id: u32,
+    /// Set by the host, e.g. `org.qemu.guest_agent.0`, empty if the port has no name
+    pub name: &'a str,
+    pub host_connected: bool,
+    /// The port is meant to be the system console
+    pub is_console: bool,
+}
+
+struct Control {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    rx_bufs: Dma,
+    /// The queue token of each receive buffer
+    rx_tokens: [u16; CONTROL_BUFS],
+    tx_buf: Dma,
+}
+
+pub struct VirtioConsole<H: Hal> {
+    transport: MmioTransport,
+    /// Only with multiport
+    control: Option<Control>,
+    ports: [Option<Port>; MAX_PORTS],
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> VirtioConsole<H> {
+    pub fn new(mut transport: MmioTransport) -> Result<Self, Error> {
+        let features = transport.begin_init(F_MULTIPORT)?;
+        let multiport = features & F_MULTIPORT != 0;
+        let port_count = if multiport {
+            (transport.read_config::<u32>(CONFIG_MAX_NR_PORTS) as usize).clamp(1, MAX_PORTS)
+        } else {
+            1
+        };
+
+        // All the queues have to be set up before the device starts
+        let mut ports = [const { None }; MAX_PORTS];
+        let mut control = None;
+        for (id, port) in ports.iter_mut().enumerate().take(port_count) {
+            *port = Some(Port::new::<H>(&mut transport, id)?);
+            if id == 0 && multiport {
+                control = Some(Control {
+                    rx: transport.setup_queue::<H>(CONTROL_RX_QUEUE, QUEUE_SIZE)?,
+                    tx: transport.setup_queue::<H>(CONTROL_TX_QUEUE, QUEUE_SIZE)?,
+                    rx_bufs: Dma::new::<H>(CONTROL_BUF_SIZE * CONTROL_BUFS)?,
+                    rx_tokens: [0; CONTROL_BUFS],
+                    tx_buf: Dma::new::<H>(CONTROL_HEADER_LEN)?,
+                });
+            }
+        }
+        transport.finish_init();
+
+        let mut console = Self {
+            transport,
+            control,
+            ports,
+            _hal: PhantomData,
+        };
+        for id in 0..port_count {
+            console.post_rx(id)?;
+        }
+        match &mut console.control {
+            Some(control) => {
+                for i in 0..CONTROL_BUFS {
+                    let buf = control
+                        .rx_bufs
+                        .range(i * CONTROL_BUF_SIZE, CONTROL_BUF_SIZE);
+                    control.rx_tokens[i] = control.rx.add(&[], &[buf])?;
+                }
+                console.transport.notify(CONTROL_RX_QUEUE);
+                // The device answers with the ports it has
+                console.send_control(0, DEVICE_READY, 1)?;
+                console.process_control()?;
+            }
+            // Without multiport the only port always exists
+            None => {
+                let port = console.ports[0].as_mut().unwrap();
+                port.added = true;
+                port.host_connected = true;
+            }
+        }
+        Ok(console)
+    }
+
+    pub fn is_multiport(&self) -> bool {
+        self.control.is_some()
+    }
+
+    /// The ports the device announced
+    pub fn ports(&self) -> impl Iterator<Item = PortInfo<'_>> {
+        self.ports
+            .iter()
+            .enumerate()
+            .filter_map(|(id, port)| Some((id, port.as_ref()?)))
+            .filter(|(_, port)| port.added)
+            .map(|(id, port)| PortInfo {
+                id: id as u32,
+                name: core::str::from_utf8(&port.name[..port.name_len]).unwrap_or(""),
+                host_connected: port.host_connected,
+                is_console: port.is_console,
+            })
+    }
+
+    /// Finds a port by name
+    pub fn find_port(&self, name: &str) -> Option<u32> {
+        self.ports()
+            .find(|port| port.name == name)
+            .map(|port| port.id)
+    }
+
+    fn port_mut(ports: &mut [Option<Port>], id: u32) -> Result<&mut Port, Error> {
+        match ports.get_mut(id as usize) {
+            Some(Some(port)) if port.added => Ok(port),
+            _ => Err(Error::NoDevice),
+        }
+    }
+
+    /// Gives the port's receive buffer to the device
+    fn post_rx(&mut self, id: usize) -> Result<(), Error> {
+        let Some(port) = &mut self.ports[id] else {
+            return Ok(());
+        };
+        if port.rx_posted {
+            return Ok(());
+        }
+        let buf = port.rx_buf.range(0, RX_BUF_SIZE);
+        port.rx.add(&[], &[buf])?;
+        port.rx_posted = true;
+        self.transport.notify(port.rx.index());
+        Ok(())
+    }
+
+    fn send_control(&mut self, id: u32, event: u16, value: u16) -> Result<(), Error> {
+        let Some(control) = &mut self.control else {
+            return Ok(());
+        };
+        let msg = control.tx_buf.as_mut_slice();
+        msg[0..4].copy_from_slice(&id.to_le_bytes());
+        msg[4..6].copy_from_slice(&event.to_le_bytes());
+        msg[6..8].copy_from_slice(&value.to_le_bytes());
+        let buf = control.tx_buf.range(0, CONTROL_HEADER_LEN);
+        self.transport.send::<H>(&mut control.tx, &[buf], &[])?;
+        Ok(())
+    }
+
+    /// Handles the control messages the device sent, like ports being added or the host side
+    /// connecting. Reads and writes do it too.
+    pub fn process_control(&mut self) -> Result<(), Error> {
+        loop {
+            let Some(control) = &mut self.control else {
+                return Ok(());
+            };
+            let Some((token, len)) = control.rx.pop_used() else {
+                return Ok(());
+            };
+            let Some(index) = control.rx_tokens.iter().position(|&t| t == token) else {
+                return Err(Error::InvalidResponse);
+            };
+            let offset = index * CONTROL_BUF_SIZE;
+            let msg = &control.rx_bufs.as_slice()[offset..offset + CONTROL_BUF_SIZE];
+            let len = (len as usize).min(CONTROL_BUF_SIZE);
+            if len < CONTROL_HEADER_LEN {
+                return Err(Error::InvalidResponse);
+            }
+            let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+            let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+            let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+            let mut name = [0u8; MAX_NAME_LEN];
+            let name_len = len - CONTROL_HEADER_LEN;
+            name[..name_len].copy_from_slice(&msg[CONTROL_HEADER_LEN..len]);
+
+            // Hand the buffer back before replying, so the device always has room
+            let buf = control.rx_bufs.range(offset, CONTROL_BUF_SIZE);
+            control.rx_tokens[index] = control.rx.add(&[], &[buf])?;
+            self.transport.notify(CONTROL_RX_QUEUE);
+
+            let port = match self.ports.get_mut(id as usize) {
+                Some(Some(port)) => port,
+                // A port we have no queues for
+                _ => {
+                    if event == DEVICE_ADD {
+                        self.send_control(id, PORT_READY, 0)?;
+                    }
+                    continue;
+                }
+            };
+            match event {
+                DEVICE_ADD => {
+                    port.added = true;
+                    self.send_control(id, PORT_READY, 1)?;
+                    // Open our side right away, there's nobody else to do it
+                    self.send_control(id, PORT_OPEN, 1)?;
+                }
+                DEVICE_REMOVE => {
+                    port.added = false;
+                    port.host_connected = false;
+                }
+                CONSOLE_PORT => port.is_console = true,
+                PORT_OPEN => port.host_connected = value == 1,
+                PORT_NAME => {
+                    // The name may be NUL-terminated
+                    let name_len = name[..name_len]
+                        .iter()
+                        .position(|&b| b == 0)
+                        .unwrap_or(name_len);
+                    port.name = name;
+                    port.name_len = name_len;
+                }
+                // Resizes and unknown events
+                _ => {}
+            }
+        }
+    }
+
+    /// Blocks until `ready` is true, handling control messages in the meantime
+    fn wait_until(&mut self, ready: impl Fn(&Self) -> bool) -> Result<(), Error> {
+        loop {
+            self.process_control()?;
+            if ready(self) {
+                return Ok(());
+            }
+            // Acknowledge first, so an interrupt arriving before the wait isn't lost
+            self.transport.ack_interrupt();
+            let control_pending = self
+                .control
+                .as_ref()
+                .is_some_and(|control| control.rx.can_pop());
+            if !control_pending && !ready(self) {
+                H::wait_interrupt(self.transport.interrupt_id())?;
+            }
+        }
+    }
+
+    /// Reads from a port, blocking until at least one byte is available
+    pub fn read(&mut self, id: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        Self::port_mut(&mut self.ports, id)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            self.wait_until(|console| match &console.ports[id as usize] {
+                Some(port) => port.has_data() || !port.added,
+                None => true,
+            })?;
+
+            let port = Self::port_mut(&mut self.ports, id)?;
+            if port.rx_pos == port.rx_len {
+                let (_, len) = port.rx.pop_used().ok_or(Error::InvalidResponse)?;
+                port.rx_pos = 0;
+                port.rx_len = (len as usize).min(RX_BUF_SIZE);
+                port.rx_posted = false;
+            }
+            let len = buf.len().min(port.rx_len - port.rx_pos);
+            buf[..len].copy_from_slice(&port.rx_buf.as_slice()[port.rx_pos..port.rx_pos + len]);
+            port.rx_pos += len;
+            if port.rx_pos == port.rx_len {
+                self.post_rx(id as usize)?;
+            }
+            // The device can return empty buffers, which aren't the end of the stream
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+
+    /// Writes to a port, blocking until the device took all of it. Data written while nothing
+    /// is connected on the host side is dropped.
+    pub fn write(&mut self, id: u32, data: &[u8]) -> Result<(), Error> {
+        self.process_control()?;
+        for chunk in data.chunks(TX_BUF_SIZE) {
+            let port = Self::port_mut(&mut self.ports, id)?;
+            port.tx_buf.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            let buf = port.tx_buf.range(0, chunk.len());
+            self.transport.send::<H>(&mut port.tx, &[buf], &[])?;
+        }
+        Ok(())
+    }
+}
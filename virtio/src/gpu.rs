@@ -0,0 +1,268 @@
+//! GPU devices in 2D mode, see "GPU Device" in the virtio specification
+//!
+//! The host keeps resources, images the driver creates and backs with guest memory. Drawing is
+//! done in guest memory, then copied to the resource with [`VirtioGpu::transfer_to_host_2d`]
+//! and shown with [`VirtioGpu::resource_flush`] once the resource is a scanout's.
+
+use crate::mmio::MmioTransport;
+use crate::queue::VirtQueue;
+use crate::{Dma, Error, Hal};
+use core::marker::PhantomData;
+
+/// Configuration space offsets
+const CONFIG_EVENTS_READ: usize = 0;
+const CONFIG_EVENTS_CLEAR: usize = 4;
+const CONFIG_NUM_SCANOUTS: usize = 8;
+
+/// The display configuration changed, e.g. the window was resized
+const EVENT_DISPLAY: u32 = 1 << 0;
+
+/// Commands
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+/// Responses
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+/// Every command and response starts with this header
+const HEADER_LEN: usize = 24;
+const RECT_LEN: usize = 16;
+pub const MAX_SCANOUTS: usize = 16;
+const DISPLAY_INFO_LEN: usize = HEADER_LEN + MAX_SCANOUTS * (RECT_LEN + 8);
+
+const QUEUE_SIZE: u16 = 16;
+/// The command being sent, and the device's response
+const CMD_OFFSET: usize = 0;
+const RESP_OFFSET: usize = 512;
+const BUF_SIZE: usize = 1024;
+
+/// Pixel formats, named after the order of the bytes in memory
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    B8G8R8A8 = 1,
+    B8G8R8X8 = 2,
+    A8R8G8B8 = 3,
+    X8R8G8B8 = 4,
+    R8G8B8A8 = 67,
+    X8B8G8R8 = 68,
+    A8B8G8R8 = 121,
+    R8G8B8X8 = 134,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.x.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.y.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.width.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.height.to_le_bytes());
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        Self::new(u32_at(0), u32_at(4), u32_at(8), u32_at(12))
+    }
+}
+
+pub struct VirtioGpu<H: Hal> {
+    transport: MmioTransport,
+    control: VirtQueue,
+    buf: Dma,
+    num_scanouts: u32,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> VirtioGpu<H> {
+    pub fn new(mut transport: MmioTransport) -> Result<Self, Error> {
+        // No 3D, no EDID
+        transport.begin_init(0)?;
+        let control = transport.setup_queue::<H>(0, QUEUE_SIZE)?;
+        let buf = Dma::new::<H>(BUF_SIZE)?;
+        transport.finish_init();
+        let num_scanouts = transport.read_config::<u32>(CONFIG_NUM_SCANOUTS);
+        Ok(Self {
+            transport,
+            control,
+            buf,
+            num_scanouts: num_scanouts.min(MAX_SCANOUTS as u32),
+            _hal: PhantomData,
+        })
+    }
+
+    pub fn num_scanouts(&self) -> u32 {
+        self.num_scanouts
+    }
+
+    /// Whether the display configuration changed since the last call, then
+    /// [`Self::display_info`] has the new one
+    pub fn take_display_event(&mut self) -> bool {
+        let events = self.transport.read_config::<u32>(CONFIG_EVENTS_READ);
+        if events & EVENT_DISPLAY == 0 {
+            return false;
+        }
+        self.transport
+            .write_config::<u32>(CONFIG_EVENTS_CLEAR, EVENT_DISPLAY);
+        true
+    }
+
+    /// Sends the command written at [`CMD_OFFSET`], returns the response
+    fn command(&mut self, len: usize, response_len: usize) -> Result<&[u8], Error> {
+        let request = self.buf.range(CMD_OFFSET, len);
+        let response = self.buf.range(RESP_OFFSET, response_len);
+        let got = self
+            .transport
+            .send::<H>(&mut self.control, &[request], &[response])? as usize;
+        let response = &self.buf.as_slice()[RESP_OFFSET..RESP_OFFSET + response_len];
+        if got < HEADER_LEN {
+            return Err(Error::InvalidResponse);
+        }
+        match u32::from_le_bytes(response[0..4].try_into().unwrap()) {
+            RESP_OK_NODATA | RESP_OK_DISPLAY_INFO => Ok(&response[..got.min(response_len)]),
+            RESP_ERR_OUT_OF_MEMORY => Err(Error::OutOfMemory),
+            RESP_ERR_INVALID_SCANOUT_ID
+            | RESP_ERR_INVALID_RESOURCE_ID
+            | RESP_ERR_INVALID_PARAMETER => Err(Error::InvalidArgument),
+            _ => Err(Error::IoError),
+        }
+    }
+
+    /// Starts a command of type `cmd`, returns the buffer to write its fields to, after the
+    /// header
+    fn begin(&mut self, cmd: u32, len: usize) -> &mut [u8] {
+        let buf = &mut self.buf.as_mut_slice()[CMD_OFFSET..CMD_OFFSET + len];
+        buf.fill(0);
+        buf[0..4].copy_from_slice(&cmd.to_le_bytes());
+        &mut buf[HEADER_LEN..]
+    }
+
+    /// The size and position of each scanout, `None` for disabled ones
+    pub fn display_info(&mut self) -> Result<[Option<Rect>; MAX_SCANOUTS], Error> {
+        self.begin(CMD_GET_DISPLAY_INFO, HEADER_LEN);
+        let response = self.command(HEADER_LEN, DISPLAY_INFO_LEN)?;
+        if response.len() < DISPLAY_INFO_LEN {
+            return Err(Error::InvalidResponse);
+        }
+        let mut displays = [None; MAX_SCANOUTS];
+        for (display, info) in displays
+            .iter_mut()
+            .zip(response[HEADER_LEN..].chunks_exact(RECT_LEN + 8))
+        {
+            let enabled = u32::from_le_bytes(info[RECT_LEN..RECT_LEN + 4].try_into().unwrap());
+            if enabled != 0 {
+                *display = Some(Rect::read(info));
+            }
+        }
+        Ok(displays)
+    }
+
+    /// Creates a resource on the host, `resource_id` is chosen by the driver and can't be 0
+    pub fn resource_create_2d(
+        &mut self,
+        resource_id: u32,
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> Result<(), Error> {
+        let fields = self.begin(CMD_RESOURCE_CREATE_2D, HEADER_LEN + 16);
+        fields[0..4].copy_from_slice(&resource_id.to_le_bytes());
+        fields[4..8].copy_from_slice(&(format as u32).to_le_bytes());
+        fields[8..12].copy_from_slice(&width.to_le_bytes());
+        fields[12..16].copy_from_slice(&height.to_le_bytes());
+        self.command(HEADER_LEN + 16, HEADER_LEN).map(|_| ())
+    }
+
+    pub fn resource_unref(&mut self, resource_id: u32) -> Result<(), Error> {
+        let fields = self.begin(CMD_RESOURCE_UNREF, HEADER_LEN + 8);
+        fields[0..4].copy_from_slice(&resource_id.to_le_bytes());
+        self.command(HEADER_LEN + 8, HEADER_LEN).map(|_| ())
+    }
+
+    /// Backs a resource with guest memory, the memory stays in use until it's detached
+    pub fn resource_attach_backing(
+        &mut self,
+        resource_id: u32,
+        backing: &Dma,
+    ) -> Result<(), Error> {
+        let fields = self.begin(CMD_RESOURCE_ATTACH_BACKING, HEADER_LEN + 24);
+        fields[0..4].copy_from_slice(&resource_id.to_le_bytes());
+        // A single entry, the memory is contiguous
+        fields[4..8].copy_from_slice(&1u32.to_le_bytes());
+        fields[8..16].copy_from_slice(&(backing.phy_addr() as u64).to_le_bytes());
+        fields[16..20].copy_from_slice(&(backing.len() as u32).to_le_bytes());
+        self.command(HEADER_LEN + 24, HEADER_LEN).map(|_| ())
+    }
+
+    pub fn resource_detach_backing(&mut self, resource_id: u32) -> Result<(), Error> {
+        let fields = self.begin(CMD_RESOURCE_DETACH_BACKING, HEADER_LEN + 8);
+        fields[0..4].copy_from_slice(&resource_id.to_le_bytes());
+        self.command(HEADER_LEN + 8, HEADER_LEN).map(|_| ())
+    }
+
+    /// Shows `rect` of the resource on a scanout, resource 0 turns the scanout off
+    pub fn set_scanout(
+        &mut self,
+        scanout_id: u32,
+        resource_id: u32,
+        rect: Rect,
+    ) -> Result<(), Error> {
+        let fields = self.begin(CMD_SET_SCANOUT, HEADER_LEN + RECT_LEN + 8);
+        rect.write(fields);
+        fields[RECT_LEN..RECT_LEN + 4].copy_from_slice(&scanout_id.to_le_bytes());
+        fields[RECT_LEN + 4..RECT_LEN + 8].copy_from_slice(&resource_id.to_le_bytes());
+        self.command(HEADER_LEN + RECT_LEN + 8, HEADER_LEN)
+            .map(|_| ())
+    }
+
+    /// Copies `rect` of the backing memory to the resource. `offset` is where the rectangle
+    /// starts in the backing memory.
+    pub fn transfer_to_host_2d(
+        &mut self,
+        resource_id: u32,
+        rect: Rect,
+        offset: u64,
+    ) -> Result<(), Error> {
+        let fields = self.begin(CMD_TRANSFER_TO_HOST_2D, HEADER_LEN + RECT_LEN + 16);
+        rect.write(fields);
+        fields[RECT_LEN..RECT_LEN + 8].copy_from_slice(&offset.to_le_bytes());
+        fields[RECT_LEN + 8..RECT_LEN + 12].copy_from_slice(&resource_id.to_le_bytes());
+        self.command(HEADER_LEN + RECT_LEN + 16, HEADER_LEN)
+            .map(|_| ())
+    }
+
+    /// Updates the scanouts showing `rect` of the resource
+    pub fn resource_flush(&mut self, resource_id: u32, rect: Rect) -> Result<(), Error> {
+        let fields = self.begin(CMD_RESOURCE_FLUSH, HEADER_LEN + RECT_LEN + 8);
+        rect.write(fields);
+        fields[RECT_LEN..RECT_LEN + 4].copy_from_slice(&resource_id.to_le_bytes());
+        self.command(HEADER_LEN + RECT_LEN + 8, HEADER_LEN)
+            .map(|_| ())
+    }
+}
@@ -0,0 +1,240 @@
+//! Input devices, see "Input Device" in the virtio specification
+//!
+//! Devices report Linux evdev events: a type, a code and a value, with an `EV_SYN` event closing
+//! each batch that belongs together, e.g. the X and Y of a pointer movement. The configuration
+//! space describes the device: its name, which events it can send and the range of its
+//! absolute axes. Event buffers are posted once and put back as soon as their event was read.
+
+use crate::mmio::MmioTransport;
+use crate::queue::VirtQueue;
+use crate::{Dma, Error, Hal};
+use core::marker::PhantomData;
+
+/// Event types
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_MSC: u16 = 0x04;
+pub const EV_LED: u16 = 0x11;
+pub const EV_REP: u16 = 0x14;
+pub const EV_MAX: u16 = 0x1f;
+
+/// `EV_SYN` codes
+pub const SYN_REPORT: u16 = 0;
+/// The device dropped events, the state has to be read again
+pub const SYN_DROPPED: u16 = 3;
+
+/// Configuration space offsets
+const CONFIG_SELECT: usize = 0;
+const CONFIG_SUBSEL: usize = 1;
+const CONFIG_SIZE: usize = 2;
+const CONFIG_DATA: usize = 8;
+pub const MAX_CONFIG_LEN: usize = 128;
+
+/// What the configuration data shows, depending on the select register
+const CFG_ID_NAME: u8 = 0x01;
+const CFG_ID_SERIAL: u8 = 0x02;
+const CFG_ID_DEVIDS: u8 = 0x03;
+const CFG_PROP_BITS: u8 = 0x10;
+const CFG_EV_BITS: u8 = 0x11;
+const CFG_ABS_INFO: u8 = 0x12;
+
+const EVENT_QUEUE: u16 = 0;
+const BUFFERS: usize = 64;
+const EVENT_LEN: usize = 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// The range and precision of an absolute axis
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct AbsInfo {
+    pub min: i32,
+    pub max: i32,
+    /// Changes smaller than this are noise
+    pub fuzz: i32,
+    /// Values within this of the center are reported as the center
+    pub flat: i32,
+    /// Units per millimeter
+    pub res: i32,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceIds {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+/// A set of codes, e.g. the keys of a keyboard, one bit each
+#[derive(Copy, Clone)]
+pub struct Bitmap {
+    bits: [u8; MAX_CONFIG_LEN],
+}
+
+impl Bitmap {
+    pub fn contains(&self, code: u16) -> bool {
+        let byte = code as usize / 8;
+        byte < MAX_CONFIG_LEN && self.bits[byte] & (1 << (code % 8)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&b| b == 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..(MAX_CONFIG_LEN * 8) as u16).filter(|&code| self.contains(code))
+    }
+}
+
+pub struct VirtioInput<H: Hal> {
+    transport: MmioTransport,
+    events: VirtQueue,
+    buffers: Dma,
+    /// The queue token of the buffer of each slot
+    tokens: [Option<u16>; BUFFERS],
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> VirtioInput<H> {
+    pub fn new(mut transport: MmioTransport) -> Result<Self, Error> {
+        transport.begin_init(0)?;
+        // The status queue, for LEDs, is left alone
+        let events = transport.setup_queue::<H>(EVENT_QUEUE, BUFFERS as u16)?;
+        let buffers = Dma::new::<H>(EVENT_LEN * BUFFERS)?;
+        transport.finish_init();
+        let mut input = Self {
+            transport,
+            events,
+            buffers,
+            tokens: [None; BUFFERS],
+            _hal: PhantomData,
+        };
+        for slot in 0..BUFFERS {
+            input.post(slot)?;
+        }
+        input.transport.notify(EVENT_QUEUE);
+        Ok(input)
+    }
+
+    /// Selects a configuration item, copies it to `buf` and returns its length, 0 if the device
+    /// doesn't have it
+    fn query(&self, select: u8, subsel: u8, buf: &mut [u8; MAX_CONFIG_LEN]) -> usize {
+        self.transport.write_config::<u8>(CONFIG_SELECT, select);
+        self.transport.write_config::<u8>(CONFIG_SUBSEL, subsel);
+        let len = (self.transport.read_config::<u8>(CONFIG_SIZE) as usize).min(MAX_CONFIG_LEN);
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = match i < len {
+                true => self.transport.read_config::<u8>(CONFIG_DATA + i),
+                false => 0,
+            };
+        }
+        len
+    }
+
+    fn query_str<'a>(&self, select: u8, buf: &'a mut [u8; MAX_CONFIG_LEN]) -> &'a str {
+        let len = self.query(select, 0, buf);
+        let len = buf[..len].iter().position(|&b| b == 0).unwrap_or(len);
+        core::str::from_utf8(&buf[..len]).unwrap_or("")
+    }
+
+    pub fn name<'a>(&self, buf: &'a mut [u8; MAX_CONFIG_LEN]) -> &'a str {
+        self.query_str(CFG_ID_NAME, buf)
+    }
+
+    pub fn serial<'a>(&self, buf: &'a mut [u8; MAX_CONFIG_LEN]) -> &'a str {
+        self.query_str(CFG_ID_SERIAL, buf)
+    }
+
+    pub fn ids(&self) -> Option<DeviceIds> {
+        let mut buf = [0u8; MAX_CONFIG_LEN];
+        if self.query(CFG_ID_DEVIDS, 0, &mut buf) < 8 {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([buf[offset], buf[offset + 1]]);
+        Some(DeviceIds {
+            bustype: u16_at(0),
+            vendor: u16_at(2),
+            product: u16_at(4),
+            version: u16_at(6),
+        })
+    }
+
+    /// The `INPUT_PROP_*` bits, e.g. whether it's a touchscreen or a touchpad
+    pub fn properties(&self) -> Bitmap {
+        let mut bits = [0u8; MAX_CONFIG_LEN];
+        self.query(CFG_PROP_BITS, 0, &mut bits);
+        Bitmap { bits }
+    }
+
+    /// The codes the device sends events of `event_type` for, empty if it sends none
+    pub fn event_codes(&self, event_type: u16) -> Bitmap {
+        let mut bits = [0u8; MAX_CONFIG_LEN];
+        if event_type <= EV_MAX {
+            self.query(CFG_EV_BITS, event_type as u8, &mut bits);
+        }
+        Bitmap { bits }
+    }
+
+    pub fn abs_info(&self, axis: u16) -> Option<AbsInfo> {
+        let mut buf = [0u8; MAX_CONFIG_LEN];
+        if axis > u8::MAX as u16 || self.query(CFG_ABS_INFO, axis as u8, &mut buf) < 20 {
+            return None;
+        }
+        let i32_at =
+            |offset: usize| i32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        Some(AbsInfo {
+            min: i32_at(0),
+            max: i32_at(4),
+            fuzz: i32_at(8),
+            flat: i32_at(12),
+            res: i32_at(16),
+        })
+    }
+
+    /// Gives the device the event buffer of `slot`, without notifying it
+    fn post(&mut self, slot: usize) -> Result<(), Error> {
+        let buf = self.buffers.range(slot * EVENT_LEN, EVENT_LEN);
+        self.tokens[slot] = Some(self.events.add(&[], &[buf])?);
+        Ok(())
+    }
+
+    /// Takes the next event, if there's one
+    pub fn pop_event(&mut self) -> Result<Option<InputEvent>, Error> {
+        let Some((token, len)) = self.events.pop_used() else {
+            return Ok(None);
+        };
+        let slot = self
+            .tokens
+            .iter()
+            .position(|&t| t == Some(token))
+            .ok_or(Error::InvalidResponse)?;
+        self.tokens[slot] = None;
+
+        let memory = &self.buffers.as_slice()[slot * EVENT_LEN..(slot + 1) * EVENT_LEN];
+        let event = InputEvent {
+            event_type: u16::from_le_bytes([memory[0], memory[1]]),
+            code: u16::from_le_bytes([memory[2], memory[3]]),
+            value: i32::from_le_bytes(memory[4..8].try_into().unwrap()),
+        };
+        self.post(slot)?;
+        self.transport.notify(EVENT_QUEUE);
+        if (len as usize) < EVENT_LEN {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(Some(event))
+    }
+
+    /// Blocks until there's an event, or for at most `timeout_ns` nanoseconds. Returns whether
+    /// there's one.
+    pub fn wait(&self, timeout_ns: u64) -> Result<bool, Error> {
+        self.transport
+            .wait_used_timeout::<H>(&self.events, timeout_ns)
+    }
+}
@@ -0,0 +1,175 @@
+//! Virtio devices over the MMIO transport, for usermode drivers
+//!
+//! The crate doesn't know how to map registers, allocate DMA memory or wait for interrupts, the
+//! driver provides that through [`Hal`]. Both the legacy (version 1) and the modern (version 2)
+//! MMIO interfaces are supported; QEMU uses the legacy one unless started with
+//! `-global virtio-mmio.force-legacy=false`.
+
+#![no_std]
+
+use core::fmt::{Display, Formatter};
+use core::ptr::NonNull;
+
+pub mod blk;
+pub mod console;
+pub mod gpu;
+pub mod input;
+pub mod mmio;
+pub mod net;
+pub mod p9;
+pub mod queue;
+pub mod rng;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// What a driver needs from the operating system
+pub trait Hal {
+    /// Allocates zeroed, physically contiguous memory that devices can access. Returns the
+    /// virtual and the physical address.
+    fn dma_alloc(len: usize) -> Result<(NonNull<u8>, usize), Error>;
+
+    /// Maps device registers, which don't have to be page-aligned
+    ///
+    /// # Safety
+    ///
+    /// `phy_addr` must be the registers of a device
+    unsafe fn mmio_map(phy_addr: usize, len: usize) -> Result<NonNull<u8>, Error>;
+
+    /// Blocks until the interrupt fires. The interrupt condition in the device must be cleared
+    /// before waiting again.
+    fn wait_interrupt(interrupt_id: u32) -> Result<(), Error>;
+
+    /// Like [`Self::wait_interrupt`], but gives up after `timeout_ns` nanoseconds. Returns
+    /// whether the interrupt fired.
+    fn wait_interrupt_timeout(interrupt_id: u32, timeout_ns: u64) -> Result<bool, Error>;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The registers don't start with the virtio magic value
+    NotVirtio,
+    UnsupportedVersion(u32),
+    /// A virtio-mmio slot without a device behind it
+    NoDevice,
+    /// The device didn't accept the negotiated features
+    FeaturesRejected,
+    /// The queue doesn't exist or is already in use
+    QueueUnavailable,
+    /// Not enough free descriptors for the request
+    QueueFull,
+    /// The device returned something that doesn't make sense
+    InvalidResponse,
+    /// The device reported an error for the request
+    IoError,
+    /// The device doesn't support the request
+    Unsupported,
+    /// The request is malformed or out of the device's bounds
+    InvalidArgument,
+    /// Writing to a read-only device
+    ReadOnly,
+    OutOfMemory,
+    /// The Hal failed, e.g. waiting for an interrupt
+    Hal,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::NotVirtio => write!(f, "not a virtio device"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported version {version}"),
+            Error::NoDevice => write!(f, "no device"),
+            Error::FeaturesRejected => write!(f, "features rejected"),
+            Error::QueueUnavailable => write!(f, "queue unavailable"),
+            Error::QueueFull => write!(f, "queue full"),
+            Error::InvalidResponse => write!(f, "invalid response"),
+            Error::IoError => write!(f, "I/O error"),
+            Error::Unsupported => write!(f, "unsupported"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::ReadOnly => write!(f, "read-only"),
+            Error::OutOfMemory => write!(f, "out of memory"),
+            Error::Hal => write!(f, "HAL error"),
+        }
+    }
+}
+
+/// Device IDs, see "Device Types" in the virtio specification
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    NineP,
+    Gpu,
+    Input,
+    Vsock,
+    Other(u32),
+}
+
+impl From<u32> for DeviceType {
+    fn from(id: u32) -> Self {
+        match id {
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::Entropy,
+            5 => DeviceType::Balloon,
+            8 => DeviceType::Scsi,
+            9 => DeviceType::NineP,
+            16 => DeviceType::Gpu,
+            18 => DeviceType::Input,
+            19 => DeviceType::Vsock,
+            id => DeviceType::Other(id),
+        }
+    }
+}
+
+/// A DMA buffer, which is never freed
+pub struct Dma {
+    virt: NonNull<u8>,
+    phy_addr: usize,
+    len: usize,
+}
+
+impl Dma {
+    pub fn new<H: Hal>(len: usize) -> Result<Self, Error> {
+        let (virt, phy_addr) = H::dma_alloc(len)?;
+        Ok(Self {
+            virt,
+            phy_addr,
+            len,
+        })
+    }
+
+    pub fn phy_addr(&self) -> usize {
+        self.phy_addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.virt.as_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_ptr(), self.len) }
+    }
+
+    /// The physical address range of `len` bytes at `offset`, for a descriptor
+    pub fn range(&self, offset: usize, len: usize) -> (usize, usize) {
+        assert!(offset + len <= self.len, "DMA range out of bounds");
+        (self.phy_addr + offset, len)
+    }
+}
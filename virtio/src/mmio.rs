@@ -0,0 +1,300 @@
+//! The virtio-mmio transport, see "Virtio Over MMIO" in the virtio specification
+
+use crate::queue::VirtQueue;
+use crate::{DeviceType, Error, Hal, PAGE_SIZE};
+use core::ptr::NonNull;
+
+/// "virt" in little endian
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+const MAGIC: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+/// Legacy only
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+/// Legacy only
+const QUEUE_ALIGN: usize = 0x03c;
+/// Legacy only
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG_GENERATION: usize = 0x0fc;
+const CONFIG: usize = 0x100;
+
+/// The size of the register window of a virtio-mmio slot
+pub const MMIO_SIZE: usize = 0x200;
+
+/// Device status bits
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+/// The device complies with the modern interface, required by version 2 devices
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// Interrupt status bits
+pub const INT_USED_BUFFER: u32 = 1;
+pub const INT_CONFIG_CHANGE: u32 = 2;
+
+pub struct MmioTransport {
+    base: NonNull<u8>,
+    version: u32,
+    device_type: DeviceType,
+    interrupt_id: u32,
+}
+
+impl MmioTransport {
+    /// Maps the registers of a `virtio,mmio` node and checks that a device is behind them
+    pub fn probe<H: Hal>(phy_addr: usize, len: usize, interrupt_id: u32) -> Result<Self, Error> {
+        let base = unsafe { H::mmio_map(phy_addr, len)? };
+        unsafe { Self::new(base, interrupt_id) }
+    }
+
+    /// # Safety
+    ///
+    /// `base` must point to mapped virtio-mmio registers
+    pub unsafe fn new(base: NonNull<u8>, interrupt_id: u32) -> Result<Self, Error> {
+        let mut transport = Self {
+            base,
+            version: 0,
+            device_type: DeviceType::Other(0),
+            interrupt_id,
+        };
+        if transport.read(MAGIC) != MAGIC_VALUE {
+            return Err(Error::NotVirtio);
+        }
+        transport.version = transport.read(VERSION);
+        if !(1..=2).contains(&transport.version) {
+            return Err(Error::UnsupportedVersion(transport.version));
+        }
+        // QEMU creates all the slots, the empty ones have a device ID of 0
+        match transport.read(DEVICE_ID) {
+            0 => return Err(Error::NoDevice),
+            id => transport.device_type = DeviceType::from(id),
+        }
+        Ok(transport)
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { (self.base.as_ptr().add(offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { (self.base.as_ptr().add(offset) as *mut u32).write_volatile(value) }
+    }
+
+    /// 1 for legacy devices, 2 for modern ones
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        self.read(VENDOR_ID)
+    }
+
+    pub fn interrupt_id(&self) -> u32 {
+        self.interrupt_id
+    }
+
+    /// Resets the device, which also drops its queues
+    pub fn reset(&mut self) {
+        self.write(STATUS, 0);
+    }
+
+    /// Starts initializing the device: resets it and negotiates features, keeping those of
+    /// `supported` that the device offers. Returns the negotiated features. Queues are set up
+    /// next, then [`Self::finish_init`] lets the device run.
+    pub fn begin_init(&mut self, supported: u64) -> Result<u64, Error> {
+        self.reset();
+        self.write(STATUS, STATUS_ACKNOWLEDGE);
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let features = if self.is_legacy() {
+            // Legacy devices only have the first 32 feature bits
+            let features = self.device_features(0) as u64 & supported & 0xffff_ffff;
+            self.set_driver_features(0, features as u32);
+            features
+        } else {
+            let offered = self.device_features(0) as u64 | (self.device_features(1) as u64) << 32;
+            if offered & F_VERSION_1 == 0 {
+                self.fail();
+                return Err(Error::FeaturesRejected);
+            }
+            let features = offered & (supported | F_VERSION_1);
+            self.set_driver_features(0, features as u32);
+            self.set_driver_features(1, (features >> 32) as u32);
+
+            self.write(STATUS, self.read(STATUS) | STATUS_FEATURES_OK);
+            if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(Error::FeaturesRejected);
+            }
+            features
+        };
+        Ok(features)
+    }
+
+    fn device_features(&self, select: u32) -> u32 {
+        self.write(DEVICE_FEATURES_SEL, select);
+        self.read(DEVICE_FEATURES)
+    }
+
+    fn set_driver_features(&self, select: u32, features: u32) {
+        self.write(DRIVER_FEATURES_SEL, select);
+        self.write(DRIVER_FEATURES, features);
+    }
+
+    fn fail(&mut self) {
+        self.write(STATUS, self.read(STATUS) | STATUS_FAILED);
+    }
+
+    /// Allocates queue `index` with at most `max_size` entries and hands it to the device
+    pub fn setup_queue<H: Hal>(&mut self, index: u16, max_size: u16) -> Result<VirtQueue, Error> {
+        self.write(QUEUE_SEL, index as u32);
+        let in_use = if self.is_legacy() {
+            self.read(QUEUE_PFN) != 0
+        } else {
+            self.read(QUEUE_READY) != 0
+        };
+        let device_max = self.read(QUEUE_NUM_MAX);
+        if device_max == 0 || in_use {
+            return Err(Error::QueueUnavailable);
+        }
+        // Sizes must be powers of 2, take the largest one both sides can do
+        let size = 1u32 << (device_max.min(max_size as u32).max(1)).ilog2();
+
+        let queue = VirtQueue::new::<H>(index, size as u16)?;
+        self.write(QUEUE_NUM, size);
+        if self.is_legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (queue.desc_phy_addr() / PAGE_SIZE) as u32);
+        } else {
+            self.write_u64(QUEUE_DESC_LOW, QUEUE_DESC_HIGH, queue.desc_phy_addr());
+            self.write_u64(QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, queue.avail_phy_addr());
+            self.write_u64(QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, queue.used_phy_addr());
+            self.write(QUEUE_READY, 1);
+        }
+        Ok(queue)
+    }
+
+    fn write_u64(&self, low: usize, high: usize, value: usize) {
+        self.write(low, value as u32);
+        self.write(high, (value as u64 >> 32) as u32);
+    }
+
+    /// Tells the device the driver is ready, after the queues are set up
+    pub fn finish_init(&mut self) {
+        self.write(STATUS, self.read(STATUS) | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device there are new buffers in a queue
+    pub fn notify(&self, queue: u16) {
+        self.write(QUEUE_NOTIFY, queue as u32);
+    }
+
+    /// Acknowledges the pending interrupts, returns the `INT_*` bits that were set
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(INTERRUPT_ACK, status);
+        }
+        status
+    }
+
+    /// Blocks until the device returns a buffer in `queue`
+    pub fn wait_used<H: Hal>(&self, queue: &VirtQueue) -> Result<(), Error> {
+        loop {
+            // Acknowledge first, so a completion between the check and the wait still interrupts
+            self.ack_interrupt();
+            if queue.can_pop() {
+                return Ok(());
+            }
+            H::wait_interrupt(self.interrupt_id)?;
+        }
+    }
+
+    /// Like [`Self::wait_used`], but gives up after `timeout_ns` nanoseconds. Returns whether
+    /// there's a buffer to pop.
+    pub fn wait_used_timeout<H: Hal>(
+        &self,
+        queue: &VirtQueue,
+        timeout_ns: u64,
+    ) -> Result<bool, Error> {
+        self.ack_interrupt();
+        if queue.can_pop() {
+            return Ok(true);
+        }
+        // The interrupt may be for another queue, the caller checks again anyway
+        H::wait_interrupt_timeout(self.interrupt_id, timeout_ns)?;
+        Ok(queue.can_pop())
+    }
+
+    /// Queues a request, notifies the device and waits for it to complete. Returns how many
+    /// bytes the device wrote to `outputs`.
+    pub fn send<H: Hal>(
+        &self,
+        queue: &mut VirtQueue,
+        inputs: &[(usize, usize)],
+        outputs: &[(usize, usize)],
+    ) -> Result<u32, Error> {
+        let token = queue.add(inputs, outputs)?;
+        self.notify(queue.index());
+        loop {
+            self.wait_used::<H>(queue)?;
+            if let Some((used, len)) = queue.pop_used() {
+                if used != token {
+                    return Err(Error::InvalidResponse);
+                }
+                return Ok(len);
+            }
+        }
+    }
+
+    /// Reads the device-specific configuration at `offset`, making sure the device didn't
+    /// change it halfway through
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        let ptr = unsafe { self.base.as_ptr().add(CONFIG + offset) as *const T };
+        if self.is_legacy() {
+            return unsafe { ptr.read_volatile() };
+        }
+        loop {
+            let generation = self.read(CONFIG_GENERATION);
+            let value = unsafe { ptr.read_volatile() };
+            if self.read(CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { (self.base.as_ptr().add(CONFIG + offset) as *mut T).write_volatile(value) }
+    }
+}
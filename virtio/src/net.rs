@@ -0,0 +1,249 @@
+//! Network cards, see "Network Device" in the virtio specification
+//!
+//! Frames are plain Ethernet frames, without the FCS. Each one is preceded by a header for the
+//! offloads; only checksum offload is negotiated, so the header just says which checksum still
+//! has to be filled in. Receive buffers are posted once and put back as soon as their frame was
+//! copied out.
+
+use crate::mmio::MmioTransport;
+use crate::queue::VirtQueue;
+use crate::{Dma, Error, Hal};
+use core::marker::PhantomData;
+
+/// Feature bits
+const F_CSUM: u64 = 1 << 0;
+const F_GUEST_CSUM: u64 = 1 << 1;
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+
+/// Configuration space offsets
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const S_LINK_UP: u16 = 1 << 0;
+
+/// Header flags
+const HDR_F_NEEDS_CSUM: u8 = 1 << 0;
+const HDR_F_DATA_VALID: u8 = 1 << 1;
+const HDR_GSO_NONE: u8 = 0;
+
+/// The legacy header lacks the buffer count at the end
+const HEADER_LEN_LEGACY: usize = 10;
+const HEADER_LEN: usize = 12;
+
+/// The largest frame, with a VLAN tag
+pub const MAX_FRAME_LEN: usize = 1518;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+/// Buffers of each direction, each takes 2 descriptors: the header and the frame
+const BUFFERS: usize = 16;
+const QUEUE_SIZE: u16 = 2 * BUFFERS as u16;
+
+/// Per-buffer memory: the header, then the frame
+const SLOT_SIZE: usize = 2048;
+const SLOT_FRAME: usize = 64;
+
+/// Where the checksum of a frame starts, and where to store it relative to that start. The
+/// checksum is the Internet checksum of everything from `start` to the end of the frame.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Checksum {
+    pub start: u16,
+    pub offset: u16,
+}
+
+/// What the device did about the checksums of a received frame
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RxChecksum {
+    /// Nothing, they have to be checked
+    None,
+    /// They were checked and are correct
+    Valid,
+    /// The frame comes from the host, which left one for us to compute, the others are valid
+    Partial(Checksum),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RxFrame {
+    pub len: usize,
+    pub checksum: RxChecksum,
+}
+
+pub struct VirtioNet<H: Hal> {
+    transport: MmioTransport,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    features: u64,
+    header_len: usize,
+    mac: Option<[u8; 6]>,
+    /// Buffers, and the queue token of the request using each one
+    rx_buffers: Dma,
+    rx_tokens: [Option<u16>; BUFFERS],
+    tx_buffers: Dma,
+    tx_tokens: [Option<u16>; BUFFERS],
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> VirtioNet<H> {
+    pub fn new(mut transport: MmioTransport) -> Result<Self, Error> {
+        let features = transport.begin_init(F_CSUM | F_GUEST_CSUM | F_MAC | F_STATUS)?;
+        let rx = transport.setup_queue::<H>(RX_QUEUE, QUEUE_SIZE)?;
+        let tx = transport.setup_queue::<H>(TX_QUEUE, QUEUE_SIZE)?;
+        let rx_buffers = Dma::new::<H>(SLOT_SIZE * BUFFERS)?;
+        let tx_buffers = Dma::new::<H>(SLOT_SIZE * BUFFERS)?;
+        transport.finish_init();
+
+        let header_len = match transport.is_legacy() {
+            true => HEADER_LEN_LEGACY,
+            false => HEADER_LEN,
+        };
+        let mac = (features & F_MAC != 0).then(|| {
+            let mut mac = [0u8; 6];
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.read_config::<u8>(CONFIG_MAC + i);
+            }
+            mac
+        });
+        let mut net = Self {
+            transport,
+            rx,
+            tx,
+            features,
+            header_len,
+            mac,
+            rx_buffers,
+            rx_tokens: [None; BUFFERS],
+            tx_buffers,
+            tx_tokens: [None; BUFFERS],
+            _hal: PhantomData,
+        };
+        for slot in 0..BUFFERS {
+            net.post_rx(slot)?;
+        }
+        net.transport.notify(RX_QUEUE);
+        Ok(net)
+    }
+
+    /// The address assigned by the host, if it has one
+    pub fn mac(&self) -> Option<[u8; 6]> {
+        self.mac
+    }
+
+    /// Whether a cable is plugged in. Devices that can't tell are always up.
+    pub fn link_up(&self) -> bool {
+        if self.features & F_STATUS == 0 {
+            return true;
+        }
+        self.transport.read_config::<u16>(CONFIG_STATUS) & S_LINK_UP != 0
+    }
+
+    /// Whether [`Self::send`] can leave checksums to the device
+    pub fn supports_tx_checksum(&self) -> bool {
+        self.features & F_CSUM != 0
+    }
+
+    /// Whether received frames can come with [`RxChecksum::Valid`] or [`RxChecksum::Partial`]
+    pub fn supports_rx_checksum(&self) -> bool {
+        self.features & F_GUEST_CSUM != 0
+    }
+
+    /// Gives the device the receive buffer of `slot`, without notifying it
+    fn post_rx(&mut self, slot: usize) -> Result<(), Error> {
+        let offset = slot * SLOT_SIZE;
+        let header = self.rx_buffers.range(offset, self.header_len);
+        let frame = self.rx_buffers.range(offset + SLOT_FRAME, MAX_FRAME_LEN);
+        self.rx_tokens[slot] = Some(self.rx.add(&[], &[header, frame])?);
+        Ok(())
+    }
+
+    /// Takes a received frame, if there's one, copying it to `buf`. Frames that don't fit are
+    /// truncated.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<Option<RxFrame>, Error> {
+        let Some((token, len)) = self.rx.pop_used() else {
+            return Ok(None);
+        };
+        let slot = self
+            .rx_tokens
+            .iter()
+            .position(|&t| t == Some(token))
+            .ok_or(Error::InvalidResponse)?;
+        self.rx_tokens[slot] = None;
+
+        let memory = &self.rx_buffers.as_slice()[slot * SLOT_SIZE..(slot + 1) * SLOT_SIZE];
+        let frame_len = (len as usize)
+            .checked_sub(self.header_len)
+            .ok_or(Error::InvalidResponse)?
+            .min(MAX_FRAME_LEN);
+        let copied = frame_len.min(buf.len());
+        buf[..copied].copy_from_slice(&memory[SLOT_FRAME..SLOT_FRAME + copied]);
+        let flags = memory[0];
+        let checksum = if flags & HDR_F_NEEDS_CSUM != 0 {
+            RxChecksum::Partial(Checksum {
+                start: u16::from_le_bytes([memory[6], memory[7]]),
+                offset: u16::from_le_bytes([memory[8], memory[9]]),
+            })
+        } else if flags & HDR_F_DATA_VALID != 0 {
+            RxChecksum::Valid
+        } else {
+            RxChecksum::None
+        };
+
+        self.post_rx(slot)?;
+        self.transport.notify(RX_QUEUE);
+        Ok(Some(RxFrame {
+            len: frame_len,
+            checksum,
+        }))
+    }
+
+    /// Blocks until a frame is received, or for at most `timeout_ns` nanoseconds. Returns whether
+    /// there's a frame.
+    pub fn wait_rx(&self, timeout_ns: u64) -> Result<bool, Error> {
+        self.transport.wait_used_timeout::<H>(&self.rx, timeout_ns)
+    }
+
+    /// Collects the frames the device is done sending
+    fn reclaim_tx(&mut self) {
+        while let Some((token, _)) = self.tx.pop_used() {
+            if let Some(slot) = self.tx_tokens.iter().position(|&t| t == Some(token)) {
+                self.tx_tokens[slot] = None;
+            }
+        }
+    }
+
+    /// Queues a frame, blocking while all the transmit buffers are in use. With `checksum`,
+    /// the device computes that checksum, see [`Self::supports_tx_checksum`].
+    pub fn send(&mut self, frame: &[u8], checksum: Option<Checksum>) -> Result<(), Error> {
+        if frame.is_empty() || frame.len() > MAX_FRAME_LEN {
+            return Err(Error::InvalidArgument);
+        }
+        if checksum.is_some() && !self.supports_tx_checksum() {
+            return Err(Error::Unsupported);
+        }
+        let slot = loop {
+            self.reclaim_tx();
+            if let Some(slot) = self.tx_tokens.iter().position(Option::is_none) {
+                break slot;
+            }
+            self.transport.wait_used::<H>(&self.tx)?;
+        };
+
+        let offset = slot * SLOT_SIZE;
+        let header_len = self.header_len;
+        let memory = &mut self.tx_buffers.as_mut_slice()[offset..offset + SLOT_SIZE];
+        let header = &mut memory[..header_len];
+        header.fill(0);
+        header[1] = HDR_GSO_NONE;
+        if let Some(checksum) = checksum {
+            header[0] = HDR_F_NEEDS_CSUM;
+            header[6..8].copy_from_slice(&checksum.start.to_le_bytes());
+            header[8..10].copy_from_slice(&checksum.offset.to_le_bytes());
+        }
+        memory[SLOT_FRAME..SLOT_FRAME + frame.len()].copy_from_slice(frame);
+
+        let header = self.tx_buffers.range(offset, header_len);
+        let data = self.tx_buffers.range(offset + SLOT_FRAME, frame.len());
+        self.tx_tokens[slot] = Some(self.tx.add(&[header, data], &[])?);
+        self.transport.notify(TX_QUEUE);
+        Ok(())
+    }
+}
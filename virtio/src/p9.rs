@@ -0,0 +1,89 @@
+//! 9P transport, see "9P Transport" in the virtio 9P specification
+//!
+//! The device only carries messages: the driver writes a whole 9P request, the device answers
+//! with a whole response. Speaking the protocol is up to the caller.
+
+use crate::mmio::MmioTransport;
+use crate::queue::VirtQueue;
+use crate::{Dma, Error, Hal};
+use core::marker::PhantomData;
+
+/// The device has a tag naming the exported directory, like QEMU's `mount_tag`
+const F_MOUNT_TAG: u64 = 1 << 0;
+
+/// Configuration space offsets: the tag length, then the tag
+const CONFIG_TAG_LEN: usize = 0;
+const CONFIG_TAG: usize = 2;
+pub const MAX_TAG_LEN: usize = 64;
+
+const QUEUE_SIZE: u16 = 16;
+
+pub struct Virtio9p<H: Hal> {
+    transport: MmioTransport,
+    queue: VirtQueue,
+    request: Dma,
+    response: Dma,
+    tag: [u8; MAX_TAG_LEN],
+    tag_len: usize,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> Virtio9p<H> {
+    /// Initializes the device with buffers for messages of up to `msize` bytes
+    pub fn new(mut transport: MmioTransport, msize: usize) -> Result<Self, Error> {
+        let features = transport.begin_init(F_MOUNT_TAG)?;
+        let queue = transport.setup_queue::<H>(0, QUEUE_SIZE)?;
+        let request = Dma::new::<H>(msize)?;
+        let response = Dma::new::<H>(msize)?;
+        transport.finish_init();
+
+        let mut tag = [0u8; MAX_TAG_LEN];
+        let mut tag_len = 0;
+        if features & F_MOUNT_TAG != 0 {
+            tag_len = (transport.read_config::<u16>(CONFIG_TAG_LEN) as usize).min(MAX_TAG_LEN);
+            for (i, byte) in tag[..tag_len].iter_mut().enumerate() {
+                *byte = transport.read_config::<u8>(CONFIG_TAG + i);
+            }
+        }
+        Ok(Self {
+            transport,
+            queue,
+            request,
+            response,
+            tag,
+            tag_len,
+            _hal: PhantomData,
+        })
+    }
+
+    /// The tag of the exported directory, empty if the device has none
+    pub fn mount_tag(&self) -> &str {
+        core::str::from_utf8(&self.tag[..self.tag_len]).unwrap_or("")
+    }
+
+    /// The largest message that fits the buffers
+    pub fn msize(&self) -> usize {
+        self.request.len()
+    }
+
+    /// Where the next request is written before [`Self::send`]
+    pub fn request_buf(&mut self) -> &mut [u8] {
+        self.request.as_mut_slice()
+    }
+
+    /// The last response
+    pub fn response(&self) -> &[u8] {
+        self.response.as_slice()
+    }
+
+    /// Sends the first `len` bytes of the request buffer and waits for the response, returns its
+    /// length
+    pub fn send(&mut self, len: usize) -> Result<usize, Error> {
+        let request = self.request.range(0, len);
+        let response = self.response.range(0, self.response.len());
+        let len = self
+            .transport
+            .send::<H>(&mut self.queue, &[request], &[response])?;
+        Ok(len as usize)
+    }
+}
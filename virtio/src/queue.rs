@@ -0,0 +1,195 @@
+//! Split virtqueues, see "Split Virtqueues" in the virtio specification
+//!
+//! A queue is a descriptor table pointing at buffers, an available ring where the driver queues
+//! descriptor chains, and a used ring where the device returns them when it's done.
+
+use crate::{Dma, Error, Hal, PAGE_SIZE};
+use core::sync::atomic::{Ordering, fence};
+
+/// The descriptor continues in `next`
+const DESC_F_NEXT: u16 = 1 << 0;
+/// The buffer is written by the device, instead of read
+const DESC_F_WRITE: u16 = 1 << 1;
+
+const DESC_SIZE: usize = 16;
+const USED_ELEM_SIZE: usize = 8;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: Dma,
+    /// Offsets of the rings in `memory`
+    avail_offset: usize,
+    used_offset: usize,
+    /// First descriptor of the free list, chained through `next`
+    free_head: u16,
+    num_free: u16,
+    /// Our copy of the available ring's index, only we write it
+    avail_idx: u16,
+    /// How far we've read the used ring
+    last_used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Allocates a queue with `size` entries, laid out as the legacy interface requires: the
+    /// descriptor table, then the available ring, then the used ring on the next page
+    pub(crate) fn new<H: Hal>(index: u16, size: u16) -> Result<Self, Error> {
+        assert!(size.is_power_of_two(), "queue size must be a power of 2");
+        let size_usize = size as usize;
+        let avail_offset = DESC_SIZE * size_usize;
+        let avail_len = 2 * (3 + size_usize);
+        let used_offset = (avail_offset + avail_len).next_multiple_of(PAGE_SIZE);
+        let used_len = 2 * 3 + USED_ELEM_SIZE * size_usize;
+        let memory = Dma::new::<H>(used_offset + used_len.next_multiple_of(PAGE_SIZE))?;
+
+        let queue = Self {
+            index,
+            size,
+            memory,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+        for i in 0..size {
+            unsafe { (*queue.desc(i)).next = i + 1 };
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(crate) fn desc_phy_addr(&self) -> usize {
+        self.memory.phy_addr()
+    }
+
+    pub(crate) fn avail_phy_addr(&self) -> usize {
+        self.memory.phy_addr() + self.avail_offset
+    }
+
+    pub(crate) fn used_phy_addr(&self) -> usize {
+        self.memory.phy_addr() + self.used_offset
+    }
+
+    fn desc(&self, index: u16) -> *mut Descriptor {
+        unsafe { (self.memory.as_ptr() as *mut Descriptor).add(index as usize) }
+    }
+
+    /// The `index`-th u16 of the available ring: flags, idx, then the ring entries
+    fn avail_u16(&self, index: usize) -> *mut u16 {
+        unsafe { (self.memory.as_ptr().add(self.avail_offset) as *mut u16).add(index) }
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe {
+            let idx = self.memory.as_ptr().add(self.used_offset + 2) as *const u16;
+            idx.read_volatile()
+        }
+    }
+
+    /// The id and length of the `index`-th used ring entry
+    fn used_elem(&self, index: u16) -> (u32, u32) {
+        let offset = self.used_offset + 4 + USED_ELEM_SIZE * (index % self.size) as usize;
+        unsafe {
+            let elem = self.memory.as_ptr().add(offset) as *const u32;
+            (elem.read_volatile(), elem.add(1).read_volatile())
+        }
+    }
+
+    pub fn available_descriptors(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Queues a request made of `inputs`, read by the device, followed by `outputs`, written by
+    /// the device. Buffers are `(physical address, length)` pairs. Returns a token to match the
+    /// request with [`Self::pop_used`]. The device still has to be notified.
+    pub fn add(
+        &mut self,
+        inputs: &[(usize, usize)],
+        outputs: &[(usize, usize)],
+    ) -> Result<u16, Error> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 || count > self.num_free as usize {
+            return Err(Error::QueueFull);
+        }
+
+        let head = self.free_head;
+        let buffers = inputs
+            .iter()
+            .map(|buf| (buf, 0))
+            .chain(outputs.iter().map(|buf| (buf, DESC_F_WRITE)));
+        for (i, (&(addr, len), flags)) in buffers.enumerate() {
+            let desc = self.desc(self.free_head);
+            unsafe {
+                (*desc).addr = addr as u64;
+                (*desc).len = len as u32;
+                (*desc).flags = if i + 1 < count {
+                    flags | DESC_F_NEXT
+                } else {
+                    flags
+                };
+                self.free_head = (*desc).next;
+            }
+        }
+        self.num_free -= count as u16;
+
+        // Publish the chain, the descriptors must be visible before the index moves
+        unsafe {
+            self.avail_u16(2 + (self.avail_idx % self.size) as usize)
+                .write_volatile(head);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.avail_u16(1).write_volatile(self.avail_idx);
+        }
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Whether the device returned a request that wasn't popped yet
+    pub fn can_pop(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.used_idx() != self.last_used_idx
+    }
+
+    /// Takes the next request the device is done with, returns its token and how many bytes the
+    /// device wrote
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.can_pop() {
+            return None;
+        }
+        let (id, len) = self.used_elem(self.last_used_idx);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // Put the chain back on the free list
+        let head = id as u16;
+        let mut index = head;
+        loop {
+            self.num_free += 1;
+            let desc = self.desc(index);
+            let (flags, next) = unsafe { ((*desc).flags, (*desc).next) };
+            if flags & DESC_F_NEXT == 0 {
+                unsafe { (*desc).next = self.free_head };
+                break;
+            }
+            index = next;
+        }
+        self.free_head = head;
+        Some((head, len))
+    }
+}
@@ -0,0 +1,49 @@
+//! Entropy sources, see "Entropy Device" in the virtio specification
+//!
+//! The device fills whatever buffers it's given with random bytes, QEMU reads them from the
+//! host's `/dev/urandom` by default.
+
+use crate::mmio::MmioTransport;
+use crate::queue::VirtQueue;
+use crate::{Dma, Error, Hal};
+use core::marker::PhantomData;
+
+const QUEUE_SIZE: u16 = 4;
+const BUF_SIZE: usize = 256;
+
+pub struct VirtioRng<H: Hal> {
+    transport: MmioTransport,
+    queue: VirtQueue,
+    buf: Dma,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> VirtioRng<H> {
+    pub fn new(mut transport: MmioTransport) -> Result<Self, Error> {
+        transport.begin_init(0)?;
+        let queue = transport.setup_queue::<H>(0, QUEUE_SIZE)?;
+        let buf = Dma::new::<H>(BUF_SIZE)?;
+        transport.finish_init();
+        Ok(Self {
+            transport,
+            queue,
+            buf,
+            _hal: PhantomData,
+        })
+    }
+
+    /// Fills `out` with random bytes, blocking until the device provided enough
+    pub fn fill(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let mut filled = 0;
+        while filled < out.len() {
+            let len = (out.len() - filled).min(BUF_SIZE);
+            let buf = self.buf.range(0, len);
+            let got = self.transport.send::<H>(&mut self.queue, &[], &[buf])? as usize;
+            // The device may return less than asked, if its source is slow
+            let got = got.min(len);
+            out[filled..filled + got].copy_from_slice(&self.buf.as_slice()[..got]);
+            filled += got;
+        }
+        Ok(())
+    }
+}